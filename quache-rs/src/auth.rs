@@ -0,0 +1,90 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+
+#[derive(Debug)]
+struct KeySet {
+    current: String,
+    /// Key replaced by the last rotation, and until when it's still accepted
+    previous: Option<(String, Instant)>,
+}
+
+/// API keys clients must present. Rotating swaps in a new key without blocking the requests being
+/// checked; the replaced key keeps working for `overlap`, so clients can switch over without
+/// failing requests.
+#[derive(Debug, Clone)]
+pub struct ApiKeys {
+    keys: Arc<ArcSwap<KeySet>>,
+    overlap: Duration,
+}
+
+/// Compares in time independent of where the inputs differ, so that keys can't be guessed
+/// byte by byte from response times.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0_u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+impl ApiKeys {
+    pub fn new(key: String, overlap: Duration) -> Self {
+        Self {
+            keys: Arc::new(ArcSwap::from_pointee(KeySet {
+                current: key,
+                previous: None,
+            })),
+            overlap,
+        }
+    }
+
+    /// Whether `candidate` is the current key.
+    pub fn is_current(&self, candidate: &str) -> bool {
+        constant_time_eq(&self.keys.load().current, candidate)
+    }
+
+    /// Whether `candidate` is the current key, or the previous one within its overlap window.
+    pub fn check(&self, candidate: &str) -> bool {
+        let keys = self.keys.load();
+        constant_time_eq(&keys.current, candidate)
+            || keys.previous.as_ref().is_some_and(|(previous, until)| {
+                Instant::now() < *until && constant_time_eq(previous, candidate)
+            })
+    }
+
+    /// Makes `new_key` the current key. The key it replaces is accepted for the overlap window.
+    pub fn rotate(&self, new_key: String) {
+        let until = Instant::now() + self.overlap;
+        self.keys.rcu(|keys| KeySet {
+            current: new_key.clone(),
+            previous: Some((keys.current.clone(), until)),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_keys_rotation() {
+        let keys = ApiKeys::new("old".to_string(), Duration::from_millis(100));
+        assert!(keys.check("old"));
+        assert!(!keys.check("ol"));
+        assert!(!keys.check("new"));
+
+        keys.rotate("new".to_string());
+        assert!(keys.check("new"));
+        assert!(keys.check("old"));
+        assert!(keys.is_current("new"));
+        assert!(!keys.is_current("old"));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(keys.check("new"));
+        assert!(!keys.check("old"));
+    }
+}
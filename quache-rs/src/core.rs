@@ -1,500 +1,6786 @@
 use std::{
-    collections::HashMap,
-    fs,
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    str::FromStr,
+    sync::{
+        Arc, LazyLock, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        TryLockError,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
     time,
 };
 
 use anyhow::{Result, anyhow};
+use dashmap::{DashMap, mapref::entry::Entry, mapref::one::Ref};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct ShardEntry {
-    ttl: f64,
-    value: serde_json::Value,
-    timestamp: u128,
+#[cfg(feature = "schema")]
+use crate::schema::Schemas;
+use crate::{
+    events::{ChangeEvent, ChangeListeners, ChangeOp},
+    flush::{FlushTarget, LocalTarget, write_file},
+    metrics::{LockMetrics, Metrics},
+};
+
+/// Errors callers may want to tell apart (e.g. to map them to different HTTP status codes).
+#[derive(Debug)]
+pub enum KVError {
+    /// The key is not stored in the KV store.
+    NotFound(String),
+    /// The key was stored, but its TTL had elapsed when it was accessed.
+    Expired(String),
+    /// The operation can't be applied to the value currently stored (e.g. incrementing a string).
+    Conflict(String),
+    /// The arguments of the operation are not valid.
+    InvalidInput(String),
+    /// The value doesn't match the JSON Schema configured for the key's prefix.
+    SchemaViolation(String),
 }
 
-#[derive(Debug, Clone)]
-pub struct Shard {
-    data: Arc<RwLock<HashMap<String, ShardEntry>>>,
+impl fmt::Display for KVError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KVError::NotFound(key) => write!(f, "key {} not found", echo_key(key)),
+            KVError::Expired(key) => write!(f, "key {} not found (expired)", echo_key(key)),
+            KVError::Conflict(msg) | KVError::InvalidInput(msg) | KVError::SchemaViolation(msg) => {
+                write!(f, "{}", msg)
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct KVStore {
-    shards: Vec<Shard>,
-    directory: String,
-    shard_dimensions: Arc<RwLock<HashMap<usize, usize>>>,
+impl std::error::Error for KVError {}
+
+/// Characters of a key echoed by error messages, unless changed with [`set_max_key_echo`].
+pub const DEFAULT_MAX_KEY_ECHO: usize = 128;
+static MAX_KEY_ECHO: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_KEY_ECHO);
+
+/// Sets how many characters of a key error messages echo: longer keys are truncated, so that
+/// very long keys don't bloat logs and responses.
+pub fn set_max_key_echo(max_key_echo: usize) {
+    MAX_KEY_ECHO.store(max_key_echo, Ordering::Relaxed);
 }
 
-impl ShardEntry {
-    pub fn new(value: serde_json::Value, ttl: Option<f64>) -> Self {
-        let actual_ttl = match ttl {
-            None => -1_f64,
-            Some(f) => f * 1000_f64,
-        };
-        let timestamp = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        Self {
-            value,
-            timestamp,
-            ttl: actual_ttl,
-        }
+/// Formats `key` for error messages, truncated with an ellipsis if it's too long.
+pub fn echo_key(key: &str) -> String {
+    let max_key_echo = MAX_KEY_ECHO.load(Ordering::Relaxed);
+    match key.char_indices().nth(max_key_echo) {
+        Some((end, _)) => format!("{}…", &key[..end]),
+        None => key.to_string(),
     }
 }
 
-impl Shard {
-    pub fn new() -> Self {
-        Self {
-            data: Arc::new(RwLock::new(HashMap::new())),
+/// Nesting depth of the values writes accept, unless changed with [`KVStore::with_max_json_depth`].
+/// It stays well below the 128 levels `serde_json` parses, which shard files add a couple to.
+pub const DEFAULT_MAX_JSON_DEPTH: usize = 64;
+
+/// Highest nesting depth [`KVStore::with_max_json_depth`] accepts, leaving room below the 128
+/// levels of `serde_json` for the levels shard files add.
+pub const MAX_JSON_DEPTH_LIMIT: usize = 120;
+
+/// TTL writes pass to create an entry that never expires, whatever the default TTL. Other
+/// non-positive TTLs are rejected.
+pub const PERSISTENT_TTL: f64 = -1_f64;
+
+/// Longest TTL (in seconds, about 31 700 years) writes accept, so that expiry times fit the
+/// millisecond counters they're stored and reported in.
+pub const MAX_TTL_SECS: f64 = 1e12;
+
+/// Shards flushed at the same time, unless changed with [`KVStore::with_flush_concurrency`].
+pub const DEFAULT_FLUSH_CONCURRENCY: usize = 4;
+
+/// Whether `value` nests arrays and objects more than `max_depth` levels deep (scalars are at
+/// depth 0, `[1]` at depth 1). Walks the value without recursing, however deep it is.
+fn exceeds_depth(value: &serde_json::Value, max_depth: usize) -> bool {
+    let mut stack = vec![(value, 0)];
+    while let Some((value, depth)) = stack.pop() {
+        let children: Vec<&serde_json::Value> = match value {
+            serde_json::Value::Array(items) => items.iter().collect(),
+            serde_json::Value::Object(fields) => fields.values().collect(),
+            _ => continue,
+        };
+        if depth == max_depth {
+            return true;
         }
+        stack.extend(children.into_iter().map(|child| (child, depth + 1)));
     }
+    false
+}
 
-    pub fn new_with_data(data: HashMap<String, ShardEntry>) -> Self {
-        Self {
-            data: Arc::new(RwLock::new(data)),
-        }
+/// Length of the hex-encoded md5 integrity hash heading every shard file.
+const INTEGRITY_HASH_LEN: usize = 32;
+const DEFAULT_BUILDER_SHARDS: usize = 5;
+const DEFAULT_BUILDER_DIRECTORY: &str = ".quache/";
+/// Random picks of an expired entry [`KVStore::random_entry`] tolerates before falling back to
+/// picking among the live entries
+const RANDOM_ENTRY_ATTEMPTS: usize = 5;
+
+fn integrity_hash(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
+/// Deserializes a TTL in milliseconds, accepting the floating point TTLs written by older versions.
+fn deserialize_ttl<'de, D>(deserializer: D) -> std::result::Result<i128, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let ttl = serde_json::Number::deserialize(deserializer)?;
+    if let Some(n) = ttl.as_i64() {
+        Ok(n as i128)
+    } else if let Some(n) = ttl.as_u64() {
+        Ok(n as i128)
+    } else {
+        Ok(ttl.as_f64().unwrap_or(-1_f64).round() as i128)
     }
+}
 
-    pub fn flush(&self, file_name: String) -> Result<()> {
-        let data = self.data.read().map_err(|e| anyhow!(e.to_string()))?;
-        let to_write = serde_json::to_string(&*data)?;
-        let integrity_hash = md5::compute(&to_write.clone().into_bytes());
-        let integrity_hash_string: String = integrity_hash
-            .to_vec()
+/// Splits the content of a shard file into its JSON payload, verifying the integrity hash.
+///
+/// Shard files start with a fixed-length header holding the hash of the payload, followed by a
+/// newline and the payload itself. Files written by older versions instead carry the hash (as the
+/// concatenated decimal digits of the md5 bytes) on their last line: those are still accepted.
+fn decode_shard_file(content: &str) -> Option<&str> {
+    let bytes = content.as_bytes();
+    if bytes.len() > INTEGRITY_HASH_LEN
+        && bytes[INTEGRITY_HASH_LEN] == b'\n'
+        && bytes[..INTEGRITY_HASH_LEN]
             .iter()
-            .map(|c| c.to_string())
-            .collect();
-        let full_content = format!("{}\n{}", to_write, integrity_hash_string);
-        fs::write(file_name, full_content.into_bytes())?;
-        Ok(())
+            .all(|b| b.is_ascii_hexdigit())
+    {
+        let payload = &content[INTEGRITY_HASH_LEN + 1..];
+        return (integrity_hash(payload.as_bytes()) == content[..INTEGRITY_HASH_LEN])
+            .then_some(payload);
     }
+    let (payload, legacy_hash) = content.rsplit_once('\n')?;
+    let computed_hash: String = md5::compute(payload.as_bytes())
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+    (computed_hash == legacy_hash).then_some(payload)
+}
 
-    pub fn evict(&self) -> Result<()> {
-        let mut data = self.data.write().map_err(|e| anyhow!(e.to_string()))?;
-        if data.len() == 0 {
-            return Ok(());
-        }
-        let current_time = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        let keys_to_remove: Vec<String> = data
-            .iter()
-            .filter(|(_, entry)| {
-                entry.ttl > 0_f64 && ((current_time - entry.timestamp) as f64) > entry.ttl
-            })
-            .map(|(k, _)| k.clone())
-            .collect();
-        for key in keys_to_remove {
-            data.remove(&key);
+/// Path of the file a shard is flushed to.
+pub fn shard_file_path(directory: &str, shard_idx: usize) -> String {
+    format!("{}/shard-{:?}", directory.trim_end_matches("/"), shard_idx)
+}
+
+/// Name of the file recording the layout of a store directory, next to the shard files.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Name of the directory (inside a store directory) a reshard writes the new shard files to,
+/// before moving them over the old ones.
+const RESHARD_DIR_NAME: &str = "reshard.tmp";
+
+/// Layout of the shard files of a store directory.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Manifest {
+    pub shards: usize,
+    /// Manifests written before hash strategies were recorded imply the default one
+    #[serde(default)]
+    pub hash_strategy: HashStrategy,
+}
+
+fn manifest_file_path(directory: &str) -> String {
+    format!("{}/{}", directory.trim_end_matches("/"), MANIFEST_FILE_NAME)
+}
+
+/// Reads the manifest of `directory`, `None` for directories written before manifests existed.
+pub fn read_manifest(directory: &str) -> Result<Option<Manifest>> {
+    let file_path = manifest_file_path(directory);
+    if !fs::exists(&file_path)? {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(file_path)?)?))
+}
+
+/// Hash strategy recorded in the manifest of `directory`, the default one if it has none.
+pub fn stored_hash_strategy(directory: &str) -> Result<HashStrategy> {
+    Ok(read_manifest(directory)?
+        .map(|manifest| manifest.hash_strategy)
+        .unwrap_or_default())
+}
+
+pub fn write_manifest(directory: &str, manifest: &Manifest) -> Result<()> {
+    fs::write(manifest_file_path(directory), serde_json::to_vec(manifest)?)?;
+    Ok(())
+}
+
+/// What to do when loading a directory whose manifest records a different number of shards
+/// than requested.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ShardMismatchPolicy {
+    /// Refuse to load the directory
+    #[default]
+    Error,
+    /// Rehash the stored keys into the requested number of shards, rewriting the shard files
+    Reshard,
+    /// Load the first shard files as they are: keys hashing to another shard are not found
+    Ignore,
+}
+
+impl FromStr for ShardMismatchPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(Self::Error),
+            "reshard" => Ok(Self::Reshard),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err(anyhow!(
+                "{} is not a shard mismatch policy (error, reshard, ignore)",
+                s
+            )),
         }
-        Ok(())
     }
+}
 
-    fn get_length(&self) -> Result<usize> {
-        let data = self.data.read().map_err(|e| anyhow!(e.to_string()))?;
-        Ok(data.len())
+/// Makes `directory` loadable with `num_shards` shards, comparing it with its manifest and
+/// applying `policy` on mismatch. Directories without a manifest are assumed to match, and get
+/// one. Keys keep being routed with the hash strategy recorded in the manifest.
+///
+/// Resharding writes the new shard files to a temporary directory first, then moves them over
+/// the old ones: a reshard interrupted before its new files are complete leaves `directory` as
+/// it was, one interrupted while moving them is completed by the next call.
+pub fn reconcile_shard_count(
+    directory: &str,
+    num_shards: usize,
+    policy: ShardMismatchPolicy,
+) -> Result<()> {
+    if !fs::exists(directory)? {
+        return Ok(());
+    }
+    finish_reshard(directory)?;
+    let (stored, hash_strategy) = match read_manifest(directory)? {
+        Some(manifest) if manifest.shards != num_shards => {
+            (manifest.shards, manifest.hash_strategy)
+        }
+        manifest => {
+            let hash_strategy = manifest.map(|m| m.hash_strategy).unwrap_or_default();
+            return write_manifest(
+                directory,
+                &Manifest {
+                    shards: num_shards,
+                    hash_strategy,
+                },
+            );
+        }
+    };
+    match policy {
+        ShardMismatchPolicy::Error => Err(KVError::InvalidInput(format!(
+            "{} holds {} shards, but {} were requested",
+            directory, stored, num_shards
+        ))
+        .into()),
+        ShardMismatchPolicy::Ignore => {
+            tracing::warn!(
+                "{} holds {} shards, loading it with {}: keys stored in another shard than they hash to won't be found",
+                directory,
+                stored,
+                num_shards
+            );
+            Ok(())
+        }
+        ShardMismatchPolicy::Reshard => {
+            let report = fsck(directory, num_shards, hash_strategy, false)?;
+            if !report.corrupt_shards.is_empty() {
+                return Err(anyhow!(
+                    "cannot reshard {}: shards {:?} are corrupt",
+                    directory,
+                    report.corrupt_shards
+                ));
+            }
+            tracing::info!(
+                "Resharding {} from {} to {} shards",
+                directory,
+                stored,
+                num_shards
+            );
+            let staging = reshard_dir_path(directory);
+            if fs::exists(&staging)? {
+                fs::remove_dir_all(&staging)?;
+            }
+            fs::create_dir_all(&staging)?;
+            for i in shard_file_indices(directory)? {
+                fs::copy(shard_file_path(directory, i), shard_file_path(&staging, i))?;
+            }
+            // every key is moved to the first `num_shards` files, the others are left empty
+            fsck(&staging, num_shards, hash_strategy, true)?;
+            for i in shard_file_indices(&staging)? {
+                if i >= num_shards {
+                    fs::remove_file(shard_file_path(&staging, i))?;
+                }
+            }
+            // the manifest marks the new shard files as complete
+            write_manifest(
+                &staging,
+                &Manifest {
+                    shards: num_shards,
+                    hash_strategy,
+                },
+            )?;
+            finish_reshard(directory)
+        }
     }
 }
 
-impl KVStore {
-    pub fn new(num_shards: usize, directory: String) -> Result<Self> {
-        if !fs::exists(&directory)? {
-            fs::create_dir_all(&directory)?;
+fn reshard_dir_path(directory: &str) -> String {
+    format!("{}/{}", directory.trim_end_matches("/"), RESHARD_DIR_NAME)
+}
+
+/// Moves the shard files of a complete reshard of `directory` over the old ones, or discards
+/// an incomplete one. Does nothing if no reshard was started.
+fn finish_reshard(directory: &str) -> Result<()> {
+    let staging = reshard_dir_path(directory);
+    if !fs::exists(&staging)? {
+        return Ok(());
+    }
+    // a manifest that can't be read was interrupted while being written
+    let Ok(Some(manifest)) = read_manifest(&staging) else {
+        tracing::warn!("Discarding the incomplete reshard of {}", directory);
+        return Ok(fs::remove_dir_all(&staging)?);
+    };
+    for i in shard_file_indices(&staging)? {
+        fs::rename(shard_file_path(&staging, i), shard_file_path(directory, i))?;
+    }
+    for i in shard_file_indices(directory)? {
+        if i >= manifest.shards {
+            fs::remove_file(shard_file_path(directory, i))?;
         }
-        let mut shards: Vec<Shard> = vec![];
-        let mut i = 0;
-        while i < num_shards {
-            shards.push(Shard::new());
-            i += 1;
+    }
+    write_manifest(directory, &manifest)?;
+    fs::remove_dir_all(&staging)?;
+    Ok(())
+}
+
+/// Returns the (sorted) indices of the shard files found in a data directory.
+pub fn shard_file_indices(directory: &str) -> Result<Vec<usize>> {
+    let mut indices: Vec<usize> = vec![];
+    for dir_entry in fs::read_dir(directory)? {
+        let file_name = dir_entry?.file_name();
+        if let Some(idx) = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix("shard-"))
+            .and_then(|idx| idx.parse::<usize>().ok())
+        {
+            indices.push(idx);
         }
-        Ok(Self {
-            directory,
-            shards,
-            shard_dimensions: Arc::new(RwLock::new(HashMap::new())),
-        })
     }
+    indices.sort();
+    Ok(indices)
+}
 
-    pub fn new_from_disk(num_shards: usize, directory: String) -> Result<Self> {
-        if !fs::exists(&directory)? {
-            return Err(anyhow!("directory {} does not exist", &directory));
+/// Randomly spreads a TTL (in seconds) by up to `jitter_percent` percent in either direction.
+/// Entries without a TTL never get one.
+fn jittered_ttl(ttl: Option<f64>, jitter_percent: f64) -> Option<f64> {
+    match ttl {
+        Some(t) if t > 0_f64 && jitter_percent > 0_f64 => {
+            let factor = 1_f64 + rand::random_range(-jitter_percent..=jitter_percent) / 100_f64;
+            Some(t * factor)
         }
-        let mut shards: Vec<Shard> = vec![];
-        let mut i = 0;
-        while i < num_shards {
-            let file_path = format!("{}/shard-{:?}", &directory.trim_end_matches("/"), i);
-            if fs::exists(&file_path)? {
-                println!("Loading shard {:?} from file", i);
-                let content = fs::read_to_string(&file_path)?;
-                let lines: Vec<&str> = content.split("\n").collect();
-                let integrity_hash_str = lines[lines.len() - 1].to_string();
-                let raw_data = lines[0..lines.len() - 1].join("\n");
-                let computed_hash = md5::compute(&raw_data.clone().into_bytes());
-                let computed_hash_string: String = computed_hash
-                    .to_vec()
-                    .iter()
-                    .map(|c| c.to_string())
-                    .collect();
-                if integrity_hash_str != computed_hash_string {
-                    return Err(anyhow!(
-                        "could not load shard {:?} because the computed hash does not match the reported integrity hash",
-                        i
-                    ));
-                }
-                let data: HashMap<String, ShardEntry> = serde_json::from_str(&raw_data)?;
-                shards.push(Shard::new_with_data(data));
-            } else {
-                println!(
-                    "File for shard {:?} not found, initializing an empty shard...",
-                    i
-                );
-                shards.push(Shard::new());
-            }
-            i += 1;
+        other => other,
+    }
+}
+
+/// What writes asking for a TTL above the maximum get, see [`KVStore::with_max_ttl`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TtlCapPolicy {
+    /// Fail the write
+    #[default]
+    Reject,
+    /// Store the entry with the maximum TTL instead
+    Clamp,
+}
+
+impl FromStr for TtlCapPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "clamp" => Ok(Self::Clamp),
+            _ => Err(anyhow!("{} is not a TTL cap policy (reject, clamp)", s)),
         }
-        Ok(Self {
-            shards,
-            directory,
-            shard_dimensions: Arc::new(RwLock::new(HashMap::new())),
-        })
     }
+}
+
+/// Hash function used to route keys to shards.
+///
+/// Shard files are only valid for the strategy that wrote them, which their manifest records.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashStrategy {
+    #[default]
+    Crc32,
+    /// 64-bit FNV-1a
+    Fnv1a,
+}
 
-    fn find_shard(&self, key: &str) -> usize {
-        let hash = crc32fast::hash(key.as_bytes()) as usize;
-        hash % self.shards.len()
+impl HashStrategy {
+    fn shard_index(&self, key: &str, num_shards: usize) -> usize {
+        let hash = match self {
+            HashStrategy::Crc32 => crc32fast::hash(key.as_bytes()) as u64,
+            HashStrategy::Fnv1a => key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            }),
+        };
+        (hash % num_shards as u64) as usize
     }
+}
 
-    pub fn put(&self, key: String, value: serde_json::Value, ttl: Option<f64>) -> Result<()> {
-        let shard_idx = self.find_shard(&key);
-        let entry = ShardEntry::new(value, ttl);
-        let mut data = self.shards[shard_idx]
-            .data
-            .write()
-            .map_err(|e| anyhow!(e.to_string()))?;
-        data.entry(key)
-            .and_modify(|v| *v = entry.clone())
-            .or_insert(entry);
+/// Resolves a `[start, stop]` range (inclusive, negative indices counting from the end) over a
+/// list of `len` items, the way Redis' `LRANGE` does. Returns `None` for empty ranges.
+fn list_bounds(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
 
-        Ok(())
+/// Stores integral scores as JSON integers, so that sorted sets round-trip the scores they were
+/// given.
+fn score_value(score: f64) -> serde_json::Value {
+    if score.fract() == 0_f64 && score.abs() < i64::MAX as f64 {
+        serde_json::Value::from(score as i64)
+    } else {
+        serde_json::Value::from(score)
     }
+}
 
-    pub fn get(&self, key: String) -> Result<serde_json::Value> {
-        let shard_idx = self.find_shard(&key);
-        let data = self.shards[shard_idx]
-            .data
-            .read()
-            .map_err(|e| anyhow!(e.to_string()))?;
-        match data.get(&key) {
-            None => return Err(anyhow!("key {} not found", key)),
-            Some(entry) => return Ok(entry.value.clone()),
+/// Reads the `{member: score}` object of a sorted set, sorted by score (ties broken by member).
+fn sorted_members(key: &str, value: &serde_json::Value) -> Result<Vec<(String, f64)>> {
+    let not_a_zset = || {
+        KVError::Conflict(format!(
+            "value of key {} is not a sorted set",
+            echo_key(key)
+        ))
+    };
+    let mut members = value
+        .as_object()
+        .ok_or_else(not_a_zset)?
+        .iter()
+        .map(|(member, score)| Ok((member.clone(), score.as_f64().ok_or_else(not_a_zset)?)))
+        .collect::<Result<Vec<(String, f64)>>>()?;
+    members.sort_by(|(m1, s1), (m2, s2)| s1.total_cmp(s2).then_with(|| m1.cmp(m2)));
+    Ok(members)
+}
+
+fn current_millis() -> u128 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShardEntry {
+    /// TTL in milliseconds, or -1 for entries that never expire
+    #[serde(deserialize_with = "deserialize_ttl")]
+    ttl: i128,
+    value: serde_json::Value,
+    timestamp: u128,
+    /// Per-key sequence number of the write that produced this entry
+    #[serde(default)]
+    seq: u64,
+    /// Casing the key was written with, when keys are stored case-insensitively
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_key: Option<String>,
+    /// Set once the entry was returned by [`KVStore::consume`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    consumed: bool,
+    /// Cached hash of the serialized value, empty until computed
+    #[serde(skip)]
+    value_hash: String,
+}
+
+/// Hash and serialized size of a value, computed before locking the shard it's written to
+/// when the value comes from the caller, so that the lock isn't held while serializing it.
+#[derive(Debug, Clone)]
+struct ValueDigest {
+    hash: String,
+    size: usize,
+}
+
+impl ValueDigest {
+    fn of(value: &serde_json::Value) -> Self {
+        let serialized = value.to_string();
+        Self {
+            hash: integrity_hash(serialized.as_bytes()),
+            size: serialized.len(),
         }
     }
+}
 
-    pub fn delete(&self, key: String) -> Result<()> {
-        let shard_idx = self.find_shard(&key);
-        let mut data = self.shards[shard_idx]
-            .data
-            .write()
-            .map_err(|e| anyhow!(e.to_string()))?;
-        data.remove(&key);
-        Ok(())
+/// What [`KVStore::upsert`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpsertOutcome {
+    Created,
+    Updated,
+    /// The key was left as it was, its state not meeting the conditions
+    Skipped,
+}
+
+/// One write of [`KVStore::put_many`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchPut {
+    pub key: String,
+    pub value: serde_json::Value,
+    #[serde(default)]
+    pub ttl: Option<f64>,
+    /// Revision the stored value must have for the write to apply: the hash of the value, as
+    /// found in the `ETag` of reads
+    #[serde(default)]
+    pub if_match: Option<String>,
+}
+
+/// Outcome of a write of [`KVStore::put_many`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchPutStatus {
+    Applied,
+    /// The key was missing or expired, or its revision didn't match `if_match`
+    Conflict,
+}
+
+/// An operation of a [`KVStore::transaction`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TxOp {
+    /// Outputs the live value of `key`, `null` if it's missing
+    Get { key: String },
+    /// Stores `value` under `key`, outputting `null`
+    Set {
+        key: String,
+        value: serde_json::Value,
+        #[serde(default)]
+        ttl: Option<f64>,
+    },
+    /// Deletes `key`, outputting whether it held a live entry
+    Del { key: String },
+    /// Adds `delta` (1 by default) to the integer stored under `key` (0 if missing), outputting
+    /// the new value
+    Incr {
+        key: String,
+        #[serde(default = "default_tx_delta")]
+        delta: i64,
+    },
+    /// Aborts the transaction unless the live value of `key` (`null` if missing) equals `value`,
+    /// outputting whether it did
+    AssertEquals {
+        key: String,
+        value: serde_json::Value,
+    },
+}
+
+fn default_tx_delta() -> i64 {
+    1
+}
+
+impl TxOp {
+    /// Key the operation applies to.
+    pub fn key(&self) -> &str {
+        match self {
+            TxOp::Get { key }
+            | TxOp::Set { key, .. }
+            | TxOp::Del { key }
+            | TxOp::Incr { key, .. }
+            | TxOp::AssertEquals { key, .. } => key,
+        }
     }
+}
 
-    pub fn to_disk(&mut self) -> Result<()> {
-        let mut i = 0;
-        while i < self.shards.len() {
-            let shard_length = self.shards[i].get_length()?;
-            let stored_shard_length: usize = {
-                let dims = self
-                    .shard_dimensions
-                    .read()
-                    .map_err(|e| anyhow!(e.to_string()))?;
-                dims.get(&i).copied().unwrap_or(0)
-            };
-            if shard_length == stored_shard_length {
-                // no changes, do not flush
-                i += 1;
-                continue;
-            }
-            {
-                let mut dims = self
-                    .shard_dimensions
-                    .write()
-                    .map_err(|e| anyhow!(e.to_string()))?;
-                dims.entry(i)
-                    .and_modify(|v| *v = shard_length)
-                    .or_insert(shard_length);
-            }
+/// Outcome of a [`KVStore::transaction`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxOutcome {
+    /// Whether the writes were applied, `false` if an assertion failed
+    pub committed: bool,
+    /// Output of each operation, in order, up to the failed assertion (if any)
+    pub results: Vec<serde_json::Value>,
+    /// Index of the assertion that aborted the transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed_op: Option<usize>,
+}
 
-            let file_path = format!("{}/shard-{:?}", &self.directory.trim_end_matches("/"), i);
-            self.shards[i].flush(file_path)?;
-            i += 1;
+/// Live value of a key, as returned by [`KVStore::get_live`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveValue {
+    pub value: serde_json::Value,
+    /// Time the entry has left to live, `None` for entries without a TTL
+    pub remaining: Option<time::Duration>,
+    /// Hash of the serialized value: identical values share the same hash
+    pub value_hash: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Shard {
+    data: ShardStorage,
+    /// Bumped after every change to the entries, starting from 1
+    version: Arc<AtomicU64>,
+    /// Version of the entries last written to the flush target, 0 if never written
+    flushed_version: Arc<AtomicU64>,
+    /// Where waits for the `RwLock` backend's lock are recorded, the store's metrics once loaded
+    lock_metrics: Arc<LockMetrics>,
+}
+
+type ShardData = RwLock<HashMap<String, ShardEntry>>;
+/// Shard entries, deserialized when first accessed (see [`Shard::from_file_lazy`]). A shard
+/// file that can't be deserialized keeps its error, returned by every access to the shard
+type LazyShardData =
+    LazyLock<Result<ShardData, String>, Box<dyn FnOnce() -> Result<ShardData, String> + Send>>;
+
+/// How the entries of a shard are stored and locked.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ShardBackend {
+    /// A `HashMap` behind a single `RwLock`: writes to a shard are serialized
+    #[default]
+    RwLock,
+    /// A `DashMap`, internally split in several locks: writes to different keys of a shard can
+    /// proceed concurrently. Operations spanning a whole shard (flushing, evictions, drains)
+    /// don't block the shard at once, so they may run concurrently with writes
+    DashMap,
+}
+
+impl FromStr for ShardBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rwlock" => Ok(Self::RwLock),
+            "dashmap" => Ok(Self::DashMap),
+            _ => Err(anyhow!("{} is not a shard backend (rwlock, dashmap)", s)),
         }
-        Ok(())
     }
+}
 
-    pub fn cleanup(&self) -> Result<()> {
-        let mut i = 0;
-        while i < self.shards.len() {
-            self.shards[i].evict()?;
-            i += 1;
+#[derive(Debug, Clone)]
+enum ShardStorage {
+    Locked(Arc<LazyShardData>),
+    Dash(Arc<DashMap<String, ShardEntry>>),
+}
+
+/// Read access to the entry of a key, see [`Shard::read_key`].
+enum KeyRef<'a> {
+    Locked(RwLockReadGuard<'a, HashMap<String, ShardEntry>>),
+    Dash(Option<Ref<'a, String, ShardEntry>>),
+}
+
+impl KeyRef<'_> {
+    fn get(&self, key: &str) -> Option<&ShardEntry> {
+        match self {
+            Self::Locked(data) => data.get(key),
+            Self::Dash(entry) => entry.as_ref().map(|entry| entry.value()),
         }
-        Ok(())
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use serial_test::serial;
+/// Write access to the entry of a key, see [`Shard::lock_key`]. It mirrors the `HashMap` API,
+/// but only the locked key may be passed to it.
+///
+/// Dropping the guard bumps the version of the shard, whether the entry changed or not: a
+/// flush starting afterwards sees the change, one already running is followed by another.
+struct KeyGuard<'a> {
+    lock: KeyLock<'a>,
+    version: &'a AtomicU64,
+}
 
-    use super::*;
+enum KeyLock<'a> {
+    Locked(RwLockWriteGuard<'a, HashMap<String, ShardEntry>>),
+    Dash {
+        map: &'a DashMap<String, ShardEntry>,
+        key: String,
+        /// `None` once the entry is removed, which releases the key
+        entry: Option<Entry<'a, String, ShardEntry>>,
+    },
+}
 
-    fn cleanup_test_file(file_name: String) {
-        if fs::exists(&file_name).expect("Should be able to check file existence") {
-            fs::remove_file(file_name).expect("Should be able to remove file");
+impl Drop for KeyGuard<'_> {
+    fn drop(&mut self) {
+        // the lock is still held: the change is in place by the time the version moves
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl KeyGuard<'_> {
+    fn get(&self, key: &str) -> Option<&ShardEntry> {
+        match &self.lock {
+            KeyLock::Locked(data) => data.get(key),
+            KeyLock::Dash {
+                entry: Some(Entry::Occupied(entry)),
+                ..
+            } => Some(entry.get()),
+            KeyLock::Dash { .. } => None,
         }
     }
 
-    fn cleanup_test_directory(directory_name: String) {
-        if fs::exists(&directory_name).expect("Should be able to check directory existence") {
-            fs::remove_dir_all(directory_name).expect("Should be able to remove directory content");
+    fn get_mut(&mut self, key: &str) -> Option<&mut ShardEntry> {
+        match &mut self.lock {
+            KeyLock::Locked(data) => data.get_mut(key),
+            KeyLock::Dash {
+                entry: Some(Entry::Occupied(entry)),
+                ..
+            } => Some(entry.get_mut()),
+            KeyLock::Dash { .. } => None,
         }
     }
 
-    #[test]
-    fn test_shard_entry_init() {
-        let shard_entry = ShardEntry::new(serde_json::Value::from("hello"), Some(0.001));
-        assert_eq!(shard_entry.value, serde_json::Value::from("hello"));
-        assert_eq!(shard_entry.ttl, 1_f64);
-        let current_time = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        assert!(current_time >= shard_entry.timestamp);
+    fn insert(&mut self, key: String, value: ShardEntry) -> Option<ShardEntry> {
+        match &mut self.lock {
+            KeyLock::Locked(data) => data.insert(key, value),
+            KeyLock::Dash {
+                map,
+                key: locked,
+                entry,
+            } => {
+                debug_assert_eq!(&key, locked);
+                match entry.take().unwrap_or_else(|| map.entry(key)) {
+                    Entry::Occupied(mut occupied) => {
+                        let previous = occupied.insert(value);
+                        *entry = Some(Entry::Occupied(occupied));
+                        Some(previous)
+                    }
+                    Entry::Vacant(vacant) => {
+                        *entry = Some(Entry::Occupied(vacant.insert_entry(value)));
+                        None
+                    }
+                }
+            }
+        }
     }
 
-    #[test]
-    fn test_shard_empty_init() {
-        let shard = Shard::new();
-        let data = shard.data.read().expect("Should be able to read data");
-        assert_eq!(data.len(), 0);
+    fn remove(&mut self, key: &str) -> Option<ShardEntry> {
+        match &mut self.lock {
+            KeyLock::Locked(data) => data.remove(key),
+            KeyLock::Dash { entry, .. } => match entry.take() {
+                Some(Entry::Occupied(occupied)) => Some(occupied.remove()),
+                other => {
+                    *entry = other;
+                    None
+                }
+            },
+        }
     }
+}
 
-    #[test]
-    fn test_shard_with_data_init() {
-        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
-        init_data.insert(
-            "hello".to_string(),
-            ShardEntry::new(serde_json::Value::from(1), None),
+/// Fails with [`KVError::NotFound`] or [`KVError::Expired`] unless `key` holds a live entry.
+fn check_live(data: &KeyGuard<'_>, key: &str, now: u128) -> Result<()> {
+    match data.get(key) {
+        None => Err(KVError::NotFound(key.to_string()).into()),
+        Some(entry) if entry.is_expired(now) => Err(KVError::Expired(key.to_string()).into()),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Removes the live entry stored under `key`, see [`check_live`]. Expired entries are evicted.
+fn take_live(data: &mut KeyGuard<'_>, key: &str, now: u128) -> Result<ShardEntry> {
+    let result = check_live(data, key, now);
+    let entry = data.remove(key);
+    result?;
+    Ok(entry.expect("the entry was just checked"))
+}
+
+/// Sequence number of an entry written under `key`, failing with [`KVError::Conflict`] if `nx`
+/// and `key` already holds a live entry.
+fn next_seq(data: &KeyGuard<'_>, key: &str, nx: bool, now: u128) -> Result<u64> {
+    match data.get(key) {
+        Some(existing) if nx && !existing.is_expired(now) => {
+            Err(KVError::Conflict(format!("key {} already exists", echo_key(key))).into())
+        }
+        Some(existing) => Ok(existing.seq + 1),
+        None => Ok(1),
+    }
+}
+
+/// A page of keys returned by [`KVStore::list_keys_shard_page`].
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ShardKeysPage {
+    pub keys: Vec<String>,
+    /// Cursor of the next page, `None` for the last page
+    pub next_cursor: Option<String>,
+    /// Number of shards whose keys were collected to build the page
+    pub shards_scanned: usize,
+}
+
+/// Returns, in lexicographic order, the first `limit` of `keys` past `after`, along with the
+/// cursor of the next page (`None` if no key is left). Only the returned keys get sorted.
+fn first_keys(
+    mut keys: Vec<String>,
+    after: Option<&str>,
+    limit: usize,
+) -> (Vec<String>, Option<String>) {
+    // An empty page would never move the cursor forward
+    let limit = limit.max(1);
+    if let Some(after) = after {
+        keys.retain(|key| key.as_str() > after);
+    }
+    if keys.len() <= limit {
+        keys.sort_unstable();
+        return (keys, None);
+    }
+    keys.select_nth_unstable(limit - 1);
+    keys.truncate(limit);
+    keys.sort_unstable();
+    let cursor = keys.last().cloned();
+    (keys, cursor)
+}
+
+/// Parses a `<shard>:<last key>` cursor of [`KVStore::list_keys_shard_page`].
+fn parse_shard_cursor(cursor: &str, shards: usize) -> Result<(usize, Option<String>)> {
+    let invalid = || KVError::InvalidInput(format!("invalid shard cursor {}", echo_key(cursor)));
+    let (shard, after) = cursor.split_once(':').ok_or_else(invalid)?;
+    let shard: usize = shard.parse().map_err(|_| invalid())?;
+    if shard >= shards {
+        return Err(invalid().into());
+    }
+    Ok((shard, (!after.is_empty()).then(|| after.to_string())))
+}
+
+/// How keys would be redistributed if the store was resharded, computed without moving any key.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RebalancePlan {
+    pub current_shards: usize,
+    pub new_shards: usize,
+    /// Number of stored entries per current shard
+    pub shard_loads: Vec<usize>,
+    /// Number of stored entries each new shard would hold
+    pub new_shard_loads: Vec<usize>,
+    pub total_keys: usize,
+    /// Number of keys that would land in a shard with a different index
+    pub keys_to_move: usize,
+}
+
+/// Outcome of the latest calls to [`KVStore::to_disk`], so that broken persistence can be noticed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct FlushStatus {
+    /// Millisecond timestamp of the latest flush, `None` if the store was never flushed
+    pub last_flush_ms: Option<u64>,
+    /// Error of the latest flush, `None` if it succeeded
+    pub last_error: Option<String>,
+    /// Number of flushes that failed since the latest successful one
+    pub consecutive_failures: u64,
+}
+
+/// Runs of [`KVStore::cleanup`], so that a stalled cleanup thread can be noticed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CleanupStatus {
+    /// Millisecond timestamp at which the latest cleanup started, `None` if none did
+    pub last_started_ms: Option<u64>,
+    /// Millisecond timestamp at which the latest cleanup finished, `None` if none did. Older than
+    /// `last_started_ms` while a cleanup is running (or if the latest one failed).
+    pub last_finished_ms: Option<u64>,
+    /// Entries evicted by the latest finished cleanup
+    pub last_evicted: usize,
+    /// Entries evicted by all cleanups since the store was opened
+    pub total_evicted: u64,
+}
+
+/// Progress of the running (or latest) flush, as returned by [`KVStore::flush_progress`].
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct FlushProgress {
+    pub running: bool,
+    pub shards_total: usize,
+    /// Shards processed so far, including the unchanged ones that didn't need writing
+    pub shards_flushed: usize,
+    pub bytes_written: u64,
+    /// Time spent flushing so far, or by the latest flush once it's over
+    pub elapsed_ms: u64,
+}
+
+/// Counters behind [`FlushProgress`], updated by the flushing thread as it goes.
+#[derive(Debug, Default)]
+struct FlushProgressCounters {
+    running: AtomicBool,
+    shards_total: AtomicUsize,
+    shards_flushed: AtomicUsize,
+    bytes_written: AtomicU64,
+    started_ms: AtomicU64,
+    elapsed_ms: AtomicU64,
+}
+
+impl FlushProgressCounters {
+    fn start(&self, shards_total: usize) {
+        self.shards_total.store(shards_total, Ordering::Relaxed);
+        self.shards_flushed.store(0, Ordering::Relaxed);
+        self.bytes_written.store(0, Ordering::Relaxed);
+        self.started_ms
+            .store(current_millis() as u64, Ordering::Relaxed);
+        self.elapsed_ms.store(0, Ordering::Relaxed);
+        self.running.store(true, Ordering::Release);
+    }
+
+    fn finish(&self) {
+        let started_ms = self.started_ms.load(Ordering::Relaxed);
+        self.elapsed_ms.store(
+            (current_millis() as u64).saturating_sub(started_ms),
+            Ordering::Relaxed,
+        );
+        self.running.store(false, Ordering::Release);
+    }
+
+    fn snapshot(&self) -> FlushProgress {
+        let running = self.running.load(Ordering::Acquire);
+        let elapsed_ms = if running {
+            (current_millis() as u64).saturating_sub(self.started_ms.load(Ordering::Relaxed))
+        } else {
+            self.elapsed_ms.load(Ordering::Relaxed)
+        };
+        FlushProgress {
+            running,
+            shards_total: self.shards_total.load(Ordering::Relaxed),
+            shards_flushed: self.shards_flushed.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            elapsed_ms,
+        }
+    }
+}
+
+/// Issues found in a data directory by [`fsck`], and whether they were repaired.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct FsckReport {
+    pub shards_checked: usize,
+    /// Shard files failing the integrity check. Repairs leave them untouched
+    pub corrupt_shards: Vec<usize>,
+    /// Keys stored in more than one shard file
+    pub duplicate_keys: Vec<String>,
+    /// Keys stored in a shard file other than the one they hash to
+    pub misplaced_keys: Vec<String>,
+    /// Keys moved to the shard they hash to by the repair
+    pub moved_keys: usize,
+    /// Shard files rewritten by the repair
+    pub rewritten_shards: Vec<usize>,
+}
+
+impl FsckReport {
+    /// Whether the directory is still inconsistent, i.e. issues were found and not repaired.
+    pub fn has_unresolved_issues(&self) -> bool {
+        !self.corrupt_shards.is_empty()
+            || (self.rewritten_shards.is_empty()
+                && !(self.duplicate_keys.is_empty() && self.misplaced_keys.is_empty()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KVStore {
+    shards: Vec<Shard>,
+    directory: String,
+    shard_dimensions: Arc<RwLock<HashMap<usize, usize>>>,
+    metrics: Arc<Metrics>,
+    case_insensitive: bool,
+    ttl_jitter_percent: f64,
+    /// Granularity (in ms) TTLs are rounded to
+    ttl_resolution_ms: u64,
+    /// Smallest TTL (in ms) written so far, `u64::MAX` if none
+    min_ttl_seen: Arc<AtomicU64>,
+    hash_strategy: HashStrategy,
+    /// In-memory stores never touch the filesystem
+    in_memory: bool,
+    listeners: ChangeListeners,
+    /// Set while the background flush loop must leave the directory alone
+    flush_paused: Arc<AtomicBool>,
+    /// Held while flushing, so that pausing can wait for the flush in progress
+    flush_lock: Arc<Mutex<()>>,
+    /// Where [`KVStore::to_disk`] writes the shards, the store directory by default
+    flush_target: Arc<dyn FlushTarget>,
+    flush_status: Arc<RwLock<FlushStatus>>,
+    cleanup_status: Arc<RwLock<CleanupStatus>>,
+    flush_progress: Arc<FlushProgressCounters>,
+    /// Most shards [`KVStore::to_disk`] writes at the same time
+    flush_concurrency: usize,
+    /// Flush shards as pretty-printed JSON
+    pretty_disk: bool,
+    /// Reads restart the TTL of the entries they return
+    sliding_expiration: bool,
+    /// TTL (in seconds) given to the writes that don't specify one
+    default_ttl: Option<f64>,
+    /// Longest TTL (in seconds) writes can ask for
+    max_ttl: Option<f64>,
+    ttl_cap_policy: TtlCapPolicy,
+    forbid_persistent: bool,
+    /// Only accept TTLs that are a whole number of seconds
+    strict_ttl_seconds: bool,
+    /// Leave the expired entries not evicted yet out of key enumerations
+    probe_expired_on_scan: bool,
+    /// Most expired entries a cleanup removes from each shard
+    eviction_batch_size: usize,
+    max_json_depth: usize,
+    #[cfg(feature = "schema")]
+    schemas: Option<Arc<Schemas>>,
+    shard_backend: ShardBackend,
+}
+
+/// Fluent alternative to [`KVStore::new`], handy when embedding the store (e.g. in tests).
+#[derive(Debug, Clone)]
+pub struct KVStoreBuilder {
+    shards: usize,
+    directory: String,
+    in_memory: bool,
+    hash_strategy: HashStrategy,
+    shard_backend: ShardBackend,
+}
+
+impl Default for KVStoreBuilder {
+    fn default() -> Self {
+        Self {
+            shards: DEFAULT_BUILDER_SHARDS,
+            directory: DEFAULT_BUILDER_DIRECTORY.to_string(),
+            in_memory: false,
+            hash_strategy: HashStrategy::default(),
+            shard_backend: ShardBackend::default(),
+        }
+    }
+}
+
+impl KVStoreBuilder {
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.shards = shards;
+        self
+    }
+
+    pub fn directory(mut self, directory: impl Into<String>) -> Self {
+        self.directory = directory.into();
+        self
+    }
+
+    /// Keeps the store purely in memory: no directory is created and `to_disk` does nothing.
+    pub fn in_memory(mut self) -> Self {
+        self.in_memory = true;
+        self
+    }
+
+    pub fn hash_strategy(mut self, hash_strategy: HashStrategy) -> Self {
+        self.hash_strategy = hash_strategy;
+        self
+    }
+
+    pub fn shard_backend(mut self, shard_backend: ShardBackend) -> Self {
+        self.shard_backend = shard_backend;
+        self
+    }
+
+    pub fn build(self) -> Result<KVStore> {
+        if self.shards == 0 {
+            return Err(
+                KVError::InvalidInput("the number of shards must be positive".to_string()).into(),
+            );
+        }
+        let mut kv_store = if self.in_memory {
+            KVStore::from_shards(
+                (0..self.shards).map(|_| Shard::new()).collect(),
+                self.directory,
+            )
+        } else {
+            KVStore::new(self.shards, self.directory)?
+        };
+        kv_store.in_memory = self.in_memory;
+        kv_store.hash_strategy = self.hash_strategy;
+        if !self.in_memory {
+            write_manifest(
+                &kv_store.directory,
+                &Manifest {
+                    shards: self.shards,
+                    hash_strategy: self.hash_strategy,
+                },
+            )?;
+        }
+        kv_store.with_shard_backend(self.shard_backend)
+    }
+}
+
+impl ShardEntry {
+    pub fn new(value: serde_json::Value, ttl: Option<f64>) -> Self {
+        Self::with_ttl_resolution(value, ttl, 1)
+    }
+
+    /// Like [`ShardEntry::new`], rounding the TTL (in seconds) to the nearest multiple of
+    /// `resolution_ms` milliseconds, and up to `resolution_ms` if it's shorter.
+    pub fn with_ttl_resolution(
+        value: serde_json::Value,
+        ttl: Option<f64>,
+        resolution_ms: u64,
+    ) -> Self {
+        let actual_ttl = match ttl {
+            None => -1,
+            Some(f) if f > 0_f64 => {
+                let resolution = resolution_ms.max(1) as i128;
+                let ttl_ms = (f * 1000_f64).round() as i128;
+                (ttl_ms.saturating_add(resolution / 2) / resolution * resolution).max(resolution)
+            }
+            Some(f) => (f * 1000_f64).round() as i128,
+        };
+        Self {
+            value,
+            timestamp: current_millis(),
+            ttl: actual_ttl,
+            seq: 0,
+            original_key: None,
+            consumed: false,
+            value_hash: String::new(),
+        }
+    }
+
+    /// Hash of the serialized value, computed unless cached.
+    pub fn value_hash(&self) -> String {
+        if self.value_hash.is_empty() {
+            integrity_hash(self.value.to_string().as_bytes())
+        } else {
+            self.value_hash.clone()
+        }
+    }
+
+    /// Key to show when enumerating the entry stored under `stored_key`.
+    fn display_key<'a>(&'a self, stored_key: &'a str) -> &'a str {
+        self.original_key.as_deref().unwrap_or(stored_key)
+    }
+
+    /// TTL in milliseconds, as stored (-1 for entries that never expire).
+    pub fn ttl_ms(&self) -> i128 {
+        self.ttl
+    }
+
+    /// Millisecond timestamp of the write that produced the entry.
+    pub fn timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn value(&self) -> &serde_json::Value {
+        &self.value
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Whether the entry's TTL has elapsed by now.
+    pub fn expired(&self) -> bool {
+        self.is_expired(current_millis())
+    }
+
+    /// Time the entry has left to live as of now (zero once elapsed), or `None` for persistent
+    /// entries.
+    pub fn remaining_ttl(&self) -> Option<time::Duration> {
+        self.remaining_ttl_at(current_millis())
+    }
+
+    fn is_expired(&self, current_time: u128) -> bool {
+        self.ttl > 0 && (current_time.saturating_sub(self.timestamp) as i128) > self.ttl
+    }
+
+    fn live_value(&self, current_time: u128) -> LiveValue {
+        LiveValue {
+            value: self.value.clone(),
+            remaining: self.remaining_ttl_at(current_time),
+            value_hash: self.value_hash(),
+        }
+    }
+
+    /// Millisecond timestamp at which the entry expires, or `None` for persistent entries.
+    fn expires_at(&self) -> Option<u128> {
+        (self.ttl > 0).then(|| self.timestamp + self.ttl as u128)
+    }
+
+    /// Time left before the entry expires as of `current_time`, or `None` for persistent entries.
+    fn remaining_ttl_at(&self, current_time: u128) -> Option<time::Duration> {
+        if self.ttl <= 0 {
+            return None;
+        }
+        let elapsed = current_time.saturating_sub(self.timestamp) as i128;
+        Some(time::Duration::from_millis(
+            (self.ttl - elapsed).max(0) as u64
+        ))
+    }
+}
+
+impl Default for Shard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shard {
+    /// An empty shard. It isn't dirty: a shard without a flushed file loads empty as well.
+    pub fn new() -> Self {
+        let shard = Self::new_with_data(HashMap::new());
+        shard.mark_flushed();
+        shard
+    }
+
+    pub fn new_with_data(data: HashMap<String, ShardEntry>) -> Self {
+        Self::with_storage(ShardStorage::Locked(Arc::new(LazyLock::new(Box::new(
+            move || Ok(RwLock::new(data)),
+        )))))
+    }
+
+    /// A shard holding `data`, never flushed yet.
+    fn with_storage(data: ShardStorage) -> Self {
+        Self {
+            data,
+            version: Arc::new(AtomicU64::new(1)),
+            flushed_version: Arc::new(AtomicU64::new(0)),
+            lock_metrics: Arc::new(LockMetrics::default()),
+        }
+    }
+
+    /// Records the lock waits of the shard in `lock_metrics`.
+    fn with_lock_metrics(self, lock_metrics: Arc<LockMetrics>) -> Self {
+        Self {
+            lock_metrics,
+            ..self
+        }
+    }
+
+    /// The entries of `data`, deserializing them on the first access to a lazily loaded shard.
+    fn loaded(data: &LazyShardData) -> Result<&ShardData> {
+        data.as_ref().map_err(|e| anyhow!(e.clone()))
+    }
+
+    /// Takes the read lock of `data`, recording the wait if it was write-locked.
+    fn read_data<'a>(
+        &self,
+        data: &'a LazyShardData,
+    ) -> Result<RwLockReadGuard<'a, HashMap<String, ShardEntry>>> {
+        let data = Self::loaded(data)?;
+        match data.try_read() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::WouldBlock) => {
+                let start = time::Instant::now();
+                let guard = data.read().map_err(|e| anyhow!(e.to_string()))?;
+                self.lock_metrics.record_contended(start.elapsed());
+                Ok(guard)
+            }
+            Err(TryLockError::Poisoned(e)) => Err(anyhow!(e.to_string())),
+        }
+    }
+
+    /// Takes the write lock of `data`, recording the wait if it was locked.
+    fn write_data<'a>(
+        &self,
+        data: &'a LazyShardData,
+    ) -> Result<RwLockWriteGuard<'a, HashMap<String, ShardEntry>>> {
+        let data = Self::loaded(data)?;
+        match data.try_write() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::WouldBlock) => {
+                let start = time::Instant::now();
+                let guard = data.write().map_err(|e| anyhow!(e.to_string()))?;
+                self.lock_metrics.record_contended(start.elapsed());
+                Ok(guard)
+            }
+            Err(TryLockError::Poisoned(e)) => Err(anyhow!(e.to_string())),
+        }
+    }
+
+    /// Moves the entries of the shard to a `backend` shard.
+    pub fn with_backend(self, backend: ShardBackend) -> Result<Self> {
+        let data = match (self.data, backend) {
+            (ShardStorage::Locked(data), ShardBackend::DashMap) => {
+                let mut data = Self::loaded(&data)?
+                    .write()
+                    .map_err(|e| anyhow!(e.to_string()))?;
+                ShardStorage::Dash(Arc::new(std::mem::take(&mut *data).into_iter().collect()))
+            }
+            (ShardStorage::Dash(data), ShardBackend::RwLock) => {
+                let entries = data
+                    .iter()
+                    .map(|item| (item.key().clone(), item.value().clone()))
+                    .collect();
+                data.clear();
+                Self::new_with_data(entries).data
+            }
+            (data, _) => data,
+        };
+        Ok(Self { data, ..self })
+    }
+
+    /// Marks the entries as changed, see [`Shard::is_dirty`].
+    fn touch(&self) {
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Whether the entries may have changed since they were last written to the flush target.
+    pub fn is_dirty(&self) -> bool {
+        self.version.load(Ordering::Acquire) != self.flushed_version.load(Ordering::Acquire)
+    }
+
+    /// Records the entries as matching the flush target, e.g. once loaded from it.
+    fn mark_flushed(&self) {
+        self.flushed_version
+            .store(self.version.load(Ordering::Acquire), Ordering::Release);
+    }
+
+    /// Locks the entry of `key` for reading.
+    fn read_key(&self, key: &str) -> Result<KeyRef<'_>> {
+        match &self.data {
+            ShardStorage::Locked(data) => Ok(KeyRef::Locked(self.read_data(data)?)),
+            ShardStorage::Dash(data) => Ok(KeyRef::Dash(data.get(key))),
+        }
+    }
+
+    /// Calls `f` with each of `writes`, in order, along with the entry stored under its key, and
+    /// stores the entry `f` returns, if any. The `RwLock` backend's write lock is only taken
+    /// once for the whole batch; `DashMap` shards lock the keys one after the other.
+    fn write_batch<T>(
+        &self,
+        writes: Vec<(String, T)>,
+        mut f: impl FnMut(&str, Option<&ShardEntry>, T) -> Option<ShardEntry>,
+    ) -> Result<()> {
+        match &self.data {
+            ShardStorage::Locked(data) => {
+                let mut data = self.write_data(data)?;
+                for (key, write) in writes {
+                    if let Some(entry) = f(&key, data.get(&key), write) {
+                        data.insert(key, entry);
+                    }
+                }
+                self.touch();
+            }
+            ShardStorage::Dash(_) => {
+                for (key, write) in writes {
+                    let mut data = self.lock_key(&key)?;
+                    if let Some(entry) = f(&key, data.get(&key), write) {
+                        data.insert(key, entry);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Locks the entry of `key` for writing. With the `RwLock` backend, this locks the whole
+    /// shard.
+    fn lock_key(&self, key: &str) -> Result<KeyGuard<'_>> {
+        let lock = match &self.data {
+            ShardStorage::Locked(data) => KeyLock::Locked(self.write_data(data)?),
+            ShardStorage::Dash(data) => KeyLock::Dash {
+                map: data,
+                key: key.to_string(),
+                entry: Some(data.entry(key.to_string())),
+            },
+        };
+        Ok(KeyGuard {
+            lock,
+            version: &self.version,
+        })
+    }
+
+    /// Calls `f` on every stored entry, expired ones included.
+    fn for_each_entry(&self, mut f: impl FnMut(&String, &ShardEntry)) -> Result<()> {
+        match &self.data {
+            ShardStorage::Locked(data) => {
+                let data = self.read_data(data)?;
+                data.iter().for_each(|(key, entry)| f(key, entry));
+            }
+            ShardStorage::Dash(data) => data.iter().for_each(|item| f(item.key(), item.value())),
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with the entry stored under each of `keys` (`None` for missing ones), taking the
+    /// `RwLock` backend's read lock only once.
+    fn for_each_key(&self, keys: &[&str], mut f: impl FnMut(Option<&ShardEntry>)) -> Result<()> {
+        match &self.data {
+            ShardStorage::Locked(data) => {
+                let data = self.read_data(data)?;
+                keys.iter().for_each(|key| f(data.get(*key)));
+            }
+            ShardStorage::Dash(data) => keys.iter().for_each(|key| f(data.get(*key).as_deref())),
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of every stored entry (expired ones included) sorted by key, e.g. to
+    /// inspect them with the [`ShardEntry`] accessors.
+    pub fn entries_snapshot(&self) -> Result<Vec<(String, ShardEntry)>> {
+        let mut entries: Vec<(String, ShardEntry)> = self.entries()?.into_iter().collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries)
+    }
+
+    /// Returns a copy of every stored entry, expired ones included.
+    fn entries(&self) -> Result<HashMap<String, ShardEntry>> {
+        let mut entries = HashMap::new();
+        self.for_each_entry(|key, entry| {
+            entries.insert(key.clone(), entry.clone());
+        })?;
+        Ok(entries)
+    }
+
+    /// Returns the `n`-th stored entry (in no particular order), unless it expired.
+    fn nth_live_entry(&self, n: usize) -> Result<Option<(String, serde_json::Value)>> {
+        let current_time = current_millis();
+        let live = |key: &String, entry: &ShardEntry| {
+            (!entry.is_expired(current_time))
+                .then(|| (entry.display_key(key).to_string(), entry.value.clone()))
+        };
+        match &self.data {
+            ShardStorage::Locked(data) => {
+                let data = self.read_data(data)?;
+                Ok(data.iter().nth(n).and_then(|(key, entry)| live(key, entry)))
+            }
+            ShardStorage::Dash(data) => Ok(data
+                .iter()
+                .nth(n)
+                .and_then(|item| live(item.key(), item.value()))),
+        }
+    }
+
+    /// Removes the entries whose key starts with `prefix`, returning them.
+    fn drain_prefix(&self, prefix: &str) -> Result<Vec<(String, ShardEntry)>> {
+        match &self.data {
+            ShardStorage::Locked(data) => {
+                let mut data = self.write_data(data)?;
+                let keys: Vec<String> = data
+                    .keys()
+                    .filter(|k| k.starts_with(prefix))
+                    .cloned()
+                    .collect();
+                let drained: Vec<(String, ShardEntry)> = keys
+                    .into_iter()
+                    .filter_map(|key| data.remove_entry(&key))
+                    .collect();
+                if !drained.is_empty() {
+                    self.touch();
+                }
+                Ok(drained)
+            }
+            ShardStorage::Dash(data) => {
+                let keys: Vec<String> = data
+                    .iter()
+                    .filter(|item| item.key().starts_with(prefix))
+                    .map(|item| item.key().clone())
+                    .collect();
+                let drained: Vec<(String, ShardEntry)> =
+                    keys.iter().filter_map(|key| data.remove(key)).collect();
+                if !drained.is_empty() {
+                    self.touch();
+                }
+                Ok(drained)
+            }
+        }
+    }
+
+    /// Replaces the entries of the shard with `entries`.
+    fn replace_entries(&self, entries: HashMap<String, ShardEntry>) -> Result<()> {
+        match &self.data {
+            ShardStorage::Locked(data) => {
+                *self.write_data(data)? = entries;
+            }
+            ShardStorage::Dash(data) => {
+                data.clear();
+                for (key, entry) in entries {
+                    data.insert(key, entry);
+                }
+            }
+        }
+        self.touch();
+        Ok(())
+    }
+
+    /// Parses the JSON payload of a shard file, caching the hash of every value.
+    fn parse_entries(raw_data: &[u8]) -> Result<HashMap<String, ShardEntry>> {
+        let mut data: HashMap<String, ShardEntry> = serde_json::from_slice(raw_data)?;
+        for entry in data.values_mut() {
+            entry.value_hash = entry.value_hash();
+        }
+        Ok(data)
+    }
+
+    /// Loads a shard from a file written by [`Shard::flush`], verifying its integrity hash.
+    pub fn from_file(file_name: &str) -> Result<Self> {
+        let content = fs::read_to_string(file_name)?;
+        Self::decode(&content, file_name)
+    }
+
+    /// Loads a shard from contents produced by [`Shard::encode`], verifying their integrity
+    /// hash. `source` only names where the contents come from in errors.
+    pub fn decode(content: &str, source: &str) -> Result<Self> {
+        let Some(raw_data) = decode_shard_file(content) else {
+            return Err(anyhow!(
+                "could not load shard file {} because the computed hash does not match the reported integrity hash",
+                source
+            ));
+        };
+        Ok(Self::new_with_data(Self::parse_entries(
+            raw_data.as_bytes(),
+        )?))
+    }
+
+    /// Like [`Shard::from_file`], but only deserializes the entries when the shard is first
+    /// accessed, which makes loading large stores faster. The file is still read, and its
+    /// integrity hash verified, upfront: if the entries then can't be deserialized, every
+    /// access to the shard fails with that error.
+    pub fn from_file_lazy(file_name: &str) -> Result<Self> {
+        let content = fs::read_to_string(file_name)?;
+        let Some(raw_data) = decode_shard_file(&content) else {
+            return Err(anyhow!(
+                "could not load shard file {} because the computed hash does not match the reported integrity hash",
+                file_name
+            ));
+        };
+        let start = raw_data.as_ptr() as usize - content.as_ptr() as usize;
+        let end = start + raw_data.len();
+        let source = file_name.to_string();
+        let load: Box<dyn FnOnce() -> Result<ShardData, String> + Send> = Box::new(move || {
+            Self::parse_entries(&content.as_bytes()[start..end])
+                .map(RwLock::new)
+                .map_err(|e| format!("shard file {} can't be deserialized: {}", source, e))
+        });
+        Ok(Self::with_storage(ShardStorage::Locked(Arc::new(
+            LazyLock::new(load),
+        ))))
+    }
+
+    /// Serializes the shard, headed by its integrity hash.
+    pub fn encode(&self) -> Result<String> {
+        let to_write = match &self.data {
+            ShardStorage::Locked(data) => {
+                let data = self.read_data(data)?;
+                serde_json::to_string(&*data)?
+            }
+            ShardStorage::Dash(data) => serde_json::to_string(&**data)?,
+        };
+        Self::with_integrity_hash(to_write)
+    }
+
+    /// Same as [`Shard::encode`], but pretty-printed with sorted keys, so that shard files are
+    /// readable and diff well. Both forms are loaded the same way.
+    pub fn encode_pretty(&self) -> Result<String> {
+        let sorted: BTreeMap<String, ShardEntry> = self.entries()?.into_iter().collect();
+        let to_write = serde_json::to_string_pretty(&sorted)?;
+        Self::with_integrity_hash(to_write)
+    }
+
+    fn with_integrity_hash(to_write: String) -> Result<String> {
+        Ok(format!(
+            "{}\n{}",
+            integrity_hash(to_write.as_bytes()),
+            to_write
+        ))
+    }
+
+    /// Writes the encoded shard to `file_name`, syncing it to the disk first if `fsync` (see
+    /// [`write_file`]).
+    pub fn flush(&self, file_name: String, fsync: bool) -> Result<()> {
+        write_file(&file_name, self.encode()?.as_bytes(), fsync)
+    }
+
+    /// Removes the expired entries, returning how many were removed.
+    pub fn evict(&self) -> Result<usize> {
+        self.evict_up_to(usize::MAX)
+    }
+
+    /// Removes at most `limit` expired entries, returning how many were removed, so that shards
+    /// with many expired entries aren't locked for long: the others are left for later calls.
+    pub fn evict_up_to(&self, limit: usize) -> Result<usize> {
+        Ok(self.evict_keys(limit)?.len())
+    }
+
+    /// Removes at most `limit` expired entries, returning their keys (with the casing they were
+    /// written with). The shard is unlocked by the time it returns.
+    pub fn evict_keys(&self, limit: usize) -> Result<Vec<String>> {
+        let data = match &self.data {
+            ShardStorage::Locked(data) => data,
+            ShardStorage::Dash(data) => {
+                let current_time = current_millis();
+                let mut evicted = vec![];
+                // retain visits every entry under its lock, unlike removing while iterating
+                data.retain(|key, entry| {
+                    let expired = evicted.len() < limit && entry.is_expired(current_time);
+                    if expired {
+                        evicted.push(entry.display_key(key).to_string());
+                    }
+                    !expired
+                });
+                if !evicted.is_empty() {
+                    self.touch();
+                }
+                return Ok(evicted);
+            }
+        };
+        let mut data = self.write_data(data)?;
+        if data.is_empty() {
+            return Ok(vec![]);
+        }
+        let current_time = current_millis();
+        let keys_to_remove: Vec<String> = data
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(current_time))
+            .map(|(k, _)| k.clone())
+            .take(limit)
+            .collect();
+        let mut evicted = vec![];
+        for key in keys_to_remove {
+            if let Some(entry) = data.remove(&key) {
+                evicted.push(entry.display_key(&key).to_string());
+            }
+        }
+        if !evicted.is_empty() {
+            self.touch();
+        }
+        Ok(evicted)
+    }
+
+    /// Releases the memory left over by removed entries.
+    pub fn compact(&self) -> Result<()> {
+        match &self.data {
+            ShardStorage::Locked(data) => self.write_data(data)?.shrink_to_fit(),
+            ShardStorage::Dash(data) => data.shrink_to_fit(),
+        }
+        Ok(())
+    }
+
+    fn get_length(&self) -> Result<usize> {
+        match &self.data {
+            ShardStorage::Locked(data) => Ok(self.read_data(data)?.len()),
+            ShardStorage::Dash(data) => Ok(data.len()),
+        }
+    }
+
+    /// Returns the unexpired entries whose key starts with `prefix` (all of them if `prefix` is `None`).
+    ///
+    /// Keys are returned with the casing they were written with.
+    pub fn live_entries(&self, prefix: Option<&str>) -> Result<Vec<(String, serde_json::Value)>> {
+        let current_time = current_millis();
+        let mut entries = vec![];
+        self.for_each_entry(|k, entry| {
+            if prefix.is_none_or(|p| k.starts_with(p)) && !entry.is_expired(current_time) {
+                entries.push((entry.display_key(k).to_string(), entry.value.clone()));
+            }
+        })?;
+        Ok(entries)
+    }
+
+    /// Same as [`Shard::live_entries`], without cloning the values. Expired entries not evicted
+    /// yet are only skipped if `skip_expired`.
+    fn live_keys(&self, prefix: Option<&str>, skip_expired: bool) -> Result<Vec<String>> {
+        let current_time = current_millis();
+        let mut keys = vec![];
+        self.for_each_entry(|k, entry| {
+            if prefix.is_none_or(|p| k.starts_with(p))
+                && !(skip_expired && entry.is_expired(current_time))
+            {
+                keys.push(entry.display_key(k).to_string());
+            }
+        })?;
+        Ok(keys)
+    }
+}
+
+impl KVStore {
+    pub fn new(num_shards: usize, directory: String) -> Result<Self> {
+        if !fs::exists(&directory)? {
+            fs::create_dir_all(&directory)?;
+        }
+        write_manifest(
+            &directory,
+            &Manifest {
+                shards: num_shards,
+                hash_strategy: HashStrategy::default(),
+            },
+        )?;
+        let mut shards: Vec<Shard> = vec![];
+        let mut i = 0;
+        while i < num_shards {
+            shards.push(Shard::new());
+            i += 1;
+        }
+        Ok(Self::from_shards(shards, directory))
+    }
+
+    /// Loads the shards previously flushed to `directory`, routing keys with the hash strategy
+    /// recorded in its manifest.
+    pub fn new_from_disk(num_shards: usize, directory: String) -> Result<Self> {
+        if !fs::exists(&directory)? {
+            return Err(anyhow!("directory {} does not exist", &directory));
+        }
+        let hash_strategy = stored_hash_strategy(&directory)?;
+        let target = LocalTarget::new(directory.clone());
+        Self::load_from_target(num_shards, directory, Arc::new(target), hash_strategy)
+    }
+
+    /// Like [`KVStore::new_from_disk`], but only deserializes each shard on its first access
+    /// (see [`Shard::from_file_lazy`]): startup is faster, the first access to every shard is
+    /// slower. The shard files are still read into memory at startup.
+    ///
+    /// Unlike [`KVStore::new_from_disk`], keys duplicated across shards (e.g. after an
+    /// interrupted reshard) aren't resolved, since that would deserialize every shard upfront:
+    /// reads see the copy in the shard the key hashes to, and `quache fsck --repair` keeps the
+    /// newest one.
+    pub fn new_from_disk_lazy(num_shards: usize, directory: String) -> Result<Self> {
+        if !fs::exists(&directory)? {
+            return Err(anyhow!("directory {} does not exist", &directory));
+        }
+        let mut shards: Vec<Shard> = vec![];
+        for i in 0..num_shards {
+            let file_path = shard_file_path(&directory, i);
+            if fs::exists(&file_path)? {
+                tracing::info!(
+                    "Reading shard {:?} from file, deserializing it on first access",
+                    i
+                );
+                let shard = Shard::from_file_lazy(&file_path)?;
+                shard.mark_flushed();
+                shards.push(shard);
+            } else {
+                tracing::info!(
+                    "File for shard {:?} not found, initializing an empty shard...",
+                    i
+                );
+                shards.push(Shard::new());
+            }
+        }
+        let mut kv_store = Self::from_shards(shards, directory);
+        kv_store.hash_strategy = stored_hash_strategy(&kv_store.directory)?;
+        Ok(kv_store)
+    }
+
+    /// Loads the shards previously flushed to `target`, which the store keeps flushing to.
+    /// `directory` is only used to create local files (e.g. dumps), and doesn't need to exist.
+    pub fn new_from_target(
+        num_shards: usize,
+        directory: String,
+        target: Arc<dyn FlushTarget>,
+    ) -> Result<Self> {
+        Self::load_from_target(num_shards, directory, target, HashStrategy::default())
+    }
+
+    fn load_from_target(
+        num_shards: usize,
+        directory: String,
+        target: Arc<dyn FlushTarget>,
+        hash_strategy: HashStrategy,
+    ) -> Result<Self> {
+        let mut shards: Vec<Shard> = vec![];
+        let mut i = 0;
+        while i < num_shards {
+            match target.read_shard(i)? {
+                Some(content) => {
+                    tracing::info!("Loading shard {:?} from {}", i, target.describe());
+                    let source = format!("{} (shard {})", target.describe(), i);
+                    let shard = Shard::decode(&content, &source)?;
+                    shard.mark_flushed();
+                    shards.push(shard);
+                }
+                None => {
+                    tracing::info!(
+                        "Shard {:?} not found in {}, initializing an empty shard...",
+                        i,
+                        target.describe()
+                    );
+                    shards.push(Shard::new());
+                }
+            }
+            i += 1;
+        }
+        let mut kv_store = Self::from_shards(shards, directory).with_flush_target(target);
+        kv_store.hash_strategy = hash_strategy;
+        kv_store.resolve_duplicate_keys()?;
+        Ok(kv_store)
+    }
+
+    /// Keeps only the newest (by timestamp) copy of the keys loaded from several shards (e.g.
+    /// after an interrupted reshard), logging a warning for each, and moves it to the shard the
+    /// key hashes to if needed. On ties, the copy in the lowest shard wins. Keys stored once in
+    /// the wrong shard are left for [`fsck`] to move. Not called on lazily loaded stores (see
+    /// [`KVStore::new_from_disk_lazy`]).
+    fn resolve_duplicate_keys(&self) -> Result<()> {
+        // shard and rank of the newest copy of every key
+        let mut newest: HashMap<String, (usize, u128)> = HashMap::new();
+        // shards holding a copy of the duplicated keys
+        let mut copies: HashSet<(String, usize)> = HashSet::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            shard.for_each_entry(|key, entry| match newest.get_mut(key) {
+                None => {
+                    newest.insert(key.clone(), (i, duplicate_rank(entry)));
+                }
+                Some((kept_idx, kept_rank)) => {
+                    tracing::warn!(
+                        "Key {} is stored in shards {} and {}, keeping the newest copy",
+                        echo_key(key),
+                        kept_idx,
+                        i
+                    );
+                    copies.insert((key.clone(), *kept_idx));
+                    copies.insert((key.clone(), i));
+                    if duplicate_rank(entry) > *kept_rank {
+                        *kept_idx = i;
+                        *kept_rank = duplicate_rank(entry);
+                    }
+                }
+            })?;
+        }
+        if copies.is_empty() {
+            return Ok(());
+        }
+        // every shard holding a copy, or the kept copy once moved
+        let mut data: HashMap<usize, HashMap<String, ShardEntry>> = HashMap::new();
+        for (key, i) in &copies {
+            let home = self.hash_strategy.shard_index(key, self.shards.len());
+            for i in [*i, home] {
+                if let std::collections::hash_map::Entry::Vacant(e) = data.entry(i) {
+                    e.insert(self.shards[i].entries()?);
+                }
+            }
+        }
+        let mut kept: Vec<(String, ShardEntry)> = vec![];
+        for (key, i) in copies {
+            let entry = data.get_mut(&i).and_then(|entries| entries.remove(&key));
+            if let Some(entry) = entry
+                && newest[&key].0 == i
+            {
+                kept.push((key, entry));
+            }
+        }
+        for (key, entry) in kept {
+            let home = self.hash_strategy.shard_index(&key, self.shards.len());
+            data.get_mut(&home)
+                .expect("loaded above")
+                .insert(key, entry);
+        }
+        for (i, entries) in data {
+            self.shards[i].replace_entries(entries)?;
+        }
+        Ok(())
+    }
+
+    pub fn builder() -> KVStoreBuilder {
+        KVStoreBuilder::default()
+    }
+
+    fn from_shards(shards: Vec<Shard>, directory: String) -> Self {
+        let metrics = Arc::new(Metrics::default());
+        Self {
+            shards: shards
+                .into_iter()
+                .map(|shard| shard.with_lock_metrics(metrics.lock_metrics()))
+                .collect(),
+            flush_target: Arc::new(LocalTarget::new(directory.clone())),
+            directory,
+            shard_dimensions: Arc::new(RwLock::new(HashMap::new())),
+            metrics,
+            case_insensitive: false,
+            ttl_jitter_percent: 0_f64,
+            ttl_resolution_ms: 1,
+            min_ttl_seen: Arc::new(AtomicU64::new(u64::MAX)),
+            hash_strategy: HashStrategy::default(),
+            in_memory: false,
+            listeners: ChangeListeners::default(),
+            flush_paused: Arc::new(AtomicBool::new(false)),
+            flush_lock: Arc::new(Mutex::new(())),
+            flush_status: Arc::new(RwLock::new(FlushStatus::default())),
+            cleanup_status: Arc::new(RwLock::new(CleanupStatus::default())),
+            flush_progress: Arc::new(FlushProgressCounters::default()),
+            flush_concurrency: DEFAULT_FLUSH_CONCURRENCY,
+            pretty_disk: false,
+            sliding_expiration: false,
+            default_ttl: None,
+            max_ttl: None,
+            ttl_cap_policy: TtlCapPolicy::default(),
+            forbid_persistent: false,
+            strict_ttl_seconds: false,
+            probe_expired_on_scan: true,
+            eviction_batch_size: usize::MAX,
+            max_json_depth: DEFAULT_MAX_JSON_DEPTH,
+            #[cfg(feature = "schema")]
+            schemas: None,
+            shard_backend: ShardBackend::default(),
+        }
+    }
+
+    /// Flushes the shards to `target` (e.g. an object storage bucket) instead of the store
+    /// directory.
+    pub fn with_flush_target(mut self, target: Arc<dyn FlushTarget>) -> Self {
+        self.flush_target = target;
+        self
+    }
+
+    /// Writes up to `flush_concurrency` changed shards at the same time in [`KVStore::to_disk`],
+    /// so that flushing large stores doesn't saturate the disk. 1 flushes the shards one after
+    /// the other, on the calling thread.
+    pub fn with_flush_concurrency(mut self, flush_concurrency: usize) -> Self {
+        self.flush_concurrency = flush_concurrency.max(1);
+        self
+    }
+
+    /// Flushes the shards as pretty-printed JSON with sorted keys, e.g. to inspect them or track
+    /// them with git.
+    pub fn with_pretty_disk(mut self, pretty_disk: bool) -> Self {
+        self.pretty_disk = pretty_disk;
+        self
+    }
+
+    /// Moves the entries of every shard to a `backend` shard.
+    pub fn with_shard_backend(mut self, backend: ShardBackend) -> Result<Self> {
+        self.shards = self
+            .shards
+            .into_iter()
+            .map(|shard| shard.with_backend(backend))
+            .collect::<Result<Vec<Shard>>>()?;
+        self.shard_backend = backend;
+        Ok(self)
+    }
+
+    /// Makes every read of an expiring key restart its TTL, so that keys expire only once they
+    /// stop being read. Entries without a TTL are not affected.
+    ///
+    /// This turns reads of expiring keys into writes: they take the shard's write lock, which
+    /// contends with other readers of the shard, and are reported to the listeners of
+    /// [`KVStore::on_change`] as puts carrying the new expiry, so that replicas keep the key too.
+    pub fn with_sliding_expiration(mut self, sliding_expiration: bool) -> Self {
+        self.sliding_expiration = sliding_expiration;
+        self
+    }
+
+    /// Gives the writes that don't specify a TTL `default_ttl` seconds to live instead of never
+    /// expiring. Writes can still opt out with an explicit [`PERSISTENT_TTL`].
+    pub fn with_default_ttl(mut self, default_ttl: Option<f64>) -> Self {
+        self.default_ttl = default_ttl;
+        self
+    }
+
+    /// Makes key lookups case-insensitive. Enumerating keys still returns the casing they were
+    /// last written with.
+    pub fn with_case_insensitive_keys(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Randomizes TTLs by up to `jitter_percent` percent (in `[0, 100)`) in either direction, so
+    /// that keys written together with the same TTL don't all expire at once.
+    pub fn with_ttl_jitter_percent(mut self, jitter_percent: f64) -> Self {
+        self.ttl_jitter_percent = jitter_percent;
+        self
+    }
+
+    /// Rounds the TTLs of writes to the nearest multiple of `resolution_ms` milliseconds (after
+    /// jitter), so that expiry times are predictable despite the float arithmetic on TTLs given
+    /// in seconds. Rounded TTLs are still capped by [`KVStore::with_max_ttl`]. Defaults to 1ms.
+    pub fn with_ttl_resolution_ms(mut self, resolution_ms: u64) -> Self {
+        self.ttl_resolution_ms = resolution_ms.max(1);
+        self
+    }
+
+    /// Caps the TTLs of writes to `max_ttl` seconds, rejecting (or clamping, depending on
+    /// `policy`) longer ones. TTLs randomized by the jitter never exceed the cap either.
+    pub fn with_max_ttl(mut self, max_ttl: Option<f64>, policy: TtlCapPolicy) -> Self {
+        self.max_ttl = max_ttl;
+        self.ttl_cap_policy = policy;
+        self
+    }
+
+    /// Rejects the writes that would create entries without a TTL, once the default TTL (if
+    /// any) is applied.
+    pub fn with_forbid_persistent(mut self, forbid_persistent: bool) -> Self {
+        self.forbid_persistent = forbid_persistent;
+        self
+    }
+
+    /// Rejects the writes whose TTL isn't a non-negative whole number of seconds, so that TTLs
+    /// are stored as exact milliseconds.
+    pub fn with_strict_ttl_seconds(mut self, strict_ttl_seconds: bool) -> Self {
+        self.strict_ttl_seconds = strict_ttl_seconds;
+        self
+    }
+
+    /// Whether key enumerations ([`KVStore::list_keys`], [`KVStore::list_keys_page`],
+    /// [`KVStore::keys_containing`]) check the TTL of every entry, leaving out the expired ones
+    /// that cleanup hasn't evicted yet. On by default; turning it off saves the checks at the
+    /// cost of listing keys that reads would report as expired.
+    pub fn with_probe_expired_on_scan(mut self, probe_expired_on_scan: bool) -> Self {
+        self.probe_expired_on_scan = probe_expired_on_scan;
+        self
+    }
+
+    /// Caps the expired entries [`KVStore::cleanup`] removes from each shard to
+    /// `eviction_batch_size`, so that shards with many expired entries aren't write-locked for
+    /// long. The remaining ones are removed by the next cleanups; reads treat them as expired
+    /// meanwhile.
+    pub fn with_eviction_batch_size(mut self, eviction_batch_size: usize) -> Self {
+        self.eviction_batch_size = eviction_batch_size.max(1);
+        self
+    }
+
+    /// Rejects the values written (with [`KVStore::put`] and its variants, but also the values
+    /// that increments, sorted set and list updates and renames produce) under the prefixes of
+    /// `schemas` that don't match their schema.
+    #[cfg(feature = "schema")]
+    pub fn with_schemas(mut self, schemas: Schemas) -> Self {
+        self.schemas = Some(Arc::new(schemas));
+        self
+    }
+
+    /// Rejects the values written (with [`KVStore::put`] and its variants) whose arrays and
+    /// objects nest more than `max_json_depth` levels deep, which the recursive serialization of
+    /// shard files could choke on. Depths above [`MAX_JSON_DEPTH_LIMIT`] are lowered to it, as
+    /// shards holding such values couldn't be loaded back.
+    pub fn with_max_json_depth(mut self, max_json_depth: usize) -> Self {
+        self.max_json_depth = max_json_depth.min(MAX_JSON_DEPTH_LIMIT);
+        self
+    }
+
+    /// Checks that `value` may be written under `key`: it's not nested too deep, and it matches
+    /// the schema configured for `key`, if any.
+    fn check_value(&self, key: &str, value: &serde_json::Value) -> Result<()> {
+        if exceeds_depth(value, self.max_json_depth) {
+            return Err(KVError::InvalidInput(format!(
+                "value is nested more than {} levels deep",
+                self.max_json_depth
+            ))
+            .into());
+        }
+        #[cfg(feature = "schema")]
+        if let Some(schemas) = &self.schemas {
+            schemas.validate(key, value)?;
+        }
+        #[cfg(not(feature = "schema"))]
+        let _ = (key, value);
+        Ok(())
+    }
+
+    /// Creates the entry for a write, applying the store-wide TTL policies.
+    fn new_entry(
+        &self,
+        value: serde_json::Value,
+        ttl: Option<f64>,
+        original_key: Option<String>,
+    ) -> Result<ShardEntry> {
+        if let Some(t) = ttl
+            && self.strict_ttl_seconds
+            && (t < 0_f64 || t.fract() != 0_f64)
+        {
+            return Err(KVError::InvalidInput(format!(
+                "TTL of {}s is not a whole number of seconds",
+                t
+            ))
+            .into());
+        }
+        if let Some(t) = ttl
+            && t != PERSISTENT_TTL
+            && !(t > 0_f64 && t.is_finite())
+        {
+            return Err(KVError::InvalidInput(format!(
+                "TTL of {}s is not a positive number of seconds (pass {} for entries that never expire)",
+                t, PERSISTENT_TTL
+            ))
+            .into());
+        }
+        if let Some(t) = ttl
+            && t > MAX_TTL_SECS
+        {
+            return Err(KVError::InvalidInput(format!(
+                "TTL of {}s is above the longest supported one of {}s",
+                t, MAX_TTL_SECS
+            ))
+            .into());
+        }
+        let ttl = match ttl.or(self.default_ttl) {
+            Some(t) if t > 0_f64 => match self.max_ttl {
+                Some(max) if t > max && self.ttl_cap_policy == TtlCapPolicy::Reject => {
+                    return Err(KVError::InvalidInput(format!(
+                        "TTL of {}s is above the maximum of {}s",
+                        t, max
+                    ))
+                    .into());
+                }
+                _ => Some(t),
+            },
+            _ if self.forbid_persistent => {
+                return Err(KVError::InvalidInput(
+                    "entries without a TTL are not allowed".to_string(),
+                )
+                .into());
+            }
+            _ => None,
+        };
+        let ttl = jittered_ttl(ttl, self.ttl_jitter_percent)
+            .map(|t| self.max_ttl.map_or(t, |max| t.min(max)));
+        let mut entry = ShardEntry::with_ttl_resolution(value, ttl, self.ttl_resolution_ms);
+        entry.original_key = original_key;
+        if let Some(max) = self.max_ttl
+            && entry.ttl > 0
+        {
+            // rounding to the resolution can go past the cap
+            entry.ttl = entry.ttl.min(((max * 1000_f64).round() as i128).max(1));
+        }
+        if entry.ttl > 0 {
+            self.min_ttl_seen
+                .fetch_min(entry.ttl as u64, Ordering::Relaxed);
+        }
+        Ok(entry)
+    }
+
+    /// Directory the store flushes its shards to.
+    pub fn directory(&self) -> &str {
+        &self.directory
+    }
+
+    /// Registers a callback invoked with every mutation of the store (puts, increments, swaps,
+    /// consumptions, deletes, drains and restores) and with the evictions of
+    /// [`KVStore::cleanup`]. Callbacks of mutations run while the key's shard is locked, so they
+    /// must be quick and must not access the store.
+    pub fn on_change(&self, listener: impl Fn(&ChangeEvent) + Send + Sync + 'static) {
+        self.listeners.add(listener);
+    }
+
+    /// Caches the hash of a value written under `key` (`digest`, computed from the value of
+    /// `entry`), records it in the metrics, and notifies the listeners.
+    fn record_put(&self, key: &str, entry: &mut ShardEntry, digest: ValueDigest) {
+        entry.value_hash = digest.hash;
+        self.metrics.record_value_size(digest.size);
+        if self.listeners.is_empty() {
+            return;
+        }
+        self.listeners.notify(ChangeEvent {
+            op: ChangeOp::Put,
+            key: entry.display_key(key).to_string(),
+            value: Some(entry.value.clone()),
+            expires_at_ms: entry.expires_at().map(|t| t as u64),
+            seq: Some(entry.seq),
+        });
+    }
+
+    /// Smallest TTL written to the store so far, or `None` if every entry was persistent.
+    pub fn min_ttl_seen(&self) -> Option<time::Duration> {
+        match self.min_ttl_seen.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            ms => Some(time::Duration::from_millis(ms)),
+        }
+    }
+
+    /// Returns the key used for hashing and lookups, along with the original casing of the key
+    /// when it differs from the normalized one.
+    fn normalize_key(&self, key: String) -> (String, Option<String>) {
+        if !self.case_insensitive {
+            return (key, None);
+        }
+        let normalized = key.to_lowercase();
+        if normalized == key {
+            (normalized, None)
+        } else {
+            (normalized, Some(key))
+        }
+    }
+
+    pub fn find_shard(&self, key: &str) -> usize {
+        self.hash_strategy.shard_index(key, self.shards.len())
+    }
+
+    /// Returns the index of the shard a key hashes to, and whether the key is currently stored there.
+    pub fn locate(&self, key: &str) -> Result<(usize, bool)> {
+        let (key, _) = self.normalize_key(key.to_string());
+        let shard_idx = self.find_shard(&key);
+        let data = self.shards[shard_idx].read_key(&key)?;
+        Ok((shard_idx, data.contains_key(&key)))
+    }
+
+    /// Returns the entry stored under `key` exactly as stored, even if its TTL has elapsed. Unlike
+    /// [`KVStore::get`], it neither evicts expired entries nor counts towards the metrics.
+    pub fn entry(&self, key: String) -> Result<ShardEntry> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let data = self.shards[shard_idx].read_key(&key)?;
+        data.get(&key)
+            .cloned()
+            .ok_or_else(|| KVError::NotFound(key).into())
+    }
+
+    /// Returns how many seconds the entry of `key` has left to live, `None` if it has no TTL.
+    /// Like [`KVStore::entry`], this neither evicts expired entries (reported as
+    /// [`KVError::Expired`]) nor counts towards the metrics.
+    pub fn ttl(&self, key: String) -> Result<Option<f64>> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let data = self.shards[shard_idx].read_key(&key)?;
+        let now = current_millis();
+        match data.get(&key) {
+            None => Err(KVError::NotFound(key).into()),
+            Some(entry) if entry.is_expired(now) => Err(KVError::Expired(key).into()),
+            Some(entry) => Ok(entry
+                .remaining_ttl_at(now)
+                .map(|remaining| remaining.as_secs_f64())),
+        }
+    }
+
+    /// Returns how long each of the stored `keys` has left to live (`None` for entries without a
+    /// TTL). Keys that are missing or expired are left out of the map.
+    ///
+    /// Keys are grouped by shard, so that each shard is read once however many keys it holds.
+    /// Like [`KVStore::entry`], this neither evicts expired entries nor counts towards the
+    /// metrics.
+    pub fn ttl_many(&self, keys: &[String]) -> Result<HashMap<String, Option<time::Duration>>> {
+        let mut by_shard: HashMap<usize, Vec<(&String, String)>> = HashMap::new();
+        for key in keys {
+            let (normalized, _) = self.normalize_key(key.clone());
+            by_shard
+                .entry(self.find_shard(&normalized))
+                .or_default()
+                .push((key, normalized));
+        }
+        let now = current_millis();
+        let mut ttls = HashMap::new();
+        for (shard_idx, keys) in &by_shard {
+            let normalized: Vec<&str> = keys.iter().map(|(_, key)| key.as_str()).collect();
+            let mut remaining = Vec::with_capacity(keys.len());
+            self.shards[*shard_idx].for_each_key(&normalized, |entry| {
+                remaining.push(
+                    entry
+                        .filter(|entry| !entry.is_expired(now))
+                        .map(|entry| entry.remaining_ttl_at(now)),
+                );
+            })?;
+            for ((key, _), remaining) in keys.iter().zip(remaining) {
+                if let Some(remaining) = remaining {
+                    ttls.insert(key.to_string(), remaining);
+                }
+            }
+        }
+        Ok(ttls)
+    }
+
+    /// Returns each of `keys` along with its live value (`None` if it's missing or expired), in
+    /// the order of `keys`.
+    ///
+    /// Keys are grouped by shard, so that each shard is read once however many keys it holds.
+    /// Unlike [`KVStore::get`], expired entries are only skipped (not evicted) and sliding TTLs
+    /// aren't restarted; hits and misses are counted per key.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<(String, Option<serde_json::Value>)>> {
+        let mut by_shard: HashMap<usize, Vec<(usize, String)>> = HashMap::new();
+        for (position, key) in keys.iter().enumerate() {
+            let (normalized, _) = self.normalize_key(key.clone());
+            by_shard
+                .entry(self.find_shard(&normalized))
+                .or_default()
+                .push((position, normalized));
+        }
+        let now = current_millis();
+        let mut values = vec![None; keys.len()];
+        for (shard_idx, keys) in &by_shard {
+            let normalized: Vec<&str> = keys.iter().map(|(_, key)| key.as_str()).collect();
+            let mut positions = keys.iter().map(|(position, _)| *position);
+            self.shards[*shard_idx].for_each_key(&normalized, |entry| {
+                let position = positions.next().expect("one entry per key");
+                values[position] = entry
+                    .filter(|entry| !entry.is_expired(now))
+                    .map(|entry| entry.value.clone());
+            })?;
+        }
+        for value in &values {
+            match value {
+                Some(_) => self.metrics.record_hit(),
+                None => self.metrics.record_miss(),
+            }
+        }
+        Ok(keys.into_iter().zip(values).collect())
+    }
+
+    /// Stores `value` under `key`, assigning it the sequence number following the stored one.
+    pub fn put(&self, key: String, value: serde_json::Value, ttl: Option<f64>) -> Result<()> {
+        let (key, original_key) = self.normalize_key(key);
+        self.check_value(&key, &value)?;
+        let shard_idx = self.find_shard(&key);
+        let digest = ValueDigest::of(&value);
+        let mut entry = self.new_entry(value, ttl, original_key)?;
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        entry.seq = data.get(&key).map_or(1, |existing| existing.seq + 1);
+        self.record_put(&key, &mut entry, digest);
+        data.insert(key, entry);
+
+        Ok(())
+    }
+
+    /// Writes a value copied from another store (e.g. while bootstrapping from a peer) as it
+    /// is, expiring at `expires_at_ms` (milliseconds since the epoch) if set. Unlike
+    /// [`KVStore::put`], no write policy applies (value checks, TTL limits and rounding, default
+    /// TTL), as the source store already enforced its own. Copies that already expired are
+    /// skipped.
+    pub fn put_copy(
+        &self,
+        key: String,
+        value: serde_json::Value,
+        expires_at_ms: Option<u64>,
+    ) -> Result<()> {
+        let (key, original_key) = self.normalize_key(key);
+        let digest = ValueDigest::of(&value);
+        let mut entry = ShardEntry::new(value, None);
+        if let Some(expires_at) = expires_at_ms {
+            let Some(ttl) = (expires_at as u128)
+                .checked_sub(entry.timestamp)
+                .filter(|t| *t > 0)
+            else {
+                return Ok(());
+            };
+            entry.ttl = ttl as i128;
+            self.min_ttl_seen.fetch_min(ttl as u64, Ordering::Relaxed);
+        }
+        entry.original_key = original_key;
+        let shard_idx = self.find_shard(&key);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        entry.seq = data.get(&key).map_or(1, |existing| existing.seq + 1);
+        self.record_put(&key, &mut entry, digest);
+        data.insert(key, entry);
+        Ok(())
+    }
+
+    /// Applies a batch of writes, returning the status of each. Writes with an `if_match`
+    /// revision only apply if the key is live and its value hash equals it, so that clients can
+    /// update keys they read without overwriting concurrent changes.
+    ///
+    /// The writes are grouped by shard, each shard being locked once for its whole group (see
+    /// [`Shard::write_batch`]); writes to the same key apply in order. Every value and TTL is
+    /// validated before anything is written, failing the whole batch if one is invalid. The
+    /// batch isn't atomic though: concurrent operations may see some writes applied and others
+    /// not yet.
+    pub fn put_many(&self, items: Vec<BatchPut>) -> Result<Vec<BatchPutStatus>> {
+        let mut groups: Vec<Vec<_>> = (0..self.shards.len()).map(|_| vec![]).collect();
+        for (position, item) in items.into_iter().enumerate() {
+            let (key, original_key) = self.normalize_key(item.key);
+            self.check_value(&key, &item.value)?;
+            let digest = ValueDigest::of(&item.value);
+            let entry = self.new_entry(item.value, item.ttl, original_key)?;
+            groups[self.find_shard(&key)].push((key, (position, entry, digest, item.if_match)));
+        }
+        let mut statuses = vec![BatchPutStatus::Conflict; groups.iter().map(Vec::len).sum()];
+        let now = current_millis();
+        for (shard_idx, writes) in groups.into_iter().enumerate() {
+            self.shards[shard_idx].write_batch(
+                writes,
+                |key, existing, (position, mut entry, digest, if_match)| {
+                    let matches = |existing: &ShardEntry| {
+                        if_match.as_ref().is_none_or(|revision| {
+                            !existing.is_expired(now) && existing.value_hash() == *revision
+                        })
+                    };
+                    entry.seq = match existing {
+                        Some(existing) if matches(existing) => existing.seq + 1,
+                        None if if_match.is_none() => 1,
+                        _ => return None,
+                    };
+                    self.record_put(key, &mut entry, digest);
+                    statuses[position] = BatchPutStatus::Applied;
+                    Some(entry)
+                },
+            )?;
+        }
+        Ok(statuses)
+    }
+
+    /// Returns the live value stored under `key`, or stores `value` (with `ttl`) and returns it if
+    /// the key is missing or expired. The check and the insertion happen under one write lock, so
+    /// concurrent callers all get the first inserted value.
+    pub fn get_or_insert(
+        &self,
+        key: String,
+        value: serde_json::Value,
+        ttl: Option<f64>,
+    ) -> Result<serde_json::Value> {
+        let (key, original_key) = self.normalize_key(key);
+        self.check_value(&key, &value)?;
+        let shard_idx = self.find_shard(&key);
+        let digest = ValueDigest::of(&value);
+        let mut entry = self.new_entry(value.clone(), ttl, original_key)?;
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        let seq = match data.get(&key) {
+            Some(existing) if !existing.is_expired(current_millis()) => {
+                return Ok(existing.value.clone());
+            }
+            Some(existing) => existing.seq + 1,
+            None => 1,
+        };
+        entry.seq = seq;
+        self.record_put(&key, &mut entry, digest);
+        data.insert(key, entry);
+        Ok(value)
+    }
+
+    /// Stores `value` under `key` only if `seq` is greater than the sequence number of the stored
+    /// entry, so that writes reordered in flight can't regress the value of a key.
+    ///
+    /// Returns whether the write was applied. Expired entries never block a write.
+    pub fn put_sequenced(
+        &self,
+        key: String,
+        value: serde_json::Value,
+        ttl: Option<f64>,
+        seq: u64,
+    ) -> Result<bool> {
+        let (key, original_key) = self.normalize_key(key);
+        self.check_value(&key, &value)?;
+        let shard_idx = self.find_shard(&key);
+        let digest = ValueDigest::of(&value);
+        let mut entry = self.new_entry(value, ttl, original_key)?;
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        if let Some(existing) = data.get(&key)
+            && existing.seq >= seq
+            && !existing.is_expired(current_millis())
+        {
+            return Ok(false);
+        }
+        entry.seq = seq;
+        self.record_put(&key, &mut entry, digest);
+        data.insert(key, entry);
+        Ok(true)
+    }
+
+    /// Stores `value` under `key` only if that wouldn't bring its expiry forward: the write is
+    /// skipped when the stored entry is live and would outlive the new one (persistent entries
+    /// outlive any expiring one).
+    ///
+    /// Returns whether the write was applied. Missing and expired keys are always written.
+    pub fn put_if_extends(
+        &self,
+        key: String,
+        value: serde_json::Value,
+        ttl: Option<f64>,
+    ) -> Result<bool> {
+        let (key, original_key) = self.normalize_key(key);
+        self.check_value(&key, &value)?;
+        let shard_idx = self.find_shard(&key);
+        let digest = ValueDigest::of(&value);
+        let mut entry = self.new_entry(value, ttl, original_key)?;
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        if let Some(existing) = data.get(&key)
+            && !existing.is_expired(current_millis())
+        {
+            let shortens = match (existing.expires_at(), entry.expires_at()) {
+                (None, Some(_)) => true,
+                (Some(current), Some(new)) => new < current,
+                (_, None) => false,
+            };
+            if shortens {
+                return Ok(false);
+            }
+        }
+        entry.seq = data.get(&key).map_or(1, |existing| existing.seq + 1);
+        self.record_put(&key, &mut entry, digest);
+        data.insert(key, entry);
+        Ok(true)
+    }
+
+    /// Runs `ops` in order, atomically: the shard of their keys is locked for the whole
+    /// transaction, and its writes are only applied once every operation succeeded. Operations
+    /// see the writes of the previous ones.
+    ///
+    /// A failed `assert_equals` aborts the transaction, discarding its writes; other failures
+    /// (e.g. incrementing a string) discard them too, and are returned as errors. Every key must
+    /// hash to the same shard, and with the `DashMap` backend (which has no shard-wide lock) the
+    /// operations must all be on the same key, failing with [`KVError::InvalidInput`] otherwise.
+    pub fn transaction(&self, ops: Vec<TxOp>) -> Result<TxOutcome> {
+        let keys: Vec<(String, Option<String>)> = ops
+            .iter()
+            .map(|op| self.normalize_key(op.key().to_string()))
+            .collect();
+        let Some((first_key, _)) = keys.first() else {
+            return Ok(TxOutcome {
+                committed: true,
+                results: vec![],
+                failed_op: None,
+            });
+        };
+        let shard_idx = self.find_shard(first_key);
+        if keys
+            .iter()
+            .any(|(key, _)| self.find_shard(key) != shard_idx)
+        {
+            return Err(KVError::InvalidInput(
+                "the keys of a transaction must be in the same shard".to_string(),
+            )
+            .into());
+        }
+        if self.shard_backend == ShardBackend::DashMap
+            && keys.iter().any(|(key, _)| key != first_key)
+        {
+            return Err(KVError::InvalidInput(
+                "transactions over several keys need the rwlock backend".to_string(),
+            )
+            .into());
+        }
+        let mut data = self.shards[shard_idx].lock_key(first_key)?;
+        let now = current_millis();
+        // `None` stages a deletion
+        let mut staged: HashMap<String, Option<ShardEntry>> = HashMap::new();
+        let mut results = vec![];
+        for (position, (op, (key, original_key))) in ops.into_iter().zip(keys).enumerate() {
+            let live = match staged.get(&key) {
+                Some(staged) => staged.clone(),
+                None => data
+                    .get(&key)
+                    .filter(|entry| !entry.is_expired(now))
+                    .cloned(),
+            };
+            let output = match op {
+                TxOp::Get { .. } => live.map_or(serde_json::Value::Null, |entry| entry.value),
+                TxOp::AssertEquals { value, .. } => {
+                    let matches =
+                        live.map_or(serde_json::Value::Null, |entry| entry.value) == value;
+                    results.push(serde_json::Value::Bool(matches));
+                    if !matches {
+                        return Ok(TxOutcome {
+                            committed: false,
+                            results,
+                            failed_op: Some(position),
+                        });
+                    }
+                    continue;
+                }
+                TxOp::Set { value, ttl, .. } => {
+                    self.check_value(&key, &value)?;
+                    staged.insert(key, Some(self.new_entry(value, ttl, original_key)?));
+                    serde_json::Value::Null
+                }
+                TxOp::Del { .. } => {
+                    staged.insert(key, None);
+                    serde_json::Value::Bool(live.is_some())
+                }
+                TxOp::Incr { delta, .. } => {
+                    let current = match &live {
+                        Some(entry) => entry.value.as_i64().ok_or_else(|| {
+                            KVError::Conflict(format!(
+                                "value of key {} is not an integer",
+                                echo_key(&key)
+                            ))
+                        })?,
+                        None => 0,
+                    };
+                    let new_value = current.checked_add(delta).ok_or_else(|| {
+                        KVError::Conflict(format!(
+                            "incrementing key {} would overflow",
+                            echo_key(&key)
+                        ))
+                    })?;
+                    self.check_value(&key, &serde_json::Value::from(new_value))?;
+                    let entry = match live {
+                        Some(mut entry) => {
+                            entry.value = serde_json::Value::from(new_value);
+                            entry
+                        }
+                        None => {
+                            self.new_entry(serde_json::Value::from(new_value), None, original_key)?
+                        }
+                    };
+                    staged.insert(key, Some(entry));
+                    serde_json::Value::from(new_value)
+                }
+            };
+            results.push(output);
+        }
+        for (key, entry) in staged {
+            match entry {
+                Some(mut entry) => {
+                    entry.seq = data.get(&key).map_or(1, |existing| existing.seq + 1);
+                    let digest = ValueDigest::of(&entry.value);
+                    self.record_put(&key, &mut entry, digest);
+                    data.insert(key, entry);
+                }
+                None => {
+                    if let Some(entry) = data.remove(&key) {
+                        self.listeners.notify(ChangeEvent {
+                            op: ChangeOp::Delete,
+                            key: entry.display_key(&key).to_string(),
+                            value: None,
+                            expires_at_ms: None,
+                            seq: None,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(TxOutcome {
+            committed: true,
+            results,
+            failed_op: None,
+        })
+    }
+
+    /// Sets the TTL of the live entry of `key` to `ttl` seconds, counted from now (the entry's
+    /// timestamp is reset), keeping its value. `None` (or [`PERSISTENT_TTL`]) makes the entry
+    /// persistent, whatever the default TTL; other non-positive TTLs delete it right away, as
+    /// they have already elapsed. Returns whether the key was stored (missing and expired keys
+    /// are left alone).
+    pub fn expire(&self, key: String, ttl: Option<f64>) -> Result<bool> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        let Some(existing) = data
+            .get(&key)
+            .filter(|existing| !existing.is_expired(current_millis()))
+        else {
+            return Ok(false);
+        };
+        let ttl = ttl.filter(|t| *t != PERSISTENT_TTL);
+        if ttl.is_some_and(|t| t <= 0_f64) {
+            let entry = data.remove(&key).expect("the entry was just read");
+            self.listeners.notify(ChangeEvent {
+                op: ChangeOp::Delete,
+                key: entry.display_key(&key).to_string(),
+                value: None,
+                expires_at_ms: None,
+                seq: None,
+            });
+            return Ok(true);
+        }
+        let mut entry = match ttl {
+            Some(_) => {
+                self.new_entry(existing.value.clone(), ttl, existing.original_key.clone())?
+            }
+            None if self.forbid_persistent => {
+                return Err(KVError::InvalidInput(
+                    "entries without a TTL are not allowed".to_string(),
+                )
+                .into());
+            }
+            None => {
+                let mut entry = ShardEntry::new(existing.value.clone(), None);
+                entry.original_key = existing.original_key.clone();
+                entry
+            }
+        };
+        entry.seq = existing.seq + 1;
+        let digest = ValueDigest::of(&entry.value);
+        self.record_put(&key, &mut entry, digest);
+        data.insert(key, entry);
+        Ok(true)
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the value stored under `key`.
+    ///
+    /// Entries whose TTL has elapsed are expired lazily: they are removed on access (taking the
+    /// write lock only in that case) and reported as [`KVError::Expired`], while keys that are
+    /// not stored at all are reported as [`KVError::NotFound`].
+    pub fn get(&self, key: String) -> Result<serde_json::Value> {
+        self.get_with_ttl(key).map(|(value, _)| value)
+    }
+
+    /// Like [`KVStore::get`], but also returns how long the entry has left to live
+    /// (`None` for entries without a TTL).
+    pub fn get_with_ttl(&self, key: String) -> Result<(serde_json::Value, Option<time::Duration>)> {
+        self.get_live(key).map(|live| (live.value, live.remaining))
+    }
+
+    /// Like [`KVStore::get_with_ttl`], but also returns the hash of the value (e.g. to derive
+    /// content-based ETags).
+    pub fn get_live(&self, key: String) -> Result<LiveValue> {
+        let result = self.lookup(key);
+        match &result {
+            Ok(_) => self.metrics.record_hit(),
+            Err(e) if e.downcast_ref::<KVError>().is_some() => self.metrics.record_miss(),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Whether reading `entry` restarts its TTL. Consumed entries keep their grace expiry.
+    fn slides(&self, entry: &ShardEntry) -> bool {
+        self.sliding_expiration && entry.ttl > 0 && !entry.consumed
+    }
+
+    fn lookup(&self, key: String) -> Result<LiveValue> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        {
+            let data = self.shards[shard_idx].read_key(&key)?;
+            let now = current_millis();
+            match data.get(&key) {
+                None => return Err(KVError::NotFound(key).into()),
+                Some(entry) if !entry.is_expired(now) && !self.slides(entry) => {
+                    return Ok(entry.live_value(now));
+                }
+                Some(_) => {}
+            }
+        }
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        // the entry might have been overwritten between releasing the read lock and acquiring the write lock
+        let now = current_millis();
+        match data.get_mut(&key) {
+            None => Err(KVError::NotFound(key).into()),
+            Some(entry) if !entry.is_expired(now) => {
+                if self.slides(entry) {
+                    entry.timestamp = now;
+                    entry.seq += 1;
+                    self.notify_expiry_change(&key, entry);
+                }
+                Ok(entry.live_value(now))
+            }
+            Some(_) => {
+                data.remove(&key);
+                Err(KVError::Expired(key).into())
+            }
+        }
+    }
+
+    /// Atomically adds `delta` (negative to decrement) to the integer stored under `key` and
+    /// returns the new value, like [`KVStore::incr_bounded`] without bounds. Missing (or expired)
+    /// keys count as 0; other values than integers fail with [`KVError::Conflict`].
+    pub fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.incr_entry(key, delta, None, None, None)
+            .map(|(value, _)| value)
+    }
+
+    /// Atomically adds `delta` to the integer stored under `key`, clamping the result to the
+    /// `[min, max]` range (any bound may be omitted). Missing (or expired) keys count as 0.
+    /// Returns the new value and whether it had to be clamped.
+    ///
+    /// The read-modify-write happens under a single write lock acquisition, so concurrent
+    /// increments can't lose updates. Existing entries keep their TTL.
+    pub fn incr_bounded(
+        &self,
+        key: String,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    ) -> Result<(i64, bool)> {
+        self.incr_entry(key, delta, min, max, None)
+    }
+
+    /// Atomically adds `delta` to the integer stored under `key`, like [`KVStore::incr_bounded`]
+    /// without bounds. A counter created by the increment (missing or expired key) gets
+    /// `window_ttl`; later increments keep its TTL and timestamp, so the window starts on the
+    /// first hit and isn't extended by the following ones.
+    pub fn incr_with_ttl_on_create(
+        &self,
+        key: String,
+        delta: i64,
+        window_ttl: Option<f64>,
+    ) -> Result<i64> {
+        self.incr_entry(key, delta, None, None, window_ttl)
+            .map(|(value, _)| value)
+    }
+
+    fn incr_entry(
+        &self,
+        key: String,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+        ttl_on_create: Option<f64>,
+    ) -> Result<(i64, bool)> {
+        if let (Some(lo), Some(hi)) = (min, max)
+            && lo > hi
+        {
+            return Err(KVError::InvalidInput(format!(
+                "min bound {} is greater than max bound {}",
+                lo, hi
+            ))
+            .into());
+        }
+        let (key, original_key) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        let current = match data.get(&key) {
+            Some(entry) if !entry.is_expired(current_millis()) => {
+                Some(entry.value.as_i64().ok_or_else(|| {
+                    KVError::Conflict(format!("value of key {} is not an integer", echo_key(&key)))
+                })?)
+            }
+            _ => None,
+        };
+        let unbounded = current.unwrap_or(0).checked_add(delta).ok_or_else(|| {
+            KVError::Conflict(format!(
+                "incrementing key {} would overflow",
+                echo_key(&key)
+            ))
+        })?;
+        let mut new_value = unbounded;
+        if let Some(lo) = min {
+            new_value = new_value.max(lo);
+        }
+        if let Some(hi) = max {
+            new_value = new_value.min(hi);
+        }
+        self.check_value(&key, &serde_json::Value::from(new_value))?;
+        match data.get_mut(&key) {
+            Some(entry) if current.is_some() => {
+                entry.value = serde_json::Value::from(new_value);
+                entry.seq += 1;
+                entry.original_key = original_key;
+            }
+            existing => {
+                let mut entry = self.new_entry(
+                    serde_json::Value::from(new_value),
+                    ttl_on_create,
+                    original_key,
+                )?;
+                entry.seq = existing.map_or(1, |e| e.seq + 1);
+                data.insert(key.clone(), entry);
+            }
+        }
+        if let Some(entry) = data.get_mut(&key) {
+            let digest = ValueDigest::of(&entry.value);
+            self.record_put(&key, entry, digest);
+        }
+        Ok((new_value, new_value != unbounded))
+    }
+
+    /// Replaces the live value of `key` with `value` only if it currently equals `expected`.
+    /// When `ttl` is given, the swap also resets the entry's TTL; otherwise the entry keeps its
+    /// current expiry. Missing and expired keys never match.
+    ///
+    /// Returns whether the swap happened. The comparison, the new value and the new TTL are all
+    /// applied under one write lock acquisition.
+    pub fn cas_with_ttl(
+        &self,
+        key: String,
+        expected: &serde_json::Value,
+        value: serde_json::Value,
+        ttl: Option<f64>,
+    ) -> Result<bool> {
+        let (key, original_key) = self.normalize_key(key);
+        self.check_value(&key, &value)?;
+        let shard_idx = self.find_shard(&key);
+        let digest = ValueDigest::of(&value);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        let Some(existing) = data.get_mut(&key) else {
+            return Ok(false);
+        };
+        if existing.is_expired(current_millis()) || &existing.value != expected {
+            return Ok(false);
+        }
+        self.update_entry(&key, existing, value, digest, ttl, original_key)?;
+        Ok(true)
+    }
+
+    /// Replaces the value of a live entry, resetting its TTL if `ttl` is given, keeping its
+    /// expiry otherwise.
+    fn update_entry(
+        &self,
+        key: &str,
+        existing: &mut ShardEntry,
+        value: serde_json::Value,
+        digest: ValueDigest,
+        ttl: Option<f64>,
+        original_key: Option<String>,
+    ) -> Result<()> {
+        let seq = existing.seq + 1;
+        match ttl {
+            Some(_) => {
+                let mut entry = self.new_entry(value, ttl, original_key)?;
+                entry.seq = seq;
+                *existing = entry;
+            }
+            None => {
+                existing.value = value;
+                existing.seq = seq;
+                existing.original_key = original_key;
+            }
+        }
+        self.record_put(key, existing, digest);
+        Ok(())
+    }
+
+    /// Creates or updates `key` depending on its current state, in one write lock acquisition:
+    /// - missing (or expired) keys are created, unless `if_match` is given without `nx`;
+    /// - live keys are updated if their value equals `if_match`, or if neither `if_match` nor
+    ///   `nx` is given.
+    ///
+    /// So `nx` alone only creates, `if_match` alone is a compare-and-swap, and both together
+    /// create the key or swap its value. Like [`KVStore::cas_with_ttl`], updates without `ttl`
+    /// keep the entry's expiry.
+    ///
+    /// Returns what was done, along with the live value of the key afterwards.
+    pub fn upsert(
+        &self,
+        key: String,
+        value: serde_json::Value,
+        ttl: Option<f64>,
+        if_match: Option<&serde_json::Value>,
+        nx: bool,
+    ) -> Result<(UpsertOutcome, Option<LiveValue>)> {
+        let (key, original_key) = self.normalize_key(key);
+        self.check_value(&key, &value)?;
+        let shard_idx = self.find_shard(&key);
+        let digest = ValueDigest::of(&value);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        let now = current_millis();
+        let live = data.get(&key).filter(|entry| !entry.is_expired(now));
+        let outcome = match (live, if_match) {
+            (None, Some(_)) if !nx => UpsertOutcome::Skipped,
+            (None, _) => UpsertOutcome::Created,
+            (Some(existing), Some(expected)) if &existing.value == expected => {
+                UpsertOutcome::Updated
+            }
+            (Some(_), Some(_)) => UpsertOutcome::Skipped,
+            (Some(_), None) if nx => UpsertOutcome::Skipped,
+            (Some(_), None) => UpsertOutcome::Updated,
+        };
+        match outcome {
+            UpsertOutcome::Created => {
+                let mut entry = self.new_entry(value, ttl, original_key)?;
+                entry.seq = data.get(&key).map_or(1, |expired| expired.seq + 1);
+                self.record_put(&key, &mut entry, digest);
+                data.insert(key.clone(), entry);
+            }
+            UpsertOutcome::Updated => {
+                let existing = data.get_mut(&key).expect("the entry was just checked");
+                self.update_entry(&key, existing, value, digest, ttl, original_key)?;
+            }
+            UpsertOutcome::Skipped => {}
+        }
+        let state = data
+            .get(&key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| entry.live_value(now));
+        Ok((outcome, state))
+    }
+
+    /// Returns the value stored under `key` and marks it as consumed: it expires after `grace_ms`
+    /// milliseconds (or earlier, if its TTL was shorter), so that plain reads can still be retried
+    /// in the meantime. Consuming an entry twice fails with [`KVError::Conflict`].
+    pub fn consume(&self, key: String, grace_ms: u64) -> Result<serde_json::Value> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        let now = current_millis();
+        let Some(entry) = data.get_mut(&key) else {
+            return Err(KVError::NotFound(key).into());
+        };
+        if entry.is_expired(now) {
+            data.remove(&key);
+            return Err(KVError::Expired(key).into());
+        }
+        if entry.consumed {
+            return Err(
+                KVError::Conflict(format!("key {} was already consumed", echo_key(&key))).into(),
+            );
+        }
+        self.check_value(&key, &entry.value)?;
+        // TTLs of 0 never expire, hence the 1ms floor
+        let grace_end = now + grace_ms.max(1) as u128;
+        let expires_at = entry.expires_at().map_or(grace_end, |e| e.min(grace_end));
+        entry.ttl = (expires_at.saturating_sub(now) as i128).max(1);
+        entry.timestamp = now;
+        entry.consumed = true;
+        entry.seq += 1;
+        self.notify_expiry_change(&key, entry);
+        Ok(entry.value.clone())
+    }
+
+    /// Notifies the listeners that the expiry of the entry stored under `key` changed (e.g. when
+    /// it's consumed, or slid by a read), as a put of its unchanged value, so that replicas
+    /// follow it.
+    fn notify_expiry_change(&self, key: &str, entry: &ShardEntry) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        self.listeners.notify(ChangeEvent {
+            op: ChangeOp::Put,
+            key: entry.display_key(key).to_string(),
+            value: Some(entry.value.clone()),
+            expires_at_ms: entry.expires_at().map(|t| t as u64),
+            seq: Some(entry.seq),
+        });
+    }
+
+    /// Sets the scores of `members` in the sorted set stored under `key`, a JSON object mapping
+    /// members to scores. Missing (or expired) keys start as an empty set, existing ones keep
+    /// their TTL. Returns how many members weren't in the set yet.
+    pub fn zadd(&self, key: String, members: HashMap<String, f64>) -> Result<usize> {
+        if let Some((member, _)) = members.iter().find(|(_, score)| !score.is_finite()) {
+            return Err(
+                KVError::InvalidInput(format!("score of member {} is not finite", member)).into(),
+            );
+        }
+        let (key, original_key) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        let now = current_millis();
+        let mut set = match data.get(&key).filter(|entry| !entry.is_expired(now)) {
+            Some(entry) => entry.value.as_object().cloned().ok_or_else(|| {
+                KVError::Conflict(format!(
+                    "value of key {} is not a sorted set",
+                    echo_key(&key)
+                ))
+            })?,
+            None => serde_json::Map::new(),
+        };
+        let mut added = 0;
+        for (member, score) in members {
+            if set.insert(member, score_value(score)).is_none() {
+                added += 1;
+            }
+        }
+        let value = serde_json::Value::Object(set);
+        self.check_value(&key, &value)?;
+        match data.get_mut(&key).filter(|entry| !entry.is_expired(now)) {
+            Some(entry) => {
+                entry.value = value;
+                entry.seq += 1;
+            }
+            None => {
+                let mut entry = self.new_entry(value, None, original_key)?;
+                entry.seq = data.get(&key).map_or(1, |expired| expired.seq + 1);
+                data.insert(key.clone(), entry);
+            }
+        }
+        let entry = data.get_mut(&key).expect("the entry was just written");
+        let digest = ValueDigest::of(&entry.value);
+        self.record_put(&key, entry, digest);
+        Ok(added)
+    }
+
+    /// Returns the members of the sorted set stored under `key` whose score is in `[min, max]`,
+    /// ordered by score (ties broken by member).
+    pub fn zrange_by_score(&self, key: String, min: f64, max: f64) -> Result<Vec<(String, f64)>> {
+        let value = self.get(key.clone())?;
+        Ok(sorted_members(&key, &value)?
+            .into_iter()
+            .filter(|(_, score)| (min..=max).contains(score))
+            .collect())
+    }
+
+    /// Returns the position of `member` in the sorted set stored under `key` (0 for the lowest
+    /// score), or `None` if it isn't in the set.
+    pub fn zrank(&self, key: String, member: &str) -> Result<Option<usize>> {
+        let value = self.get(key.clone())?;
+        Ok(sorted_members(&key, &value)?
+            .iter()
+            .position(|(m, _)| m == member))
+    }
+
+    /// Whether a live entry is stored under `key`, checked under a read lock without copying its
+    /// value. Like [`KVStore::value_len`], this neither evicts expired entries nor counts towards
+    /// the metrics.
+    pub fn exists(&self, key: String) -> Result<bool> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let data = self.shards[shard_idx].read_key(&key)?;
+        Ok(data
+            .get(&key)
+            .is_some_and(|entry| !entry.is_expired(current_millis())))
+    }
+
+    /// Returns the length of the value stored under `key`: the number of items of an array, of
+    /// fields of an object, or of characters of a string. Other values have no length and fail
+    /// with [`KVError::Conflict`].
+    ///
+    /// The value is measured in place, without copying it. Like [`KVStore::entry`], this neither
+    /// evicts expired entries nor counts towards the metrics.
+    pub fn value_len(&self, key: String) -> Result<usize> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let data = self.shards[shard_idx].read_key(&key)?;
+        let entry = match data.get(&key) {
+            None => return Err(KVError::NotFound(key).into()),
+            Some(entry) if entry.is_expired(current_millis()) => {
+                return Err(KVError::Expired(key).into());
+            }
+            Some(entry) => entry,
+        };
+        match &entry.value {
+            serde_json::Value::Array(items) => Ok(items.len()),
+            serde_json::Value::Object(fields) => Ok(fields.len()),
+            serde_json::Value::String(s) => Ok(s.chars().count()),
+            _ => Err(
+                KVError::Conflict(format!("value of key {} has no length", echo_key(&key))).into(),
+            ),
+        }
+    }
+
+    /// Returns the items of the list stored under `key` between `start` and `stop` (both
+    /// inclusive). Negative indices count from the end of the list, `-1` being the last item.
+    pub fn list_range(&self, key: String, start: i64, stop: i64) -> Result<serde_json::Value> {
+        let value = self.get(key.clone())?;
+        let items = value.as_array().ok_or_else(|| {
+            KVError::Conflict(format!("value of key {} is not a list", echo_key(&key)))
+        })?;
+        let range = match list_bounds(items.len(), start, stop) {
+            Some((from, to)) => items[from..=to].to_vec(),
+            None => vec![],
+        };
+        Ok(serde_json::Value::Array(range))
+    }
+
+    /// Keeps only the items of the list stored under `key` between `start` and `stop` (indexed
+    /// like in [`KVStore::list_range`]), under a single write lock acquisition. Trimming every
+    /// item leaves an empty list. The entry keeps its TTL.
+    pub fn list_trim(&self, key: String, start: i64, stop: i64) -> Result<()> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        let entry = match data.get_mut(&key) {
+            Some(entry) if !entry.is_expired(current_millis()) => entry,
+            _ => return Err(KVError::NotFound(key).into()),
+        };
+        let mut items = entry.value.as_array().cloned().ok_or_else(|| {
+            KVError::Conflict(format!("value of key {} is not a list", echo_key(&key)))
+        })?;
+        match list_bounds(items.len(), start, stop) {
+            Some((from, to)) => {
+                items.truncate(to + 1);
+                items.drain(..from);
+            }
+            None => items.clear(),
+        }
+        let value = serde_json::Value::Array(items);
+        self.check_value(&key, &value)?;
+        entry.value = value;
+        entry.seq += 1;
+        let digest = ValueDigest::of(&entry.value);
+        self.record_put(&key, entry, digest);
+        Ok(())
+    }
+
+    pub fn delete(&self, key: String) -> Result<()> {
+        let (key, _) = self.normalize_key(key);
+        let shard_idx = self.find_shard(&key);
+        let mut data = self.shards[shard_idx].lock_key(&key)?;
+        if let Some(entry) = data.remove(&key) {
+            self.listeners.notify(ChangeEvent {
+                op: ChangeOp::Delete,
+                key: entry.display_key(&key).to_string(),
+                value: None,
+                expires_at_ms: None,
+                seq: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Moves the live entry stored under `from` to `to`, keeping its value and TTL and replacing
+    /// whatever `to` held. With `nx`, the move fails with [`KVError::Conflict`] instead if `to`
+    /// holds a live entry.
+    ///
+    /// When the keys hash to different shards, both are locked for the whole move, in shard
+    /// index order so that concurrent renames can't deadlock. Within a `DashMap` shard, the keys
+    /// are locked one after the other: readers may briefly find neither key.
+    pub fn rename(&self, from: String, to: String, nx: bool) -> Result<()> {
+        let (from, _) = self.normalize_key(from);
+        let (to, original_to) = self.normalize_key(to);
+        if from == to {
+            return Err(KVError::InvalidInput(
+                "the source and destination keys are the same".to_string(),
+            )
+            .into());
+        }
+        let from_idx = self.find_shard(&from);
+        let to_idx = self.find_shard(&to);
+        let now = current_millis();
+        if from_idx == to_idx && self.shard_backend == ShardBackend::DashMap {
+            let entry = {
+                let mut source = self.shards[from_idx].lock_key(&from)?;
+                self.check_renamed(&source, &from, &to)?;
+                take_live(&mut source, &from, now)?
+            };
+            let mut destination = self.shards[to_idx].lock_key(&to)?;
+            let seq = match next_seq(&destination, &to, nx, now) {
+                Ok(seq) => seq,
+                Err(e) => {
+                    drop(destination);
+                    let mut source = self.shards[from_idx].lock_key(&from)?;
+                    // unless written in the meantime
+                    if source.get(&from).is_none() {
+                        source.insert(from, entry);
+                    }
+                    return Err(e);
+                }
+            };
+            self.notify_rename(&from, &entry);
+            return self.insert_renamed(&mut destination, to, original_to, entry, seq);
+        }
+        if from_idx == to_idx {
+            let mut data = self.shards[from_idx].lock_key(&from)?;
+            check_live(&data, &from, now)?;
+            self.check_renamed(&data, &from, &to)?;
+            let seq = next_seq(&data, &to, nx, now)?;
+            let entry = take_live(&mut data, &from, now)?;
+            self.notify_rename(&from, &entry);
+            return self.insert_renamed(&mut data, to, original_to, entry, seq);
+        }
+        let (mut source, mut destination) = if from_idx < to_idx {
+            let source = self.shards[from_idx].lock_key(&from)?;
+            (source, self.shards[to_idx].lock_key(&to)?)
+        } else {
+            let destination = self.shards[to_idx].lock_key(&to)?;
+            (self.shards[from_idx].lock_key(&from)?, destination)
+        };
+        check_live(&source, &from, now)?;
+        self.check_renamed(&source, &from, &to)?;
+        let seq = next_seq(&destination, &to, nx, now)?;
+        let entry = take_live(&mut source, &from, now)?;
+        self.notify_rename(&from, &entry);
+        self.insert_renamed(&mut destination, to, original_to, entry, seq)
+    }
+
+    /// Checks the value stored under `from` against the limits and schema of `to`.
+    fn check_renamed(&self, source: &KeyGuard<'_>, from: &str, to: &str) -> Result<()> {
+        match source.get(from) {
+            Some(entry) => self.check_value(to, &entry.value),
+            None => Ok(()),
+        }
+    }
+
+    fn notify_rename(&self, from: &str, entry: &ShardEntry) {
+        self.listeners.notify(ChangeEvent {
+            op: ChangeOp::Delete,
+            key: entry.display_key(from).to_string(),
+            value: None,
+            expires_at_ms: None,
+            seq: None,
+        });
+    }
+
+    fn insert_renamed(
+        &self,
+        data: &mut KeyGuard<'_>,
+        to: String,
+        original_to: Option<String>,
+        mut entry: ShardEntry,
+        seq: u64,
+    ) -> Result<()> {
+        entry.original_key = original_to;
+        entry.seq = seq;
+        let digest = ValueDigest::of(&entry.value);
+        self.record_put(&to, &mut entry, digest);
+        data.insert(to, entry);
+        Ok(())
+    }
+
+    /// Returns a random live key, or `None` if the store holds none.
+    pub fn random_key(&self) -> Result<Option<String>> {
+        Ok(self.random_entry()?.map(|(key, _)| key))
+    }
+
+    /// Returns a random live key with its value, or `None` if the store holds none. Every stored
+    /// entry is equally likely to be picked, whatever its shard.
+    pub fn random_entry(&self) -> Result<Option<(String, serde_json::Value)>> {
+        for _ in 0..RANDOM_ENTRY_ATTEMPTS {
+            let lengths = self
+                .shards
+                .iter()
+                .map(|shard| shard.get_length())
+                .collect::<Result<Vec<usize>>>()?;
+            let total: usize = lengths.iter().sum();
+            if total == 0 {
+                return Ok(None);
+            }
+            let mut pick = rand::random_range(0..total);
+            let shard_idx = lengths
+                .iter()
+                .position(|len| {
+                    let found = pick < *len;
+                    if !found {
+                        pick -= len;
+                    }
+                    found
+                })
+                .expect("pick is below the total length");
+            // the shard may have shrunk since its length was read
+            if let Some(entry) = self.shards[shard_idx].nth_live_entry(pick)? {
+                return Ok(Some(entry));
+            }
+        }
+        // mostly expired entries: pick among the live ones instead
+        let mut live = vec![];
+        for shard in &self.shards {
+            live.extend(shard.live_entries(None)?);
+        }
+        if live.is_empty() {
+            return Ok(None);
+        }
+        let pick = rand::random_range(0..live.len());
+        Ok(Some(live.swap_remove(pick)))
+    }
+
+    /// Removes the keys starting with `prefix`, returning the unexpired ones with their values.
+    ///
+    /// Each shard is drained under a single write lock acquisition, so a key is returned by at
+    /// most one of several concurrent drains. Atomicity isn't guaranteed across shards: keys
+    /// written to an already drained shard while the others are drained are left in the store.
+    pub fn drain_prefix(&self, prefix: &str) -> Result<HashMap<String, serde_json::Value>> {
+        let (prefix, _) = self.normalize_key(prefix.to_string());
+        let now = current_millis();
+        let mut drained = HashMap::new();
+        for shard in &self.shards {
+            for (key, entry) in shard.drain_prefix(&prefix)? {
+                if entry.is_expired(now) {
+                    continue;
+                }
+                let display_key = entry.display_key(&key).to_string();
+                self.listeners.notify(ChangeEvent {
+                    op: ChangeOp::Delete,
+                    key: display_key.clone(),
+                    value: None,
+                    expires_at_ms: None,
+                    seq: None,
+                });
+                drained.insert(display_key, entry.value);
+            }
+        }
+        Ok(drained)
+    }
+
+    /// Returns the unexpired entries of the shard with index `shard_idx` along with their
+    /// remaining TTL in seconds (`None` for entries without a TTL), e.g. to copy the store to
+    /// another instance one shard at a time.
+    pub fn shard_export(
+        &self,
+        shard_idx: usize,
+    ) -> Result<Vec<(String, serde_json::Value, Option<f64>)>> {
+        let now = current_millis();
+        let mut entries = vec![];
+        self.shards[shard_idx].for_each_entry(|key, entry| {
+            if !entry.is_expired(now) {
+                // a TTL of 0 would make the copy persistent
+                let ttl = entry
+                    .remaining_ttl_at(now)
+                    .map(|remaining| remaining.as_secs_f64().max(0.001));
+                entries.push((entry.display_key(key).to_string(), entry.value.clone(), ttl));
+            }
+        })?;
+        Ok(entries)
+    }
+
+    /// Returns a page of at most `limit` unexpired keys starting with `prefix`, in lexicographic
+    /// order and past the `after` cursor, along with the cursor of the next page (`None` for the
+    /// last page).
+    pub fn list_keys_page(
+        &self,
+        prefix: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        Ok(first_keys(self.list_keys(prefix)?, after, limit))
+    }
+
+    /// Returns a page of at most `limit` unexpired keys starting with `prefix`, going through the
+    /// shards in order (and through each shard's keys in lexicographic order), past the `cursor`
+    /// returned along with the previous page.
+    ///
+    /// Unlike [`KVStore::list_keys_page`], which collects the keys of every shard for each page,
+    /// only the shards needed to fill the page are scanned: the cursor records the shard to
+    /// resume from, as `<shard>:<last key>` (`<shard>:` to start at the beginning of the shard).
+    pub fn list_keys_shard_page(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ShardKeysPage> {
+        let prefix = prefix.map(|p| self.normalize_key(p.to_string()).0);
+        // An empty page would never move the cursor forward
+        let limit = limit.max(1);
+        let (mut shard_idx, mut after) = match cursor {
+            Some(cursor) => parse_shard_cursor(cursor, self.shards.len())?,
+            None => (0, None),
+        };
+        let mut page = ShardKeysPage::default();
+        while shard_idx < self.shards.len() && page.keys.len() < limit {
+            let mut keys =
+                self.shards[shard_idx].live_keys(prefix.as_deref(), self.probe_expired_on_scan)?;
+            page.shards_scanned += 1;
+            if let Some(after) = after.take() {
+                keys.retain(|key| *key > after);
+            }
+            keys.sort();
+            let room = limit - page.keys.len();
+            if keys.len() > room {
+                keys.truncate(room);
+                page.keys.extend(keys);
+                page.next_cursor = Some(format!("{}:{}", shard_idx, page.keys[limit - 1]));
+                return Ok(page);
+            }
+            page.keys.extend(keys);
+            shard_idx += 1;
+        }
+        if shard_idx < self.shards.len() {
+            page.next_cursor = Some(format!("{}:", shard_idx));
+        }
+        Ok(page)
+    }
+
+    /// Returns up to `limit` unexpired keys starting with `prefix`, skipping the first `cursor`
+    /// ones, along with the cursor of the next page (0 once every key was returned).
+    ///
+    /// Shards are walked in order, so a page only collects the keys of the shards it reaches.
+    /// The order of the keys within a shard is unspecified, but stable as long as the shard
+    /// isn't modified: keys written during a scan may be skipped or returned twice. Keys
+    /// removed between two pages shift the later keys back, so that some are silently
+    /// skipped; this includes deletes, but also the keys expiring and the evictions of
+    /// [`KVStore::cleanup`].
+    pub fn scan(
+        &self,
+        prefix: Option<String>,
+        cursor: usize,
+        limit: usize,
+    ) -> Result<(Vec<String>, usize)> {
+        let prefix = prefix.map(|p| self.normalize_key(p).0);
+        // An empty page would never move the cursor forward
+        let limit = limit.max(1);
+        let mut skip = cursor;
+        let mut keys = vec![];
+        for shard in &self.shards {
+            let shard_keys = shard.live_keys(prefix.as_deref(), self.probe_expired_on_scan)?;
+            if skip >= shard_keys.len() {
+                skip -= shard_keys.len();
+                continue;
+            }
+            let room = limit - keys.len();
+            let remaining = shard_keys.len() - skip;
+            keys.extend(shard_keys.into_iter().skip(skip).take(room));
+            skip = 0;
+            if remaining > room {
+                return Ok((keys, cursor + limit));
+            }
+        }
+        Ok((keys, 0))
+    }
+
+    /// Returns the unexpired keys starting with `prefix` (all of them if `prefix` is `None`),
+    /// in no particular order.
+    pub fn list_keys(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        let prefix = prefix.map(|p| self.normalize_key(p.to_string()).0);
+        let mut keys: Vec<String> = vec![];
+        for shard in &self.shards {
+            keys.extend(shard.live_keys(prefix.as_deref(), self.probe_expired_on_scan)?);
+        }
+        Ok(keys)
+    }
+
+    /// Returns the unexpired entries of the shard with index `shard_idx` whose key starts with
+    /// `prefix`, e.g. to go through the entries under a prefix one shard at a time.
+    pub fn shard_live_entries(
+        &self,
+        shard_idx: usize,
+        prefix: &str,
+    ) -> Result<Vec<(String, serde_json::Value)>> {
+        let (prefix, _) = self.normalize_key(prefix.to_string());
+        self.shards[shard_idx].live_entries(Some(&prefix))
+    }
+
+    /// Returns the sorted keys whose unexpired value holds, at the JSON pointer `path`, an array
+    /// containing `element`. Values without an array at `path` never match.
+    ///
+    /// This scans every entry of the store, so it takes time proportional to its size.
+    pub fn keys_containing(&self, path: &str, element: &serde_json::Value) -> Result<Vec<String>> {
+        Ok(self
+            .keys_containing_page(path, element, None, usize::MAX)?
+            .0)
+    }
+
+    /// Like [`KVStore::keys_containing`], returning a page of at most `limit` keys past the
+    /// `after` cursor, along with the cursor of the next page (`None` for the last page), the
+    /// way [`KVStore::list_keys_page`] does.
+    pub fn keys_containing_page(
+        &self,
+        path: &str,
+        element: &serde_json::Value,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        if !path.is_empty() && !path.starts_with('/') {
+            return Err(KVError::InvalidInput(format!(
+                "{} is not a JSON pointer, it should start with /",
+                path
+            ))
+            .into());
+        }
+        let current_time = current_millis();
+        let mut keys = vec![];
+        for shard in &self.shards {
+            shard.for_each_entry(|k, entry| {
+                if !(self.probe_expired_on_scan && entry.is_expired(current_time))
+                    && let Some(serde_json::Value::Array(items)) = entry.value.pointer(path)
+                    && items.contains(element)
+                {
+                    keys.push(entry.display_key(k).to_string());
+                }
+            })?;
+        }
+        Ok(first_keys(keys, after, limit))
+    }
+
+    /// Writes the shards changed since the previous flush to the flush target, recording the
+    /// outcome in [`KVStore::flush_status`]. Flushes run one at a time, and explicit ones run
+    /// even while flushing is paused: see [`KVStore::to_disk_unless_paused`].
+    pub fn to_disk(&self) -> Result<()> {
+        let _flushing = self.lock_flushes();
+        self.flush_all()
+    }
+
+    /// Like [`KVStore::to_disk`] for background flushes: skipped while flushing is paused,
+    /// returning whether it flushed. The pause is checked once no other flush is in progress,
+    /// so nothing is written after [`KVStore::pause_flushing`] returns.
+    pub fn to_disk_unless_paused(&self) -> Result<bool> {
+        let _flushing = self.lock_flushes();
+        if self.is_flushing_paused() {
+            return Ok(false);
+        }
+        self.flush_all()?;
+        Ok(true)
+    }
+
+    fn lock_flushes(&self) -> MutexGuard<'_, ()> {
+        // nothing is guarded: a flush that panicked leaves nothing to recover
+        self.flush_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn flush_all(&self) -> Result<()> {
+        if self.in_memory {
+            return Ok(());
+        }
+        self.flush_progress.start(self.shards.len());
+        let result = self.flush_changed_shards();
+        self.flush_progress.finish();
+        self.record_flush(result.as_ref().err())?;
+        result
+    }
+
+    /// Writes the shards of a store loaded from disk that have no file yet in the flush target
+    /// (as empty shards, unless written to since), so that the target holds a file for every
+    /// shard and flushes know the length of every shard on disk. Returns how many files were
+    /// created.
+    pub fn init_missing_shard_files(&self) -> Result<usize> {
+        if self.in_memory {
+            return Ok(0);
+        }
+        let mut created = 0;
+        for shard_idx in 0..self.shards.len() {
+            if self.flush_target.read_shard(shard_idx)?.is_none() {
+                self.write_shard(shard_idx)?;
+                created += 1;
+            }
+        }
+        Ok(created)
+    }
+
+    /// Writes the shard with index `shard_idx` to the flush target if it changed since it was
+    /// last written (see [`Shard::is_dirty`]), returning whether it did. The outcome is recorded
+    /// in [`KVStore::flush_status`] like full flushes.
+    ///
+    /// Like [`KVStore::to_disk_unless_paused`], this is skipped while flushing is paused.
+    pub fn flush_shard(&self, shard_idx: usize) -> Result<bool> {
+        let shard = &self.shards[shard_idx];
+        let _flushing = self.lock_flushes();
+        if self.in_memory || self.is_flushing_paused() || !shard.is_dirty() {
+            return Ok(false);
+        }
+        let result = self.write_shard(shard_idx);
+        self.record_flush(result.as_ref().err())?;
+        result.map(|_| true)
+    }
+
+    /// Encodes a shard the way it's flushed.
+    fn encode_shard(&self, shard_idx: usize) -> Result<String> {
+        let shard = &self.shards[shard_idx];
+        if self.pretty_disk {
+            shard.encode_pretty()
+        } else {
+            shard.encode()
+        }
+    }
+
+    /// Returns the index of every shard along with the size (in bytes) a flush would write for
+    /// it in the current format, without writing anything, e.g. to preview the effect of a
+    /// format change. Unchanged shards are included, although flushes skip them.
+    pub fn flush_dry_run(&self) -> Result<Vec<(usize, usize)>> {
+        (0..self.shards.len())
+            .map(|shard_idx| Ok((shard_idx, self.encode_shard(shard_idx)?.len())))
+            .collect()
+    }
+
+    /// Encodes and writes a shard, recording its length and version as flushed.
+    fn write_shard(&self, shard_idx: usize) -> Result<()> {
+        let shard = &self.shards[shard_idx];
+        // read first: changes made while encoding bump it again, so they're flushed next time
+        let version = shard.version.load(Ordering::Acquire);
+        let shard_length = shard.get_length()?;
+        let contents = self.encode_shard(shard_idx)?;
+        self.flush_target.write_shard(shard_idx, &contents)?;
+        self.flush_progress
+            .bytes_written
+            .fetch_add(contents.len() as u64, Ordering::Relaxed);
+        // only recorded once written, so that failed flushes are retried
+        self.shard_dimensions
+            .write()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .insert(shard_idx, shard_length);
+        shard.flushed_version.store(version, Ordering::Release);
+        Ok(())
+    }
+
+    fn record_flush(&self, error: Option<&anyhow::Error>) -> Result<()> {
+        let mut status = self
+            .flush_status
+            .write()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        status.last_flush_ms = Some(current_millis() as u64);
+        match error {
+            None => {
+                status.last_error = None;
+                status.consecutive_failures = 0;
+            }
+            Some(e) => {
+                status.last_error = Some(e.to_string());
+                status.consecutive_failures += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush_status(&self) -> Result<FlushStatus> {
+        let status = self
+            .flush_status
+            .read()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        Ok(status.clone())
+    }
+
+    /// Progress of the running flush, or outcome of the latest one. Flushes running concurrently
+    /// (e.g. an explicit one during a background one) share the counters.
+    pub fn flush_progress(&self) -> FlushProgress {
+        self.flush_progress.snapshot()
+    }
+
+    /// Flushes the changed shards, `flush_concurrency` at a time: that many workers take the
+    /// shards in order until none is left, each stopping at its first error.
+    fn flush_changed_shards(&self) -> Result<()> {
+        let workers = self.flush_concurrency.min(self.shards.len());
+        if workers <= 1 {
+            return (0..self.shards.len()).try_for_each(|i| self.flush_if_changed(i));
+        }
+        let next_shard = AtomicUsize::new(0);
+        let flush_next = || -> Result<()> {
+            loop {
+                let i = next_shard.fetch_add(1, Ordering::Relaxed);
+                if i >= self.shards.len() {
+                    return Ok(());
+                }
+                self.flush_if_changed(i)?;
+            }
+        };
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers).map(|_| scope.spawn(flush_next)).collect();
+            // join every worker before reporting the first error
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("a flush worker panicked")))
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .collect()
+        })
+    }
+
+    fn flush_if_changed(&self, shard_idx: usize) -> Result<()> {
+        if self.shards[shard_idx].is_dirty() {
+            self.write_shard(shard_idx)?;
+        }
+        self.flush_progress
+            .shards_flushed
+            .fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stops the background flush loop from writing to disk (e.g. while the directory is being
+    /// snapshotted), waiting for the flush in progress, if any. Changes keep accumulating and
+    /// are flushed once resumed. Explicit calls to [`KVStore::to_disk`] are not affected.
+    pub fn pause_flushing(&self) {
+        self.flush_paused.store(true, Ordering::SeqCst);
+        drop(self.lock_flushes());
+    }
+
+    pub fn resume_flushing(&self) {
+        self.flush_paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_flushing_paused(&self) -> bool {
+        self.flush_paused.load(Ordering::SeqCst)
+    }
+
+    /// Replaces the whole content of the store with the shards flushed to `directory` (e.g. a
+    /// backup), returning the number of entries restored. The shard files are loaded and their
+    /// integrity verified first, so the store is left untouched if any of them is invalid.
+    ///
+    /// The content of every shard is swapped at once (one shard at a time with the `DashMap`
+    /// backend): operations already holding a shard complete on the old data, later ones see the
+    /// restored data. Every clone of the store
+    /// follows, and the restored data is flushed to the store's own target on the next flush.
+    /// Once the shards are unlocked, the listeners are notified of a delete for every key that
+    /// isn't restored, and of a put for every restored one.
+    ///
+    /// The backup must have been written with the store's number of shards (per its manifest, if
+    /// any) and hash strategy: every key must be stored in the shard it hashes to.
+    pub fn restore_from(&self, directory: &str) -> Result<usize> {
+        let invalid = |reason: String| {
+            KVError::InvalidInput(format!("cannot restore {}: {}", directory, reason))
+        };
+        let manifest = read_manifest(directory).map_err(|e| invalid(e.to_string()))?;
+        if let Some(manifest) = manifest
+            && manifest.shards != self.num_shards()
+        {
+            return Err(invalid(format!(
+                "it holds {} shards, but the store has {}",
+                manifest.shards,
+                self.num_shards()
+            ))
+            .into());
+        }
+        if let Some(manifest) = manifest
+            && manifest.hash_strategy != self.hash_strategy
+        {
+            return Err(invalid(format!(
+                "it was written with the {:?} hash strategy, but the store uses {:?}",
+                manifest.hash_strategy, self.hash_strategy
+            ))
+            .into());
+        }
+        let restored = Self::new_from_disk(self.num_shards(), directory.to_string())
+            .map_err(|e| invalid(e.to_string()))?;
+        let mut restored_entries = vec![];
+        for (i, shard) in restored.shards.iter().enumerate() {
+            let entries = shard.entries()?;
+            if let Some(key) = entries.keys().find(|key| self.find_shard(key) != i) {
+                return Err(invalid(format!(
+                    "key {} is stored in shard {}, but hashes to shard {}",
+                    echo_key(key),
+                    i,
+                    self.find_shard(key)
+                ))
+                .into());
+            }
+            restored_entries.push(entries);
+        }
+        let entries = restored_entries.iter().map(HashMap::len).sum();
+        // DashMap shards can't be locked as a whole: they are replaced one at a time
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            guards.push(match &shard.data {
+                ShardStorage::Locked(data) => Some(
+                    Shard::loaded(data)?
+                        .write()
+                        .map_err(|e| anyhow!(e.to_string()))?,
+                ),
+                ShardStorage::Dash(_) => None,
+            });
+        }
+        let now = current_millis();
+        let mut events = vec![];
+        for ((shard, guard), data) in self.shards.iter().zip(&mut guards).zip(restored_entries) {
+            if !self.listeners.is_empty() {
+                match guard {
+                    Some(guard) => events.extend(Self::restore_events(guard, &data, now)),
+                    None => events.extend(Self::restore_events(&shard.entries()?, &data, now)),
+                }
+            }
+            match guard {
+                Some(guard) => {
+                    **guard = data;
+                    shard.touch();
+                }
+                None => shard.replace_entries(data)?,
+            }
+        }
+        drop(guards);
+        for event in events {
+            self.listeners.notify(event);
+        }
+        Ok(entries)
+    }
+
+    /// Changes turning the `previous` entries of a shard into the `restored` ones: deletes for
+    /// the keys without a live restored entry, then puts for the live restored entries.
+    fn restore_events(
+        previous: &HashMap<String, ShardEntry>,
+        restored: &HashMap<String, ShardEntry>,
+        now: u128,
+    ) -> Vec<ChangeEvent> {
+        let is_live = |key: &String| restored.get(key).is_some_and(|e| !e.is_expired(now));
+        let deletes = previous
+            .iter()
+            .filter(|(key, _)| !is_live(key))
+            .map(|(key, entry)| ChangeEvent {
+                op: ChangeOp::Delete,
+                key: entry.display_key(key).to_string(),
+                value: None,
+                expires_at_ms: None,
+                seq: None,
+            });
+        let puts = restored
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| ChangeEvent {
+                op: ChangeOp::Put,
+                key: entry.display_key(key).to_string(),
+                value: Some(entry.value.clone()),
+                expires_at_ms: entry.expires_at().map(|t| t as u64),
+                seq: None,
+            });
+        deletes.chain(puts).collect()
+    }
+
+    /// Evicts the expired entries of every shard, returning how many were evicted. The listeners
+    /// are notified of every eviction once its shard is unlocked.
+    ///
+    /// Runs are recorded in [`KVStore::cleanup_status`] and the metrics.
+    pub fn cleanup(&self) -> Result<usize> {
+        self.cleanup_status
+            .write()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .last_started_ms = Some(current_millis() as u64);
+        let mut evicted = 0;
+        let mut i = 0;
+        while i < self.shards.len() {
+            let keys = self.shards[i].evict_keys(self.eviction_batch_size)?;
+            evicted += keys.len();
+            for key in keys {
+                self.listeners.notify(ChangeEvent {
+                    op: ChangeOp::Expire,
+                    key,
+                    value: None,
+                    expires_at_ms: None,
+                    seq: None,
+                });
+            }
+            i += 1;
+        }
+        let finished_ms = current_millis() as u64;
+        let mut status = self
+            .cleanup_status
+            .write()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        status.last_finished_ms = Some(finished_ms);
+        status.last_evicted = evicted;
+        status.total_evicted += evicted as u64;
+        self.metrics.record_cleanup(evicted, finished_ms);
+        Ok(evicted)
+    }
+
+    pub fn cleanup_status(&self) -> Result<CleanupStatus> {
+        let status = self
+            .cleanup_status
+            .read()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        Ok(status.clone())
+    }
+
+    /// Shrinks every shard to fit its live entries.
+    pub fn compact(&self) -> Result<()> {
+        for shard in &self.shards {
+            shard.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Computes how the stored entries (expired ones included, until evicted) would be
+    /// redistributed across `new_count` shards, without modifying the store.
+    pub fn rebalance_plan(&self, new_count: usize) -> Result<RebalancePlan> {
+        if new_count == 0 {
+            return Err(
+                KVError::InvalidInput("the number of shards must be positive".to_string()).into(),
+            );
+        }
+        let mut shard_loads: Vec<usize> = vec![];
+        let mut new_shard_loads: Vec<usize> = vec![0; new_count];
+        let mut keys_to_move = 0;
+        for (i, shard) in self.shards.iter().enumerate() {
+            let mut load = 0;
+            shard.for_each_entry(|key, _| {
+                load += 1;
+                let new_idx = self.hash_strategy.shard_index(key, new_count);
+                new_shard_loads[new_idx] += 1;
+                if new_idx != i {
+                    keys_to_move += 1;
+                }
+            })?;
+            shard_loads.push(load);
+        }
+        Ok(RebalancePlan {
+            current_shards: self.shards.len(),
+            new_shards: new_count,
+            total_keys: shard_loads.iter().sum(),
+            shard_loads,
+            new_shard_loads,
+            keys_to_move,
+        })
+    }
+
+    /// Returns a copy of the store with its entries rehashed into `new_count` shards. The copy
+    /// keeps the directory and options of the store, but nothing is flushed until `to_disk` runs.
+    /// Every key keeps its value and expiry, so the listeners have no change to hear of.
+    pub fn resharded(&self, new_count: usize) -> Result<Self> {
+        if new_count == 0 {
+            return Err(
+                KVError::InvalidInput("the number of shards must be positive".to_string()).into(),
+            );
+        }
+        let mut new_data: Vec<HashMap<String, ShardEntry>> = vec![HashMap::new(); new_count];
+        for shard in &self.shards {
+            shard.for_each_entry(|key, entry| {
+                new_data[self.hash_strategy.shard_index(key, new_count)]
+                    .insert(key.clone(), entry.clone());
+            })?;
+        }
+        let metrics = Arc::new(Metrics::default());
+        Ok(Self {
+            shards: new_data
+                .into_iter()
+                .map(|data| {
+                    Shard::new_with_data(data)
+                        .with_lock_metrics(metrics.lock_metrics())
+                        .with_backend(self.shard_backend)
+                })
+                .collect::<Result<Vec<Shard>>>()?,
+            shard_dimensions: Arc::new(RwLock::new(HashMap::new())),
+            metrics,
+            ..self.clone()
+        })
+    }
+}
+
+/// Rank of a copy of a key stored in several shards: the copy with the highest rank (the one
+/// written last) is kept, ties going to the copy in the lowest shard. Shared by loading and
+/// [`fsck`], so that both keep the same copy.
+fn duplicate_rank(entry: &ShardEntry) -> u128 {
+    entry.timestamp
+}
+
+/// Verifies the shard files of `directory`, as written by a store with `num_shards` shards
+/// hashing keys with `hash_strategy`: integrity hashes, keys duplicated across shards and keys
+/// stored in the wrong shard.
+///
+/// With `repair`, every readable shard file is rewritten in canonical form, with misplaced keys
+/// moved to the shard they hash to and only the newest copy of duplicated keys kept (by
+/// timestamp, like when loading a store). Keys belonging to a corrupt shard stay where they are.
+pub fn fsck(
+    directory: &str,
+    num_shards: usize,
+    hash_strategy: HashStrategy,
+    repair: bool,
+) -> Result<FsckReport> {
+    let mut report = FsckReport::default();
+    let mut loaded: Vec<(usize, HashMap<String, ShardEntry>)> = vec![];
+    for i in shard_file_indices(directory)? {
+        report.shards_checked += 1;
+        match Shard::from_file(&shard_file_path(directory, i)) {
+            Ok(shard) => loaded.push((i, shard.entries()?)),
+            Err(e) => {
+                tracing::warn!("Shard {:?} is corrupt: {}", i, e);
+                report.corrupt_shards.push(i);
+            }
+        }
+    }
+
+    let mut copies: HashMap<&str, usize> = HashMap::new();
+    for (i, data) in &loaded {
+        for key in data.keys() {
+            *copies.entry(key).or_default() += 1;
+            if hash_strategy.shard_index(key, num_shards) != *i {
+                report.misplaced_keys.push(key.clone());
+            }
+        }
+    }
+    report.duplicate_keys = copies
+        .into_iter()
+        .filter(|(_, n)| *n > 1)
+        .map(|(key, _)| key.to_string())
+        .collect();
+    report.duplicate_keys.sort();
+    report.misplaced_keys.sort();
+    report.misplaced_keys.dedup();
+    if !repair {
+        return Ok(report);
+    }
+
+    let mut repaired: HashMap<usize, HashMap<String, ShardEntry>> =
+        loaded.iter().map(|(i, _)| (*i, HashMap::new())).collect();
+    for (i, data) in loaded {
+        for (key, entry) in data {
+            let target = hash_strategy.shard_index(&key, num_shards);
+            let destination = if report.corrupt_shards.contains(&target) {
+                i
+            } else {
+                target
+            };
+            if destination != i {
+                report.moved_keys += 1;
+            }
+            let shard = repaired.entry(destination).or_default();
+            let newer = shard
+                .get(&key)
+                .is_none_or(|kept| duplicate_rank(&entry) > duplicate_rank(kept));
+            if newer {
+                shard.insert(key, entry);
+            }
+        }
+    }
+    for (i, data) in repaired {
+        Shard::new_with_data(data).flush(shard_file_path(directory, i), false)?;
+        report.rewritten_shards.push(i);
+    }
+    report.rewritten_shards.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use super::*;
+
+    /// Entries of a shard created with the `RwLock` backend
+    fn locked(shard: &Shard) -> &ShardData {
+        match &shard.data {
+            ShardStorage::Locked(data) => Shard::loaded(data).expect("the shard should load"),
+            ShardStorage::Dash(_) => panic!("the shard should use the RwLock backend"),
+        }
+    }
+
+    fn cleanup_test_file(file_name: String) {
+        if fs::exists(&file_name).expect("Should be able to check file existence") {
+            fs::remove_file(file_name).expect("Should be able to remove file");
+        }
+    }
+
+    fn cleanup_test_directory(directory_name: String) {
+        if fs::exists(&directory_name).expect("Should be able to check directory existence") {
+            fs::remove_dir_all(directory_name).expect("Should be able to remove directory content");
+        }
+    }
+
+    #[test]
+    fn test_shard_entry_init() {
+        let shard_entry = ShardEntry::new(serde_json::Value::from("hello"), Some(0.001));
+        assert_eq!(shard_entry.value, serde_json::Value::from("hello"));
+        assert_eq!(shard_entry.ttl, 1);
+        assert!(current_millis() >= shard_entry.timestamp);
+    }
+
+    #[test]
+    fn test_shard_entry_ttl_resolution() {
+        for (ttl, resolution_ms, expected) in [
+            (0.0015, 1, 2),
+            (0.001, 100, 100),
+            (0.149, 100, 100),
+            (0.15, 100, 200),
+            (2.0, 100, 2000),
+            (2.0004, 1000, 2000),
+            (0.3, 1000, 1000),
+        ] {
+            let shard_entry = ShardEntry::with_ttl_resolution(
+                serde_json::Value::from(1),
+                Some(ttl),
+                resolution_ms,
+            );
+            assert_eq!(shard_entry.ttl, expected, "{}s at {}ms", ttl, resolution_ms);
+        }
+        let shard_entry = ShardEntry::with_ttl_resolution(serde_json::Value::from(1), None, 100);
+        assert_eq!(shard_entry.ttl, -1);
+        // huge TTLs saturate instead of overflowing while rounding
+        let shard_entry =
+            ShardEntry::with_ttl_resolution(serde_json::Value::from(1), Some(1e300), 100);
+        assert!(shard_entry.ttl > i128::MAX - 100);
+
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store")
+            .with_ttl_resolution_ms(1000);
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), Some(1.4))
+            .expect("Should be able to call .put without errors");
+        let (_, remaining) = kv_store
+            .get_with_ttl("hey".to_string())
+            .expect("Should be able to get the key");
+        assert!(remaining.unwrap() <= time::Duration::from_secs(1));
+
+        let kv_store = kv_store.with_max_ttl(Some(1.7), TtlCapPolicy::Clamp);
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), Some(1.6))
+            .expect("Should be able to call .put without errors");
+        let (_, remaining) = kv_store
+            .get_with_ttl("hey".to_string())
+            .expect("Should be able to get the key");
+        assert!(remaining.unwrap() <= time::Duration::from_millis(1700));
+
+        let kv_store = kv_store.with_max_ttl(None, TtlCapPolicy::Reject);
+        let err = kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), Some(1e300))
+            .expect_err("TTLs above the longest supported one should be rejected");
+        assert!(matches!(err.downcast_ref(), Some(KVError::InvalidInput(_))));
+        kv_store
+            .put(
+                "hey".to_string(),
+                serde_json::Value::from(1),
+                Some(MAX_TTL_SECS),
+            )
+            .expect("Should be able to put the longest supported TTL");
+        assert_eq!(
+            kv_store.entry("hey".to_string()).unwrap().ttl_ms(),
+            (MAX_TTL_SECS * 1000_f64) as i128
+        );
+    }
+
+    #[test]
+    fn test_shard_entry_expiry_precision() {
+        // 2^53 is where f64 stops representing every integer: with float arithmetic, an elapsed
+        // time of 2^53 + 1 ms rounds down to 2^53 and the entry would wrongly be considered live
+        let ttl: i128 = 1 << 53;
+        let mut entry = ShardEntry::new(serde_json::Value::from(1), None);
+        entry.ttl = ttl;
+        let elapsed = (ttl + 1) as u128;
+        assert!(((elapsed as f64) <= (ttl as f64)));
+        assert!(entry.is_expired(entry.timestamp + elapsed));
+        assert!(!entry.is_expired(entry.timestamp + ttl as u128));
+        // timestamps ahead of the current time (e.g. after a clock adjustment) don't underflow
+        assert!(!entry.is_expired(entry.timestamp - 1));
+    }
+
+    #[test]
+    fn test_shard_entry_float_ttl_compatibility() {
+        let legacy: ShardEntry =
+            serde_json::from_str(r#"{"ttl":1500.0,"value":1,"timestamp":1700000000000}"#)
+                .expect("Should be able to deserialize a float TTL");
+        assert_eq!(legacy.ttl, 1500);
+        let persistent: ShardEntry =
+            serde_json::from_str(r#"{"ttl":-1.0,"value":1,"timestamp":1700000000000}"#)
+                .expect("Should be able to deserialize a float TTL");
+        assert_eq!(persistent.ttl, -1);
+        let entry: ShardEntry = serde_json::from_str(&serde_json::to_string(&legacy).unwrap())
+            .expect("Should be able to round-trip an integer TTL");
+        assert_eq!(entry.ttl, 1500);
+    }
+
+    #[test]
+    fn test_shard_empty_init() {
+        let shard = Shard::new();
+        let data = shard.entries().expect("Should be able to read data");
+        assert_eq!(data.len(), 0);
+    }
+
+    #[test]
+    fn test_shard_with_data_init() {
+        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
+        init_data.insert(
+            "hello".to_string(),
+            ShardEntry::new(serde_json::Value::from(1), None),
+        );
+        init_data.insert(
+            "hey".to_string(),
+            ShardEntry::new(serde_json::Value::from(2), Some(2_f64)),
+        );
+        let shard = Shard::new_with_data(init_data);
+        let data = shard.entries().expect("Should be able to read data");
+        assert_eq!(data.len(), 2);
+        let hello_entry = data
+            .get("hello")
+            .expect("Should be able to retrieve 'hello' key");
+        let hey_entry = data
+            .get("hey")
+            .expect("Should be able to retrieve 'hey' key");
+        assert_eq!(hello_entry.value, serde_json::Value::from(1));
+        assert_eq!(hey_entry.value, serde_json::Value::from(2));
+        assert_eq!(hello_entry.ttl, -1);
+        assert_eq!(hey_entry.ttl, 2000);
+    }
+
+    #[test]
+    fn test_shard_get_length() {
+        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
+        init_data.insert(
+            "hello".to_string(),
+            ShardEntry::new(serde_json::Value::from(1), None),
+        );
+        init_data.insert(
+            "hey".to_string(),
+            ShardEntry::new(serde_json::Value::from(2), Some(2_f64)),
+        );
+        let shard = Shard::new_with_data(init_data);
+        assert_eq!(
+            shard
+                .get_length()
+                .expect("should be able to retrieve length"),
+            2
+        );
+        let shard_1 = Shard::new();
+        assert_eq!(
+            shard_1
+                .get_length()
+                .expect("should be able to retrieve length"),
+            0
+        );
+    }
+
+    #[test]
+    fn test_shard_evict() {
+        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
+        init_data.insert(
+            "hello".to_string(),
+            ShardEntry::new(serde_json::Value::from(1), None),
+        );
+        init_data.insert(
+            "hey".to_string(),
+            ShardEntry::new(serde_json::Value::from(2), Some(0.001)), // 1 millisecond
+        );
+        init_data.insert(
+            "bye".to_string(),
+            ShardEntry::new(serde_json::Value::from(3), Some(2_f64)), // 2 seconds
+        );
+        let shard = Shard::new_with_data(init_data);
+        assert_eq!(shard.get_length().expect("Should be able to get length"), 3);
+        std::thread::sleep(time::Duration::from_millis(5)); // this should discard the 'hey' entry
+        let evicted = shard
+            .evict()
+            .expect("Should be able to evict expired entries");
+        assert_eq!(evicted, 1);
+        assert_eq!(shard.get_length().expect("Should be able to get length"), 2);
+        let data = shard.entries().expect("Should be able to read data");
+        assert_eq!(data.len(), 2);
+        let hello_entry = data.get("hello");
+        assert!(hello_entry.is_some());
+        let bye_entry = data.get("bye");
+        assert!(bye_entry.is_some());
+        let hey_entry = data.get("hey");
+        assert!(hey_entry.is_none());
+    }
+
+    #[test]
+    fn test_shard_evict_up_to() {
+        for backend in [ShardBackend::RwLock, ShardBackend::DashMap] {
+            let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
+            for i in 0..10 {
+                init_data.insert(
+                    format!("key-{}", i),
+                    ShardEntry::new(serde_json::Value::from(i), Some(0.001)),
+                );
+            }
+            let shard = Shard::new_with_data(init_data)
+                .with_backend(backend)
+                .expect("Should be able to change the backend");
+            std::thread::sleep(time::Duration::from_millis(5));
+            assert_eq!(shard.evict_up_to(3).unwrap(), 3);
+            assert_eq!(shard.get_length().unwrap(), 7);
+            assert_eq!(shard.evict_up_to(3).unwrap(), 3);
+            assert_eq!(shard.evict().unwrap(), 4);
+            assert_eq!(shard.get_length().unwrap(), 0);
+        }
+
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(1)
+            .build()
+            .expect("Should be able to create KV store")
+            .with_eviction_batch_size(4);
+        for i in 0..10 {
+            kv_store
+                .put(
+                    format!("key-{}", i),
+                    serde_json::Value::from(i),
+                    Some(0.001),
+                )
+                .expect("Should be able to call .put without errors");
+        }
+        std::thread::sleep(time::Duration::from_millis(5));
+        assert_eq!(kv_store.cleanup().unwrap(), 4);
+        assert_eq!(kv_store.cleanup().unwrap(), 4);
+        assert_eq!(kv_store.cleanup().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_shard_compact() {
+        let shard = Shard::new();
+        {
+            let mut data = locked(&shard)
+                .write()
+                .expect("Should be able to write data");
+            for i in 0..1000 {
+                data.insert(
+                    format!("key-{}", i),
+                    ShardEntry::new(serde_json::Value::from(i), Some(0.001)),
+                );
+            }
+        }
+        std::thread::sleep(time::Duration::from_millis(5));
+        shard
+            .evict()
+            .expect("Should be able to evict expired entries");
+        let capacity_before = locked(&shard)
+            .read()
+            .expect("Should be able to read data")
+            .capacity();
+        shard.compact().expect("Should be able to compact shard");
+        let capacity_after = locked(&shard)
+            .read()
+            .expect("Should be able to read data")
+            .capacity();
+        assert!(capacity_after < capacity_before);
+    }
+
+    #[test]
+    fn test_shard_flush() {
+        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
+        init_data.insert(
+            "hello".to_string(),
+            ShardEntry::new(serde_json::Value::from(1), None),
+        );
+        init_data.insert(
+            "hey".to_string(),
+            ShardEntry::new(serde_json::Value::from(2), None),
+        );
+        let shard = Shard::new_with_data(init_data);
+        shard
+            .flush("shard-0-test".to_string(), false)
+            .expect("Should be able to flush to file");
+
+        assert!(fs::exists("shard-0-test").expect("Should be able to check file existence"));
+        let content = fs::read_to_string("shard-0-test").expect("Should be able to read file path");
+        let (integrity_hash_str, raw_data) = content
+            .split_once("\n")
+            .expect("Should be able to split header from data");
+        assert_eq!(integrity_hash_str.len(), INTEGRITY_HASH_LEN);
+        assert_eq!(integrity_hash_str, integrity_hash(raw_data.as_bytes()));
+        let data: HashMap<String, ShardEntry> =
+            serde_json::from_str(raw_data).expect("Should be able to deserialize data");
+        assert_eq!(data.len(), 2);
+        let hello_entry = data
+            .get("hello")
+            .expect("Should be able to retrieve 'hello' key");
+        let hey_entry = data
+            .get("hey")
+            .expect("Should be able to retrieve 'hey' key");
+        assert_eq!(hello_entry.value, serde_json::Value::from(1));
+        assert_eq!(hey_entry.value, serde_json::Value::from(2));
+        assert_eq!(hello_entry.ttl, -1);
+        assert_eq!(hey_entry.ttl, -1);
+
+        cleanup_test_file("shard-0-test".to_string())
+    }
+
+    #[test]
+    fn test_shard_flush_fsync() {
+        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
+        init_data.insert(
+            "hello".to_string(),
+            ShardEntry::new(serde_json::Value::from(1), None),
+        );
+        init_data.insert(
+            "hey".to_string(),
+            ShardEntry::new(serde_json::Value::from(2), None),
+        );
+        let shard = Shard::new_with_data(init_data);
+        shard
+            .flush("shard-0-fsync-test".to_string(), true)
+            .expect("Should be able to flush to file with fsync");
+        let synced = Shard::from_file("shard-0-fsync-test").expect("Should be able to load shard");
+        assert_eq!(synced.get_length().unwrap(), 2);
+
+        // concurrent flushes each go through their own temporary file
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    shard
+                        .flush("shard-0-fsync-test".to_string(), true)
+                        .expect("Should be able to flush concurrently");
+                });
+            }
+        });
+        let leftovers = fs::read_dir(".")
+            .expect("Should be able to list the directory")
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.starts_with("shard-0-fsync-test.") && name.ends_with(".tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        Shard::from_file("shard-0-fsync-test").expect("Should be able to load shard");
+
+        cleanup_test_file("shard-0-fsync-test".to_string())
+    }
+
+    #[test]
+    fn test_decode_shard_file() {
+        let payload = r#"{"multi":{"ttl":-1.0,"value":"line one\nline two","timestamp":0}}"#;
+        let content = format!("{}\n{}", integrity_hash(payload.as_bytes()), payload);
+        assert_eq!(decode_shard_file(&content), Some(payload));
+        let tampered = content.replace("line two", "line 2");
+        assert_eq!(decode_shard_file(&tampered), None);
+
+        // files written with the hash on the trailing line are still readable
+        let legacy_hash: String = md5::compute(payload.as_bytes())
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        let legacy_content = format!("{}\n{}", payload, legacy_hash);
+        assert_eq!(decode_shard_file(&legacy_content), Some(payload));
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_init() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        assert!(fs::exists(".quache-test/").expect("Should be able to check directory existence"));
+        let shard_dimensions = kv_store
+            .shard_dimensions
+            .read()
+            .expect("Should be able to acquire read lock");
+        assert_eq!(shard_dimensions.len(), 0);
+        assert_eq!(kv_store.shards.len(), 3);
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_find_shard() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        let shard_num_0 = kv_store.find_shard("notthekindofthingyouwouldfind");
+        assert_eq!(shard_num_0, 0);
+        let shard_num_1 = kv_store.find_shard("thisisaverylongkey");
+        assert_eq!(shard_num_1, 1);
+        let shard_num_2 = kv_store.find_shard("this is an interesting key");
+        assert_eq!(shard_num_2, 2);
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_put() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors"); // goes to shard-2
+        assert_eq!(
+            kv_store.shards[2]
+                .get_length()
+                .expect("Should be able to get length"),
+            1
+        );
+        assert_eq!(
+            kv_store.shards[1]
+                .get_length()
+                .expect("Should be able to get length"),
+            0
+        );
+        assert_eq!(
+            kv_store.shards[0]
+                .get_length()
+                .expect("Should be able to get length"),
+            0
+        );
+        let data = kv_store.shards[2]
+            .entries()
+            .expect("Should be able to read entries");
+        assert!(data.contains_key("hey"));
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_get() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors"); // goes to shard-2
+        let result = kv_store
+            .get("hey".to_string())
+            .expect("Should be able to get the 'hey' key");
+        assert_eq!(result, serde_json::Value::from(1));
+        let notfound = kv_store.get("hello".to_string());
+        assert!(notfound.is_err_and(|e| e.to_string().contains("not found")));
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_put_sequenced() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        let applied = kv_store
+            .put_sequenced("hey".to_string(), serde_json::Value::from(2), None, 2)
+            .expect("Should be able to call .put_sequenced without errors");
+        assert!(applied);
+        // a write with an older sequence number arriving late does not regress the value
+        let applied = kv_store
+            .put_sequenced("hey".to_string(), serde_json::Value::from(1), None, 1)
+            .expect("Should be able to call .put_sequenced without errors");
+        assert!(!applied);
+        let applied = kv_store
+            .put_sequenced("hey".to_string(), serde_json::Value::from(22), None, 2)
+            .expect("Should be able to call .put_sequenced without errors");
+        assert!(!applied);
+        assert_eq!(
+            kv_store
+                .get("hey".to_string())
+                .expect("Should be able to get the 'hey' key"),
+            serde_json::Value::from(2)
+        );
+        let applied = kv_store
+            .put_sequenced("hey".to_string(), serde_json::Value::from(3), None, 3)
+            .expect("Should be able to call .put_sequenced without errors");
+        assert!(applied);
+        // unsequenced writes are assigned the next sequence number
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(4), None)
+            .expect("Should be able to call .put without errors");
+        let applied = kv_store
+            .put_sequenced("hey".to_string(), serde_json::Value::from(5), None, 4)
+            .expect("Should be able to call .put_sequenced without errors");
+        assert!(!applied);
+        assert_eq!(
+            kv_store
+                .get("hey".to_string())
+                .expect("Should be able to get the 'hey' key"),
+            serde_json::Value::from(4)
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_list_keys() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        for key in ["hey", "hello", "thisisaverylongkey"] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(1), None)
+                .expect("Should be able to call .put without errors");
+        }
+        kv_store
+            .put(
+                "expiring".to_string(),
+                serde_json::Value::from(1),
+                Some(0.001),
+            )
+            .expect("Should be able to call .put without errors");
+        std::thread::sleep(time::Duration::from_millis(5));
+        let mut keys = kv_store
+            .list_keys(None)
+            .expect("Should be able to list keys");
+        keys.sort();
+        assert_eq!(keys, vec!["hello", "hey", "thisisaverylongkey"]);
+        let mut prefixed = kv_store
+            .list_keys(Some("he"))
+            .expect("Should be able to list keys");
+        prefixed.sort();
+        assert_eq!(prefixed, vec!["hello", "hey"]);
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    fn test_kv_store_expire() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        assert!(!kv_store.expire("missing".to_string(), Some(1_f64)).unwrap());
+
+        assert!(kv_store.expire("hello".to_string(), Some(0.05)).unwrap());
+        assert_eq!(kv_store.entry("hello".to_string()).unwrap().ttl_ms(), 50);
+        assert!(kv_store.expire("hello".to_string(), None).unwrap());
+        assert_eq!(kv_store.entry("hello".to_string()).unwrap().ttl_ms(), -1);
+
+        assert!(kv_store.expire("hello".to_string(), Some(0.05)).unwrap());
+        std::thread::sleep(time::Duration::from_millis(60));
+        assert_eq!(kv_store.cleanup().unwrap(), 1);
+        assert!(kv_store.get("hello".to_string()).is_err());
+        assert!(!kv_store.expire("hello".to_string(), None).unwrap());
+
+        // elapsed TTLs expire the key right away
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        assert!(kv_store.expire("hello".to_string(), Some(0_f64)).unwrap());
+        assert!(kv_store.get("hello".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_kv_store_rejects_non_positive_ttls() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        for ttl in [0_f64, -0.5, -2_f64, f64::NAN, f64::INFINITY] {
+            let err = kv_store
+                .put("hello".to_string(), serde_json::Value::from(1), Some(ttl))
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<KVError>(),
+                    Some(KVError::InvalidInput(_))
+                ),
+                "{}",
+                ttl
+            );
+        }
+        kv_store
+            .put(
+                "hello".to_string(),
+                serde_json::Value::from(1),
+                Some(PERSISTENT_TTL),
+            )
+            .expect("Should be able to put a persistent entry");
+        assert_eq!(kv_store.entry("hello".to_string()).unwrap().ttl_ms(), -1);
+        // sub-millisecond TTLs last at least a millisecond
+        kv_store
+            .put(
+                "hello".to_string(),
+                serde_json::Value::from(1),
+                Some(0.0001),
+            )
+            .expect("Should be able to call .put without errors");
+        assert_eq!(kv_store.entry("hello".to_string()).unwrap().ttl_ms(), 1);
+    }
+
+    #[test]
+    fn test_kv_store_ttl() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        for (key, ttl) in [
+            ("persistent", None),
+            ("expiring", Some(12.5)),
+            ("stale", Some(0.001)),
+        ] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(1), ttl)
+                .expect("Should be able to call .put without errors");
+        }
+        std::thread::sleep(time::Duration::from_millis(5));
+
+        assert_eq!(kv_store.ttl("persistent".to_string()).unwrap(), None);
+        let remaining = kv_store
+            .ttl("expiring".to_string())
+            .unwrap()
+            .expect("expiring key should have a TTL");
+        assert!(remaining > 12_f64 && remaining <= 12.5);
+        let error = kv_store.ttl("stale".to_string()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(KVError::Expired(_))));
+        let error = kv_store.ttl("missing".to_string()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(KVError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_kv_store_get_many() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(4)
+            .build()
+            .expect("Should be able to create KV store");
+        for i in 0..10 {
+            kv_store
+                .put(format!("key{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to call .put without errors");
+        }
+        kv_store
+            .put(
+                "expiring".to_string(),
+                serde_json::Value::from("stale"),
+                Some(0.001),
+            )
+            .expect("Should be able to call .put without errors");
+        std::thread::sleep(time::Duration::from_millis(5));
+
+        let keys = ["key7", "missing", "expiring", "key0", "key7"];
+        let values = kv_store
+            .get_many(keys.iter().map(|key| key.to_string()).collect())
+            .expect("Should be able to get keys");
+        assert_eq!(
+            values,
+            vec![
+                ("key7".to_string(), Some(serde_json::Value::from(7))),
+                ("missing".to_string(), None),
+                ("expiring".to_string(), None),
+                ("key0".to_string(), Some(serde_json::Value::from(0))),
+                ("key7".to_string(), Some(serde_json::Value::from(7))),
+            ]
+        );
+        let snapshot = kv_store.metrics().snapshot();
+        assert_eq!((snapshot.hits, snapshot.misses), (3, 2));
+    }
+
+    #[test]
+    fn test_kv_store_scan() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(4)
+            .build()
+            .expect("Should be able to create KV store");
+        for i in 0..23 {
+            kv_store
+                .put(format!("user:{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to call .put without errors");
+        }
+        for key in ["other", "user:stale"] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(0), Some(0.001))
+                .expect("Should be able to call .put without errors");
+        }
+        std::thread::sleep(time::Duration::from_millis(5));
+
+        let mut scanned = vec![];
+        let mut cursor = 0;
+        loop {
+            let (keys, next) = kv_store
+                .scan(Some("user:".to_string()), cursor, 5)
+                .expect("Should be able to scan keys");
+            assert!(keys.len() <= 5);
+            scanned.extend(keys);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        scanned.sort();
+        let mut expected: Vec<String> = (0..23).map(|i| format!("user:{}", i)).collect();
+        expected.sort();
+        assert_eq!(scanned, expected);
+
+        let (keys, next) = kv_store
+            .scan(None, 100, 5)
+            .expect("Should be able to scan past the end");
+        assert!(keys.is_empty());
+        assert_eq!(next, 0);
+    }
+
+    #[test]
+    fn test_kv_store_list_keys_shard_page() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(4)
+            .build()
+            .expect("Should be able to create KV store");
+        let mut shard_sizes = [0; 4];
+        for i in 0..40 {
+            let key = format!("key{}", i);
+            shard_sizes[kv_store.locate(&key).unwrap().0] += 1;
+            kv_store
+                .put(key, serde_json::Value::from(i), None)
+                .expect("Should be able to call .put without errors");
+        }
+        assert!(shard_sizes.iter().all(|size| *size > 0));
+
+        let mut listed = vec![];
+        let mut cursor = None;
+        loop {
+            let page = kv_store
+                .list_keys_shard_page(None, cursor.as_deref(), 5)
+                .expect("Should be able to list keys");
+            assert!(page.keys.len() <= 5);
+            let shards: HashSet<usize> = page
+                .keys
+                .iter()
+                .map(|key| kv_store.locate(key).unwrap().0)
+                .collect();
+            // Only the shards the page's keys come from are scanned
+            assert_eq!(page.shards_scanned, shards.len());
+            listed.extend(page.keys);
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        listed.sort();
+        let mut expected: Vec<String> = (0..40).map(|i| format!("key{}", i)).collect();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        assert!(kv_store.list_keys_shard_page(None, Some("4:"), 5).is_err());
+        assert!(kv_store.list_keys_shard_page(None, Some("key"), 5).is_err());
+    }
+
+    #[test]
+    fn test_kv_store_put_copy_skips_write_policies() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store")
+            .with_strict_ttl_seconds(true)
+            .with_forbid_persistent(true)
+            .with_max_ttl(Some(10_f64), TtlCapPolicy::Reject);
+        let now = current_millis() as u64;
+        kv_store
+            .put_copy(
+                "short".to_string(),
+                serde_json::Value::from(1),
+                Some(now + 1_500),
+            )
+            .expect("Should copy entries with fractional TTLs");
+        kv_store
+            .put_copy(
+                "long".to_string(),
+                serde_json::Value::from(2),
+                Some(now + 60_000),
+            )
+            .expect("Should copy entries above the maximum TTL");
+        kv_store
+            .put_copy("persistent".to_string(), serde_json::Value::from(3), None)
+            .expect("Should copy persistent entries");
+        kv_store
+            .put_copy(
+                "expired".to_string(),
+                serde_json::Value::from(4),
+                Some(now - 1),
+            )
+            .expect("Should skip expired copies");
+        assert!(kv_store.ttl("short".to_string()).unwrap().unwrap() <= 1.5);
+        assert!(kv_store.ttl("long".to_string()).unwrap().unwrap() > 10_f64);
+        assert_eq!(kv_store.ttl("persistent".to_string()).unwrap(), None);
+        assert!(kv_store.get("expired".to_string()).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_case_insensitive_keys() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store")
+            .with_case_insensitive_keys(true);
+        kv_store
+            .put("Hello".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        for key in ["Hello", "hello", "HELLO"] {
+            assert_eq!(
+                kv_store
+                    .get(key.to_string())
+                    .expect("Should be able to get key case-insensitively"),
+                serde_json::Value::from(1)
+            );
+        }
+        assert_eq!(
+            kv_store
+                .list_keys(None)
+                .expect("Should be able to list keys"),
+            vec!["Hello"]
+        );
+        // overwriting with a different casing keeps a single entry, with the latest casing
+        kv_store
+            .put("HeLLo".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to call .put without errors");
+        assert_eq!(
+            kv_store
+                .list_keys(Some("HE"))
+                .expect("Should be able to list keys"),
+            vec!["HeLLo"]
+        );
+        assert_eq!(
+            kv_store
+                .get("hello".to_string())
+                .expect("Should be able to get key case-insensitively"),
+            serde_json::Value::from(2)
+        );
+        kv_store
+            .delete("HELLO".to_string())
+            .expect("Should be able to delete key");
+        assert!(kv_store.get("Hello".to_string()).is_err());
+
+        // case-sensitive stores (the default) keep keys distinct
+        let case_sensitive = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        case_sensitive
+            .put("Hello".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        assert!(case_sensitive.get("hello".to_string()).is_err());
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_kv_store_schema_uses_normalized_keys() {
+        let schemas = crate::schema::Schemas::from_value(&serde_json::json!({
+            "users:": {"type": "object", "required": ["name"]}
+        }))
+        .expect("Should be able to compile schemas");
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store")
+            .with_case_insensitive_keys(true)
+            .with_schemas(schemas);
+        assert!(
+            kv_store
+                .put("USERS:1".to_string(), serde_json::json!({"age": 1}), None)
+                .is_err()
+        );
+        kv_store
+            .put("tmp".to_string(), serde_json::json!({"age": 1}), None)
+            .expect("Should be able to put outside the schema prefix");
+        // renaming into a schema prefix validates the moved value and keeps the source
+        assert!(
+            kv_store
+                .rename("tmp".to_string(), "users:2".to_string(), false)
+                .is_err()
+        );
+        assert_eq!(
+            kv_store
+                .get("tmp".to_string())
+                .expect("The source should survive a rejected rename"),
+            serde_json::json!({"age": 1})
+        );
+        assert!(kv_store.get("users:2".to_string()).is_err());
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_kv_store_schema_applies_to_derived_writes() {
+        let schemas = crate::schema::Schemas::from_value(&serde_json::json!({
+            "users:": {"type": "object", "required": ["name"]},
+            "board:": {"type": "object", "maxProperties": 2},
+            "counter:": {"type": "integer", "maximum": 10}
+        }))
+        .expect("Should be able to compile schemas");
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store")
+            .with_schemas(schemas);
+        // incr would create an integer where an object is required
+        assert!(
+            kv_store
+                .incr_bounded("users:1".to_string(), 1, None, None)
+                .is_err()
+        );
+        assert!(kv_store.get("users:1".to_string()).is_err());
+        kv_store
+            .incr_bounded("counter:1".to_string(), 10, None, None)
+            .expect("Should be able to increment up to the maximum");
+        assert!(
+            kv_store
+                .incr_bounded("counter:1".to_string(), 1, None, None)
+                .is_err()
+        );
+        assert_eq!(
+            kv_store.get("counter:1".to_string()).unwrap(),
+            serde_json::Value::from(10)
+        );
+
+        kv_store
+            .zadd(
+                "board:1".to_string(),
+                HashMap::from([("a".to_string(), 1_f64)]),
+            )
+            .expect("Should be able to add a member");
+        let members = HashMap::from([("b".to_string(), 2_f64), ("c".to_string(), 3_f64)]);
+        assert!(kv_store.zadd("board:1".to_string(), members).is_err());
+        assert_eq!(
+            kv_store.get("board:1".to_string()).unwrap(),
+            serde_json::json!({"a": 1})
+        );
+        assert!(
+            kv_store
+                .zadd(
+                    "users:2".to_string(),
+                    HashMap::from([("a".to_string(), 1_f64)])
+                )
+                .is_err()
+        );
+        assert!(kv_store.get("users:2".to_string()).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_incr() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        // missing keys start from 0
+        assert_eq!(
+            kv_store
+                .incr_bounded("counter".to_string(), 5, None, None)
+                .expect("Should be able to increment"),
+            (5, false)
+        );
+        assert_eq!(
+            kv_store
+                .incr_bounded("counter".to_string(), -2, None, None)
+                .expect("Should be able to increment"),
+            (3, false)
+        );
+        assert_eq!(
+            kv_store
+                .get("counter".to_string())
+                .expect("Should be able to get counter"),
+            serde_json::Value::from(3)
+        );
+        kv_store
+            .put("text".to_string(), serde_json::Value::from("hello"), None)
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .put("float".to_string(), serde_json::Value::from(1.5), None)
+            .expect("Should be able to call .put without errors");
+        for key in ["text", "float"] {
+            let result = kv_store.incr_bounded(key.to_string(), 1, None, None);
+            assert!(
+                result.is_err_and(|e| matches!(
+                    e.downcast_ref::<KVError>(),
+                    Some(KVError::Conflict(_))
+                ))
+            );
+        }
+        kv_store
+            .put("max".to_string(), serde_json::Value::from(i64::MAX), None)
+            .expect("Should be able to call .put without errors");
+        assert!(
+            kv_store
+                .incr_bounded("max".to_string(), 1, None, None)
+                .is_err()
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    fn test_kv_store_incr_by_concurrently() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(1)
+            .build()
+            .expect("Should be able to create KV store");
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let kv_store = &kv_store;
+                scope.spawn(move || {
+                    for _ in 0..100 {
+                        let delta = if t % 2 == 0 { 3 } else { -1 };
+                        kv_store
+                            .incr_by("views".to_string(), delta)
+                            .expect("Should be able to increment");
+                    }
+                });
+            }
+        });
+        // 4 threads adding 3 and 4 subtracting 1, 100 times each
+        assert_eq!(kv_store.incr_by("views".to_string(), 0).unwrap(), 800);
+        kv_store
+            .put("text".to_string(), serde_json::Value::from("1"), None)
+            .expect("Should be able to call .put without errors");
+        assert!(
+            kv_store
+                .incr_by("text".to_string(), 1)
+                .is_err_and(|e| matches!(e.downcast_ref::<KVError>(), Some(KVError::Conflict(_))))
+        );
+    }
+
+    #[test]
+    fn test_kv_store_incr_with_ttl_on_create() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        assert_eq!(
+            kv_store
+                .incr_with_ttl_on_create("hits".to_string(), 1, Some(0.2))
+                .expect("Should be able to increment"),
+            1
+        );
+        let created = kv_store
+            .entry("hits".to_string())
+            .expect("Should be able to read entry");
+        assert_eq!(created.ttl_ms(), 200);
+
+        std::thread::sleep(time::Duration::from_millis(20));
+        assert_eq!(
+            kv_store
+                .incr_with_ttl_on_create("hits".to_string(), 2, Some(10.0))
+                .expect("Should be able to increment"),
+            3
+        );
+        let updated = kv_store
+            .entry("hits".to_string())
+            .expect("Should be able to read entry");
+        assert_eq!(updated.ttl_ms(), 200);
+        assert_eq!(updated.timestamp(), created.timestamp());
+
+        // once the window is over, the next hit opens a new one
+        std::thread::sleep(time::Duration::from_millis(200));
+        assert_eq!(
+            kv_store
+                .incr_with_ttl_on_create("hits".to_string(), 1, Some(10.0))
+                .expect("Should be able to increment"),
+            1
+        );
+        assert_eq!(
+            kv_store
+                .entry("hits".to_string())
+                .expect("Should be able to read entry")
+                .ttl_ms(),
+            10_000
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_incr_bounded() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        // clamped at the ceiling
+        assert_eq!(
+            kv_store
+                .incr_bounded("gauge".to_string(), 8, Some(0), Some(10))
+                .expect("Should be able to increment"),
+            (8, false)
+        );
+        assert_eq!(
+            kv_store
+                .incr_bounded("gauge".to_string(), 8, Some(0), Some(10))
+                .expect("Should be able to increment"),
+            (10, true)
+        );
+        // clamped at the floor
+        assert_eq!(
+            kv_store
+                .incr_bounded("gauge".to_string(), -25, Some(0), Some(10))
+                .expect("Should be able to increment"),
+            (0, true)
+        );
+        // a single bound only clamps one side
+        assert_eq!(
+            kv_store
+                .incr_bounded("gauge".to_string(), -3, None, Some(10))
+                .expect("Should be able to increment"),
+            (-3, false)
+        );
+        assert!(
+            kv_store
+                .incr_bounded("gauge".to_string(), 1, Some(10), Some(0))
+                .is_err_and(|e| matches!(
+                    e.downcast_ref::<KVError>(),
+                    Some(KVError::InvalidInput(_))
+                ))
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_get_or_insert() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        let first = kv_store
+            .get_or_insert("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .get_or_insert without errors");
+        assert_eq!(first, serde_json::Value::from(1));
+        // a second caller with a different value gets the first inserted one
+        let second = kv_store
+            .get_or_insert("hey".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to call .get_or_insert without errors");
+        assert_eq!(second, serde_json::Value::from(1));
+        assert_eq!(
+            kv_store
+                .get("hey".to_string())
+                .expect("Should be able to get the 'hey' key"),
+            serde_json::Value::from(1)
+        );
+        // expired entries are replaced
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(1), Some(0.001))
+            .expect("Should be able to call .put without errors");
+        std::thread::sleep(time::Duration::from_millis(5));
+        let replaced = kv_store
+            .get_or_insert("hello".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to call .get_or_insert without errors");
+        assert_eq!(replaced, serde_json::Value::from(2));
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_rebalance_plan() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        for i in 0..200 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to call .put without errors");
+        }
+        let plan = kv_store
+            .rebalance_plan(7)
+            .expect("Should be able to compute the rebalance plan");
+        assert_eq!(plan.current_shards, 3);
+        assert_eq!(plan.new_shards, 7);
+        assert_eq!(plan.total_keys, 200);
+        assert_eq!(plan.shard_loads.iter().sum::<usize>(), 200);
+        assert_eq!(plan.new_shard_loads.len(), 7);
+        assert_eq!(plan.new_shard_loads.iter().sum::<usize>(), 200);
+        // planning does not touch the store
+        assert_eq!(kv_store.shards.len(), 3);
+
+        let resharded = kv_store
+            .resharded(7)
+            .expect("Should be able to reshard the store");
+        let mut moved = 0;
+        for i in 0..200 {
+            let key = format!("key-{}", i);
+            let (new_idx, exists) = resharded
+                .locate(&key)
+                .expect("Should be able to locate key");
+            assert!(exists);
+            if new_idx != kv_store.find_shard(&key) {
+                moved += 1;
+            }
+        }
+        assert_eq!(plan.keys_to_move, moved);
+        for (i, load) in plan.new_shard_loads.iter().enumerate() {
+            assert_eq!(
+                resharded.shards[i]
+                    .get_length()
+                    .expect("Should be able to get length"),
+                *load
+            );
+        }
+        assert!(kv_store.rebalance_plan(0).is_err());
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_ttl_jitter() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store")
+            .with_ttl_jitter_percent(10_f64);
+        let ttls: Vec<i128> = (0..10)
+            .map(|i| {
+                let key = format!("key-{}", i);
+                kv_store
+                    .put(key.clone(), serde_json::Value::from(i), Some(100_f64))
+                    .expect("Should be able to call .put without errors");
+                let data = kv_store.shards[kv_store.find_shard(&key)]
+                    .entries()
+                    .expect("Should be able to read entries");
+                data.get(&key).expect("Should be able to find key").ttl
+            })
+            .collect();
+        for ttl in &ttls {
+            assert!((90_000..=110_000).contains(ttl));
+        }
+        assert!(ttls.iter().any(|ttl| *ttl != ttls[0]));
+
+        // keys without a TTL never expire, jitter or not
+        kv_store
+            .put("persistent".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        let data = kv_store.shards[kv_store.find_shard("persistent")]
+            .entries()
+            .expect("Should be able to read entries");
+        assert_eq!(
+            data.get("persistent")
+                .expect("Should be able to find key")
+                .ttl,
+            -1
         );
-        init_data.insert(
-            "hey".to_string(),
-            ShardEntry::new(serde_json::Value::from(2), Some(2_f64)),
+        drop(data);
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    /// Exercises the operations of a store whose shards use `backend`
+    fn check_backend_behavior(backend: ShardBackend) {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store")
+            .with_shard_backend(backend)
+            .expect("Should be able to switch backend");
+
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to call .put without errors");
+        assert_eq!(
+            kv_store.get("hey".to_string()).unwrap(),
+            serde_json::Value::from(2)
+        );
+        assert_eq!(kv_store.entry("hey".to_string()).unwrap().seq(), 2);
+        assert_eq!(kv_store.locate("hey").unwrap(), (2, true));
+        assert_eq!(
+            kv_store
+                .get_or_insert("hey".to_string(), 3.into(), None)
+                .unwrap(),
+            serde_json::Value::from(2)
+        );
+        assert!(
+            !kv_store
+                .put_sequenced("hey".to_string(), 4.into(), None, 1)
+                .unwrap()
+        );
+        assert!(
+            !kv_store
+                .put_if_extends("hey".to_string(), 4.into(), Some(10_f64))
+                .unwrap()
+        );
+
+        // expired entries are removed on access and by evictions
+        kv_store
+            .put("short".to_string(), 1.into(), Some(0.001))
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .put("shorter".to_string(), 1.into(), Some(0.001))
+            .expect("Should be able to call .put without errors");
+        std::thread::sleep(time::Duration::from_millis(5));
+        assert!(
+            kv_store
+                .get("short".to_string())
+                .is_err_and(|e| matches!(e.downcast_ref::<KVError>(), Some(KVError::Expired(_))))
+        );
+        assert!(
+            kv_store
+                .get("short".to_string())
+                .is_err_and(|e| matches!(e.downcast_ref::<KVError>(), Some(KVError::NotFound(_))))
+        );
+        assert_eq!(kv_store.cleanup().unwrap(), 1);
+        kv_store.compact().expect("Should be able to compact");
+
+        assert_eq!(
+            kv_store
+                .incr_bounded("counter".to_string(), 5, None, Some(3))
+                .unwrap(),
+            (3, true)
+        );
+        assert!(
+            kv_store
+                .cas_with_ttl("counter".to_string(), &3.into(), 7.into(), None)
+                .unwrap()
+        );
+        assert_eq!(
+            kv_store.consume("counter".to_string(), 0).unwrap(),
+            serde_json::Value::from(7)
+        );
+        assert_eq!(
+            kv_store
+                .zadd("zset".to_string(), HashMap::from([("a".to_string(), 2.0)]))
+                .unwrap(),
+            1
+        );
+        assert_eq!(kv_store.zrank("zset".to_string(), "a").unwrap(), Some(0));
+        kv_store
+            .put("list".to_string(), serde_json::json!([1, 2, 3]), None)
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .list_trim("list".to_string(), 1, -1)
+            .expect("Should be able to trim");
+        assert_eq!(
+            kv_store.get("list".to_string()).unwrap(),
+            serde_json::json!([2, 3])
+        );
+        kv_store
+            .delete("list".to_string())
+            .expect("Should be able to delete");
+        assert!(kv_store.get("list".to_string()).is_err());
+
+        for i in 0..5 {
+            kv_store
+                .put(format!("drain-{}", i), i.into(), None)
+                .expect("Should be able to call .put without errors");
+        }
+        assert_eq!(kv_store.list_keys(Some("drain-")).unwrap().len(), 5);
+        assert_eq!(kv_store.drain_prefix("drain-").unwrap().len(), 5);
+        assert!(kv_store.list_keys(Some("drain-")).unwrap().is_empty());
+        assert!(kv_store.random_entry().unwrap().is_some());
+        assert_eq!(kv_store.rebalance_plan(2).unwrap().total_keys, 3);
+
+        // flushed shards load back the same, whatever the backend
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        let reloaded = KVStore::new_from_disk(3, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        for i in 0..3 {
+            assert_eq!(
+                reloaded.shards[i].encode_pretty().unwrap(),
+                kv_store.shards[i].encode_pretty().unwrap()
+            );
+        }
+        let resharded = kv_store.resharded(5).expect("Should be able to reshard");
+        assert_eq!(resharded.shard_backend, backend);
+        assert_eq!(
+            resharded.get("hey".to_string()).unwrap(),
+            serde_json::Value::from(2)
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_backends_behave_alike() {
+        check_backend_behavior(ShardBackend::RwLock);
+        check_backend_behavior(ShardBackend::DashMap);
+    }
+
+    #[test]
+    fn test_kv_store_dashmap_concurrent_writes() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(1)
+            .shard_backend(ShardBackend::DashMap)
+            .build()
+            .expect("Should be able to create KV store");
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let kv_store = &kv_store;
+                scope.spawn(move || {
+                    for i in 0..200 {
+                        kv_store
+                            .incr_bounded("shared".to_string(), 1, None, None)
+                            .expect("Should be able to increment");
+                        kv_store
+                            .put(format!("key-{}-{}", t, i), i.into(), None)
+                            .expect("Should be able to call .put without errors");
+                    }
+                });
+            }
+        });
+        assert_eq!(
+            kv_store.get("shared".to_string()).unwrap(),
+            serde_json::Value::from(1600)
+        );
+        assert_eq!(kv_store.list_keys(Some("key-")).unwrap().len(), 1600);
+    }
+
+    #[test]
+    fn test_kv_store_records_lock_contention() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(1)
+            .build()
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        assert_eq!(kv_store.metrics().snapshot().locks.contended, 0);
+
+        let (locked_sender, locked) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let kv_store = &kv_store;
+            scope.spawn(move || {
+                let _guard = kv_store.shards[0].lock_key("hey").unwrap();
+                locked_sender.send(()).unwrap();
+                std::thread::sleep(time::Duration::from_millis(50));
+            });
+            locked.recv().unwrap();
+            assert_eq!(
+                kv_store.get("hey".to_string()).unwrap(),
+                serde_json::Value::from(1)
+            );
+        });
+        let locks = kv_store.metrics().snapshot().locks;
+        assert_eq!(locks.contended, 1);
+        assert!(locks.total_wait_us > 0);
+        assert_eq!(
+            locks.wait_times.under_10ms + locks.wait_times.at_least_10ms,
+            1
+        );
+    }
+
+    #[test]
+    fn test_kv_store_error_truncates_long_keys() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        let long_key = "k".repeat(1000);
+        let err = kv_store.get(long_key.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("key {}… not found", "k".repeat(DEFAULT_MAX_KEY_ECHO))
+        );
+        // the error still carries the whole key
+        assert!(matches!(
+            err.downcast_ref::<KVError>(),
+            Some(KVError::NotFound(key)) if *key == long_key
+        ));
+        assert_eq!(
+            kv_store.get("hey".to_string()).unwrap_err().to_string(),
+            "key hey not found"
+        );
+        // multi-byte characters are never split
+        assert_eq!(
+            echo_key(&"é".repeat(200)),
+            format!("{}…", "é".repeat(DEFAULT_MAX_KEY_ECHO))
+        );
+    }
+
+    #[test]
+    fn test_kv_store_max_ttl() {
+        let build = |policy| {
+            KVStore::builder()
+                .in_memory()
+                .build()
+                .expect("Should be able to create KV store")
+                .with_max_ttl(Some(60_f64), policy)
+        };
+        let ttl_millis = |kv_store: &KVStore, key: &str| {
+            kv_store
+                .entry(key.to_string())
+                .expect("Should be able to read entry")
+                .ttl_ms()
+        };
+
+        let rejecting = build(TtlCapPolicy::Reject);
+        let err = rejecting
+            .put(
+                "long".to_string(),
+                serde_json::Value::from(1),
+                Some(120_f64),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KVError>(),
+            Some(KVError::InvalidInput(_))
+        ));
+        assert!(rejecting.entry("long".to_string()).is_err());
+        rejecting
+            .put(
+                "short".to_string(),
+                serde_json::Value::from(1),
+                Some(30_f64),
+            )
+            .expect("Should be able to put key");
+        assert_eq!(ttl_millis(&rejecting, "short"), 30_000);
+        // persistent entries are still allowed unless forbidden
+        rejecting
+            .put("forever".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+
+        let clamping = build(TtlCapPolicy::Clamp);
+        clamping
+            .put(
+                "long".to_string(),
+                serde_json::Value::from(1),
+                Some(120_f64),
+            )
+            .expect("Should be able to put key");
+        assert_eq!(ttl_millis(&clamping, "long"), 60_000);
+
+        let strict = build(TtlCapPolicy::Clamp).with_forbid_persistent(true);
+        for ttl in [None, Some(0_f64)] {
+            assert!(
+                strict
+                    .put("forever".to_string(), serde_json::Value::from(1), ttl)
+                    .is_err()
+            );
+        }
+        let strict = strict.with_default_ttl(Some(10_f64));
+        strict
+            .put("forever".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        assert_eq!(ttl_millis(&strict, "forever"), 10_000);
+        assert_eq!(
+            "clamp".parse::<TtlCapPolicy>().unwrap(),
+            TtlCapPolicy::Clamp
+        );
+        assert!("drop".parse::<TtlCapPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_kv_store_keys_containing() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        for (key, value) in [
+            ("apple", serde_json::json!({"tags": ["red", "fruit"]})),
+            ("cherry", serde_json::json!({"tags": ["fruit", "red"]})),
+            ("sky", serde_json::json!({"tags": ["blue"]})),
+            ("rose", serde_json::json!({"tags": "red"})),
+            ("plain", serde_json::json!("red")),
+        ] {
+            kv_store
+                .put(key.to_string(), value, None)
+                .expect("Should be able to put key");
+        }
+        let red = serde_json::Value::from("red");
+        assert_eq!(
+            kv_store
+                .keys_containing("/tags", &red)
+                .expect("Should be able to query"),
+            vec!["apple".to_string(), "cherry".to_string()]
+        );
+        assert!(
+            kv_store
+                .keys_containing("/colors", &red)
+                .expect("Should be able to query")
+                .is_empty()
+        );
+        assert!(kv_store.keys_containing("tags", &red).is_err());
+    }
+
+    #[test]
+    fn test_kv_store_rename() {
+        for backend in [ShardBackend::RwLock, ShardBackend::DashMap] {
+            let kv_store = KVStore::builder()
+                .in_memory()
+                .shards(3)
+                .shard_backend(backend)
+                .build()
+                .expect("Should be able to create KV store");
+            let source_shard = kv_store.find_shard("src");
+            let keys: Vec<String> = (0..20).map(|i| format!("dst{}", i)).collect();
+            let same_shard = keys
+                .iter()
+                .find(|k| kv_store.find_shard(k) == source_shard)
+                .expect("Some key should share the shard");
+            let other_shard = keys
+                .iter()
+                .find(|k| kv_store.find_shard(k) != source_shard)
+                .expect("Some key should be in another shard");
+
+            for destination in [same_shard, other_shard] {
+                kv_store
+                    .put("src".to_string(), serde_json::Value::from(1), Some(60_f64))
+                    .expect("Should be able to put key");
+                kv_store
+                    .put(destination.clone(), serde_json::Value::from(2), None)
+                    .expect("Should be able to put key");
+
+                let err = kv_store
+                    .rename("src".to_string(), destination.clone(), true)
+                    .expect_err("Rename should be blocked by the destination");
+                assert!(matches!(err.downcast_ref(), Some(KVError::Conflict(_))));
+                assert_eq!(kv_store.get("src".to_string()).unwrap(), 1);
+                assert_eq!(kv_store.get(destination.clone()).unwrap(), 2);
+
+                kv_store
+                    .rename("src".to_string(), destination.clone(), false)
+                    .expect("Should be able to overwrite the destination");
+                let (value, ttl) = kv_store.get_with_ttl(destination.clone()).unwrap();
+                assert_eq!(value, 1);
+                assert!(ttl.is_some());
+                assert!(kv_store.get("src".to_string()).is_err());
+
+                kv_store
+                    .rename(destination.clone(), "src".to_string(), true)
+                    .expect("Should be able to rename to a missing key");
+                assert_eq!(kv_store.get("src".to_string()).unwrap(), 1);
+                assert!(kv_store.get(destination.clone()).is_err());
+                kv_store.delete("src".to_string()).unwrap();
+            }
+
+            let err = kv_store
+                .rename("missing".to_string(), "src".to_string(), false)
+                .expect_err("Missing keys can't be renamed");
+            assert!(matches!(err.downcast_ref(), Some(KVError::NotFound(_))));
+        }
+    }
+
+    #[test]
+    fn test_kv_store_max_json_depth() {
+        let nested = |depth: usize| {
+            (0..depth).fold(serde_json::Value::from(1), |value, i| {
+                if i % 2 == 0 {
+                    serde_json::json!([value])
+                } else {
+                    serde_json::json!({ "inner": value })
+                }
+            })
+        };
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store")
+            .with_max_json_depth(3);
+        kv_store
+            .put("scalar".to_string(), serde_json::Value::from(1), None)
+            .expect("Scalars should be accepted");
+        kv_store
+            .put("at-limit".to_string(), nested(3), None)
+            .expect("Values at the limit should be accepted");
+        let err = kv_store
+            .put("beyond".to_string(), nested(4), None)
+            .expect_err("Values beyond the limit should be rejected");
+        assert!(matches!(err.downcast_ref(), Some(KVError::InvalidInput(_))));
+        assert!(kv_store.get("beyond".to_string()).is_err());
+
+        // deeper than serde_json would parse back from a shard file
+        let kv_store = kv_store.with_max_json_depth(DEFAULT_MAX_JSON_DEPTH);
+        assert!(
+            kv_store
+                .put("deep".to_string(), nested(1000), None)
+                .is_err()
+        );
+
+        // the deepest values accepted still load back from disk
+        let directory = ".quache-json-depth-test/";
+        let kv_store = KVStore::new(3, directory.to_string())
+            .expect("Should be able to create KV store")
+            .with_max_json_depth(usize::MAX);
+        assert!(
+            kv_store
+                .put("beyond".to_string(), nested(MAX_JSON_DEPTH_LIMIT + 1), None)
+                .is_err()
+        );
+        kv_store
+            .put("deepest".to_string(), nested(MAX_JSON_DEPTH_LIMIT), None)
+            .expect("Values at the limit should be accepted");
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        let kv_store = KVStore::new_from_disk(3, directory.to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(
+            kv_store.get("deepest".to_string()).unwrap(),
+            nested(MAX_JSON_DEPTH_LIMIT)
+        );
+        cleanup_test_directory(directory.to_string());
+    }
+
+    #[test]
+    fn test_kv_store_init_missing_shard_files() {
+        let directory = ".quache-init-shards-test/";
+        let kv_store =
+            KVStore::new(3, directory.to_string()).expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors"); // goes to shard-2
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        assert!(!fs::exists(shard_file_path(directory, 0)).unwrap());
+
+        let kv_store = KVStore::new_from_disk(3, directory.to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(kv_store.init_missing_shard_files().unwrap(), 2);
+        for i in 0..3 {
+            assert!(fs::exists(shard_file_path(directory, i)).unwrap());
+        }
+        let dims = kv_store.shard_dimensions.read().unwrap();
+        assert_eq!(dims.get(&0), Some(&0));
+        assert_eq!(dims.get(&1), Some(&0));
+        drop(dims);
+        assert_eq!(kv_store.init_missing_shard_files().unwrap(), 0);
+
+        let kv_store = KVStore::new_from_disk(3, directory.to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(
+            kv_store.get("hey".to_string()).unwrap(),
+            serde_json::Value::from(1)
+        );
+        cleanup_test_directory(directory.to_string());
+    }
+
+    #[test]
+    fn test_kv_store_flushes_changes_keeping_the_length() {
+        let directory = ".quache-dirty-test/";
+        let kv_store =
+            KVStore::new(3, directory.to_string()).expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        // same number of keys in every shard, but new values
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to call .put without errors");
+        kv_store.to_disk().expect("Should be able to flush to disk");
+
+        let loaded = KVStore::new_from_disk(3, directory.to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(
+            loaded.get("hey".to_string()).unwrap(),
+            serde_json::Value::from(2)
+        );
+        // nothing changed since loading: nothing to rewrite
+        loaded.to_disk().expect("Should be able to flush to disk");
+        assert_eq!(loaded.flush_progress().bytes_written, 0);
+        let lazy = KVStore::new_from_disk_lazy(3, directory.to_string())
+            .expect("Should be able to map the KV Store from disk");
+        lazy.to_disk().expect("Should be able to flush to disk");
+        assert_eq!(lazy.flush_progress().bytes_written, 0);
+        cleanup_test_directory(directory.to_string());
+    }
+
+    #[test]
+    fn test_kv_store_null_value_round_trip() {
+        let directory = ".quache-null-test/";
+        let kv_store =
+            KVStore::new(3, directory.to_string()).expect("Should be able to create KV store");
+        kv_store
+            .put("nothing".to_string(), serde_json::Value::Null, None)
+            .expect("Should be able to store null");
+        assert_eq!(
+            kv_store.get("nothing".to_string()).unwrap(),
+            serde_json::Value::Null
+        );
+        kv_store.to_disk().expect("Should be able to flush to disk");
+
+        let kv_store = KVStore::new_from_disk(3, directory.to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(
+            kv_store.get("nothing".to_string()).unwrap(),
+            serde_json::Value::Null
+        );
+        assert!(kv_store.exists("nothing".to_string()).unwrap());
+        cleanup_test_directory(directory.to_string());
+    }
+
+    #[test]
+    fn test_kv_store_transaction() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(1)
+            .build()
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("stock".to_string(), serde_json::Value::from(3), None)
+            .expect("Should be able to call .put without errors");
+        let outcome = kv_store
+            .transaction(vec![
+                TxOp::AssertEquals {
+                    key: "stock".to_string(),
+                    value: serde_json::Value::from(3),
+                },
+                TxOp::Incr {
+                    key: "stock".to_string(),
+                    delta: -1,
+                },
+                TxOp::Set {
+                    key: "order".to_string(),
+                    value: serde_json::json!({"item": "book"}),
+                    ttl: None,
+                },
+                TxOp::Get {
+                    key: "order".to_string(),
+                },
+                TxOp::Del {
+                    key: "missing".to_string(),
+                },
+            ])
+            .expect("Should be able to run the transaction");
+        assert_eq!(
+            outcome,
+            TxOutcome {
+                committed: true,
+                results: vec![
+                    serde_json::Value::Bool(true),
+                    serde_json::Value::from(2),
+                    serde_json::Value::Null,
+                    serde_json::json!({"item": "book"}),
+                    serde_json::Value::Bool(false),
+                ],
+                failed_op: None,
+            }
+        );
+        assert_eq!(kv_store.get("stock".to_string()).unwrap(), 2);
+
+        let outcome = kv_store
+            .transaction(vec![
+                TxOp::Del {
+                    key: "order".to_string(),
+                },
+                TxOp::AssertEquals {
+                    key: "stock".to_string(),
+                    value: serde_json::Value::from(3),
+                },
+                TxOp::Incr {
+                    key: "stock".to_string(),
+                    delta: -1,
+                },
+            ])
+            .expect("Should be able to run the transaction");
+        assert_eq!(
+            outcome,
+            TxOutcome {
+                committed: false,
+                results: vec![
+                    serde_json::Value::Bool(true),
+                    serde_json::Value::Bool(false)
+                ],
+                failed_op: Some(1),
+            }
+        );
+        // the deletion was rolled back
+        assert_eq!(
+            kv_store.get("order".to_string()).unwrap(),
+            serde_json::json!({"item": "book"})
+        );
+        assert_eq!(kv_store.get("stock".to_string()).unwrap(), 2);
+
+        // failures other than assertions discard the writes too
+        let result = kv_store.transaction(vec![
+            TxOp::Del {
+                key: "stock".to_string(),
+            },
+            TxOp::Incr {
+                key: "order".to_string(),
+                delta: 1,
+            },
+        ]);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref(),
+            Some(KVError::Conflict(_))
+        ));
+        assert_eq!(kv_store.get("stock".to_string()).unwrap(), 2);
+
+        let sharded = KVStore::builder()
+            .in_memory()
+            .shards(4)
+            .build()
+            .expect("Should be able to create KV store");
+        let first_shard = sharded.locate("a").unwrap().0;
+        let other = (0..)
+            .map(|i| format!("key{}", i))
+            .find(|key| sharded.locate(key).unwrap().0 != first_shard)
+            .unwrap();
+        let result = sharded.transaction(vec![
+            TxOp::Get {
+                key: "a".to_string(),
+            },
+            TxOp::Get { key: other },
+        ]);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref(),
+            Some(KVError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_kv_store_put_many() {
+        for backend in [ShardBackend::RwLock, ShardBackend::DashMap] {
+            let kv_store = KVStore::builder()
+                .in_memory()
+                .shards(4)
+                .shard_backend(backend)
+                .build()
+                .expect("Should be able to create KV store");
+            let items: Vec<BatchPut> = (0..100)
+                .map(|i| BatchPut {
+                    key: format!("key-{}", i),
+                    value: serde_json::Value::from(i),
+                    ttl: (i % 2 == 0).then_some(60_f64),
+                    if_match: None,
+                })
+                .collect();
+            let statuses = kv_store
+                .put_many(items)
+                .expect("Should be able to put the batch");
+            assert_eq!(statuses, vec![BatchPutStatus::Applied; 100]);
+            for i in 0..100 {
+                let key = format!("key-{}", i);
+                let shard_idx = kv_store.find_shard(&key);
+                let entries = kv_store.shards[shard_idx].entries().unwrap();
+                let entry = entries.get(&key).expect("key should be in its shard");
+                assert_eq!(entry.value, serde_json::Value::from(i));
+                assert_eq!(entry.ttl > 0, i % 2 == 0);
+            }
+            let stored: usize = kv_store
+                .shards
+                .iter()
+                .map(|shard| shard.get_length().unwrap())
+                .sum();
+            assert_eq!(stored, 100);
+
+            // an invalid item fails the whole batch before anything is written
+            let kv_store = kv_store.with_forbid_persistent(true);
+            let items = vec![
+                BatchPut {
+                    key: "valid".to_string(),
+                    value: serde_json::Value::from(1),
+                    ttl: Some(60_f64),
+                    if_match: None,
+                },
+                BatchPut {
+                    key: "persistent".to_string(),
+                    value: serde_json::Value::from(2),
+                    ttl: None,
+                    if_match: None,
+                },
+            ];
+            assert!(kv_store.put_many(items).is_err());
+            assert!(!kv_store.exists("valid".to_string()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_kv_store_exists() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("present".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .put(
+                "expired".to_string(),
+                serde_json::Value::from(2),
+                Some(0.001),
+            )
+            .expect("Should be able to call .put without errors");
+        std::thread::sleep(time::Duration::from_millis(5));
+        assert!(kv_store.exists("present".to_string()).unwrap());
+        assert!(!kv_store.exists("absent".to_string()).unwrap());
+        assert!(!kv_store.exists("expired".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_kv_store_ttl_many() {
+        for backend in [ShardBackend::RwLock, ShardBackend::DashMap] {
+            let kv_store = KVStore::builder()
+                .in_memory()
+                .shard_backend(backend)
+                .build()
+                .expect("Should be able to create KV store")
+                .with_case_insensitive_keys(true);
+            for i in 0..10 {
+                kv_store
+                    .put(
+                        format!("Key{}", i),
+                        serde_json::Value::from(i),
+                        Some(60_f64),
+                    )
+                    .expect("Should be able to put key");
+            }
+            kv_store
+                .put("persistent".to_string(), serde_json::Value::Null, None)
+                .expect("Should be able to put key");
+            kv_store
+                .put("stale".to_string(), serde_json::Value::Null, Some(0.001))
+                .expect("Should be able to put key");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+
+            let mut keys: Vec<String> = (0..10).map(|i| format!("key{}", i)).collect();
+            keys.extend(["persistent", "stale", "missing"].map(String::from));
+            let ttls = kv_store
+                .ttl_many(&keys)
+                .expect("Should be able to get TTLs");
+            assert_eq!(ttls.len(), 11);
+            assert!(ttls["key3"].is_some_and(|ttl| ttl <= std::time::Duration::from_secs(60)));
+            assert_eq!(ttls["persistent"], None);
+            assert!(!ttls.contains_key("stale"));
+            assert!(!ttls.contains_key("missing"));
+        }
+    }
+
+    #[test]
+    fn test_kv_store_probe_expired_on_scan() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("live".to_string(), serde_json::json!([1]), None)
+            .expect("Should be able to put key");
+        kv_store
+            .put("stale".to_string(), serde_json::json!([1]), Some(0.001))
+            .expect("Should be able to put key");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        // not evicted yet, but left out of scans
+        let (keys, _) = kv_store
+            .list_keys_page(None, None, 10)
+            .expect("Should be able to list keys");
+        assert_eq!(keys, vec!["live".to_string()]);
+        let one = serde_json::Value::from(1);
+        assert_eq!(
+            kv_store
+                .keys_containing("", &one)
+                .expect("Should be able to query"),
+            vec!["live".to_string()]
+        );
+
+        let kv_store = kv_store.with_probe_expired_on_scan(false);
+        let (keys, _) = kv_store
+            .list_keys_page(None, None, 10)
+            .expect("Should be able to list keys");
+        assert_eq!(keys, vec!["live".to_string(), "stale".to_string()]);
+    }
+
+    #[test]
+    fn test_kv_store_default_ttl() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store")
+            .with_default_ttl(Some(60.5));
+        for (key, ttl, expected_ttl_millis) in [
+            ("default", None, 60_500),
+            ("explicit", Some(2.0), 2_000),
+            ("persistent", Some(PERSISTENT_TTL), -1),
+        ] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(1), ttl)
+                .expect("Should be able to put key");
+            let entry = kv_store
+                .entry(key.to_string())
+                .expect("Should be able to read entry");
+            assert_eq!(entry.ttl_ms(), expected_ttl_millis, "{}", key);
+        }
+        assert_eq!(
+            kv_store
+                .get_with_ttl("persistent".to_string())
+                .expect("Should be able to get key")
+                .1,
+            None
         );
-        let shard = Shard::new_with_data(init_data);
-        let data = shard.data.read().expect("Should be able to read data");
-        assert_eq!(data.len(), 2);
-        let hello_entry = data
-            .get("hello")
-            .expect("Should be able to retrieve 'hello' key");
-        let hey_entry = data
-            .get("hey")
-            .expect("Should be able to retrieve 'hey' key");
-        assert_eq!(hello_entry.value, serde_json::Value::from(1));
-        assert_eq!(hey_entry.value, serde_json::Value::from(2));
-        assert_eq!(hello_entry.ttl, -1_f64);
-        assert_eq!(hey_entry.ttl, 2000_f64);
     }
 
     #[test]
-    fn test_shard_get_length() {
-        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
-        init_data.insert(
-            "hello".to_string(),
-            ShardEntry::new(serde_json::Value::from(1), None),
-        );
-        init_data.insert(
-            "hey".to_string(),
-            ShardEntry::new(serde_json::Value::from(2), Some(2_f64)),
+    fn test_kv_store_sliding_expiration() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store")
+            .with_sliding_expiration(true);
+        kv_store
+            .put("read".to_string(), serde_json::Value::from(1), Some(0.1))
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .put("unread".to_string(), serde_json::Value::from(2), Some(0.1))
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .put("persistent".to_string(), serde_json::Value::from(3), None)
+            .expect("Should be able to call .put without errors");
+        let persistent_timestamp = kv_store
+            .entry("persistent".to_string())
+            .unwrap()
+            .timestamp();
+
+        // read for well over the TTL
+        for _ in 0..6 {
+            std::thread::sleep(time::Duration::from_millis(40));
+            assert!(kv_store.get("read".to_string()).is_ok());
+        }
+        assert!(
+            kv_store
+                .get("unread".to_string())
+                .is_err_and(|e| matches!(e.downcast_ref::<KVError>(), Some(KVError::Expired(_))))
         );
-        let shard = Shard::new_with_data(init_data);
+        assert!(kv_store.get("persistent".to_string()).is_ok());
         assert_eq!(
-            shard
-                .get_length()
-                .expect("should be able to retrieve length"),
-            2
+            kv_store
+                .entry("persistent".to_string())
+                .unwrap()
+                .timestamp(),
+            persistent_timestamp
         );
-        let shard_1 = Shard::new();
+
+        // once reads stop, the key expires
+        std::thread::sleep(time::Duration::from_millis(150));
+        assert!(kv_store.get("read".to_string()).is_err());
+
+        // reads don't push back the grace expiry of consumed keys
+        kv_store
+            .put(
+                "consumed".to_string(),
+                serde_json::Value::from(4),
+                Some(60_f64),
+            )
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .consume("consumed".to_string(), 100)
+            .expect("Should be able to consume");
+        for _ in 0..3 {
+            std::thread::sleep(time::Duration::from_millis(40));
+            let _ = kv_store.get("consumed".to_string());
+        }
+        assert!(kv_store.get("consumed".to_string()).is_err());
+
+        // sliding is reported to listeners (e.g. replication), with the new expiry
+        let events = Arc::new(Mutex::new(vec![]));
+        let captured = events.clone();
+        kv_store.on_change(move |event| captured.lock().unwrap().push(event.clone()));
+        kv_store
+            .put(
+                "touched".to_string(),
+                serde_json::Value::from(5),
+                Some(60_f64),
+            )
+            .expect("Should be able to call .put without errors");
+        std::thread::sleep(time::Duration::from_millis(5));
+        kv_store.get("touched".to_string()).unwrap();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].op, ChangeOp::Put);
+        assert_eq!(events[1].value, Some(serde_json::Value::from(5)));
+        assert!(events[1].expires_at_ms > events[0].expires_at_ms);
+        assert!(events[1].seq > events[0].seq);
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_get_expired() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), Some(0.001)) // 1 millisecond ttl
+            .expect("Should be able to call .put without errors"); // goes to shard-2
+        std::thread::sleep(time::Duration::from_millis(5));
+        let expired = kv_store.get("hey".to_string());
+        assert!(
+            expired
+                .is_err_and(|e| matches!(e.downcast_ref::<KVError>(), Some(KVError::Expired(_))))
+        );
+        // the expired entry is removed on access, without waiting for cleanup
         assert_eq!(
-            shard_1
+            kv_store.shards[2]
                 .get_length()
-                .expect("should be able to retrieve length"),
+                .expect("Should be able to get length"),
             0
         );
+        let never_present = kv_store.get("hello".to_string());
+        assert!(
+            never_present
+                .is_err_and(|e| matches!(e.downcast_ref::<KVError>(), Some(KVError::NotFound(_))))
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
     }
 
     #[test]
-    fn test_shard_evict() {
-        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
-        init_data.insert(
-            "hello".to_string(),
-            ShardEntry::new(serde_json::Value::from(1), None),
+    #[serial]
+    fn test_kv_store_put_if_extends() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        // new keys are always created
+        assert!(
+            kv_store
+                .put_if_extends(
+                    "lease".to_string(),
+                    serde_json::Value::from(1),
+                    Some(10_f64)
+                )
+                .expect("Should be able to call .put_if_extends without errors")
         );
-        init_data.insert(
-            "hey".to_string(),
-            ShardEntry::new(serde_json::Value::from(2), Some(0.001)), // 1 millisecond
+        // extending the lease applies
+        assert!(
+            kv_store
+                .put_if_extends(
+                    "lease".to_string(),
+                    serde_json::Value::from(2),
+                    Some(60_f64)
+                )
+                .expect("Should be able to call .put_if_extends without errors")
         );
-        init_data.insert(
-            "bye".to_string(),
-            ShardEntry::new(serde_json::Value::from(3), Some(2_f64)), // 2 seconds
+        // shortening it is skipped
+        assert!(
+            !kv_store
+                .put_if_extends("lease".to_string(), serde_json::Value::from(3), Some(5_f64))
+                .expect("Should be able to call .put_if_extends without errors")
         );
-        let shard = Shard::new_with_data(init_data);
-        assert_eq!(shard.get_length().expect("Should be able to get length"), 3);
-        std::thread::sleep(time::Duration::from_millis(5)); // this should discard the 'hey' entry
-        shard
-            .evict()
-            .expect("Should be able to evict expired entries");
-        assert_eq!(shard.get_length().expect("Should be able to get length"), 2);
-        let data = shard.data.read().expect("Should be able to read data");
-        assert_eq!(data.len(), 2);
-        let hello_entry = data.get("hello");
-        assert!(hello_entry.is_some());
-        let bye_entry = data.get("bye");
-        assert!(bye_entry.is_some());
-        let hey_entry = data.get("hey");
-        assert!(hey_entry.is_none());
+        assert_eq!(
+            kv_store.get("lease".to_string()).unwrap(),
+            serde_json::Value::from(2)
+        );
+        // persistent entries can't be replaced by expiring ones
+        assert!(
+            kv_store
+                .put_if_extends("lease".to_string(), serde_json::Value::from(4), None)
+                .expect("Should be able to call .put_if_extends without errors")
+        );
+        assert!(
+            !kv_store
+                .put_if_extends(
+                    "lease".to_string(),
+                    serde_json::Value::from(5),
+                    Some(600_f64)
+                )
+                .expect("Should be able to call .put_if_extends without errors")
+        );
+        assert_eq!(
+            kv_store.get("lease".to_string()).unwrap(),
+            serde_json::Value::from(4)
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
     }
 
     #[test]
-    fn test_shard_flush() {
-        let mut init_data: HashMap<String, ShardEntry> = HashMap::new();
-        init_data.insert(
-            "hello".to_string(),
-            ShardEntry::new(serde_json::Value::from(1), None),
+    fn test_kv_store_builder_in_memory() {
+        let kv_store = KVStore::builder()
+            .shards(4)
+            .directory(".quache-builder-test/")
+            .in_memory()
+            .build()
+            .expect("Should be able to build KV store");
+        assert_eq!(kv_store.shards.len(), 4);
+        for i in 0..20 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to call .put without errors");
+        }
+        assert_eq!(
+            kv_store.get("key-7".to_string()).unwrap(),
+            serde_json::Value::from(7)
         );
-        init_data.insert(
-            "hey".to_string(),
-            ShardEntry::new(serde_json::Value::from(2), None),
+        // every shard holds its own data
+        let total: usize = kv_store
+            .shards
+            .iter()
+            .map(|shard| shard.get_length().unwrap())
+            .sum();
+        assert_eq!(total, 20);
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        assert!(!fs::exists(".quache-builder-test/").unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_builder() {
+        assert!(KVStore::builder().shards(0).in_memory().build().is_err());
+
+        let kv_store = KVStore::builder()
+            .shards(3)
+            .directory(".quache-test/")
+            .hash_strategy(HashStrategy::Fnv1a)
+            .build()
+            .expect("Should be able to build KV store");
+        assert!(fs::exists(".quache-test/").unwrap());
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        let shard_idx = HashStrategy::Fnv1a.shard_index("hey", 3);
+        assert_eq!(kv_store.find_shard("hey"), shard_idx);
+        assert_eq!(kv_store.shards[shard_idx].get_length().unwrap(), 1);
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        assert!(fs::exists(shard_file_path(".quache-test/", shard_idx)).unwrap());
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    fn test_kv_store_drain_prefix() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to build KV store");
+        for (key, value) in [("jobs:1", 1), ("jobs:2", 2), ("other", 3)] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(value), None)
+                .expect("Should be able to call .put without errors");
+        }
+        kv_store
+            .put(
+                "jobs:3".to_string(),
+                serde_json::Value::from(3),
+                Some(0.001),
+            )
+            .expect("Should be able to call .put without errors");
+        std::thread::sleep(time::Duration::from_millis(5));
+
+        let drained = kv_store
+            .drain_prefix("jobs:")
+            .expect("Should be able to drain keys");
+        assert_eq!(
+            drained,
+            HashMap::from([
+                ("jobs:1".to_string(), serde_json::Value::from(1)),
+                ("jobs:2".to_string(), serde_json::Value::from(2)),
+            ])
         );
-        let shard = Shard::new_with_data(init_data);
-        shard
-            .flush("shard-0-test".to_string())
-            .expect("Should be able to flush to file");
+        assert_eq!(kv_store.list_keys(None).unwrap(), vec!["other".to_string()]);
+        assert!(kv_store.entry("jobs:3".to_string()).is_err());
+        assert!(kv_store.drain_prefix("jobs:").unwrap().is_empty());
+    }
 
-        assert!(fs::exists("shard-0-test").expect("Should be able to check file existence"));
-        let content = fs::read_to_string("shard-0-test").expect("Should be able to read file path");
-        let lines: Vec<&str> = content.split("\n").collect();
-        let integrity_hash_str = lines[lines.len() - 1].to_string();
-        let raw_data = lines[0..lines.len() - 1].join("\n");
-        let computed_hash = md5::compute(&raw_data.clone().into_bytes());
-        let computed_hash_string: String = computed_hash
-            .to_vec()
-            .iter()
-            .map(|c| c.to_string())
+    #[test]
+    fn test_kv_store_sorted_set() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to build KV store");
+        let added = kv_store
+            .zadd(
+                "board".to_string(),
+                HashMap::from([
+                    ("alice".to_string(), 10_f64),
+                    ("bob".to_string(), 5_f64),
+                    ("carol".to_string(), 7.5),
+                ]),
+            )
+            .expect("Should be able to add members");
+        assert_eq!(added, 3);
+        assert_eq!(
+            kv_store.get("board".to_string()).unwrap(),
+            serde_json::json!({"alice": 10, "bob": 5, "carol": 7.5})
+        );
+        assert_eq!(
+            kv_store
+                .zrange_by_score("board".to_string(), 6_f64, f64::INFINITY)
+                .unwrap(),
+            vec![("carol".to_string(), 7.5), ("alice".to_string(), 10_f64)]
+        );
+        assert_eq!(kv_store.zrank("board".to_string(), "bob").unwrap(), Some(0));
+
+        // updating a score moves the member, without counting as an addition
+        let added = kv_store
+            .zadd(
+                "board".to_string(),
+                HashMap::from([("bob".to_string(), 20_f64), ("dave".to_string(), 1_f64)]),
+            )
+            .unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(kv_store.zrank("board".to_string(), "bob").unwrap(), Some(3));
+        assert_eq!(
+            kv_store.zrank("board".to_string(), "dave").unwrap(),
+            Some(0)
+        );
+        assert_eq!(kv_store.zrank("board".to_string(), "erin").unwrap(), None);
+        assert_eq!(
+            kv_store
+                .zrange_by_score("board".to_string(), 7.5, 10_f64)
+                .unwrap(),
+            vec![("carol".to_string(), 7.5), ("alice".to_string(), 10_f64)]
+        );
+
+        kv_store
+            .put("plain".to_string(), serde_json::Value::from(1), None)
+            .unwrap();
+        let err = kv_store
+            .zadd(
+                "plain".to_string(),
+                HashMap::from([("a".to_string(), 1_f64)]),
+            )
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(KVError::Conflict(_))));
+        assert!(
+            kv_store
+                .zadd(
+                    "board".to_string(),
+                    HashMap::from([("a".to_string(), f64::NAN)])
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_kv_store_random_entry() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to build KV store");
+        assert_eq!(kv_store.random_key().unwrap(), None);
+
+        let stored: HashMap<String, serde_json::Value> = (0..10)
+            .map(|i| (format!("key-{}", i), serde_json::Value::from(i)))
             .collect();
-        assert_eq!(integrity_hash_str, computed_hash_string);
-        let data: HashMap<String, ShardEntry> =
-            serde_json::from_str(&raw_data).expect("Should be able to deserialize data");
-        assert_eq!(data.len(), 2);
-        let hello_entry = data
-            .get("hello")
-            .expect("Should be able to retrieve 'hello' key");
-        let hey_entry = data
-            .get("hey")
-            .expect("Should be able to retrieve 'hey' key");
-        assert_eq!(hello_entry.value, serde_json::Value::from(1));
-        assert_eq!(hey_entry.value, serde_json::Value::from(2));
-        assert_eq!(hello_entry.ttl, -1_f64);
-        assert_eq!(hey_entry.ttl, -1_f64);
+        for (key, value) in &stored {
+            kv_store
+                .put(key.clone(), value.clone(), None)
+                .expect("Should be able to call .put without errors");
+        }
+        kv_store
+            .put(
+                "expired".to_string(),
+                serde_json::Value::from(-1),
+                Some(0.001),
+            )
+            .expect("Should be able to call .put without errors");
+        std::thread::sleep(time::Duration::from_millis(5));
+        for _ in 0..100 {
+            let (key, value) = kv_store.random_entry().unwrap().unwrap();
+            assert_eq!(stored.get(&key), Some(&value));
+            assert!(stored.contains_key(&kv_store.random_key().unwrap().unwrap()));
+        }
+    }
 
-        cleanup_test_file("shard-0-test".to_string())
+    #[test]
+    fn test_kv_store_consume() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to build KV store");
+        kv_store
+            .put("code".to_string(), serde_json::Value::from("123456"), None)
+            .expect("Should be able to call .put without errors");
+        assert_eq!(
+            kv_store.consume("code".to_string(), 50).unwrap(),
+            serde_json::Value::from("123456")
+        );
+        // retries can still read the value during the grace period, but not consume it again
+        assert_eq!(
+            kv_store.get("code".to_string()).unwrap(),
+            serde_json::Value::from("123456")
+        );
+        let err = kv_store.consume("code".to_string(), 50).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(KVError::Conflict(_))));
+
+        std::thread::sleep(time::Duration::from_millis(60));
+        let err = kv_store.consume("code".to_string(), 50).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(KVError::Expired(_))));
+        assert!(kv_store.get("code".to_string()).is_err());
+        assert!(kv_store.consume("missing".to_string(), 50).is_err());
     }
 
     #[test]
     #[serial]
-    fn test_kv_store_init() {
+    fn test_kv_store_cas_with_ttl() {
         let kv_store = KVStore::new(3, ".quache-test/".to_string())
             .expect("Should be able to create KV store");
-        assert!(fs::exists(".quache-test/").expect("Should be able to check directory existence"));
-        let shard_dimensions = kv_store
-            .shard_dimensions
-            .read()
-            .expect("Should be able to acquire read lock");
-        assert_eq!(shard_dimensions.len(), 0);
-        assert_eq!(kv_store.shards.len(), 3);
+        kv_store
+            .put("lock".to_string(), serde_json::Value::from("a"), None)
+            .expect("Should be able to call .put without errors");
+
+        // a failed swap leaves both the value and the TTL untouched
+        assert!(
+            !kv_store
+                .cas_with_ttl(
+                    "lock".to_string(),
+                    &serde_json::Value::from("b"),
+                    serde_json::Value::from("c"),
+                    Some(10_f64),
+                )
+                .expect("Should be able to call .cas_with_ttl without errors")
+        );
+        let entry = kv_store.entry("lock".to_string()).unwrap();
+        assert_eq!(entry.value(), &serde_json::Value::from("a"));
+        assert_eq!(entry.ttl_ms(), -1);
+
+        assert!(
+            kv_store
+                .cas_with_ttl(
+                    "lock".to_string(),
+                    &serde_json::Value::from("a"),
+                    serde_json::Value::from("c"),
+                    Some(10_f64),
+                )
+                .expect("Should be able to call .cas_with_ttl without errors")
+        );
+        let entry = kv_store.entry("lock".to_string()).unwrap();
+        assert_eq!(entry.value(), &serde_json::Value::from("c"));
+        assert_eq!(entry.ttl_ms(), 10_000);
+
+        // without a TTL, the swap keeps the current one
+        assert!(
+            kv_store
+                .cas_with_ttl(
+                    "lock".to_string(),
+                    &serde_json::Value::from("c"),
+                    serde_json::Value::from("d"),
+                    None,
+                )
+                .expect("Should be able to call .cas_with_ttl without errors")
+        );
+        assert_eq!(kv_store.entry("lock".to_string()).unwrap().ttl_ms(), 10_000);
+
+        // missing keys never match
+        assert!(
+            !kv_store
+                .cas_with_ttl(
+                    "missing".to_string(),
+                    &serde_json::Value::Null,
+                    serde_json::Value::from(1),
+                    None,
+                )
+                .expect("Should be able to call .cas_with_ttl without errors")
+        );
 
         cleanup_test_directory(".quache-test/".to_string());
     }
 
     #[test]
-    #[serial]
-    fn test_kv_store_find_shard() {
-        let kv_store = KVStore::new(3, ".quache-test/".to_string())
-            .expect("Should be able to create KV store");
-        let shard_num_0 = kv_store.find_shard("notthekindofthingyouwouldfind");
-        assert_eq!(shard_num_0, 0);
-        let shard_num_1 = kv_store.find_shard("thisisaverylongkey");
-        assert_eq!(shard_num_1, 1);
-        let shard_num_2 = kv_store.find_shard("this is an interesting key");
-        assert_eq!(shard_num_2, 2);
+    fn test_kv_store_list_range_and_trim() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to build KV store");
+        kv_store
+            .put(
+                "list".to_string(),
+                serde_json::json!([0, 1, 2, 3, 4, 5]),
+                None,
+            )
+            .expect("Should be able to call .put without errors");
+        for (start, stop, expected) in [
+            (0, 2, serde_json::json!([0, 1, 2])),
+            (4, 100, serde_json::json!([4, 5])),
+            (-2, -1, serde_json::json!([4, 5])),
+            (-100, 1, serde_json::json!([0, 1])),
+            (1, -5, serde_json::json!([1])),
+            (3, 1, serde_json::json!([])),
+            (6, 8, serde_json::json!([])),
+        ] {
+            assert_eq!(
+                kv_store
+                    .list_range("list".to_string(), start, stop)
+                    .expect("Should be able to read range"),
+                expected,
+                "range {}..={}",
+                start,
+                stop
+            );
+        }
 
-        cleanup_test_directory(".quache-test/".to_string());
+        kv_store
+            .list_trim("list".to_string(), 1, -2)
+            .expect("Should be able to trim");
+        assert_eq!(
+            kv_store.get("list".to_string()).unwrap(),
+            serde_json::json!([1, 2, 3, 4])
+        );
+        kv_store
+            .list_trim("list".to_string(), 5, 10)
+            .expect("Should be able to trim");
+        assert_eq!(
+            kv_store.get("list".to_string()).unwrap(),
+            serde_json::json!([])
+        );
+
+        kv_store
+            .put("scalar".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        for result in [
+            kv_store.list_range("scalar".to_string(), 0, -1).map(|_| ()),
+            kv_store.list_trim("scalar".to_string(), 0, -1),
+        ] {
+            assert!(
+                result.is_err_and(|e| matches!(
+                    e.downcast_ref::<KVError>(),
+                    Some(KVError::Conflict(_))
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_kv_store_value_size_metrics() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to build KV store");
+        for (i, size) in [10, 500, 2_000, 100_000, 300_000].into_iter().enumerate() {
+            kv_store
+                .put(
+                    format!("key-{}", i),
+                    serde_json::Value::from("x".repeat(size)),
+                    None,
+                )
+                .expect("Should be able to call .put without errors");
+        }
+        // overwrites count as new writes
+        kv_store
+            .put("key-0".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        let value_sizes = kv_store.metrics().snapshot().value_sizes;
+        assert_eq!(value_sizes.under_256b, 2);
+        assert_eq!(value_sizes.under_1k, 1);
+        assert_eq!(value_sizes.under_16k, 1);
+        assert_eq!(value_sizes.under_256k, 1);
+        assert_eq!(value_sizes.at_least_256k, 1);
+    }
+
+    #[test]
+    fn test_kv_store_on_change() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to build KV store");
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = events.clone();
+        kv_store.on_change(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .incr_bounded("hey".to_string(), 2, None, None)
+            .expect("Should be able to increment");
+        kv_store
+            .delete("hey".to_string())
+            .expect("Should be able to delete");
+        // deleting a missing key changes nothing
+        kv_store
+            .delete("hey".to_string())
+            .expect("Should be able to delete");
+
+        let put = |value: i64, seq: u64| ChangeEvent {
+            op: ChangeOp::Put,
+            key: "hey".to_string(),
+            value: Some(serde_json::Value::from(value)),
+            expires_at_ms: None,
+            seq: Some(seq),
+        };
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                put(1, 1),
+                put(3, 2),
+                ChangeEvent {
+                    op: ChangeOp::Delete,
+                    key: "hey".to_string(),
+                    value: None,
+                    expires_at_ms: None,
+                    seq: None,
+                },
+            ]
+        );
+
+        // consuming shortens the expiry, which followers must hear of
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(4), None)
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .consume("hey".to_string(), 60_000)
+            .expect("Should be able to consume");
+        let consumed = events.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(consumed.op, ChangeOp::Put);
+        assert_eq!(consumed.value, Some(serde_json::Value::from(4)));
+        assert!(consumed.expires_at_ms.is_some());
     }
 
     #[test]
     #[serial]
-    fn test_kv_store_put() {
+    fn test_kv_store_min_ttl_seen() {
         let kv_store = KVStore::new(3, ".quache-test/".to_string())
             .expect("Should be able to create KV store");
         kv_store
             .put("hey".to_string(), serde_json::Value::from(1), None)
-            .expect("Should be able to call .put without errors"); // goes to shard-2
-        assert_eq!(
-            kv_store.shards[2]
-                .get_length()
-                .expect("Should be able to get length"),
-            1
-        );
-        assert_eq!(
-            kv_store.shards[1]
-                .get_length()
-                .expect("Should be able to get length"),
-            0
-        );
+            .expect("Should be able to call .put without errors");
+        assert_eq!(kv_store.min_ttl_seen(), None);
+        for ttl in [10_f64, 0.5, 3_f64] {
+            kv_store
+                .put("hey".to_string(), serde_json::Value::from(1), Some(ttl))
+                .expect("Should be able to call .put without errors");
+        }
         assert_eq!(
-            kv_store.shards[0]
-                .get_length()
-                .expect("Should be able to get length"),
-            0
+            kv_store.min_ttl_seen(),
+            Some(time::Duration::from_millis(500))
         );
-        let data = kv_store.shards[2]
-            .data
-            .read()
-            .expect("Should be able to acquire read lock");
-        assert!(data.contains_key("hey"));
+
         cleanup_test_directory(".quache-test/".to_string());
     }
 
     #[test]
     #[serial]
-    fn test_kv_store_get() {
+    fn test_kv_store_get_with_ttl() {
         let kv_store = KVStore::new(3, ".quache-test/".to_string())
             .expect("Should be able to create KV store");
         kv_store
-            .put("hey".to_string(), serde_json::Value::from(1), None)
-            .expect("Should be able to call .put without errors"); // goes to shard-2
-        let result = kv_store
-            .get("hey".to_string())
-            .expect("Should be able to get the 'hey' key");
-        assert_eq!(result, serde_json::Value::from(1));
-        let notfound = kv_store.get("hello".to_string());
-        assert_eq!(
-            notfound.is_err_and(|e| e.to_string().contains("not found")),
-            true
-        );
+            .put("hey".to_string(), serde_json::Value::from(1), Some(10_f64))
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to call .put without errors");
+        let (value, remaining) = kv_store
+            .get_with_ttl("hey".to_string())
+            .expect("Should be able to get key");
+        assert_eq!(value, serde_json::Value::from(1));
+        let remaining = remaining.expect("Expiring keys should have a remaining TTL");
+        assert!(remaining <= time::Duration::from_secs(10));
+        assert!(remaining > time::Duration::from_secs(9));
+        let (_, remaining) = kv_store
+            .get_with_ttl("hello".to_string())
+            .expect("Should be able to get key");
+        assert_eq!(remaining, None);
 
         cleanup_test_directory(".quache-test/".to_string());
     }
@@ -511,16 +6797,35 @@ mod tests {
             .delete("hello".to_string())
             .expect("Should be able to delete key");
         let notfound = kv_store.get("hello".to_string());
-        assert_eq!(
-            notfound.is_err_and(|e| e.to_string().contains("not found")),
-            true
-        );
+        assert!(notfound.is_err_and(|e| e.to_string().contains("not found")));
         let delete_not_exist = kv_store.delete("hello".to_string());
         assert!(delete_not_exist.is_ok()); // assert that delete with non-existing key is just a no-op
 
         cleanup_test_directory(".quache-test/".to_string());
     }
 
+    #[test]
+    #[serial]
+    fn test_kv_store_locate() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors"); // goes to shard-2
+        let (shard, exists) = kv_store
+            .locate("hey")
+            .expect("Should be able to locate key");
+        assert_eq!(shard, 2);
+        assert!(exists);
+        let (shard, exists) = kv_store
+            .locate("thisisaverylongkey")
+            .expect("Should be able to locate key");
+        assert_eq!(shard, 1);
+        assert!(!exists);
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
     #[test]
     #[serial]
     fn test_kv_store_cleanup() {
@@ -567,20 +6872,17 @@ mod tests {
             0
         );
         let data_2 = kv_store.shards[2]
-            .data
-            .read()
-            .expect("Should be able to acquire read lock");
+            .entries()
+            .expect("Should be able to read entries");
         assert!(data_2.contains_key("hey"));
         let data_1 = kv_store.shards[1]
-            .data
-            .read()
-            .expect("Should be able to acquire read lock");
+            .entries()
+            .expect("Should be able to read entries");
         assert!(data_1.contains_key("thisisaverylongkey"));
 
         let data_0 = kv_store.shards[0]
-            .data
-            .read()
-            .expect("Should be able to acquire read lock");
+            .entries()
+            .expect("Should be able to read entries");
         assert!(!data_0.contains_key("notthekindofthingyouwouldfind"));
 
         cleanup_test_directory(".quache-test/".to_string());
@@ -589,7 +6891,7 @@ mod tests {
     #[test]
     #[serial]
     fn test_kv_store_flush_and_restore_from_memory() {
-        let mut kv_store = KVStore::new(3, ".quache-test/".to_string())
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
             .expect("Should be able to create KV store");
         kv_store
             .put("hey".to_string(), serde_json::Value::from(1), None)
@@ -620,8 +6922,7 @@ mod tests {
                     assert_eq!(*d, 1);
                 }
                 None => {
-                    eprintln!("No dimension found for shard {:?}", i);
-                    assert!(false); // fail here
+                    panic!("No dimension found for shard {:?}", i);
                 }
             }
         }
@@ -647,22 +6948,657 @@ mod tests {
             1
         );
         let data_2 = kv_store_1.shards[2]
-            .data
-            .read()
-            .expect("Should be able to acquire read lock");
+            .entries()
+            .expect("Should be able to read entries");
         assert!(data_2.contains_key("hey"));
         let data_1 = kv_store_1.shards[1]
-            .data
-            .read()
-            .expect("Should be able to acquire read lock");
+            .entries()
+            .expect("Should be able to read entries");
         assert!(data_1.contains_key("thisisaverylongkey"));
 
         let data_0 = kv_store_1.shards[0]
-            .data
-            .read()
-            .expect("Should be able to acquire read lock");
+            .entries()
+            .expect("Should be able to read entries");
         assert!(data_0.contains_key("notthekindofthingyouwouldfind"));
 
         cleanup_test_directory(".quache-test/".to_string());
     }
+
+    #[derive(Debug, Default)]
+    struct MemoryTarget {
+        objects: RwLock<HashMap<usize, String>>,
+    }
+
+    impl FlushTarget for MemoryTarget {
+        fn write_shard(&self, shard_idx: usize, contents: &str) -> Result<()> {
+            self.objects
+                .write()
+                .unwrap()
+                .insert(shard_idx, contents.to_string());
+            Ok(())
+        }
+
+        fn read_shard(&self, shard_idx: usize) -> Result<Option<String>> {
+            Ok(self.objects.read().unwrap().get(&shard_idx).cloned())
+        }
+
+        fn describe(&self) -> String {
+            "memory".to_string()
+        }
+    }
+
+    #[test]
+    fn test_kv_store_flush_and_restore_from_target() {
+        let target = Arc::new(MemoryTarget::default());
+        let kv_store =
+            KVStore::new_from_target(3, ".quache-target-test/".to_string(), target.clone())
+                .expect("Should be able to create KV store from an empty target");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors"); // goes to shard-2
+        kv_store
+            .to_disk()
+            .expect("Should be able to flush to the target");
+        assert_eq!(target.objects.read().unwrap().len(), 1);
+        assert!(target.objects.read().unwrap().contains_key(&2));
+        assert!(!fs::exists(".quache-target-test/").unwrap());
+
+        let kv_store_1 =
+            KVStore::new_from_target(3, ".quache-target-test/".to_string(), target.clone())
+                .expect("Should be able to create the KV Store from the target");
+        assert_eq!(
+            kv_store_1
+                .get("hey".to_string())
+                .expect("Should be able to get the 'hey' key"),
+            serde_json::Value::from(1)
+        );
+        assert_eq!(kv_store_1.init_missing_shard_files().unwrap(), 2);
+        assert_eq!(target.objects.read().unwrap().len(), 3);
+        assert!(!fs::exists(".quache-target-test/").unwrap());
+
+        target
+            .objects
+            .write()
+            .unwrap()
+            .entry(2)
+            .and_modify(|c| c.push(' '));
+        assert!(KVStore::new_from_target(3, ".quache-target-test/".to_string(), target).is_err());
+    }
+
+    /// Records how many shards are written at the same time.
+    #[derive(Debug, Default)]
+    struct SlowTarget {
+        inner: MemoryTarget,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl FlushTarget for SlowTarget {
+        fn write_shard(&self, shard_idx: usize, contents: &str) -> Result<()> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            std::thread::sleep(time::Duration::from_millis(20));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.inner.write_shard(shard_idx, contents)
+        }
+
+        fn read_shard(&self, shard_idx: usize) -> Result<Option<String>> {
+            self.inner.read_shard(shard_idx)
+        }
+
+        fn describe(&self) -> String {
+            "slow".to_string()
+        }
+    }
+
+    #[test]
+    fn test_kv_store_flush_concurrency() {
+        for (flush_concurrency, max_expected) in [(1, 1), (4, 4)] {
+            let target = Arc::new(SlowTarget::default());
+            let kv_store = KVStore::new_from_target(
+                8,
+                ".quache-concurrency-test/".to_string(),
+                target.clone(),
+            )
+            .expect("Should be able to create KV store from an empty target")
+            .with_flush_concurrency(flush_concurrency);
+            for i in 0..64 {
+                kv_store
+                    .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                    .expect("Should be able to call .put without errors");
+            }
+            kv_store
+                .to_disk()
+                .expect("Should be able to flush to the target");
+            let changed_shards = kv_store
+                .shards
+                .iter()
+                .filter(|shard| shard.get_length().unwrap() > 0)
+                .count();
+            assert_eq!(
+                target.inner.objects.read().unwrap().len(),
+                changed_shards,
+                "every changed shard should be written with concurrency {}",
+                flush_concurrency
+            );
+            let max_in_flight = target.max_in_flight.load(Ordering::SeqCst);
+            assert!(max_in_flight >= 1 && max_in_flight <= max_expected);
+        }
+    }
+
+    #[test]
+    fn test_kv_store_load_keeps_newest_duplicate() {
+        let directory = ".quache-duplicates-test/";
+        let repaired_directory = ".quache-duplicates-fsck-test/";
+        // "hey" goes to shard-2, the copy in shard-0 is misplaced
+        for (misplaced_is_newer, expected) in [(true, 0), (false, 2)] {
+            for dir in [directory, repaired_directory] {
+                fs::create_dir_all(dir).unwrap();
+                for i in [0, 2] {
+                    let newer = (i == 0) == misplaced_is_newer;
+                    let mut entry = ShardEntry::new(serde_json::Value::from(i), None);
+                    entry.timestamp = if newer { 2_000 } else { 1_000 };
+                    // the timestamp decides, not the sequence number
+                    entry.seq = if newer { 1 } else { 5 };
+                    Shard::new_with_data(HashMap::from([("hey".to_string(), entry)]))
+                        .flush(shard_file_path(dir, i as usize), false)
+                        .unwrap();
+                }
+            }
+            fsck(repaired_directory, 3, HashStrategy::default(), true)
+                .expect("Should be able to repair the directory");
+            for dir in [directory, repaired_directory] {
+                let kv_store = KVStore::new_from_disk(3, dir.to_string())
+                    .expect("Should be able to create the KV Store from disk");
+                assert_eq!(
+                    kv_store.get("hey".to_string()).unwrap(),
+                    serde_json::Value::from(expected),
+                    "{}",
+                    dir
+                );
+                assert_eq!(kv_store.list_keys(None).unwrap(), vec!["hey".to_string()]);
+                assert_eq!(kv_store.shards[0].get_length().unwrap(), 0);
+                cleanup_test_directory(dir.to_string());
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_fsck_repairs_misplaced_key() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors"); // goes to shard-2
+        kv_store
+            .put(
+                "notthekindofthingyouwouldfind".to_string(),
+                serde_json::Value::from(3),
+                None,
+            )
+            .expect("Should be able to call .put without errors"); // goes to shard-0
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        let report = fsck(".quache-test/", 3, HashStrategy::default(), false)
+            .expect("Should be able to check the directory");
+        assert_eq!(report.shards_checked, 2);
+        assert!(!report.has_unresolved_issues());
+
+        // move "hey" from shard-2 to shard-0
+        let shard_0 = Shard::from_file(&shard_file_path(".quache-test/", 0)).unwrap();
+        let shard_2 = Shard::from_file(&shard_file_path(".quache-test/", 2)).unwrap();
+        let entry = shard_2.lock_key("hey").unwrap().remove("hey").unwrap();
+        shard_0
+            .lock_key("hey")
+            .unwrap()
+            .insert("hey".to_string(), entry);
+        shard_0
+            .flush(shard_file_path(".quache-test/", 0), false)
+            .unwrap();
+        shard_2
+            .flush(shard_file_path(".quache-test/", 2), false)
+            .unwrap();
+        assert!(
+            KVStore::new_from_disk(3, ".quache-test/".to_string())
+                .unwrap()
+                .get("hey".to_string())
+                .is_err()
+        );
+
+        let report = fsck(".quache-test/", 3, HashStrategy::default(), false)
+            .expect("Should be able to check the directory");
+        assert_eq!(report.misplaced_keys, vec!["hey".to_string()]);
+        assert!(report.duplicate_keys.is_empty());
+        assert!(report.has_unresolved_issues());
+
+        let report = fsck(".quache-test/", 3, HashStrategy::default(), true)
+            .expect("Should be able to repair the directory");
+        assert_eq!(report.moved_keys, 1);
+        assert_eq!(report.rewritten_shards, vec![0, 2]);
+        assert!(!report.has_unresolved_issues());
+        let report = fsck(".quache-test/", 3, HashStrategy::default(), false)
+            .expect("Should be able to check the directory");
+        assert!(report.misplaced_keys.is_empty());
+
+        let kv_store_1 = KVStore::new_from_disk(3, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(
+            kv_store_1.get("hey".to_string()).unwrap(),
+            serde_json::Value::from(1)
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_flush_and_restore_pretty() {
+        let kv_store = KVStore::new(1, ".quache-test/".to_string())
+            .expect("Should be able to create KV store")
+            .with_pretty_disk(true);
+        kv_store
+            .put(
+                "zebra".to_string(),
+                serde_json::json!({"b": 1, "a": 2}),
+                None,
+            )
+            .expect("Should be able to call .put without errors");
+        kv_store
+            .put(
+                "apple".to_string(),
+                serde_json::Value::from(1),
+                Some(60_f64),
+            )
+            .expect("Should be able to call .put without errors");
+        kv_store.to_disk().expect("Should be able to flush to disk");
+
+        let content = fs::read_to_string(shard_file_path(".quache-test/", 0)).unwrap();
+        let (hash, payload) = content.split_once('\n').unwrap();
+        assert_eq!(hash, integrity_hash(payload.as_bytes()));
+        assert!(payload.lines().count() > 1);
+        assert!(payload.find("\"apple\"").unwrap() < payload.find("\"zebra\"").unwrap());
+
+        let kv_store_1 = KVStore::new_from_disk(1, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(
+            kv_store_1.get("zebra".to_string()).unwrap(),
+            serde_json::json!({"a": 2, "b": 1})
+        );
+        assert!(
+            kv_store_1
+                .get_with_ttl("apple".to_string())
+                .unwrap()
+                .1
+                .is_some()
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_restore_lazy() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        for i in 0..30 {
+            kv_store
+                .put(
+                    format!("key-{}", i),
+                    serde_json::json!({"n": i}),
+                    Some(60_f64),
+                )
+                .expect("Should be able to call .put without errors");
+        }
+        kv_store.to_disk().expect("Should be able to flush to disk");
+
+        let eager = KVStore::new_from_disk(3, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        let lazy = KVStore::new_from_disk_lazy(3, ".quache-test/".to_string())
+            .expect("Should be able to map the KV Store from disk");
+        for i in 0..3 {
+            assert_eq!(
+                lazy.shards[i].encode_pretty().unwrap(),
+                eager.shards[i].encode_pretty().unwrap()
+            );
+        }
+        assert_eq!(
+            lazy.get("key-7".to_string()).unwrap(),
+            serde_json::json!({"n": 7})
+        );
+
+        // the integrity hash is still verified upfront
+        let file_path = shard_file_path(".quache-test/", 0);
+        let content = fs::read_to_string(&file_path).unwrap();
+        fs::write(&file_path, content.replacen("\"n\"", "\"m\"", 1)).unwrap();
+        assert!(KVStore::new_from_disk_lazy(3, ".quache-test/".to_string()).is_err());
+
+        // entries that can't be deserialized fail the accesses to the shard, not the process
+        fs::write(
+            &file_path,
+            Shard::with_integrity_hash("{\"key\": 1}".to_string()).unwrap(),
+        )
+        .unwrap();
+        let broken = KVStore::new_from_disk_lazy(3, ".quache-test/".to_string())
+            .expect("Should defer deserializing the shard");
+        assert!(broken.shards[0].get_length().is_err());
+        assert!(broken.shards[0].encode().is_err());
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_restore_from() {
+        let backup = KVStore::new(3, ".quache-test/backup/".to_string())
+            .expect("Should be able to create KV store");
+        for i in 0..10 {
+            backup
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to call .put without errors");
+        }
+        backup.to_disk().expect("Should be able to flush to disk");
+
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("stale".to_string(), serde_json::Value::from(true), None)
+            .expect("Should be able to call .put without errors");
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = events.clone();
+        kv_store.on_change(move |event| recorded.lock().unwrap().push(event.clone()));
+        let clone = kv_store.clone();
+        assert_eq!(
+            kv_store
+                .restore_from(".quache-test/backup/")
+                .expect("Should be able to restore"),
+            10
+        );
+        {
+            let events = events.lock().unwrap();
+            assert_eq!(events.len(), 11);
+            assert!(
+                events
+                    .iter()
+                    .any(|e| e.op == ChangeOp::Delete && e.key == "stale")
+            );
+            assert_eq!(events.iter().filter(|e| e.op == ChangeOp::Put).count(), 10);
+        }
+        assert!(clone.get("stale".to_string()).is_err());
+        assert_eq!(
+            clone.get("key-4".to_string()).unwrap(),
+            serde_json::Value::from(4)
+        );
+
+        // the restored data is flushed to the store's own directory
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        let reloaded = KVStore::new_from_disk(3, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(reloaded.list_keys(None).unwrap().len(), 10);
+
+        // invalid backups leave the store untouched
+        let file_path = shard_file_path(".quache-test/backup/", 0);
+        let content = fs::read_to_string(&file_path).unwrap();
+        fs::write(&file_path, content.replacen("key", "kex", 1)).unwrap();
+        kv_store
+            .put("fresh".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to call .put without errors");
+        let err = kv_store.restore_from(".quache-test/backup/").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KVError>(),
+            Some(KVError::InvalidInput(_))
+        ));
+        assert!(kv_store.restore_from(".quache-test/missing/").is_err());
+        assert_eq!(kv_store.list_keys(None).unwrap().len(), 11);
+
+        // so do backups written with another layout
+        let other_layouts = [
+            KVStore::new(4, ".quache-test/four-shards/".to_string()),
+            KVStore::builder()
+                .shards(3)
+                .directory(".quache-test/fnv1a/")
+                .hash_strategy(HashStrategy::Fnv1a)
+                .build(),
+        ];
+        for (other, directory) in other_layouts
+            .into_iter()
+            .zip([".quache-test/four-shards/", ".quache-test/fnv1a/"])
+        {
+            let other = other.expect("Should be able to create KV store");
+            for i in 0..10 {
+                other
+                    .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                    .expect("Should be able to call .put without errors");
+            }
+            other.to_disk().expect("Should be able to flush to disk");
+            let err = kv_store.restore_from(directory).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<KVError>(),
+                    Some(KVError::InvalidInput(_))
+                ),
+                "{}",
+                err
+            );
+        }
+        assert_eq!(kv_store.list_keys(None).unwrap().len(), 11);
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    /// Flushes 20 keys with 4 shards to `.quache-test/`
+    fn flush_four_shards() {
+        let kv_store = KVStore::new(4, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        for i in 0..20 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to call .put without errors");
+        }
+        kv_store.to_disk().expect("Should be able to flush to disk");
+    }
+
+    #[test]
+    #[serial]
+    fn test_reconcile_shard_count() {
+        flush_four_shards();
+        assert_eq!(
+            read_manifest(".quache-test/").unwrap(),
+            Some(Manifest {
+                shards: 4,
+                hash_strategy: HashStrategy::default()
+            })
+        );
+        // matching counts are fine whatever the policy
+        reconcile_shard_count(".quache-test/", 4, Default::default())
+            .expect("Should accept the stored shard count");
+
+        let err =
+            reconcile_shard_count(".quache-test/", 2, ShardMismatchPolicy::Error).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KVError>(),
+            Some(KVError::InvalidInput(_))
+        ));
+        assert_eq!(shard_file_indices(".quache-test/").unwrap().len(), 4);
+
+        // ignoring loads the first files as they are: keys of the other files are lost
+        reconcile_shard_count(".quache-test/", 2, ShardMismatchPolicy::Ignore)
+            .expect("Should ignore the mismatch");
+        assert_eq!(
+            read_manifest(".quache-test/").unwrap(),
+            Some(Manifest {
+                shards: 4,
+                hash_strategy: HashStrategy::default()
+            })
+        );
+        let ignored = KVStore::new_from_disk(2, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert!(ignored.list_keys(None).unwrap().len() < 20);
+
+        reconcile_shard_count(".quache-test/", 2, ShardMismatchPolicy::Reshard)
+            .expect("Should be able to reshard");
+        assert_eq!(
+            read_manifest(".quache-test/").unwrap(),
+            Some(Manifest {
+                shards: 2,
+                hash_strategy: HashStrategy::default()
+            })
+        );
+        assert_eq!(shard_file_indices(".quache-test/").unwrap(), vec![0, 1]);
+        let resharded = KVStore::new_from_disk(2, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        for i in 0..20 {
+            assert_eq!(
+                resharded.get(format!("key-{}", i)).unwrap(),
+                serde_json::Value::from(i)
+            );
+        }
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_reconcile_shard_count_keeps_hash_strategy() {
+        let kv_store = KVStore::builder()
+            .shards(4)
+            .directory(".quache-test/")
+            .hash_strategy(HashStrategy::Fnv1a)
+            .build()
+            .expect("Should be able to create KV store");
+        for i in 0..20 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to call .put without errors");
+        }
+        kv_store.to_disk().expect("Should be able to flush to disk");
+
+        reconcile_shard_count(".quache-test/", 2, ShardMismatchPolicy::Reshard)
+            .expect("Should be able to reshard");
+        assert_eq!(
+            read_manifest(".quache-test/").unwrap(),
+            Some(Manifest {
+                shards: 2,
+                hash_strategy: HashStrategy::Fnv1a
+            })
+        );
+        let resharded = KVStore::new_from_disk(2, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        for i in 0..20 {
+            assert_eq!(
+                resharded.get(format!("key-{}", i)).unwrap(),
+                serde_json::Value::from(i)
+            );
+        }
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_reconcile_shard_count_finishes_interrupted_reshards() {
+        flush_four_shards();
+        let staging = reshard_dir_path(".quache-test/");
+
+        // reshards interrupted before their new files are complete are discarded
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(shard_file_path(&staging, 0), "partial").unwrap();
+        reconcile_shard_count(".quache-test/", 4, ShardMismatchPolicy::Error)
+            .expect("Should discard the incomplete reshard");
+        assert!(!fs::exists(&staging).unwrap());
+        let kv_store = KVStore::new_from_disk(4, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(kv_store.list_keys(None).unwrap().len(), 20);
+
+        // reshards interrupted while moving their new files are completed
+        fs::create_dir_all(&staging).unwrap();
+        for i in 0..4 {
+            fs::copy(
+                shard_file_path(".quache-test/", i),
+                shard_file_path(&staging, i),
+            )
+            .unwrap();
+        }
+        fsck(&staging, 2, HashStrategy::default(), true).unwrap();
+        for i in 2..4 {
+            fs::remove_file(shard_file_path(&staging, i)).unwrap();
+        }
+        write_manifest(
+            &staging,
+            &Manifest {
+                shards: 2,
+                hash_strategy: HashStrategy::default(),
+            },
+        )
+        .unwrap();
+        fs::rename(
+            shard_file_path(&staging, 0),
+            shard_file_path(".quache-test/", 0),
+        )
+        .unwrap();
+        reconcile_shard_count(".quache-test/", 2, ShardMismatchPolicy::Error)
+            .expect("Should complete the interrupted reshard");
+        assert!(!fs::exists(&staging).unwrap());
+        assert_eq!(shard_file_indices(".quache-test/").unwrap(), vec![0, 1]);
+        let resharded = KVStore::new_from_disk(2, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        for i in 0..20 {
+            assert_eq!(
+                resharded.get(format!("key-{}", i)).unwrap(),
+                serde_json::Value::from(i)
+            );
+        }
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_reconcile_shard_count_without_manifest() {
+        flush_four_shards();
+        fs::remove_file(manifest_file_path(".quache-test/")).unwrap();
+        reconcile_shard_count(".quache-test/", 2, ShardMismatchPolicy::Error)
+            .expect("Directories without a manifest are assumed to match");
+        assert_eq!(
+            read_manifest(".quache-test/").unwrap(),
+            Some(Manifest {
+                shards: 2,
+                hash_strategy: HashStrategy::default()
+            })
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
+
+    #[test]
+    #[serial]
+    fn test_kv_store_flush_and_restore_values_with_newlines() {
+        let kv_store = KVStore::new(3, ".quache-test/".to_string())
+            .expect("Should be able to create KV store");
+        let multiline = serde_json::Value::from("first line\nsecond line\n");
+        let nested = serde_json::json!({ "text": "a\nb", "lines": ["\n", "c\r\nd"] });
+        kv_store
+            .put("hey".to_string(), multiline.clone(), None)
+            .expect("Should be able to call .put without errors"); // goes to shard-2
+        kv_store
+            .put("thisisaverylongkey".to_string(), nested.clone(), None)
+            .expect("Should be able to call .put without errors"); // goes to shard-1
+        kv_store.to_disk().expect("Should be able to flush to disk");
+
+        let kv_store_1 = KVStore::new_from_disk(3, ".quache-test/".to_string())
+            .expect("Should be able to create the KV Store from disk");
+        assert_eq!(
+            kv_store_1
+                .get("hey".to_string())
+                .expect("Should be able to get the 'hey' key"),
+            multiline
+        );
+        assert_eq!(
+            kv_store_1
+                .get("thisisaverylongkey".to_string())
+                .expect("Should be able to get the 'thisisaverylongkey' key"),
+            nested
+        );
+
+        cleanup_test_directory(".quache-test/".to_string());
+    }
 }
@@ -0,0 +1,112 @@
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Put,
+    Delete,
+    /// Eviction of an entry whose TTL elapsed
+    Expire,
+}
+
+/// A mutation applied to a KV store.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChangeEvent {
+    pub op: ChangeOp,
+    pub key: String,
+    /// New value of the key, omitted for deletes and expirations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    /// Millisecond timestamp at which the new value expires, omitted for persistent values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at_ms: Option<u64>,
+    /// Sequence number of the new value, omitted for deletes, expirations and restored values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+type Listener = Box<dyn Fn(&ChangeEvent) + Send + Sync>;
+
+/// Callbacks notified of every mutation of a store, shared between its clones.
+#[derive(Clone, Default)]
+pub struct ChangeListeners(Arc<RwLock<Vec<Listener>>>);
+
+impl fmt::Debug for ChangeListeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.0.read().map(|listeners| listeners.len()).unwrap_or(0);
+        write!(f, "ChangeListeners({})", count)
+    }
+}
+
+impl ChangeListeners {
+    pub fn add(&self, listener: impl Fn(&ChangeEvent) + Send + Sync + 'static) {
+        if let Ok(mut listeners) = self.0.write() {
+            listeners.push(Box::new(listener));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0
+            .read()
+            .map(|listeners| listeners.is_empty())
+            .unwrap_or(true)
+    }
+
+    pub fn notify(&self, event: ChangeEvent) {
+        if let Ok(listeners) = self.0.read() {
+            for listener in listeners.iter() {
+                listener(&event);
+            }
+        }
+    }
+}
+
+/// Matches `key` against a glob `pattern`, where `*` matches any sequence of characters
+/// (including none) and `?` matches exactly one character.
+pub fn glob_matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // position of the last `*` seen, and of the key character it was matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == key[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, k));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // let the last `*` swallow one more character
+            p = star + 1;
+            k = matched + 1;
+            backtrack = Some((star, k));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("orders:*", "orders:1"));
+        assert!(glob_matches("orders:*", "orders:"));
+        assert!(!glob_matches("orders:*", "users:1"));
+        assert!(glob_matches("*:1", "orders:1"));
+        assert!(glob_matches("o?ders:*:total", "orders:42:total"));
+        assert!(!glob_matches("o?ders:*:total", "orders:42:count"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("exact", "exact"));
+        assert!(!glob_matches("exact", "exactly"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    fs,
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Result;
+
+use crate::core::shard_file_path;
+
+/// Where shards are flushed to and loaded back from.
+///
+/// Targets only move the encoded shard contents around: integrity hashing and (de)serialization
+/// are left to [`crate::core::Shard`].
+pub trait FlushTarget: std::fmt::Debug + Send + Sync {
+    /// Stores the encoded contents of the shard with index `shard_idx`, replacing previous ones.
+    fn write_shard(&self, shard_idx: usize, contents: &str) -> Result<()>;
+
+    /// Returns the encoded contents of the shard with index `shard_idx`, or `None` if it was
+    /// never written.
+    fn read_shard(&self, shard_idx: usize) -> Result<Option<String>>;
+
+    /// Human-readable location of the target, used in logs.
+    fn describe(&self) -> String;
+}
+
+/// Distinguishes the temporary files of concurrent writes to the same path.
+static TMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes `contents` to the file at `path`.
+///
+/// With `fsync`, the contents are written to a temporary file next to it, synced to the disk and
+/// then renamed over `path`: once this returns, the file survives a power loss, and a crash
+/// midway leaves the previous contents in place. This costs a disk sync per write. Every write
+/// gets its own temporary file, so concurrent writes to `path` (e.g. the flush worker and an
+/// `/admin/flush`) can't rename each other's partial contents in place.
+pub fn write_file(path: &str, contents: &[u8], fsync: bool) -> Result<()> {
+    if !fsync {
+        fs::write(path, contents)?;
+        return Ok(());
+    }
+    let tmp_path = format!(
+        "{}.{}-{}.tmp",
+        path,
+        std::process::id(),
+        TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let written = fs::File::create(&tmp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&tmp_path, path)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Flushes each shard to its own file in a local directory.
+#[derive(Debug, Clone)]
+pub struct LocalTarget {
+    directory: String,
+    fsync: bool,
+}
+
+impl LocalTarget {
+    pub fn new(directory: impl Into<String>) -> Self {
+        Self {
+            directory: directory.into(),
+            fsync: false,
+        }
+    }
+
+    /// Syncs every shard file to the disk before replacing the previous one (see
+    /// [`write_file`]).
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+}
+
+impl FlushTarget for LocalTarget {
+    fn write_shard(&self, shard_idx: usize, contents: &str) -> Result<()> {
+        write_file(
+            &shard_file_path(&self.directory, shard_idx),
+            contents.as_bytes(),
+            self.fsync,
+        )
+    }
+
+    fn read_shard(&self, shard_idx: usize) -> Result<Option<String>> {
+        let file_path = shard_file_path(&self.directory, shard_idx);
+        if !fs::exists(&file_path)? {
+            return Ok(None);
+        }
+        Ok(Some(fs::read_to_string(file_path)?))
+    }
+
+    fn describe(&self) -> String {
+        self.directory.clone()
+    }
+}
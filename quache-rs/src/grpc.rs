@@ -0,0 +1,276 @@
+use std::net::SocketAddr;
+
+use tonic::{Request, Response, Status};
+
+use crate::{
+    auth::ApiKeys,
+    core::{KVError, KVStore},
+    server::log_key,
+};
+
+pub mod proto {
+    tonic::include_proto!("quache");
+}
+
+use proto::{
+    BatchGetEntry, BatchGetReply, BatchGetRequest, DeleteReply, DeleteRequest, GetReply,
+    GetRequest, PutReply, PutRequest,
+    quache_server::{Quache, QuacheServer},
+};
+
+/// Maps store errors to gRPC status codes, the same way the HTTP server maps them to status codes.
+fn to_status(e: anyhow::Error) -> Status {
+    match e.downcast_ref::<KVError>() {
+        Some(KVError::NotFound(_)) | Some(KVError::Expired(_)) => Status::not_found(e.to_string()),
+        Some(KVError::Conflict(_)) => Status::failed_precondition(e.to_string()),
+        Some(KVError::InvalidInput(_)) | Some(KVError::SchemaViolation(_)) => {
+            Status::invalid_argument(e.to_string())
+        }
+        None => Status::internal(e.to_string()),
+    }
+}
+
+/// gRPC service backed by the same `KVStore` as the HTTP API.
+#[derive(Debug, Clone)]
+pub struct QuacheService {
+    kv_store: KVStore,
+    log_keys: bool,
+    read_only: bool,
+    api_keys: Option<ApiKeys>,
+}
+
+impl QuacheService {
+    pub fn new(kv_store: KVStore) -> Self {
+        Self {
+            kv_store,
+            log_keys: false,
+            read_only: false,
+            api_keys: None,
+        }
+    }
+
+    /// Logs the method and key(s) of every call, at debug level.
+    pub fn with_log_keys(mut self, log_keys: bool) -> Self {
+        self.log_keys = log_keys;
+        self
+    }
+
+    /// Rejects puts and deletes with `PERMISSION_DENIED`.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Rejects calls without `authorization: Bearer <key>` metadata holding one of `api_keys`
+    /// with `UNAUTHENTICATED`. Sharing the HTTP server's keys keeps rotations in effect.
+    pub fn with_api_keys(mut self, api_keys: Option<ApiKeys>) -> Self {
+        self.api_keys = api_keys;
+        self
+    }
+
+    fn check_api_key<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(api_keys) = &self.api_keys else {
+            return Ok(());
+        };
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| api_keys.check(token)) {
+            return Err(Status::unauthenticated("missing or invalid API key"));
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), Status> {
+        if self.read_only {
+            return Err(Status::permission_denied("this instance is read-only"));
+        }
+        Ok(())
+    }
+
+    fn log_key(&self, method: &str, key: &str) {
+        if self.log_keys {
+            log_key(method, key);
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Quache for QuacheService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetReply>, Status> {
+        self.check_api_key(&request)?;
+        let key = request.into_inner().key;
+        self.log_key("Get", &key);
+        let value = self.kv_store.get(key).map_err(to_status)?;
+        Ok(Response::new(GetReply {
+            value_json: value.to_string(),
+        }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutReply>, Status> {
+        self.check_api_key(&request)?;
+        let request = request.into_inner();
+        self.log_key("Put", &request.key);
+        self.check_writable()?;
+        let value: serde_json::Value = serde_json::from_str(&request.value_json).map_err(|e| {
+            Status::invalid_argument(format!("value_json is not valid JSON: {}", e))
+        })?;
+        self.kv_store
+            .put(request.key, value, request.ttl)
+            .map_err(to_status)?;
+        Ok(Response::new(PutReply {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteReply>, Status> {
+        self.check_api_key(&request)?;
+        let key = request.into_inner().key;
+        self.log_key("Delete", &key);
+        self.check_writable()?;
+        self.kv_store.delete(key).map_err(to_status)?;
+        Ok(Response::new(DeleteReply {}))
+    }
+
+    async fn batch_get(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> Result<Response<BatchGetReply>, Status> {
+        self.check_api_key(&request)?;
+        let mut entries = vec![];
+        for key in request.into_inner().keys {
+            self.log_key("BatchGet", &key);
+            let entry = match self.kv_store.get(key.clone()) {
+                Ok(value) => BatchGetEntry {
+                    key,
+                    found: true,
+                    value_json: value.to_string(),
+                },
+                Err(e) if e.downcast_ref::<KVError>().is_some() => BatchGetEntry {
+                    key,
+                    found: false,
+                    value_json: String::new(),
+                },
+                Err(e) => return Err(to_status(e)),
+            };
+            entries.push(entry);
+        }
+        Ok(Response::new(BatchGetReply { entries }))
+    }
+}
+
+pub async fn serve_grpc(service: QuacheService, addr: SocketAddr) -> anyhow::Result<()> {
+    tracing::info!("Starting to serve gRPC on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(QuacheServer::new(service))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cleanup_test_directory(directory_name: String) {
+        if std::fs::exists(&directory_name).expect("Should be able to check directory existence") {
+            std::fs::remove_dir_all(directory_name)
+                .expect("Should be able to remove directory content");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grpc_service() {
+        let kv_store =
+            KVStore::new(3, ".quache-grpc/".to_string()).expect("Should be able to create test");
+        let service = QuacheService::new(kv_store.clone());
+
+        service
+            .put(Request::new(PutRequest {
+                key: "hello".to_string(),
+                value_json: r#"{"a": [1, 2]}"#.to_string(),
+                ttl: None,
+            }))
+            .await
+            .expect("Should be able to put");
+        let invalid = service
+            .put(Request::new(PutRequest {
+                key: "hello".to_string(),
+                value_json: "not json".to_string(),
+                ttl: None,
+            }))
+            .await;
+        assert_eq!(invalid.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        let reply = service
+            .get(Request::new(GetRequest {
+                key: "hello".to_string(),
+            }))
+            .await
+            .expect("Should be able to get")
+            .into_inner();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&reply.value_json).unwrap(),
+            serde_json::json!({"a": [1, 2]})
+        );
+
+        let reply = service
+            .batch_get(Request::new(BatchGetRequest {
+                keys: vec!["hello".to_string(), "missing".to_string()],
+            }))
+            .await
+            .expect("Should be able to batch get")
+            .into_inner();
+        assert_eq!(reply.entries.len(), 2);
+        assert!(reply.entries[0].found);
+        assert!(!reply.entries[1].found);
+
+        service
+            .delete(Request::new(DeleteRequest {
+                key: "hello".to_string(),
+            }))
+            .await
+            .expect("Should be able to delete");
+        let missing = service
+            .get(Request::new(GetRequest {
+                key: "hello".to_string(),
+            }))
+            .await;
+        assert_eq!(missing.unwrap_err().code(), tonic::Code::NotFound);
+
+        cleanup_test_directory(".quache-grpc/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_grpc_api_key() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        let service = QuacheService::new(kv_store).with_api_keys(Some(ApiKeys::new(
+            "secret".to_string(),
+            std::time::Duration::from_secs(60),
+        )));
+        let get = |token: Option<&str>| {
+            let mut request = Request::new(GetRequest {
+                key: "hello".to_string(),
+            });
+            if let Some(token) = token {
+                request
+                    .metadata_mut()
+                    .insert("authorization", token.parse().unwrap());
+            }
+            request
+        };
+
+        let missing = service.get(get(None)).await;
+        assert_eq!(missing.unwrap_err().code(), tonic::Code::Unauthenticated);
+        let invalid = service.get(get(Some("Bearer wrong"))).await;
+        assert_eq!(invalid.unwrap_err().code(), tonic::Code::Unauthenticated);
+        let valid = service.get(get(Some("Bearer secret"))).await;
+        assert_eq!(valid.unwrap_err().code(), tonic::Code::NotFound);
+    }
+}
@@ -0,0 +1,24 @@
+#[cfg(feature = "server")]
+pub mod auth;
+pub mod core;
+pub mod events;
+pub mod flush;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod metrics;
+pub mod namespaces;
+#[cfg(feature = "server")]
+pub mod ratelimit;
+#[cfg(feature = "server")]
+pub mod replication;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "schema")]
+pub mod schema;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod wal;
+#[cfg(feature = "server")]
+pub mod webhook;
+pub mod workers;
@@ -1,21 +1,72 @@
-mod core;
-mod server;
-
-use std::time;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::Duration,
+};
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use tracing_subscriber::filter::LevelFilter;
 
-use crate::{core::KVStore, server::KVStoreServer};
+#[cfg(feature = "schema")]
+use quache_rs::schema::Schemas;
+use quache_rs::{
+    core::{
+        DEFAULT_FLUSH_CONCURRENCY, DEFAULT_MAX_JSON_DEPTH, DEFAULT_MAX_KEY_ECHO, KVStore,
+        MAX_JSON_DEPTH_LIMIT, MAX_TTL_SECS, Shard, ShardBackend, ShardMismatchPolicy, TtlCapPolicy,
+        fsck, reconcile_shard_count, set_max_key_echo, shard_file_indices, shard_file_path,
+        stored_hash_strategy,
+    },
+    flush::LocalTarget,
+    namespaces::Namespaces,
+    replication::bootstrap_from,
+    server::{
+        DEFAULT_KEY_ROTATION_OVERLAP_SECS, DEFAULT_MAX_FLUSH_FAILURES, DEFAULT_MAX_PAGE_SIZE,
+        DEFAULT_RETRY_AFTER_SECS, DEFAULT_SHUTDOWN_TIMEOUT_SECS, KVStoreServer, ResponseStyle,
+        open_stores,
+    },
+    wal::{WalFollower, follow_wal, write_wal},
+    webhook::send_webhooks,
+    workers::{
+        MaintenanceWindow, cleanup_worker, namespaces_to_disk_worker, shard_flush_worker,
+        to_disk_worker,
+    },
+};
+#[cfg(feature = "s3")]
+use quache_rs::{flush::FlushTarget, s3::S3Target};
 
 const DEFAULT_DIRECTORY: &str = ".quache/";
 const DEFAULT_SHARD_NUMBER: usize = 5;
 const DEFAULT_FLUSHING_INTERVAL: u64 = 1000;
 const DEFAULT_CLEANUP_INTERVAL: u64 = 500;
+const DEFAULT_FOLLOW_INTERVAL: u64 = 1000;
+const MIN_INTERVAL: u64 = 1;
+const PANIC_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+#[cfg(feature = "s3")]
+const DEFAULT_S3_ENDPOINT: &str = "https://s3.amazonaws.com";
+#[cfg(feature = "s3")]
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// Set while the panic hook is flushing, so a panic during the flush doesn't flush again.
+static PANIC_FLUSHING: AtomicBool = AtomicBool::new(false);
 
 /// quache is a single-node in-memory KV store that can be served as an API server
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 struct CliArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Only log errors
+    #[arg(short, long, default_value_t = false, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also log debug messages, such as every flush and cleanup
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+
     /// Directory which to flush the KV store data to. Defaults to .quache/
     #[arg(short, long, default_value=None)]
     directory: Option<String>,
@@ -28,6 +79,14 @@ struct CliArgs {
     #[arg(short, long, default_value_t = false)]
     load: bool,
 
+    /// With --load, write an empty file for every shard that has none yet, so that the directory (or the --s3-bucket) holds a file per shard
+    #[arg(long, default_value_t = false)]
+    init_shards_on_load: bool,
+
+    /// What to do when loading a directory written with another number of shards: error, reshard (rewrites the shard files) or ignore (keys may not be found). Defaults to error
+    #[arg(long, default_value = "error")]
+    on_shard_mismatch: ShardMismatchPolicy,
+
     /// Host to bind the server to. Defaults to 0.0.0.0
     #[arg(short, long, default_value = None)]
     bind: Option<String>,
@@ -37,58 +96,703 @@ struct CliArgs {
     port: Option<u16>,
 
     /// Flushing interval (in ms). Defaults to 1000ms
-    #[arg(short, long, default_value_t = DEFAULT_FLUSHING_INTERVAL)]
+    #[arg(short, long, default_value_t = DEFAULT_FLUSHING_INTERVAL, value_parser = parse_interval)]
     flushing_interval: u64,
 
+    /// Flush every shard on its own schedule instead of all of them every flushing interval: shards with changes are checked every flushing interval, unchanged ones back off up to this interval (in ms)
+    #[arg(long, value_parser = parse_interval)]
+    shard_flush_max_interval: Option<u64>,
+
     /// Cleanup (of expired entries) interval (in ms). Defaults to 5ß0ms
-    #[arg(short, long, default_value_t = DEFAULT_CLEANUP_INTERVAL)]
+    #[arg(short, long, default_value_t = DEFAULT_CLEANUP_INTERVAL, value_parser = parse_interval)]
     cleanup_interval: u64,
+
+    /// Most expired entries a cleanup removes from each shard, so that shards with many expired entries aren't locked for long: the others are removed by the next cleanups. Unlimited by default
+    #[arg(long)]
+    eviction_batch_size: Option<usize>,
+
+    /// Respond with 410 Gone (instead of 404 Not Found) when getting a key whose TTL has elapsed
+    #[arg(long, default_value_t = false)]
+    expired_gone: bool,
+
+    /// Base URL of a peer quache instance to forward every write to (best-effort). Can be repeated
+    #[arg(long)]
+    replicate_to: Vec<String>,
+
+    /// Key sent as `Authorization: Bearer <key>` to the peers of --replicate-to and --bootstrap-from, when they require an --api-key
+    #[arg(long)]
+    peer_api_key: Option<String>,
+
+    /// Body of GET /kv/{key} responses: wrapped ({"value": ...}) or raw (the bare value). Requests can pick with ?raw=true or ?raw=false. Defaults to wrapped
+    #[arg(long, default_value = "wrapped")]
+    response_style: ResponseStyle,
+
+    /// URL to POST every change to, as {op, key, value, timestamp} (best-effort: failed deliveries are retried a few times, then dropped)
+    #[arg(long)]
+    webhook_url: Option<String>,
+
+    /// Secret sent with every webhook in the X-Quache-Webhook-Secret header, for receivers to check
+    #[arg(long, requires = "webhook_url")]
+    webhook_secret: Option<String>,
+
+    /// Seconds clients are asked to wait (via the Retry-After header) before retrying a 503 response. Defaults to 1
+    #[arg(long, default_value_t = DEFAULT_RETRY_AFTER_SECS)]
+    retry_after_secs: u64,
+
+    /// With --load, deserialize each shard on its first access instead of at startup, for faster startups. Keys duplicated across shards aren't resolved at startup: run `fsck --repair` after an interrupted reshard. Can't be combined with --s3-bucket
+    #[arg(long, default_value_t = false)]
+    lazy_load: bool,
+
+    /// Write shard files as pretty-printed JSON with sorted keys, e.g. to read them or track them with git
+    #[arg(long, default_value_t = false)]
+    pretty_disk: bool,
+
+    /// Sync every shard file to the disk (writing it to a temporary file, then renaming it) when flushing, so that flushed data survives power losses. Slows flushes down. Doesn't apply to --s3-bucket
+    #[arg(long, default_value_t = false)]
+    fsync: bool,
+
+    /// Most shards written to disk at the same time by a flush, so that flushing large stores doesn't saturate the disk. 1 writes them one after the other. Defaults to 4
+    #[arg(long, default_value_t = DEFAULT_FLUSH_CONCURRENCY)]
+    flush_concurrency: usize,
+
+    /// How shards store their entries: rwlock (one lock per shard) or dashmap (concurrent writes to different keys of a shard). Defaults to rwlock
+    #[arg(long, default_value = "rwlock")]
+    backend: ShardBackend,
+
+    /// Characters of a key echoed by error messages (in logs and responses) before it's truncated. Defaults to 128
+    #[arg(long, default_value_t = DEFAULT_MAX_KEY_ECHO)]
+    max_key_echo: usize,
+
+    /// Deepest nesting of arrays and objects accepted in written values: deeper ones get 400 Bad Request. Defaults to 64, at most 120
+    #[arg(long, default_value_t = DEFAULT_MAX_JSON_DEPTH, value_parser = parse_max_json_depth)]
+    max_json_depth: usize,
+
+    /// Restart the TTL of expiring keys every time they are read, so that only keys nobody reads expire. Reads of expiring keys then take write locks
+    #[arg(long, default_value_t = false)]
+    sliding_expiration: bool,
+
+    /// TTL (in seconds) of the keys written without one, instead of living forever. Writes can opt out with an explicit TTL of -1
+    #[arg(long, value_parser = parse_positive_secs)]
+    default_ttl_secs: Option<f64>,
+
+    /// Longest TTL (in seconds) writes can ask for, see --ttl-cap-policy
+    #[arg(long, value_parser = parse_positive_secs)]
+    max_ttl_secs: Option<f64>,
+
+    /// What writes asking for a TTL above --max-ttl-secs get: reject (400 Bad Request) or clamp (stored with the maximum TTL). Defaults to reject
+    #[arg(long, default_value = "reject")]
+    ttl_cap_policy: TtlCapPolicy,
+
+    /// Reject writes that would create entries without a TTL (after applying --default-ttl-secs)
+    #[arg(long, default_value_t = false)]
+    forbid_persistent: bool,
+
+    /// Reject writes whose TTL isn't a non-negative whole number of seconds (400 Bad Request)
+    #[arg(long, default_value_t = false)]
+    strict_ttl_seconds: bool,
+
+    /// Leave expired keys cleanup hasn't evicted yet out of listings, scans and prefix queries. Pass false to skip the TTL checks
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    probe_expired_on_scan: bool,
+
+    /// JSON file mapping key prefixes to the JSON Schema of their values, e.g. {"users:": {"type": "object"}}. Writes of values that don't match the schema of the longest prefix of their key get 422 Unprocessable Entity
+    #[cfg(feature = "schema")]
+    #[arg(long)]
+    schema_file: Option<String>,
+
+    /// Match keys case-insensitively, while still listing them with the casing they were written with
+    #[arg(long, default_value_t = false)]
+    case_insensitive_keys: bool,
+
+    /// Randomly spread TTLs by up to this percentage (0-100) so keys written together don't expire together. Defaults to 0
+    #[arg(long, default_value_t = 0_f64, value_parser = parse_jitter_percent)]
+    ttl_jitter_percent: f64,
+
+    /// Round TTLs to a multiple of this many milliseconds (and up to it if shorter), so that expiry is predictable. Defaults to 1
+    #[arg(long, default_value_t = 1)]
+    ttl_resolution_ms: u64,
+
+    /// On panic, flush the KV store to disk (best-effort) before the default panic behavior
+    #[arg(long, default_value_t = false)]
+    panic_hook: bool,
+
+    /// Don't run the background cleanup loop: expired entries are only evicted on access or via POST /admin/cleanup
+    #[arg(long, default_value_t = false)]
+    disable_cleanup: bool,
+
+    /// Respond with 429 Too Many Requests once a single key receives more than this many requests per second. Off by default
+    #[arg(long, default_value = None, value_parser = clap::value_parser!(u64).range(1..))]
+    max_request_rate_per_key: Option<u64>,
+
+    /// Also serve the gRPC interface on this port. Off by default
+    #[cfg(feature = "grpc")]
+    #[arg(long, default_value = None)]
+    grpc_port: Option<u16>,
+
+    /// Log the method and key (never the value) of every request. Logged at debug level, so combine with --verbose
+    #[arg(long, default_value_t = false)]
+    log_keys: bool,
+
+    /// Append every change of the KV store to this write-ahead log file, which replicas can --follow
+    #[arg(long, default_value = None)]
+    wal: Option<String>,
+
+    /// Run as a read-only replica applying the write-ahead log at this path or http(s) URL (polled with Range requests, so the server should support them). Client writes get 403 Forbidden
+    #[arg(long, default_value = None)]
+    follow: Option<String>,
+
+    /// How often (in ms) a replica polls the followed write-ahead log. Defaults to 1000ms
+    #[arg(long, default_value_t = DEFAULT_FOLLOW_INTERVAL, value_parser = parse_interval)]
+    follow_interval: u64,
+
+    /// Base URL of a peer quache instance to copy every entry from (via its GET /_kv/export) before serving
+    #[arg(long)]
+    bootstrap_from: Option<String>,
+
+    /// Attempts at bootstrapping from --bootstrap-from before giving up and exiting, retrying with exponential backoff. Defaults to 1
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), requires = "bootstrap_from")]
+    bootstrap_attempts: u32,
+
+    /// Most keys a single GET /kv listing returns: larger limits are capped and clients follow the returned cursor. Defaults to 1000
+    #[arg(long, default_value_t = DEFAULT_MAX_PAGE_SIZE, value_parser = parse_page_size)]
+    max_page_size: usize,
+
+    /// Directory POST /admin/restore reads backups from, given as paths relative to it. Restores are disabled without it
+    #[arg(long)]
+    backup_dir: Option<String>,
+
+    /// Consecutive failed flushes after which GET /ready answers 503 Service Unavailable. Defaults to 3
+    #[arg(long, default_value_t = DEFAULT_MAX_FLUSH_FAILURES, value_parser = clap::value_parser!(u64).range(1..))]
+    max_flush_failures: u64,
+
+    /// Seconds graceful shutdown (on Ctrl+C or SIGTERM) waits for in-flight requests before abandoning them and flushing to disk. Defaults to 30
+    #[arg(long, default_value_t = DEFAULT_SHUTDOWN_TIMEOUT_SECS)]
+    shutdown_timeout_secs: u64,
+
+    /// Seconds an HTTP/1 keep-alive connection may stay idle (waiting for its next request) before the server closes it. Unlimited by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout_secs: Option<u64>,
+
+    /// Require every request (but GET /ready) to present this key as `Authorization: Bearer <key>`. POST /admin/rotate-key replaces it at runtime
+    #[arg(long)]
+    api_key: Option<String>,
+
+    /// Seconds the previous API key is still accepted after a rotation. Defaults to 60
+    #[arg(long, default_value_t = DEFAULT_KEY_ROTATION_OVERLAP_SECS)]
+    key_rotation_overlap_secs: u64,
+
+    /// JSON file listing additional named stores (name, directory, shards, load) to serve under /store/{name}/kv. The store options given on the command line (TTL limits, schemas, backend...) apply to them too
+    #[arg(long, default_value = None)]
+    stores_config: Option<String>,
+
+    /// Serve namespaces under /ns/{namespace}/kv, created by their first write and dropped with DELETE /ns/{namespace}. Each is flushed to its own subdirectory of --directory, and loaded from it with --load. Local directories only: can't be combined with --s3-bucket
+    #[arg(long, default_value_t = false)]
+    namespaces: bool,
+
+    /// Daily UTC window (HH:MM-HH:MM) during which cleanup also compacts shards. Off by default
+    #[arg(long, default_value = None)]
+    maintenance_window: Option<MaintenanceWindow>,
+
+    /// Flush the shards to (and load them from) this S3-compatible bucket instead of the data directory
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = None)]
+    s3_bucket: Option<String>,
+
+    /// Base URL of the S3-compatible service. Defaults to https://s3.amazonaws.com
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = DEFAULT_S3_ENDPOINT)]
+    s3_endpoint: String,
+
+    /// Region of the S3 bucket. Defaults to us-east-1
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = DEFAULT_S3_REGION)]
+    s3_region: String,
+
+    /// Prefix of the shard objects in the S3 bucket. Defaults to none
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = None)]
+    s3_prefix: Option<String>,
+
+    /// Access key for the S3 bucket. Defaults to the AWS_ACCESS_KEY_ID environment variable
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = None)]
+    s3_access_key: Option<String>,
+
+    /// Secret key for the S3 bucket. Defaults to the AWS_SECRET_ACCESS_KEY environment variable
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = None)]
+    s3_secret_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Print the key-value pairs stored in a data directory as JSON, without starting the server. Local directories only: can't be combined with --s3-bucket
+    Dump {
+        /// Data directory to read the shard files from. Defaults to .quache/
+        #[arg(long, default_value = DEFAULT_DIRECTORY)]
+        dir: String,
+
+        /// Only print the keys starting with this prefix
+        #[arg(long, default_value = None)]
+        prefix: Option<String>,
+    },
+    /// Verify the shard files of a data directory (integrity hashes, duplicated and misplaced keys) and print a report as JSON. Local directories only: can't be combined with --s3-bucket
+    Fsck {
+        /// Data directory to check. Defaults to .quache/
+        #[arg(long, default_value = DEFAULT_DIRECTORY)]
+        dir: String,
+
+        /// Number of shards the data directory was written with. Defaults to 5
+        #[arg(long, default_value_t = DEFAULT_SHARD_NUMBER)]
+        shards: usize,
+
+        /// Rewrite the shard files in canonical form, moving misplaced keys to their shard
+        #[arg(long, default_value_t = false)]
+        repair: bool,
+    },
+}
+
+/// Maps the `--quiet`/`--verbose` flags to the most verbose level that gets logged.
+fn log_level(quiet: bool, verbose: bool) -> LevelFilter {
+    if quiet {
+        LevelFilter::ERROR
+    } else if verbose {
+        LevelFilter::DEBUG
+    } else {
+        LevelFilter::INFO
+    }
+}
+
+fn parse_interval(s: &str) -> Result<u64, String> {
+    let interval: u64 = s.parse().map_err(|e| format!("{}", e))?;
+    if interval < MIN_INTERVAL {
+        return Err(format!(
+            "interval must be at least {}ms, otherwise the background loop never sleeps",
+            MIN_INTERVAL
+        ));
+    }
+    Ok(interval)
+}
+
+fn parse_page_size(s: &str) -> Result<usize, String> {
+    let page_size: usize = s.parse().map_err(|e| format!("{}", e))?;
+    if page_size == 0 {
+        return Err("page size must be at least 1".to_string());
+    }
+    Ok(page_size)
+}
+
+fn parse_max_json_depth(s: &str) -> Result<usize, String> {
+    let depth: usize = s.parse().map_err(|e| format!("{}", e))?;
+    if depth > MAX_JSON_DEPTH_LIMIT {
+        return Err(format!(
+            "{} is above {}: shards holding values nested this deep couldn't be loaded back",
+            depth, MAX_JSON_DEPTH_LIMIT
+        ));
+    }
+    Ok(depth)
+}
+
+fn parse_jitter_percent(s: &str) -> Result<f64, String> {
+    let percent: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(0_f64..100_f64).contains(&percent) {
+        return Err(format!("{} is not in the [0, 100) range", percent));
+    }
+    Ok(percent)
+}
+
+fn parse_positive_secs(s: &str) -> Result<f64, String> {
+    let ttl: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !ttl.is_finite() || ttl <= 0_f64 {
+        return Err(format!("{} is not a positive number of seconds", ttl));
+    }
+    if ttl > MAX_TTL_SECS {
+        return Err(format!(
+            "{} is above the longest supported TTL of {}s",
+            ttl, MAX_TTL_SECS
+        ));
+    }
+    Ok(ttl)
+}
+
+/// Rejects the flags that only work on a local data directory when --s3-bucket is set.
+#[cfg(feature = "s3")]
+fn check_s3_flags(args: &CliArgs) -> Result<()> {
+    if args.s3_bucket.is_none() {
+        return Ok(());
+    }
+    if args.command.is_some() {
+        anyhow::bail!(
+            "dump and fsck read a local data directory (--dir), they can't be combined with --s3-bucket"
+        );
+    }
+    if args.lazy_load {
+        anyhow::bail!("--lazy-load reads local shard files, it can't be combined with --s3-bucket");
+    }
+    Ok(())
+}
+
+/// Builds the S3 flush target requested on the command line, if any.
+#[cfg(feature = "s3")]
+fn s3_target(args: &CliArgs) -> Result<Option<std::sync::Arc<dyn FlushTarget>>> {
+    let Some(bucket) = &args.s3_bucket else {
+        return Ok(None);
+    };
+    let credential = |flag: &Option<String>, var: &str| {
+        flag.clone()
+            .or_else(|| std::env::var(var).ok())
+            .ok_or_else(|| {
+                anyhow::anyhow!("missing S3 credentials: pass them as flags or set {}", var)
+            })
+    };
+    let target = S3Target::new(
+        &args.s3_endpoint,
+        bucket,
+        &args.s3_region,
+        credential(&args.s3_access_key, "AWS_ACCESS_KEY_ID")?,
+        credential(&args.s3_secret_key, "AWS_SECRET_ACCESS_KEY")?,
+    )?
+    .with_prefix(args.s3_prefix.as_deref().unwrap_or_default());
+    Ok(Some(std::sync::Arc::new(target)))
+}
+
+/// Flushes `kv_store` to disk from a separate thread, giving up after `timeout`.
+/// Returns whether the flush completed successfully in time.
+///
+/// Re-entrant calls (e.g. from a panic raised while flushing) return `false` right away.
+fn flush_before_panic(kv_store: &KVStore, timeout: Duration) -> bool {
+    if PANIC_FLUSHING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    let (tx, rx) = mpsc::channel();
+    let kv = kv_store.clone();
+    std::thread::spawn(move || {
+        let _ = tx.send(kv.to_disk());
+    });
+    let flushed = match rx.recv_timeout(timeout) {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            tracing::error!("Could not flush the KV store before panicking: {}", e);
+            false
+        }
+        Err(_) => {
+            tracing::error!("Timed out flushing the KV store before panicking");
+            false
+        }
+    };
+    PANIC_FLUSHING.store(false, Ordering::SeqCst);
+    flushed
+}
+
+/// Applies the store options of the command line to `kv_store`, be it the default store or a
+/// named one. `local_flushes` tells whether it flushes to its directory (rather than to S3).
+fn configure_store(kv_store: KVStore, args: &CliArgs, local_flushes: bool) -> Result<KVStore> {
+    let kv_store = kv_store
+        .with_case_insensitive_keys(args.case_insensitive_keys)
+        .with_pretty_disk(args.pretty_disk)
+        .with_flush_concurrency(args.flush_concurrency)
+        .with_sliding_expiration(args.sliding_expiration)
+        .with_default_ttl(args.default_ttl_secs)
+        .with_max_ttl(args.max_ttl_secs, args.ttl_cap_policy)
+        .with_forbid_persistent(args.forbid_persistent)
+        .with_strict_ttl_seconds(args.strict_ttl_seconds)
+        .with_probe_expired_on_scan(args.probe_expired_on_scan)
+        .with_eviction_batch_size(args.eviction_batch_size.unwrap_or(usize::MAX))
+        .with_max_json_depth(args.max_json_depth)
+        .with_ttl_jitter_percent(args.ttl_jitter_percent)
+        .with_ttl_resolution_ms(args.ttl_resolution_ms)
+        .with_shard_backend(args.backend)?;
+    #[cfg(feature = "schema")]
+    let kv_store = match &args.schema_file {
+        Some(path) => kv_store.with_schemas(Schemas::from_file(path)?),
+        None => kv_store,
+    };
+    let kv_store = if args.fsync && local_flushes {
+        let target = LocalTarget::new(kv_store.directory()).with_fsync(true);
+        kv_store.with_flush_target(Arc::new(target))
+    } else {
+        kv_store
+    };
+    Ok(kv_store)
+}
+
+/// Installs a panic hook that flushes `kv_stores` before running the previous hook.
+fn install_panic_hook(kv_stores: Vec<KVStore>) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        for kv_store in &kv_stores {
+            flush_before_panic(kv_store, PANIC_FLUSH_TIMEOUT);
+        }
+        default_hook(info);
+    }));
+}
+
+/// Collects the unexpired entries of every shard file in `directory`.
+///
+/// Shard files failing the integrity check are reported on stderr and skipped.
+fn dump_directory(
+    directory: &str,
+    prefix: Option<&str>,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut dump = serde_json::Map::new();
+    for i in shard_file_indices(directory)? {
+        match Shard::from_file(&shard_file_path(directory, i)) {
+            Ok(shard) => dump.extend(shard.live_entries(prefix)?),
+            Err(e) => tracing::warn!("Skipping shard {:?}: {}", i, e),
+        }
+    }
+    Ok(dump)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = CliArgs::parse();
-    let actual_dir = match args.directory {
+    tracing_subscriber::fmt()
+        .with_max_level(log_level(args.quiet, args.verbose))
+        .with_writer(std::io::stderr)
+        .init();
+    #[cfg(feature = "s3")]
+    check_s3_flags(&args)?;
+    match args.command {
+        Some(Command::Dump { dir, prefix }) => {
+            let dump = dump_directory(&dir, prefix.as_deref())?;
+            println!("{}", serde_json::to_string_pretty(&dump)?);
+            return Ok(());
+        }
+        Some(Command::Fsck {
+            dir,
+            shards,
+            repair,
+        }) => {
+            let report = fsck(&dir, shards, stored_hash_strategy(&dir)?, repair)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if report.has_unresolved_issues() {
+                anyhow::bail!("{} is inconsistent", dir);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    set_max_key_echo(args.max_key_echo);
+    if let (Some(default_ttl), Some(max_ttl)) = (args.default_ttl_secs, args.max_ttl_secs)
+        && default_ttl > max_ttl
+        && args.ttl_cap_policy == TtlCapPolicy::Reject
+    {
+        anyhow::bail!(
+            "--default-ttl-secs ({}) is above --max-ttl-secs ({}): every write without a TTL would be rejected",
+            default_ttl,
+            max_ttl
+        );
+    }
+    #[cfg(feature = "s3")]
+    let flush_target = s3_target(&args)?;
+    #[cfg(not(feature = "s3"))]
+    let flush_target = None;
+    let actual_dir = match &args.directory {
         None => DEFAULT_DIRECTORY.to_string(),
-        Some(d) => d,
+        Some(d) => d.clone(),
     };
-    let kv_store = if !args.load {
-        KVStore::new(args.shards, actual_dir)?
+    if args.load && flush_target.is_none() {
+        reconcile_shard_count(&actual_dir, args.shards, args.on_shard_mismatch)?;
+    }
+    let local_flushes = flush_target.is_none();
+    if args.namespaces && !local_flushes {
+        anyhow::bail!("--namespaces can't be combined with --s3-bucket");
+    }
+    let kv_store = match flush_target {
+        Some(target) if args.load => KVStore::new_from_target(args.shards, actual_dir, target)?,
+        Some(target) => KVStore::new(args.shards, actual_dir)?.with_flush_target(target),
+        None if args.load && args.lazy_load => {
+            KVStore::new_from_disk_lazy(args.shards, actual_dir)?
+        }
+        None if args.load => KVStore::new_from_disk(args.shards, actual_dir)?,
+        None => KVStore::new(args.shards, actual_dir)?,
+    };
+    let kv_store = configure_store(kv_store, &args, local_flushes)?;
+    if args.init_shards_on_load && args.load {
+        let created = kv_store.init_missing_shard_files()?;
+        tracing::info!("Created {} missing shard files", created);
+    }
+    if let Some(peer) = &args.bootstrap_from {
+        let copied = bootstrap_from(
+            &kv_store,
+            peer,
+            args.bootstrap_attempts,
+            args.peer_api_key.as_deref(),
+        )
+        .await?;
+        tracing::info!("Bootstrapped {} entries from {}", copied, peer);
+    }
+    let stores = match &args.stores_config {
+        Some(config_path) => open_stores(config_path, args.on_shard_mismatch, |kv_store| {
+            configure_store(kv_store, &args, true)
+        })?,
+        None => Default::default(),
+    };
+    let namespaces = if args.namespaces {
+        let namespace_args = args.clone();
+        let configure = move |kv_store| configure_store(kv_store, &namespace_args, true);
+        let root = kv_store.directory().to_string();
+        Some(if args.load {
+            Namespaces::new_from_disk(args.shards, root, args.on_shard_mismatch, configure)?
+        } else {
+            Namespaces::new(args.shards, root, configure)?
+        })
     } else {
-        KVStore::new_from_disk(args.shards, actual_dir)?
+        None
     };
-    let server = KVStoreServer::new(args.port, args.bind);
-    let mut kv_1 = kv_store.clone();
-    std::thread::spawn(move || {
-        loop {
-            std::thread::sleep(time::Duration::from_millis(args.flushing_interval));
-            let flush_result = kv_1.to_disk();
-            match flush_result {
-                Ok(_) => {}
-                Err(e) => eprintln!(
-                    "An error occurred while flushing to disk: {}",
-                    e.to_string()
-                ),
-            }
-        }
-    });
+    let mut server = KVStoreServer::new(args.port, args.bind);
+    server.stores = stores;
+    server.namespaces = namespaces.clone();
+    server.expired_gone = args.expired_gone;
+    server.replicate_to = args.replicate_to;
+    server.peer_api_key = args.peer_api_key;
+    server.retry_after_secs = args.retry_after_secs;
+    server.max_request_rate_per_key = args.max_request_rate_per_key;
+    server.log_keys = args.log_keys;
+    server.max_flush_failures = args.max_flush_failures;
+    server.max_page_size = args.max_page_size;
+    server.shutdown_timeout_secs = args.shutdown_timeout_secs;
+    server.api_key = args.api_key;
+    server.key_rotation_overlap_secs = args.key_rotation_overlap_secs;
+    server.response_style = args.response_style;
+    server.idle_timeout_secs = args.idle_timeout_secs;
+    server.backup_dir = args.backup_dir;
+    if let Some(wal_path) = &args.wal {
+        write_wal(&kv_store, wal_path)?;
+    }
+    if let Some(webhook_url) = &args.webhook_url {
+        send_webhooks(&kv_store, webhook_url, args.webhook_secret.clone())?;
+    }
+    if let Some(source) = args.follow {
+        server.read_only = true;
+        tokio::spawn(follow_wal(
+            kv_store.clone(),
+            WalFollower::new(source),
+            Duration::from_millis(args.follow_interval),
+        ));
+    }
+    #[cfg(feature = "grpc")]
+    {
+        server.grpc_port = args.grpc_port;
+    }
+    let mut all_stores = vec![kv_store.clone()];
+    all_stores.extend(server.stores.values().cloned());
+    if args.panic_hook {
+        install_panic_hook(all_stores.clone());
+    }
+    let kv_1 = all_stores.clone();
+    match args.shard_flush_max_interval {
+        Some(max_interval) => std::thread::spawn(move || {
+            shard_flush_worker(kv_1, args.flushing_interval, max_interval)
+        }),
+        None => std::thread::spawn(move || to_disk_worker(kv_1, args.flushing_interval)),
+    };
+    if let Some(namespaces) = namespaces {
+        std::thread::spawn(move || namespaces_to_disk_worker(namespaces, args.flushing_interval));
+    }
 
-    let kv_2 = kv_store.clone();
-    std::thread::spawn(move || {
-        loop {
-            std::thread::sleep(time::Duration::from_millis(args.cleanup_interval));
-            let cleanup_result = kv_2.cleanup();
-            match cleanup_result {
-                Ok(_) => {}
-                Err(e) => eprintln!(
-                    "An error occurred while cleaning up expired entries: {}",
-                    e.to_string()
-                ),
-            }
-        }
-    });
+    if !args.disable_cleanup {
+        let kv_2 = all_stores;
+        std::thread::spawn(move || {
+            cleanup_worker(kv_2, args.cleanup_interval, args.maintenance_window)
+        });
+    }
 
     server.serve(kv_store).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_directory() {
+        let kv_store = KVStore::new(3, ".quache-dump-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key"); // goes to shard-2
+        kv_store
+            .put(
+                "thisisaverylongkey".to_string(),
+                serde_json::Value::from(2),
+                None,
+            )
+            .expect("Should be able to put key"); // goes to shard-1
+        kv_store
+            .put(
+                "notthekindofthingyouwouldfind".to_string(),
+                serde_json::Value::from(3),
+                None,
+            )
+            .expect("Should be able to put key"); // goes to shard-0
+        kv_store.to_disk().expect("Should be able to flush to disk");
+
+        let dump =
+            dump_directory(".quache-dump-test/", None).expect("Should be able to dump directory");
+        assert_eq!(dump.len(), 3);
+        assert_eq!(dump.get("hey"), Some(&serde_json::Value::from(1)));
+        assert_eq!(
+            dump.get("thisisaverylongkey"),
+            Some(&serde_json::Value::from(2))
+        );
+        assert_eq!(
+            dump.get("notthekindofthingyouwouldfind"),
+            Some(&serde_json::Value::from(3))
+        );
+
+        let prefixed = dump_directory(".quache-dump-test/", Some("this"))
+            .expect("Should be able to dump directory");
+        assert_eq!(prefixed.len(), 1);
+        assert!(prefixed.contains_key("thisisaverylongkey"));
+
+        // corrupt shards are skipped instead of failing the whole dump
+        let corrupt_path = shard_file_path(".quache-dump-test/", 0);
+        let content = std::fs::read_to_string(&corrupt_path).expect("Should be able to read file");
+        std::fs::write(&corrupt_path, content.replace("3", "4"))
+            .expect("Should be able to write file");
+        let partial =
+            dump_directory(".quache-dump-test/", None).expect("Should be able to dump directory");
+        assert_eq!(partial.len(), 2);
+        assert!(!partial.contains_key("notthekindofthingyouwouldfind"));
+
+        std::fs::remove_dir_all(".quache-dump-test/")
+            .expect("Should be able to remove directory content");
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(true, false), LevelFilter::ERROR);
+        assert_eq!(log_level(false, false), LevelFilter::INFO);
+        assert_eq!(log_level(false, true), LevelFilter::DEBUG);
+        assert!(CliArgs::try_parse_from(["quache-rs", "-q", "-v"]).is_err());
+    }
+
+    #[test]
+    fn test_zero_interval_rejected() {
+        for flag in ["--flushing-interval", "--cleanup-interval"] {
+            assert!(CliArgs::try_parse_from(["quache-rs", flag, "0"]).is_err());
+            assert!(CliArgs::try_parse_from(["quache-rs", flag, "1"]).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_flush_before_panic() {
+        let kv_store = KVStore::new(3, ".quache-panic-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        assert!(flush_before_panic(&kv_store, PANIC_FLUSH_TIMEOUT));
+
+        let dump =
+            dump_directory(".quache-panic-test/", None).expect("Should be able to dump directory");
+        assert_eq!(dump.get("hey"), Some(&serde_json::Value::from(1)));
+
+        std::fs::remove_dir_all(".quache-panic-test/")
+            .expect("Should be able to remove directory content");
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Upper bounds (exclusive, in bytes) of the value size buckets; larger values go to a last bucket
+const VALUE_SIZE_BOUNDS: [usize; 4] = [256, 1024, 16 * 1024, 256 * 1024];
+/// Upper bounds (exclusive, in microseconds) of the lock wait buckets; longer waits go to a last
+/// bucket
+const LOCK_WAIT_BOUNDS_US: [u64; 4] = [10, 100, 1_000, 10_000];
+
+/// Operation counters of a KV store, shared between its clones.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Writes per value size bucket, see [`VALUE_SIZE_BOUNDS`]
+    value_sizes: [AtomicU64; VALUE_SIZE_BOUNDS.len() + 1],
+    evicted: AtomicU64,
+    /// Millisecond timestamp at which the latest cleanup finished, 0 if none did
+    last_cleanup_ms: AtomicU64,
+    /// Shared with the shards of the store, which record their lock waits in it
+    locks: Arc<LockMetrics>,
+}
+
+/// Waits for the shard locks of a KV store (`RwLock` backend only).
+#[derive(Debug, Default)]
+pub struct LockMetrics {
+    contended: AtomicU64,
+    wait_us: AtomicU64,
+    /// Contended acquisitions per wait time bucket, see [`LOCK_WAIT_BOUNDS_US`]
+    wait_times: [AtomicU64; LOCK_WAIT_BOUNDS_US.len() + 1],
+}
+
+impl LockMetrics {
+    /// Counts a lock acquisition that found the lock taken and waited `wait` for it.
+    pub fn record_contended(&self, wait: Duration) {
+        let wait_us = wait.as_micros() as u64;
+        let bucket = LOCK_WAIT_BOUNDS_US
+            .iter()
+            .position(|bound| wait_us < *bound)
+            .unwrap_or(LOCK_WAIT_BOUNDS_US.len());
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.wait_us.fetch_add(wait_us, Ordering::Relaxed);
+        self.wait_times[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn read(&self, load: impl Fn(&AtomicU64) -> u64) -> LockContention {
+        let [
+            under_10us,
+            under_100us,
+            under_1ms,
+            under_10ms,
+            at_least_10ms,
+        ] = self.wait_times.each_ref().map(&load);
+        LockContention {
+            contended: load(&self.contended),
+            total_wait_us: load(&self.wait_us),
+            wait_times: LockWaitHistogram {
+                under_10us,
+                under_100us,
+                under_1ms,
+                under_10ms,
+                at_least_10ms,
+            },
+        }
+    }
+}
+
+/// Number of contended lock acquisitions per time spent waiting for the lock.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct LockWaitHistogram {
+    pub under_10us: u64,
+    pub under_100us: u64,
+    pub under_1ms: u64,
+    pub under_10ms: u64,
+    pub at_least_10ms: u64,
+}
+
+/// How often the shard locks were already taken when an operation wanted them, and how long
+/// operations waited for them. Acquisitions that didn't wait aren't counted.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct LockContention {
+    pub contended: u64,
+    /// Time spent waiting by all contended acquisitions, in microseconds
+    pub total_wait_us: u64,
+    pub wait_times: LockWaitHistogram,
+}
+
+/// Number of values written per serialized size.
+///
+/// The counts are cumulative (since start, or since the last [`Metrics::take_snapshot`]): they
+/// track every write, not the values currently stored, so overwrites and deletes don't
+/// decrease them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ValueSizeHistogram {
+    pub under_256b: u64,
+    pub under_1k: u64,
+    pub under_16k: u64,
+    pub under_256k: u64,
+    pub at_least_256k: u64,
+}
+
+impl ValueSizeHistogram {
+    fn from_counts(counts: [u64; VALUE_SIZE_BOUNDS.len() + 1]) -> Self {
+        let [under_256b, under_1k, under_16k, under_256k, at_least_256k] = counts;
+        Self {
+            under_256b,
+            under_1k,
+            under_16k,
+            under_256k,
+            at_least_256k,
+        }
+    }
+}
+
+/// Point-in-time values of the [`Metrics`] counters.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct MetricsSnapshot {
+    pub hits: u64,
+    pub misses: u64,
+    #[serde(default)]
+    pub value_sizes: ValueSizeHistogram,
+    /// Expired entries evicted by cleanups
+    #[serde(default)]
+    pub evicted: u64,
+    /// Millisecond timestamp at which the latest cleanup finished, `None` if none did. Unlike
+    /// the counters, it isn't reset by [`Metrics::take_snapshot`].
+    #[serde(default)]
+    pub last_cleanup_ms: Option<u64>,
+    #[serde(default)]
+    pub locks: LockContention,
+}
+
+impl Metrics {
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a written value of `size` bytes in its size bucket.
+    pub fn record_value_size(&self, size: usize) {
+        let bucket = VALUE_SIZE_BOUNDS
+            .iter()
+            .position(|bound| size < *bound)
+            .unwrap_or(VALUE_SIZE_BOUNDS.len());
+        self.value_sizes[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the entries evicted by a cleanup that finished at `finished_ms`.
+    pub fn record_cleanup(&self, evicted: usize, finished_ms: u64) {
+        self.evicted.fetch_add(evicted as u64, Ordering::Relaxed);
+        self.last_cleanup_ms.store(finished_ms, Ordering::Relaxed);
+    }
+
+    /// Lock wait counters, to share with the shards of the store.
+    pub fn lock_metrics(&self) -> Arc<LockMetrics> {
+        self.locks.clone()
+    }
+
+    fn last_cleanup_ms(&self) -> Option<u64> {
+        match self.last_cleanup_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+
+    /// Reads the counters without modifying them.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            value_sizes: ValueSizeHistogram::from_counts(
+                self.value_sizes
+                    .each_ref()
+                    .map(|count| count.load(Ordering::Relaxed)),
+            ),
+            evicted: self.evicted.load(Ordering::Relaxed),
+            last_cleanup_ms: self.last_cleanup_ms(),
+            locks: self.locks.read(|count| count.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Reads the counters and resets them to zero, so that consecutive calls partition the
+    /// recorded operations between them.
+    pub fn take_snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            hits: self.hits.swap(0, Ordering::Relaxed),
+            misses: self.misses.swap(0, Ordering::Relaxed),
+            value_sizes: ValueSizeHistogram::from_counts(
+                self.value_sizes
+                    .each_ref()
+                    .map(|count| count.swap(0, Ordering::Relaxed)),
+            ),
+            evicted: self.evicted.swap(0, Ordering::Relaxed),
+            last_cleanup_ms: self.last_cleanup_ms(),
+            locks: self.locks.read(|count| count.swap(0, Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_snapshot_resets_counters() {
+        let metrics = Metrics::default();
+        metrics.record_hit();
+        metrics.record_hit();
+        metrics.record_miss();
+        assert_eq!(
+            metrics.snapshot(),
+            MetricsSnapshot {
+                hits: 2,
+                misses: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            metrics.take_snapshot(),
+            MetricsSnapshot {
+                hits: 2,
+                misses: 1,
+                ..Default::default()
+            }
+        );
+        metrics.record_miss();
+        assert_eq!(
+            metrics.take_snapshot(),
+            MetricsSnapshot {
+                hits: 0,
+                misses: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+    }
+
+    #[test]
+    fn test_value_size_histogram() {
+        let metrics = Metrics::default();
+        for size in [0, 255, 256, 1023, 1024, 16 * 1024, 256 * 1024, 1 << 20] {
+            metrics.record_value_size(size);
+        }
+        assert_eq!(
+            metrics.snapshot().value_sizes,
+            ValueSizeHistogram {
+                under_256b: 2,
+                under_1k: 2,
+                under_16k: 1,
+                under_256k: 1,
+                at_least_256k: 2,
+            }
+        );
+    }
+}
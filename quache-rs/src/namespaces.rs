@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::core::{KVError, KVStore, ShardMismatchPolicy, reconcile_shard_count};
+
+/// Applied to the store of every namespace once it's opened, e.g. to set its TTL limits.
+type Configure = Arc<dyn Fn(KVStore) -> Result<KVStore> + Send + Sync>;
+
+/// Independent stores sharing a root directory, each flushed to its own `<root>/<namespace>/`
+/// subdirectory so that a namespace's files can be backed up or dropped on their own.
+#[derive(Clone)]
+pub struct Namespaces {
+    root: String,
+    num_shards: usize,
+    configure: Configure,
+    stores: Arc<RwLock<HashMap<String, KVStore>>>,
+}
+
+impl fmt::Debug for Namespaces {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Namespaces")
+            .field("root", &self.root)
+            .field("num_shards", &self.num_shards)
+            .field("stores", &self.stores)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Namespaces are used as directory names, so they may only contain ASCII alphanumerics, `-`
+/// and `_`.
+fn validate_namespace(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(KVError::InvalidInput(format!("invalid namespace {:?}", name)).into());
+    }
+    Ok(())
+}
+
+impl Namespaces {
+    pub fn new(
+        num_shards: usize,
+        root: String,
+        configure: impl Fn(KVStore) -> Result<KVStore> + Send + Sync + 'static,
+    ) -> Result<Self> {
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            num_shards,
+            configure: Arc::new(configure),
+            stores: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    /// Loads every namespace found in `root`, i.e. every subdirectory with a valid name. Their
+    /// shard files are resharded first if needed, following `on_shard_mismatch` (see
+    /// [`reconcile_shard_count`]).
+    pub fn new_from_disk(
+        num_shards: usize,
+        root: String,
+        on_shard_mismatch: ShardMismatchPolicy,
+        configure: impl Fn(KVStore) -> Result<KVStore> + Send + Sync + 'static,
+    ) -> Result<Self> {
+        if !fs::exists(&root)? {
+            return Err(anyhow!("directory {} does not exist", &root));
+        }
+        let namespaces = Self::new(num_shards, root, configure)?;
+        let mut stores = HashMap::new();
+        for dir_entry in fs::read_dir(&namespaces.root)? {
+            let dir_entry = dir_entry?;
+            let Some(name) = dir_entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !dir_entry.file_type()?.is_dir() || validate_namespace(&name).is_err() {
+                continue;
+            }
+            tracing::info!("Loading namespace {}", name);
+            let directory = namespace_directory(&namespaces.root, &name);
+            reconcile_shard_count(&directory, num_shards, on_shard_mismatch)?;
+            let kv_store = KVStore::new_from_disk(num_shards, directory)?;
+            stores.insert(name, (namespaces.configure)(kv_store)?);
+        }
+        *namespaces
+            .stores
+            .write()
+            .map_err(|e| anyhow!(e.to_string()))? = stores;
+        Ok(namespaces)
+    }
+
+    /// Returns the store of a namespace, creating the namespace if needed.
+    pub fn get_or_create(&self, name: &str) -> Result<KVStore> {
+        if let Some(kv_store) = self.get(name)? {
+            return Ok(kv_store);
+        }
+        validate_namespace(name)?;
+        let mut stores = self.stores.write().map_err(|e| anyhow!(e.to_string()))?;
+        if let Some(kv_store) = stores.get(name) {
+            return Ok(kv_store.clone());
+        }
+        let kv_store = KVStore::new(self.num_shards, namespace_directory(&self.root, name))?;
+        let kv_store = (self.configure)(kv_store)?;
+        stores.insert(name.to_string(), kv_store.clone());
+        Ok(kv_store)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<KVStore>> {
+        let stores = self.stores.read().map_err(|e| anyhow!(e.to_string()))?;
+        Ok(stores.get(name).cloned())
+    }
+
+    /// Returns the names of the namespaces, sorted.
+    pub fn names(&self) -> Result<Vec<String>> {
+        let stores = self.stores.read().map_err(|e| anyhow!(e.to_string()))?;
+        let mut names: Vec<String> = stores.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Returns the stores of the namespaces, e.g. for the background flushing to go through.
+    pub fn stores(&self) -> Result<Vec<KVStore>> {
+        let stores = self.stores.read().map_err(|e| anyhow!(e.to_string()))?;
+        Ok(stores.values().cloned().collect())
+    }
+
+    /// Removes a namespace with its subdirectory, returning whether it existed. The flushing of
+    /// its store is paused first, so that an in-flight background flush can't write the
+    /// subdirectory back. Clones of the store obtained before keep working, but are no longer
+    /// flushed.
+    pub fn drop_namespace(&self, name: &str) -> Result<bool> {
+        let mut stores = self.stores.write().map_err(|e| anyhow!(e.to_string()))?;
+        let Some(kv_store) = stores.remove(name) else {
+            return Ok(false);
+        };
+        kv_store.pause_flushing();
+        let directory = namespace_directory(&self.root, name);
+        if fs::exists(&directory)? {
+            fs::remove_dir_all(directory)?;
+        }
+        Ok(true)
+    }
+
+    /// Flushes every namespace to its subdirectory.
+    pub fn to_disk(&self) -> Result<()> {
+        for kv_store in self.stores()? {
+            kv_store.to_disk()?;
+        }
+        Ok(())
+    }
+}
+
+/// Directory the shards of a namespace are flushed to.
+pub fn namespace_directory(root: &str, name: &str) -> String {
+    format!("{}/{}/", root.trim_end_matches('/'), name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{shard_file_indices, shard_file_path};
+
+    #[test]
+    fn test_namespaces_flush_and_drop() {
+        let root = ".quache-namespaces-test/";
+        let namespaces =
+            Namespaces::new(3, root.to_string(), Ok).expect("Should be able to create namespaces");
+        assert!(namespaces.get_or_create("../escape").is_err());
+        let sessions = namespaces.get_or_create("sessions").unwrap();
+        let jobs = namespaces.get_or_create("jobs").unwrap();
+        sessions
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .unwrap();
+        jobs.put("hey".to_string(), serde_json::Value::from(2), None)
+            .unwrap();
+        namespaces.to_disk().expect("Should be able to flush");
+
+        // each namespace gets its own file tree, the root holds no shard of its own
+        let sessions_dir = namespace_directory(root, "sessions");
+        let jobs_dir = namespace_directory(root, "jobs");
+        let shard_idx = sessions.find_shard("hey");
+        assert_eq!(shard_file_indices(&sessions_dir).unwrap(), vec![shard_idx]);
+        assert_eq!(shard_file_indices(&jobs_dir).unwrap(), vec![shard_idx]);
+        assert!(!fs::exists(shard_file_path(root, shard_idx)).unwrap());
+
+        let loaded = Namespaces::new_from_disk(3, root.to_string(), ShardMismatchPolicy::Error, Ok)
+            .expect("Should be able to load namespaces");
+        assert_eq!(loaded.names().unwrap(), vec!["jobs", "sessions"]);
+        assert_eq!(
+            loaded
+                .get("jobs")
+                .unwrap()
+                .unwrap()
+                .get("hey".to_string())
+                .unwrap(),
+            serde_json::Value::from(2)
+        );
+
+        // dropping a namespace leaves the files of the others alone
+        assert!(namespaces.drop_namespace("sessions").unwrap());
+        assert!(!namespaces.drop_namespace("sessions").unwrap());
+        assert!(!fs::exists(&sessions_dir).unwrap());
+        assert_eq!(shard_file_indices(&jobs_dir).unwrap(), vec![shard_idx]);
+        assert_eq!(namespaces.names().unwrap(), vec!["jobs"]);
+        namespaces.to_disk().expect("Should be able to flush");
+        assert!(!fs::exists(&sessions_dir).unwrap());
+
+        fs::remove_dir_all(root).expect("Should be able to remove directory content");
+    }
+}
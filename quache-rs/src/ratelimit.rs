@@ -0,0 +1,93 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const LIMITER_SHARDS: usize = 16;
+/// Minimum time between two decays of the counters of a shard
+const DECAY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct LimiterShard {
+    counters: HashMap<String, f64>,
+    last_decay: Instant,
+}
+
+/// Per-key leaky bucket: every key can burst up to `max_per_sec` requests, and its counter
+/// drains at `max_per_sec` requests per second.
+///
+/// Counters live in a sharded map, so checking a key only locks the limiter shard it hashes to.
+/// Each shard decays its counters (dropping the drained ones) when accessed, at most once every
+/// [`DECAY_INTERVAL`].
+#[derive(Debug, Clone)]
+pub struct KeyRateLimiter {
+    max_per_sec: f64,
+    shards: Arc<Vec<Mutex<LimiterShard>>>,
+}
+
+impl KeyRateLimiter {
+    pub fn new(max_per_sec: u64) -> Self {
+        let shards = (0..LIMITER_SHARDS)
+            .map(|_| {
+                Mutex::new(LimiterShard {
+                    counters: HashMap::new(),
+                    last_decay: Instant::now(),
+                })
+            })
+            .collect();
+        Self {
+            max_per_sec: max_per_sec as f64,
+            shards: Arc::new(shards),
+        }
+    }
+
+    /// Returns a limiter with the same rate but its own counters, so that keys with the same name
+    /// in different stores don't share a budget.
+    pub fn for_another_store(&self) -> Self {
+        Self::new(self.max_per_sec as u64)
+    }
+
+    /// Records a request for `key`, returning `false` if the key is over its rate.
+    pub fn check(&self, key: &str) -> bool {
+        let shard_idx = crc32fast::hash(key.as_bytes()) as usize % LIMITER_SHARDS;
+        // a poisoned lock only means another request panicked mid-update: the counters are still usable
+        let mut shard = self.shards[shard_idx]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let elapsed = shard.last_decay.elapsed();
+        if elapsed >= DECAY_INTERVAL {
+            let drained = elapsed.as_secs_f64() * self.max_per_sec;
+            shard.counters.retain(|_, count| {
+                *count -= drained;
+                *count > 0_f64
+            });
+            shard.last_decay = Instant::now();
+        }
+        let count = shard.counters.entry(key.to_string()).or_insert(0_f64);
+        if *count + 1_f64 > self.max_per_sec {
+            return false;
+        }
+        *count += 1_f64;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_rate_limiter() {
+        let limiter = KeyRateLimiter::new(5);
+        for _ in 0..5 {
+            assert!(limiter.check("hot"));
+        }
+        assert!(!limiter.check("hot"));
+        assert!(limiter.check("cold"));
+
+        // the counter drains over time
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(limiter.check("hot"));
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{self, Duration},
+};
+
+use anyhow::anyhow;
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
+use tokio::sync::mpsc;
+
+use crate::{
+    core::KVStore,
+    events::{ChangeEvent, ChangeOp},
+    server::ImportLine,
+};
+
+const BOOTSTRAP_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Forwards the writes of a store to peer quache instances on a best-effort basis.
+///
+/// Every change of the store is queued for each peer as it's applied, and each peer's queue is
+/// forwarded one request at a time: a peer receives the writes in the order they were applied,
+/// along with their sequence numbers. A failure to reach a peer is logged and counted, then the
+/// queue moves on; writes on this instance never fail (or wait) because of replication.
+#[derive(Debug, Clone)]
+pub struct Replicator {
+    peers: Vec<Url>,
+    client: Client,
+    api_key: Option<String>,
+    failures: Arc<AtomicU64>,
+}
+
+impl Replicator {
+    pub fn new(peers: Vec<String>) -> anyhow::Result<Self> {
+        let mut urls: Vec<Url> = vec![];
+        for peer in peers {
+            let url = Url::parse(&peer)?;
+            if peer_url(&url, &["kv"]).is_none() {
+                return Err(anyhow!("{} can't be a base URL", peer));
+            }
+            urls.push(url);
+        }
+        Ok(Self {
+            peers: urls,
+            client: Client::new(),
+            api_key: None,
+            failures: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Sends `api_key` as `Authorization: Bearer <key>`, for peers started with `--api-key`.
+    pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    /// Number of forwarded writes that did not succeed on a peer.
+    #[cfg(test)]
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Forwards the changes `kv_store` goes through from now on to the peers. Expirations are
+    /// left out: peers evict the entries whose expiry they were sent on their own. Must be called
+    /// from within a Tokio runtime.
+    pub fn start(&self, kv_store: &KVStore) {
+        let mut queues = vec![];
+        for peer in &self.peers {
+            let (queue, events) = mpsc::unbounded_channel();
+            tokio::spawn(self.clone().forward(peer.clone(), events));
+            queues.push(queue);
+        }
+        // listeners run while the key is locked: queues get the writes of a key in order
+        kv_store.on_change(move |event| {
+            if event.op == ChangeOp::Expire {
+                return;
+            }
+            for queue in &queues {
+                let _ = queue.send(event.clone());
+            }
+        });
+    }
+
+    async fn forward(self, peer: Url, mut events: mpsc::UnboundedReceiver<ChangeEvent>) {
+        while let Some(event) = events.recv().await {
+            let Some(request) = self.request(&peer, &event) else {
+                continue;
+            };
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {}
+                // the peer already holds a write at least as recent
+                Ok(response) if response.status() == StatusCode::CONFLICT => {}
+                Ok(response) => {
+                    self.record_failure(&peer, &format!("status {}", response.status()))
+                }
+                Err(e) => self.record_failure(&peer, &e.to_string()),
+            }
+        }
+    }
+
+    /// Request replaying `event` on `peer`, or `None` if the value expired in the meantime.
+    fn request(&self, peer: &Url, event: &ChangeEvent) -> Option<RequestBuilder> {
+        let request = match event.op {
+            ChangeOp::Put => {
+                let ttl = match event.expires_at_ms {
+                    None => None,
+                    Some(expires_at) => {
+                        let now = time::SystemTime::now()
+                            .duration_since(time::UNIX_EPOCH)
+                            .expect("Time went backwards")
+                            .as_millis() as u64;
+                        if expires_at <= now {
+                            return None;
+                        }
+                        Some((expires_at - now) as f64 / 1000_f64)
+                    }
+                };
+                let body = serde_json::json!({
+                    "key": event.key,
+                    "value": event.value,
+                    "ttl": ttl,
+                    "seq": event.seq,
+                });
+                self.client
+                    .post(peer_url(peer, &["kv"]).expect("checked by Replicator::new"))
+                    .json(&body)
+            }
+            ChangeOp::Delete => self
+                .client
+                .delete(peer_url(peer, &["kv", &event.key]).expect("checked by Replicator::new")),
+            ChangeOp::Expire => return None,
+        };
+        Some(match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        })
+    }
+
+    fn record_failure(&self, peer: &Url, reason: &str) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!(
+            "Failed to replicate write to {}: {} ({} replication failures so far)",
+            peer,
+            reason,
+            failures
+        );
+    }
+}
+
+/// Copies every live entry of the peer quache instance at `peer` (a base URL) into `kv_store`,
+/// from the peer's `GET /_kv/export`, and returns the number of copied entries.
+///
+/// `api_key`, if any, is sent as `Authorization: Bearer <key>`. Entries keep their remaining
+/// TTL, and are copied as they are (see [`KVStore::put_copy`]): the write policies of
+/// `kv_store` don't apply. A failed attempt (unreachable peer, error status, export
+/// interrupted mid-way) is retried with exponential backoff, up to `attempts` attempts in all;
+/// entries copied by a failed attempt are left in the store and overwritten by the next one.
+pub async fn bootstrap_from(
+    kv_store: &KVStore,
+    peer: &str,
+    attempts: u32,
+    api_key: Option<&str>,
+) -> anyhow::Result<usize> {
+    let url = peer_url(&Url::parse(peer)?, &["_kv", "export"])
+        .ok_or_else(|| anyhow!("{} can't be a base URL", peer))?;
+    let client = Client::new();
+    let mut backoff = BOOTSTRAP_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match copy_export(&client, url.clone(), api_key, kv_store).await {
+            Ok(copied) => return Ok(copied),
+            Err(e) if attempt >= attempts => {
+                return Err(e.context(format!("bootstrap from {} failed", peer)));
+            }
+            Err(e) => tracing::warn!(
+                "Bootstrap attempt {} from {} failed, retrying: {}",
+                attempt,
+                peer,
+                e
+            ),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+async fn copy_export(
+    client: &Client,
+    url: Url,
+    api_key: Option<&str>,
+    kv_store: &KVStore,
+) -> anyhow::Result<usize> {
+    let mut request = client.get(url);
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let mut response = request.send().await?.error_for_status()?;
+    let mut buffer: Vec<u8> = vec![];
+    let mut copied = 0;
+    let mut copy = |line: &[u8]| -> anyhow::Result<()> {
+        if !line.trim_ascii().is_empty() {
+            let entry: ImportLine = serde_json::from_slice(line)?;
+            let expires_at_ms = entry.ttl.map(|ttl| {
+                let now = time::SystemTime::now()
+                    .duration_since(time::UNIX_EPOCH)
+                    .expect("Time went backwards");
+                (now.as_secs_f64() * 1000_f64 + ttl * 1000_f64).ceil() as u64
+            });
+            kv_store.put_copy(entry.key, entry.value, expires_at_ms)?;
+            copied += 1;
+        }
+        Ok(())
+    };
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        let mut start = 0;
+        while let Some(end) = buffer[start..].iter().position(|b| *b == b'\n') {
+            copy(&buffer[start..start + end])?;
+            start += end + 1;
+        }
+        buffer.drain(..start);
+    }
+    // every exported line ends with a newline: anything left means the export was cut short
+    if !buffer.is_empty() {
+        return Err(anyhow!("export ended in the middle of a line"));
+    }
+    Ok(copied)
+}
+
+/// Appends (URL-encoded) path segments to a peer's base URL.
+fn peer_url(peer: &Url, segments: &[&str]) -> Option<Url> {
+    let mut url = peer.clone();
+    url.path_segments_mut()
+        .ok()?
+        .pop_if_empty()
+        .extend(segments);
+    Some(url)
+}
@@ -0,0 +1,242 @@
+use std::time;
+
+use anyhow::{Result, anyhow};
+use hmac_sha256::{HMAC, Hash};
+
+use crate::flush::FlushTarget;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Flushes each shard to its own object in an S3-compatible bucket.
+///
+/// Requests are addressed path-style (`<endpoint>/<bucket>/<key>`), which every S3-compatible
+/// service supports, and signed with AWS Signature Version 4.
+#[derive(Debug, Clone)]
+pub struct S3Target {
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    prefix: String,
+    agent: ureq::Agent,
+}
+
+impl S3Target {
+    /// `endpoint` is the base URL of the service (e.g. `https://s3.eu-west-1.amazonaws.com`).
+    pub fn new(
+        endpoint: &str,
+        bucket: impl Into<String>,
+        region: impl Into<String>,
+        access_key: impl Into<String>,
+        secret_key: impl Into<String>,
+    ) -> Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .strip_prefix("https://")
+            .or_else(|| endpoint.strip_prefix("http://"))
+            .filter(|host| !host.is_empty() && !host.contains('/'))
+            .ok_or_else(|| {
+                anyhow!(
+                    "S3 endpoint {} must be an http(s) URL without a path",
+                    endpoint
+                )
+            })?
+            .to_string();
+        Ok(Self {
+            endpoint,
+            host,
+            bucket: bucket.into(),
+            region: region.into(),
+            access_key: access_key.into(),
+            secret_key: secret_key.into(),
+            prefix: String::new(),
+            agent: ureq::Agent::new_with_defaults(),
+        })
+    }
+
+    /// Stores the shard objects under `prefix` (e.g. `quache/`), so that several stores can
+    /// share a bucket.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        let prefix = prefix.trim_matches('/');
+        self.prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", prefix)
+        };
+        self
+    }
+
+    fn object_path(&self, shard_idx: usize) -> String {
+        format!(
+            "/{}/{}",
+            uri_encode(&self.bucket),
+            uri_encode(&format!("{}shard-{}", self.prefix, shard_idx))
+        )
+    }
+
+    /// Returns the `x-amz-date`, `x-amz-content-sha256` and `authorization` headers of a request.
+    fn sign(&self, method: &str, path: &str, payload: &[u8], now_secs: u64) -> [(&str, String); 3] {
+        let (date, amz_date) = amz_timestamps(now_secs);
+        let payload_hash = hex(&Hash::hash(payload));
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, self.host, payload_hash, amz_date, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Hash::hash(canonical_request.as_bytes()))
+        );
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            HMAC::mac(date.as_bytes(), format!("AWS4{}", self.secret_key)),
+            |key, part| HMAC::mac(part.as_bytes(), key),
+        );
+        let signature = hex(&HMAC::mac(string_to_sign.as_bytes(), signing_key));
+        [
+            ("x-amz-date", amz_date),
+            ("x-amz-content-sha256", payload_hash),
+            (
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key, scope, SIGNED_HEADERS, signature
+                ),
+            ),
+        ]
+    }
+}
+
+impl FlushTarget for S3Target {
+    fn write_shard(&self, shard_idx: usize, contents: &str) -> Result<()> {
+        let path = self.object_path(shard_idx);
+        let mut request = self.agent.put(format!("{}{}", self.endpoint, path));
+        for (name, value) in self.sign("PUT", &path, contents.as_bytes(), current_secs()) {
+            request = request.header(name, value);
+        }
+        request.send(contents)?;
+        Ok(())
+    }
+
+    fn read_shard(&self, shard_idx: usize) -> Result<Option<String>> {
+        let path = self.object_path(shard_idx);
+        let mut request = self.agent.get(format!("{}{}", self.endpoint, path));
+        for (name, value) in self.sign("GET", &path, b"", current_secs()) {
+            request = request.header(name, value);
+        }
+        match request.call() {
+            Ok(mut response) => Ok(Some(response.body_mut().read_to_string()?)),
+            Err(ureq::Error::StatusCode(404)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+fn current_secs() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encodes an object path segment as SigV4 expects, keeping `/` separators.
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Formats a UNIX timestamp as the `YYYYMMDD` and `YYYYMMDDTHHMMSSZ` strings used by SigV4.
+fn amz_timestamps(secs: u64) -> (String, String) {
+    // days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / SECONDS_PER_DAY) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let secs_of_day = secs % SECONDS_PER_DAY;
+    let amz_date = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    );
+    (date, amz_date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amz_timestamps() {
+        assert_eq!(
+            amz_timestamps(0),
+            ("19700101".to_string(), "19700101T000000Z".to_string())
+        );
+        assert_eq!(
+            amz_timestamps(1_700_000_000),
+            ("20231114".to_string(), "20231114T221320Z".to_string())
+        );
+        assert_eq!(
+            amz_timestamps(951_782_400),
+            ("20000229".to_string(), "20000229T000000Z".to_string())
+        );
+    }
+
+    #[test]
+    fn test_s3_target_addressing() {
+        let target = S3Target::new("http://localhost:9000/", "bucket", "us-east-1", "ak", "sk")
+            .expect("Should be able to create target")
+            .with_prefix("/stores/main/");
+        assert_eq!(target.host, "localhost:9000");
+        assert_eq!(target.object_path(3), "/bucket/stores/main/shard-3");
+        assert!(S3Target::new("localhost:9000", "bucket", "us-east-1", "ak", "sk").is_err());
+        assert!(
+            S3Target::new(
+                "https://example.com/path",
+                "bucket",
+                "us-east-1",
+                "ak",
+                "sk"
+            )
+            .is_err()
+        );
+
+        let headers = target.sign("GET", "/bucket/stores/main/shard-3", b"", 0);
+        assert_eq!(headers[0].1, "19700101T000000Z");
+        assert!(
+            headers[2]
+                .1
+                .starts_with("AWS4-HMAC-SHA256 Credential=ak/19700101/us-east-1/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature=")
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::{fmt, fs};
+
+use anyhow::{Result, anyhow};
+use jsonschema::Validator;
+
+use crate::core::KVError;
+
+/// JSON Schemas the values written under some key prefixes must match.
+///
+/// A key is checked against the schema of the longest prefix it starts with; keys matching no
+/// prefix aren't checked.
+pub struct Schemas {
+    /// Sorted by decreasing prefix length, so that the first match is the longest
+    by_prefix: Vec<(String, Validator)>,
+}
+
+impl fmt::Debug for Schemas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Schemas")
+            .field(
+                "prefixes",
+                &self.by_prefix.iter().map(|(p, _)| p).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Schemas {
+    /// Compiles a JSON object mapping key prefixes to their schema, e.g.
+    /// `{"users:": {"type": "object", "required": ["name"]}}`.
+    pub fn from_value(config: &serde_json::Value) -> Result<Self> {
+        let serde_json::Value::Object(schemas) = config else {
+            return Err(anyhow!(
+                "schemas should be a JSON object mapping key prefixes to their schema"
+            ));
+        };
+        let mut by_prefix = schemas
+            .iter()
+            .map(|(prefix, schema)| {
+                jsonschema::validator_for(schema)
+                    .map(|validator| (prefix.clone(), validator))
+                    .map_err(|e| anyhow!("invalid schema for prefix {:?}: {}", prefix, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        by_prefix.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Ok(Self { by_prefix })
+    }
+
+    /// Same as [`Schemas::from_value`], reading the JSON object from the file at `path`.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let config: serde_json::Value = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("{} is not valid JSON: {}", path, e))?;
+        Self::from_value(&config)
+    }
+
+    /// Checks `value` against the schema of `key`'s prefix, failing with
+    /// [`KVError::SchemaViolation`] listing every mismatch.
+    pub fn validate(&self, key: &str, value: &serde_json::Value) -> Result<()> {
+        let Some((prefix, validator)) = self
+            .by_prefix
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+        else {
+            return Ok(());
+        };
+        let errors: Vec<String> = validator
+            .iter_errors(value)
+            .map(|e| match e.instance_path().as_str() {
+                "" => format!("at the root: {}", e),
+                path => format!("at {}: {}", path, e),
+            })
+            .collect();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(KVError::SchemaViolation(format!(
+            "value doesn't match the schema of prefix {:?}: {}",
+            prefix,
+            errors.join("; ")
+        ))
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schemas_pick_longest_prefix() {
+        let schemas = Schemas::from_value(&serde_json::json!({
+            "users:": {"type": "object"},
+            "users:admin:": {"type": "object", "required": ["role"]},
+        }))
+        .expect("Should be able to compile schemas");
+        assert!(schemas.validate("users:1", &serde_json::json!({})).is_ok());
+        assert!(schemas.validate("users:1", &serde_json::json!(1)).is_err());
+        assert!(
+            schemas
+                .validate("users:admin:1", &serde_json::json!({}))
+                .is_err()
+        );
+        assert!(
+            schemas
+                .validate("users:admin:1", &serde_json::json!({"role": "owner"}))
+                .is_ok()
+        );
+        assert!(schemas.validate("orders:1", &serde_json::json!(1)).is_ok());
+
+        assert!(Schemas::from_value(&serde_json::json!({"users:": {"type": 1}})).is_err());
+        assert!(Schemas::from_value(&serde_json::json!([])).is_err());
+    }
+}
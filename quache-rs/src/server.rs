@@ -1,35 +1,126 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{self, Component},
     str::FromStr,
+    time::Duration,
 };
 
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    http::StatusCode,
+    body::Body,
+    extract::{
+        ConnectInfo, OriginalUri, Path, Query, Request, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post, put},
 };
+use futures_util::StreamExt;
+use hyper::{
+    body::Incoming,
+    service::{Service as _, service_fn},
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
-use crate::core::KVStore;
+use crate::{
+    auth::ApiKeys,
+    core::{
+        BatchPut, BatchPutStatus, CleanupStatus, FlushProgress, FlushStatus, KVError, KVStore,
+        RebalancePlan, ShardEntry, ShardMismatchPolicy, TxOp, UpsertOutcome, echo_key,
+        reconcile_shard_count,
+    },
+    events::{ChangeEvent, glob_matches},
+    metrics::MetricsSnapshot,
+    namespaces::Namespaces,
+    ratelimit::KeyRateLimiter,
+    replication::Replicator,
+};
 
 const DEFAULT_PORT: u16 = 8000;
 const DEFAULT_HOST: &str = "0.0.0.0";
+pub const DEFAULT_RETRY_AFTER_SECS: u64 = 1;
+/// Number of change events buffered for subscribers before the slowest ones lag behind
+const EVENTS_CAPACITY: usize = 1024;
+/// Most keys a single listing returns, whatever the client asks for
+pub const DEFAULT_MAX_PAGE_SIZE: usize = 1000;
+/// Consecutive failed flushes after which `/ready` reports the instance as not ready
+pub const DEFAULT_MAX_FLUSH_FAILURES: u64 = 3;
+/// Seconds in-flight requests get to complete once shutdown starts
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+/// Seconds the previous API key keeps working after a rotation
+pub const DEFAULT_KEY_ROTATION_OVERLAP_SECS: u64 = 60;
+/// How long (in ms) a consumed key can still be read, unless asked otherwise
+const DEFAULT_CONSUME_GRACE_MS: u64 = 5000;
+/// Keys of `GET /_kv/prefix/{prefix}/stream` read at once
+const PREFIX_STREAM_CHUNK: usize = 100;
+/// Header carrying the cursor of the next page of a streamed response
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+/// Shape of the body of `GET /kv/{key}` responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ResponseStyle {
+    /// `{"value": ...}`
+    #[default]
+    Wrapped,
+    /// The value itself
+    Raw,
+}
+
+impl FromStr for ResponseStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "wrapped" => Ok(Self::Wrapped),
+            "raw" => Ok(Self::Raw),
+            _ => Err(anyhow::anyhow!(
+                "{} is not a response style (wrapped, raw)",
+                s
+            )),
+        }
+    }
+}
 
 struct AppError(anyhow::Error);
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let code: StatusCode = if self.0.to_string().contains("not found") {
-            StatusCode::NOT_FOUND
-        } else {
-            StatusCode::INTERNAL_SERVER_ERROR
+        let code: StatusCode = match self.0.downcast_ref::<KVError>() {
+            Some(KVError::NotFound(_)) | Some(KVError::Expired(_)) => StatusCode::NOT_FOUND,
+            Some(KVError::Conflict(_)) => StatusCode::CONFLICT,
+            Some(KVError::InvalidInput(_)) => StatusCode::BAD_REQUEST,
+            Some(KVError::SchemaViolation(_)) => StatusCode::UNPROCESSABLE_ENTITY,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
         };
-        (code, format!("Error: {}", self.0)).into_response()
+        error_response(code, self.0)
     }
 }
 
+/// Plain-text error response, the message prefixed with `Error: `.
+fn error_response(code: StatusCode, message: impl std::fmt::Display) -> Response {
+    (
+        code,
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        format!("Error: {}", message),
+    )
+        .into_response()
+}
+
+/// JSON response, for handlers that pick their status code or add headers themselves.
+fn json_response<T: Serialize>(code: StatusCode, body: T) -> Response {
+    (code, Json(body)).into_response()
+}
+
 impl<E: Into<anyhow::Error>> From<E> for AppError {
     fn from(e: E) -> Self {
         Self(e.into())
@@ -39,6 +130,117 @@ impl<E: Into<anyhow::Error>> From<E> for AppError {
 #[derive(Clone, Debug)]
 struct AppState {
     kv_store: KVStore,
+    expired_gone: bool,
+    retry_after_secs: u64,
+    /// Additional named stores, served under `/store/{name}/kv`
+    stores: HashMap<String, KVStore>,
+    /// Namespaces served under `/ns/{namespace}/kv`, when enabled
+    namespaces: Option<Namespaces>,
+    rate_limiter: Option<KeyRateLimiter>,
+    /// Mutations of `kv_store`, fanned out to the subscribers
+    events: broadcast::Sender<ChangeEvent>,
+    log_keys: bool,
+    /// Rejects client writes, e.g. on replicas following a primary's WAL
+    read_only: bool,
+    max_flush_failures: u64,
+    max_page_size: usize,
+    /// Keys requests must present, when authentication is enabled
+    api_keys: Option<ApiKeys>,
+    /// Default shape of the values returned by `GET /kv/{key}`, overridden by `?raw=`
+    response_style: ResponseStyle,
+    /// Directory `/admin/restore` reads backups from, restores are disabled without it
+    backup_dir: Option<String>,
+}
+
+impl AppState {
+    fn new(kv_store: KVStore) -> Self {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let sender = events.clone();
+        kv_store.on_change(move |event| {
+            // sending only fails when nobody is subscribed
+            let _ = sender.send(event.clone());
+        });
+        Self {
+            kv_store,
+            expired_gone: false,
+            retry_after_secs: DEFAULT_RETRY_AFTER_SECS,
+            stores: HashMap::new(),
+            namespaces: None,
+            rate_limiter: None,
+            events,
+            log_keys: false,
+            read_only: false,
+            max_flush_failures: DEFAULT_MAX_FLUSH_FAILURES,
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+            api_keys: None,
+            response_style: ResponseStyle::default(),
+            backup_dir: None,
+        }
+    }
+}
+
+/// A named store listed in the stores config file.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct StoreConfig {
+    pub name: String,
+    pub directory: String,
+    pub shards: usize,
+    /// Load the store from its directory instead of starting empty
+    #[serde(default)]
+    pub load: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct StoresConfig {
+    stores: Vec<StoreConfig>,
+}
+
+/// Opens the stores listed in a JSON config file of the form
+/// `{"stores": [{"name": "sessions", "directory": ".quache-sessions/", "shards": 5}]}`.
+///
+/// Store names are used as path segments, so they may only contain ASCII alphanumerics, `-` and
+/// `_`. Names and directories must be unique.
+///
+/// Every store goes through `configure` once opened, so that the options of the default store
+/// (TTL limits, schemas, key normalization...) apply to them too. Stores loaded from disk are
+/// resharded first if needed, following `on_shard_mismatch` (see [`reconcile_shard_count`]).
+pub fn open_stores(
+    config_path: &str,
+    on_shard_mismatch: ShardMismatchPolicy,
+    configure: impl Fn(KVStore) -> anyhow::Result<KVStore>,
+) -> anyhow::Result<HashMap<String, KVStore>> {
+    let config: StoresConfig = serde_json::from_str(&std::fs::read_to_string(config_path)?)?;
+    let mut stores = HashMap::new();
+    let mut directories = vec![];
+    for store in config.stores {
+        if store.name.is_empty()
+            || !store
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow::anyhow!("invalid store name {:?}", store.name));
+        }
+        if stores.contains_key(&store.name) {
+            return Err(anyhow::anyhow!("store {} is configured twice", store.name));
+        }
+        if directories.contains(&store.directory) {
+            return Err(anyhow::anyhow!(
+                "directory {} is used by more than one store",
+                store.directory
+            ));
+        }
+        let kv_store = if store.load {
+            reconcile_shard_count(&store.directory, store.shards, on_shard_mismatch)?;
+            KVStore::new_from_disk(store.shards, store.directory.clone())?
+        } else {
+            KVStore::new(store.shards, store.directory.clone())?
+        };
+        let kv_store = configure(kv_store)?;
+        directories.push(store.directory);
+        stores.insert(store.name, kv_store);
+    }
+    Ok(stores)
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -46,116 +248,4855 @@ struct GetResponse {
     value: serde_json::Value,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+struct GetQuery {
+    /// Return the bare value (or wrap it, if `false`) whatever the server's response style
+    raw: Option<bool>,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct PutRequest {
     key: String,
     value: serde_json::Value,
     ttl: Option<f64>,
+    /// Optional per-key sequence number: the write is applied only if it exceeds the stored one
+    #[serde(default)]
+    seq: Option<u64>,
+}
+
+/// A line of an NDJSON import or export
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct ImportLine {
+    pub(crate) key: String,
+    pub(crate) value: serde_json::Value,
+    /// Seconds left to live, `None` for entries without a TTL
+    pub(crate) ttl: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ImportLineError {
+    /// 1-based line number in the uploaded body
+    line: usize,
+    error: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ImportResponse {
+    imported: usize,
+    errors: Vec<ImportLineError>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct PutQuery {
+    /// Skip the write if it would bring the expiry of a live key forward
+    #[serde(default)]
+    only_extend: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct PutValueRequest {
+    value: serde_json::Value,
+    ttl: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct PutKeyQuery {
+    /// Only store the value if the key is missing, returning the stored value either way
+    #[serde(default)]
+    get_or_insert: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct PutTextQuery {
+    ttl: Option<f64>,
+}
+
+fn default_delta() -> i64 {
+    1
+}
+
+/// Deserializes a present field into `Some`, even when `null`, so that `null` can be told
+/// apart from a missing field.
+fn deserialize_some<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<serde_json::Value>, D::Error> {
+    serde_json::Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct UpsertRequest {
+    value: serde_json::Value,
+    /// TTL of the created entry, or new TTL of the updated one (which keeps its expiry otherwise)
+    ttl: Option<f64>,
+    /// Only update the key if its live value equals this one (`null` included)
+    #[serde(default, deserialize_with = "deserialize_some")]
+    if_match: Option<serde_json::Value>,
+    /// Create the key if it's missing, don't update it (unless `if_match` matches)
+    #[serde(default)]
+    nx: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct UpsertResponse {
+    outcome: UpsertOutcome,
+    /// Live value of the key after the upsert, `null` if it's missing
+    value: Option<serde_json::Value>,
+    /// Seconds the key has left to live, `null` for persistent or missing keys
+    ttl: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BatchPutRequest {
+    /// Applied in order; `if_match` may be given as the `ETag` of a read
+    items: Vec<BatchPut>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct BatchPutResult {
+    key: String,
+    status: BatchPutStatus,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BatchPutResponse {
+    /// Number of items applied
+    written: usize,
+    /// Outcome of every item, in request order
+    results: Vec<BatchPutResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct CasRequest {
+    key: String,
+    expected: serde_json::Value,
+    value: serde_json::Value,
+    /// New TTL to apply if the swap succeeds; the current one is kept when omitted
+    ttl: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct IncrRequest {
+    #[serde(default = "default_delta")]
+    delta: i64,
+    min: Option<i64>,
+    max: Option<i64>,
+    /// TTL given to the counter when the increment creates it; later increments keep it
+    window_ttl: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct IncrResponse {
+    value: i64,
+    clamped: bool,
+}
+
+/// Inclusive list range, negative indices counting from the end
+#[derive(Deserialize, Serialize, Debug)]
+struct ListRange {
+    start: i64,
+    stop: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ReadyResponse {
+    ready: bool,
+    /// Flush status of the default store
+    flush: FlushStatus,
+    /// Names of the stores (`default` for the default one) whose flushes keep failing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    failing_stores: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct InfoResponse {
+    directory: String,
+    shards: usize,
+    read_only: bool,
+    flush: FlushStatus,
+    cleanup: CleanupStatus,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ZaddRequest {
+    /// Scores by member
+    members: HashMap<String, f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct TtlResponse {
+    /// Seconds left to live, `null` for keys without a TTL
+    ttl: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct LenResponse {
+    len: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ZaddResponse {
+    added: usize,
+}
+
+/// Inclusive score range, unbounded on the omitted sides
+#[derive(Deserialize, Serialize, Debug)]
+struct ScoreRange {
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct ScoredMember {
+    member: String,
+    score: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ZrangeResponse {
+    members: Vec<ScoredMember>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct RandomEntryResponse {
+    key: String,
+    value: serde_json::Value,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct DrainRequest {
+    prefix: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct DrainResponse {
+    entries: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct TxRequest {
+    ops: Vec<TxOp>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ExpireRequest {
+    /// New TTL in seconds, counted from now; `null` (or omitted) makes the key persistent, and
+    /// a non-positive one (but -1) deletes it
+    #[serde(default)]
+    ttl: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct RenameRequest {
+    to: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct RenameQuery {
+    /// Fail with `409` instead of replacing a live destination
+    #[serde(default)]
+    nx: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ConsumeQuery {
+    #[serde(default = "default_consume_grace_ms")]
+    grace_ms: u64,
+}
+
+fn default_consume_grace_ms() -> u64 {
+    DEFAULT_CONSUME_GRACE_MS
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ListKeysQuery {
+    prefix: Option<String>,
+    /// Maximum number of keys to return, capped to the server's maximum page size
+    limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+    /// Page through the shards one after the other instead of through all keys in lexicographic
+    /// order, so that a page only scans the shards it needs
+    #[serde(default)]
+    by_shard: bool,
+    /// Number of keys to skip, paging by position (see [`KVStore::scan`]) instead of by
+    /// `cursor`: `next_offset` of the previous page
+    offset: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ListKeysResponse {
+    keys: Vec<String>,
+    /// Pass as `cursor` to get the next page, omitted on the last page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    /// Pass as `offset` to get the next page when paging by `offset`, omitted on the last page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_offset: Option<usize>,
+}
+
+/// Item of the JSON array streamed by `GET /_kv/prefix/{prefix}/stream`.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct PrefixEntry {
+    key: String,
+    value: serde_json::Value,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct RotateKeyRequest {
+    key: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ContainsQuery {
+    /// JSON pointer to the array to look into
+    path: String,
+    /// JSON-encoded element to look for
+    value: String,
+    /// Maximum number of keys to return, capped to the server's maximum page size
+    limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct QueryResponse {
+    keys: Vec<String>,
+    /// Pass as `cursor` to get the next page, omitted on the last page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct PrefixStreamQuery {
+    /// Maximum number of entries to stream, capped to the server's maximum page size
+    limit: Option<usize>,
+    /// Cursor of the previous page, from its `x-next-cursor` header
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BatchTtlRequest {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BatchTtlResponse {
+    /// Seconds left to live of the stored keys, `null` for the ones without a TTL
+    ttls: HashMap<String, Option<f64>>,
+    /// Requested keys that are missing or expired, in request order
+    missing: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct MgetRequest {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct MgetResponse {
+    /// Value of each requested key, `null` for the ones that are missing or expired
+    results: HashMap<String, Option<serde_json::Value>>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct RebalanceQuery {
+    shards: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct SubscribeQuery {
+    /// Glob matched against the keys, e.g. `orders:*`
+    pattern: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct LagNotice {
+    /// Number of events the subscriber missed before being disconnected
+    lagged: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct ShardSize {
+    shard: usize,
+    bytes: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct FlushDryRunResponse {
+    /// Size of every shard as a flush would write it
+    shards: Vec<ShardSize>,
+    total_bytes: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct CleanupResponse {
+    evicted: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct RestoreRequest {
+    /// Directory holding the shard files to restore (e.g. a backup of the store directory),
+    /// relative to the backup directory
+    directory: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct RestoreResponse {
+    restored: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct LocateResponse {
+    key: String,
+    shard: usize,
+    exists: bool,
+}
+
+/// A stored entry as-is, along with fields derived from it.
+#[derive(Deserialize, Serialize, Debug)]
+struct EntryResponse {
+    entry: ShardEntry,
+    /// Milliseconds the entry has left to live, `None` for persistent entries
+    remaining_ms: Option<u128>,
+    expired: bool,
+}
+
+pub struct KVStoreServer {
+    pub host: IpAddr,
+    pub port: u16,
+    /// Answer GET requests for keys whose TTL elapsed with `410 Gone` instead of `404 Not Found`
+    pub expired_gone: bool,
+    /// Base URLs of peer quache instances every write is forwarded to (best-effort)
+    pub replicate_to: Vec<String>,
+    /// Key sent as `Authorization: Bearer <key>` with the writes forwarded to peers
+    pub peer_api_key: Option<String>,
+    /// Seconds clients are asked to wait (via `Retry-After`) before retrying a `503` response
+    pub retry_after_secs: u64,
+    /// Additional named stores, served under `/store/{name}/kv`
+    pub stores: HashMap<String, KVStore>,
+    /// Namespaces served under `/ns/{namespace}/kv`, each flushed to its own subdirectory
+    pub namespaces: Option<Namespaces>,
+    /// Requests per second a single key can receive before getting `429 Too Many Requests`
+    pub max_request_rate_per_key: Option<u64>,
+    /// Port to serve the gRPC interface on (same host), alongside HTTP
+    #[cfg(feature = "grpc")]
+    pub grpc_port: Option<u16>,
+    /// Log the operation and key (never the value) of every request, at debug level
+    pub log_keys: bool,
+    /// Reject client writes with `403 Forbidden` (e.g. on replicas following a WAL)
+    pub read_only: bool,
+    /// Consecutive failed flushes of any store after which `/ready` answers `503`
+    pub max_flush_failures: u64,
+    /// Most keys a single listing returns: larger `limit`s are capped, clients follow the cursor
+    pub max_page_size: usize,
+    /// Seconds graceful shutdown waits for in-flight requests before abandoning them
+    pub shutdown_timeout_secs: u64,
+    /// Key every request (but `/ready`) must present as `Authorization: Bearer <key>`
+    pub api_key: Option<String>,
+    /// Seconds the previous key is still accepted after `/admin/rotate-key`
+    pub key_rotation_overlap_secs: u64,
+    /// Whether `GET /kv/{key}` wraps values in `{"value": ...}` unless requests ask otherwise
+    pub response_style: ResponseStyle,
+    /// Seconds a keep-alive connection may wait for its next request before it's closed
+    pub idle_timeout_secs: Option<u64>,
+    /// Directory `/admin/restore` reads backups from (by relative path), restores are disabled
+    /// without it
+    pub backup_dir: Option<String>,
+}
+
+/// Logs an operation on `key` for debugging. Values must never be passed here, as they may be
+/// sensitive.
+pub(crate) fn log_key(operation: &str, key: &str) {
+    tracing::debug!("{} {}", operation, key);
+}
+
+/// Characters left as they are in the keys of `Location` headers: RFC 3986 unreserved ones
+const KEY_PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+async fn handle_post(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Query(query): Query<PutQuery>,
+    Json(payload): Json<PutRequest>,
+) -> Result<Response, AppError> {
+    if state.log_keys {
+        log_key("POST", &payload.key);
+    }
+    if query.only_extend {
+        if payload.seq.is_some() {
+            return Err(KVError::InvalidInput(
+                "only_extend can't be combined with seq".to_string(),
+            )
+            .into());
+        }
+        let applied =
+            state
+                .kv_store
+                .put_if_extends(payload.key.clone(), payload.value, payload.ttl)?;
+        if !applied {
+            return Ok(StatusCode::CONFLICT.into_response());
+        }
+    } else if let Some(seq) = payload.seq {
+        let applied =
+            state
+                .kv_store
+                .put_sequenced(payload.key.clone(), payload.value, payload.ttl, seq)?;
+        if !applied {
+            return Ok(StatusCode::CONFLICT.into_response());
+        }
+    } else {
+        state
+            .kv_store
+            .put(payload.key.clone(), payload.value, payload.ttl)?;
+    }
+    // relative to the path the request was sent to, so that named stores get their own prefix
+    let location = format!(
+        "{}/{}",
+        uri.path().trim_end_matches('/'),
+        utf8_percent_encode(&payload.key, KEY_PATH_SEGMENT)
+    );
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)]).into_response())
+}
+
+/// Imports the `{key, value, ttl}` object on a line of an NDJSON upload. Blank lines are skipped.
+fn import_line(state: &AppState, line: &[u8]) -> anyhow::Result<bool> {
+    if line.trim_ascii().is_empty() {
+        return Ok(false);
+    }
+    let entry: ImportLine = serde_json::from_slice(line)?;
+    if state.log_keys {
+        log_key("IMPORT", &entry.key);
+    }
+    state.kv_store.put(entry.key, entry.value, entry.ttl)?;
+    Ok(true)
+}
+
+/// Imports an NDJSON body line by line, as it is received: invalid lines are reported and
+/// don't stop the import.
+async fn handle_import_ndjson(
+    State(state): State<AppState>,
+    body: Body,
+) -> Result<Json<ImportResponse>, AppError> {
+    let mut stream = body.into_data_stream();
+    let mut buffer: Vec<u8> = vec![];
+    let mut line_number = 0;
+    let mut response = ImportResponse {
+        imported: 0,
+        errors: vec![],
+    };
+    let mut import = |line: &[u8]| {
+        line_number += 1;
+        match import_line(&state, line) {
+            Ok(imported) => response.imported += imported as usize,
+            Err(e) => response.errors.push(ImportLineError {
+                line: line_number,
+                error: e.to_string(),
+            }),
+        }
+    };
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk?);
+        let mut start = 0;
+        while let Some(end) = buffer[start..].iter().position(|b| *b == b'\n') {
+            import(&buffer[start..start + end]);
+            start += end + 1;
+        }
+        buffer.drain(..start);
+    }
+    if !buffer.is_empty() {
+        import(&buffer);
+    }
+    Ok(Json(response))
+}
+
+async fn handle_post_key(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<PutKeyQuery>,
+    Json(payload): Json<PutValueRequest>,
+) -> Result<Response, AppError> {
+    if query.get_or_insert {
+        let value = state
+            .kv_store
+            .get_or_insert(key, payload.value, payload.ttl)?;
+        return Ok(json_response(StatusCode::OK, GetResponse { value }));
+    }
+    state.kv_store.put(key, payload.value, payload.ttl)?;
+    Ok(StatusCode::CREATED.into_response())
+}
+
+/// Byte range asked for by a `Range` header, resolved against the length of the value.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// First and last byte, both included
+    Satisfiable(usize, usize),
+    Unsatisfiable,
 }
 
-pub struct KVStoreServer {
-    pub host: IpAddr,
-    pub port: u16,
-}
+/// Parses a `Range: bytes=...` header asking for a single range (`start-end`, `start-` or
+/// `-suffix_length`) of a value of `len` bytes. Returns `None` for other headers, which are
+/// ignored so that the whole value is served.
+fn parse_byte_range(range: &str, len: usize) -> Option<ByteRange> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+    if start.is_empty() {
+        let suffix_length: usize = end.parse().ok()?;
+        if suffix_length == 0 || len == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable(
+            len.saturating_sub(suffix_length),
+            len - 1,
+        ));
+    }
+    let start: usize = start.parse().ok()?;
+    let end: usize = match end {
+        "" => usize::MAX,
+        end => end.parse().ok()?,
+    };
+    if end < start {
+        return None;
+    }
+    if start >= len {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Satisfiable(start, end.min(len - 1)))
+}
+
+/// Returns a string value as plain text, without the JSON quoting. A `Range` header gets the
+/// requested bytes of the value (`206 Partial Content`), or `416 Range Not Satisfiable` if
+/// they're past its end.
+async fn handle_get_text(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    match state.kv_store.get(key.clone())? {
+        serde_json::Value::String(text) => {
+            let content_type = (header::CONTENT_TYPE, "text/plain; charset=utf-8");
+            let accept_ranges = (header::ACCEPT_RANGES, "bytes");
+            let range = headers
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| parse_byte_range(v, text.len()));
+            match range {
+                None => Ok(([content_type, accept_ranges], text).into_response()),
+                Some(ByteRange::Satisfiable(start, end)) => {
+                    let content_range = format!("bytes {}-{}/{}", start, end, text.len());
+                    Ok((
+                        StatusCode::PARTIAL_CONTENT,
+                        [content_type, accept_ranges],
+                        [(header::CONTENT_RANGE, content_range)],
+                        text.into_bytes()[start..=end].to_vec(),
+                    )
+                        .into_response())
+                }
+                Some(ByteRange::Unsatisfiable) => Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{}", text.len()))],
+                )
+                    .into_response()),
+            }
+        }
+        _ => Err(
+            KVError::Conflict(format!("value of key {} is not a string", echo_key(&key))).into(),
+        ),
+    }
+}
+
+/// Stores the request body, as is, as a string value.
+async fn handle_put_text(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<PutTextQuery>,
+    text: String,
+) -> Result<StatusCode, AppError> {
+    let value = serde_json::Value::String(text);
+    state.kv_store.put(key, value, query.ttl)?;
+    Ok(StatusCode::CREATED)
+}
+
+/// Whether an `If-None-Match` header lists `etag` (or `*`), comparing tags weakly.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+async fn handle_get(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<GetQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let raw = query
+        .raw
+        .unwrap_or(state.response_style == ResponseStyle::Raw);
+    match state.kv_store.get_live(key) {
+        Ok(live) => {
+            // lets HTTP caches in front of quache honor the entry's TTL
+            let cache_control = match live.remaining {
+                Some(ttl) => format!("max-age={}", ttl.as_secs()),
+                None => "no-store".to_string(),
+            };
+            // content-based, so identical values share the same ETag
+            let etag = format!("W/\"{}\"", live.value_hash);
+            let not_modified = headers
+                .get(header::IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| etag_matches(v, &etag));
+            let headers = [(header::CACHE_CONTROL, cache_control), (header::ETAG, etag)];
+            if not_modified {
+                return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+            }
+            let body = if raw {
+                json_response(StatusCode::OK, live.value)
+            } else {
+                json_response(StatusCode::OK, GetResponse { value: live.value })
+            };
+            Ok((headers, body).into_response())
+        }
+        Err(e) if state.expired_gone && matches!(e.downcast_ref(), Some(KVError::Expired(_))) => {
+            Ok(error_response(StatusCode::GONE, e))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn handle_delete(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.kv_store.delete(key)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_upsert(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Json(payload): Json<UpsertRequest>,
+) -> Result<Response, AppError> {
+    let (outcome, live) = state.kv_store.upsert(
+        key,
+        payload.value,
+        payload.ttl,
+        payload.if_match.as_ref(),
+        payload.nx,
+    )?;
+    let code = match outcome {
+        UpsertOutcome::Created => StatusCode::CREATED,
+        UpsertOutcome::Updated | UpsertOutcome::Skipped => StatusCode::OK,
+    };
+    let ttl = live
+        .as_ref()
+        .and_then(|live| live.remaining)
+        .map(|remaining| remaining.as_secs_f64());
+    Ok(json_response(
+        code,
+        UpsertResponse {
+            outcome,
+            value: live.map(|live| live.value),
+            ttl,
+        },
+    ))
+}
+
+/// Applies a batch of writes, each optionally conditioned on the revision of the key (see
+/// [`KVStore::put_many`]), reporting which were applied.
+async fn handle_batch_put(
+    State(state): State<AppState>,
+    Json(mut payload): Json<BatchPutRequest>,
+) -> Result<Json<BatchPutResponse>, AppError> {
+    for item in &mut payload.items {
+        if state.log_keys {
+            log_key("BATCH", &item.key);
+        }
+        // accept ETags as returned by reads, e.g. `W/"<hash>"`
+        if let Some(revision) = &mut item.if_match {
+            *revision = revision
+                .trim()
+                .trim_start_matches("W/")
+                .trim_matches('"')
+                .to_string();
+        }
+    }
+    let statuses = state.kv_store.put_many(payload.items.clone())?;
+    let results: Vec<BatchPutResult> = payload
+        .items
+        .into_iter()
+        .zip(statuses)
+        .map(|(item, status)| BatchPutResult {
+            key: item.key,
+            status,
+        })
+        .collect();
+    let written = results
+        .iter()
+        .filter(|result| result.status == BatchPutStatus::Applied)
+        .count();
+    Ok(Json(BatchPutResponse { written, results }))
+}
+
+async fn handle_cas(
+    State(state): State<AppState>,
+    Json(payload): Json<CasRequest>,
+) -> Result<StatusCode, AppError> {
+    if state.log_keys {
+        log_key("CAS", &payload.key);
+    }
+    let swapped =
+        state
+            .kv_store
+            .cas_with_ttl(payload.key, &payload.expected, payload.value, payload.ttl)?;
+    if !swapped {
+        return Ok(StatusCode::CONFLICT);
+    }
+    Ok(StatusCode::OK)
+}
+
+async fn handle_incr(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Json(payload): Json<IncrRequest>,
+) -> Result<Json<IncrResponse>, AppError> {
+    if payload.window_ttl.is_some() {
+        if payload.min.is_some() || payload.max.is_some() {
+            return Err(KVError::InvalidInput(
+                "window_ttl can't be combined with min or max".to_string(),
+            )
+            .into());
+        }
+        let value =
+            state
+                .kv_store
+                .incr_with_ttl_on_create(key, payload.delta, payload.window_ttl)?;
+        return Ok(Json(IncrResponse {
+            value,
+            clamped: false,
+        }));
+    }
+    let (value, clamped) =
+        state
+            .kv_store
+            .incr_bounded(key, payload.delta, payload.min, payload.max)?;
+    Ok(Json(IncrResponse { value, clamped }))
+}
+
+/// Existence check: the response a `GET` would get (status, `ETag` and `Cache-Control`
+/// headers included), without its body.
+async fn handle_head(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<GetQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (parts, _) = match handle_get(State(state), Path(key), Query(query), headers).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    }
+    .into_parts();
+    Response::from_parts(parts, Body::empty())
+}
+
+async fn handle_ttl(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Json<TtlResponse>, AppError> {
+    let ttl = state.kv_store.ttl(key)?;
+    Ok(Json(TtlResponse { ttl }))
+}
+
+async fn handle_len(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Json<LenResponse>, AppError> {
+    let len = state.kv_store.value_len(key)?;
+    Ok(Json(LenResponse { len }))
+}
+
+async fn handle_list_range(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(range): Query<ListRange>,
+) -> Result<Json<GetResponse>, AppError> {
+    let value = state.kv_store.list_range(key, range.start, range.stop)?;
+    Ok(Json(GetResponse { value }))
+}
+
+async fn handle_list_trim(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Json(range): Json<ListRange>,
+) -> Result<StatusCode, AppError> {
+    state.kv_store.list_trim(key, range.start, range.stop)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Runs a transaction, answering 409 Conflict (with the outcome) if an assertion aborted it.
+async fn handle_tx(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequest>,
+) -> Result<Response, AppError> {
+    if state.log_keys {
+        for op in &payload.ops {
+            log_key("TX", op.key());
+        }
+    }
+    let outcome = state.kv_store.transaction(payload.ops)?;
+    let status = if outcome.committed {
+        StatusCode::OK
+    } else {
+        StatusCode::CONFLICT
+    };
+    Ok(json_response(status, outcome))
+}
+
+async fn handle_expire(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Json(payload): Json<ExpireRequest>,
+) -> Result<StatusCode, AppError> {
+    if !state.kv_store.expire(key.clone(), payload.ttl)? {
+        return Err(KVError::NotFound(key).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_rename(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<RenameQuery>,
+    Json(payload): Json<RenameRequest>,
+) -> Result<StatusCode, AppError> {
+    state.kv_store.rename(key, payload.to, query.nx)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_zadd(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Json(payload): Json<ZaddRequest>,
+) -> Result<Json<ZaddResponse>, AppError> {
+    let added = state.kv_store.zadd(key, payload.members)?;
+    Ok(Json(ZaddResponse { added }))
+}
+
+async fn handle_zrange(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(range): Query<ScoreRange>,
+) -> Result<Json<ZrangeResponse>, AppError> {
+    let members = state
+        .kv_store
+        .zrange_by_score(
+            key,
+            range.min.unwrap_or(f64::NEG_INFINITY),
+            range.max.unwrap_or(f64::INFINITY),
+        )?
+        .into_iter()
+        .map(|(member, score)| ScoredMember { member, score })
+        .collect();
+    Ok(Json(ZrangeResponse { members }))
+}
+
+async fn handle_random(State(state): State<AppState>) -> Result<Response, AppError> {
+    match state.kv_store.random_entry()? {
+        Some((key, value)) => Ok(json_response(
+            StatusCode::OK,
+            RandomEntryResponse { key, value },
+        )),
+        None => Ok(error_response(StatusCode::NOT_FOUND, "the store is empty")),
+    }
+}
+
+async fn handle_drain(
+    State(state): State<AppState>,
+    Json(payload): Json<DrainRequest>,
+) -> Result<Json<DrainResponse>, AppError> {
+    let entries = state.kv_store.drain_prefix(&payload.prefix)?;
+    if state.log_keys {
+        for key in entries.keys() {
+            log_key("DRAIN", key);
+        }
+    }
+    Ok(Json(DrainResponse { entries }))
+}
+
+async fn handle_consume(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<ConsumeQuery>,
+) -> Result<Json<GetResponse>, AppError> {
+    let value = state.kv_store.consume(key, query.grace_ms)?;
+    Ok(Json(GetResponse { value }))
+}
+
+/// Streams a page of the live entries whose key starts with `prefix` as a JSON array of
+/// [`PrefixEntry`], a few keys at a time, so that neither side holds the whole page. Pages go
+/// through the shards in order (see [`KVStore::list_keys_shard_page`]), the cursor of the next
+/// one being sent in the `x-next-cursor` header. Entries changed while streaming may or may not
+/// be included.
+async fn handle_prefix_stream(
+    State(state): State<AppState>,
+    Path(prefix): Path<String>,
+    Query(query): Query<PrefixStreamQuery>,
+) -> Result<Response, AppError> {
+    let limit = page_size(&state, query.limit);
+    let kv_store = state.kv_store;
+    // the page is picked upfront, so that its cursor can be sent before the entries
+    let page = kv_store.list_keys_shard_page(Some(&prefix), query.cursor.as_deref(), limit)?;
+    let mut first = true;
+    let chunks: Vec<Vec<String>> = page
+        .keys
+        .chunks(PREFIX_STREAM_CHUNK)
+        .map(<[String]>::to_vec)
+        .collect();
+    let items = futures_util::stream::iter(chunks).map(move |keys| -> anyhow::Result<String> {
+        let mut chunk = String::new();
+        // keys deleted or expired since the page was picked are left out
+        for (key, value) in kv_store.get_many(keys)? {
+            let Some(value) = value else { continue };
+            if !std::mem::take(&mut first) {
+                chunk.push(',');
+            }
+            chunk.push_str(&serde_json::to_string(&PrefixEntry { key, value })?);
+        }
+        Ok(chunk)
+    });
+    let array = futures_util::stream::once(async { Ok("[".to_string()) })
+        .chain(items)
+        .chain(futures_util::stream::once(async { Ok("]".to_string()) }));
+    let mut response = (
+        [(header::CONTENT_TYPE, "application/json")],
+        Body::from_stream(array),
+    )
+        .into_response();
+    if let Some(cursor) = page.next_cursor {
+        response
+            .headers_mut()
+            .insert(NEXT_CURSOR_HEADER, HeaderValue::from_str(&cursor)?);
+    }
+    Ok(response)
+}
+
+/// Streams the live entries of the store as NDJSON lines of [`ImportLine`] (with their
+/// remaining TTL), one shard at a time, e.g. for another instance to bootstrap from. Writes
+/// made while streaming may or may not be included.
+async fn handle_export(State(state): State<AppState>) -> Response {
+    let kv_store = state.kv_store;
+    let lines = futures_util::stream::iter(0..kv_store.num_shards()).then(move |shard_idx| {
+        let kv_store = kv_store.clone();
+        // scanning a shard blocks, so it's kept off the async runtime
+        async move {
+            tokio::task::spawn_blocking(move || -> anyhow::Result<String> {
+                let mut chunk = String::new();
+                for (key, value, ttl) in kv_store.shard_export(shard_idx)? {
+                    chunk.push_str(&serde_json::to_string(&ImportLine { key, value, ttl })?);
+                    chunk.push('\n');
+                }
+                Ok(chunk)
+            })
+            .await?
+        }
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Keys whose value holds an array containing the given element. Scans the whole store.
+async fn handle_query_contains(
+    State(state): State<AppState>,
+    Query(query): Query<ContainsQuery>,
+) -> Result<Json<QueryResponse>, AppError> {
+    let element: serde_json::Value = serde_json::from_str(&query.value)
+        .map_err(|e| KVError::InvalidInput(format!("value should be JSON-encoded: {}", e)))?;
+    let limit = page_size(&state, query.limit);
+    let (keys, next_cursor) = state.kv_store.keys_containing_page(
+        &query.path,
+        &element,
+        query.cursor.as_deref(),
+        limit,
+    )?;
+    Ok(Json(QueryResponse { keys, next_cursor }))
+}
+
+async fn handle_batch_ttl(
+    State(state): State<AppState>,
+    Json(payload): Json<BatchTtlRequest>,
+) -> Result<Json<BatchTtlResponse>, AppError> {
+    if state.log_keys {
+        for key in &payload.keys {
+            log_key("TTL", key);
+        }
+    }
+    let ttls = state.kv_store.ttl_many(&payload.keys)?;
+    let missing = payload
+        .keys
+        .into_iter()
+        .filter(|key| !ttls.contains_key(key))
+        .collect();
+    let ttls = ttls
+        .into_iter()
+        .map(|(key, remaining)| (key, remaining.map(|r| r.as_secs_f64())))
+        .collect();
+    Ok(Json(BatchTtlResponse { ttls, missing }))
+}
+
+async fn handle_mget(
+    State(state): State<AppState>,
+    Json(payload): Json<MgetRequest>,
+) -> Result<Json<MgetResponse>, AppError> {
+    if state.log_keys {
+        for key in &payload.keys {
+            log_key("MGET", key);
+        }
+    }
+    let results = state.kv_store.get_many(payload.keys)?.into_iter().collect();
+    Ok(Json(MgetResponse { results }))
+}
+
+/// Keys a page holds: `limit` if the request asks for one, capped to the maximum page size.
+fn page_size(state: &AppState, limit: Option<usize>) -> usize {
+    limit.map_or(state.max_page_size, |l| l.min(state.max_page_size))
+}
+
+async fn handle_list_keys(
+    State(state): State<AppState>,
+    Query(query): Query<ListKeysQuery>,
+) -> Result<Json<ListKeysResponse>, AppError> {
+    let limit = page_size(&state, query.limit);
+    if let Some(offset) = query.offset {
+        if query.cursor.is_some() || query.by_shard {
+            return Err(KVError::InvalidInput(
+                "offset can't be combined with cursor or by_shard".to_string(),
+            )
+            .into());
+        }
+        let (keys, next_offset) = state.kv_store.scan(query.prefix, offset, limit)?;
+        return Ok(Json(ListKeysResponse {
+            keys,
+            next_cursor: None,
+            next_offset: (next_offset > 0).then_some(next_offset),
+        }));
+    }
+    let (keys, next_cursor) = if query.by_shard {
+        let page = state.kv_store.list_keys_shard_page(
+            query.prefix.as_deref(),
+            query.cursor.as_deref(),
+            limit,
+        )?;
+        (page.keys, page.next_cursor)
+    } else {
+        state
+            .kv_store
+            .list_keys_page(query.prefix.as_deref(), query.cursor.as_deref(), limit)?
+    };
+    Ok(Json(ListKeysResponse {
+        keys,
+        next_cursor,
+        next_offset: None,
+    }))
+}
+
+async fn handle_locate(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Json<LocateResponse>, AppError> {
+    let (shard, exists) = state.kv_store.locate(&key)?;
+    Ok(Json(LocateResponse { key, shard, exists }))
+}
+
+async fn handle_debug_entry(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Json<EntryResponse>, AppError> {
+    let entry = state.kv_store.entry(key)?;
+    Ok(Json(EntryResponse {
+        remaining_ms: entry.remaining_ttl().map(|remaining| remaining.as_millis()),
+        expired: entry.expired(),
+        entry,
+    }))
+}
+
+async fn handle_rebalance(
+    State(state): State<AppState>,
+    Query(query): Query<RebalanceQuery>,
+) -> Result<Json<RebalancePlan>, AppError> {
+    Ok(Json(state.kv_store.rebalance_plan(query.shards)?))
+}
+
+async fn handle_subscribe(
+    State(state): State<AppState>,
+    Query(query): Query<SubscribeQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    // subscribe before upgrading, so that no event is missed once the handshake completes
+    let events = state.events.subscribe();
+    ws.on_upgrade(move |socket| stream_events(socket, events, query.pattern))
+}
+
+/// Forwards the events whose key matches `pattern` as JSON text messages. Subscribers that fall
+/// too far behind are sent a [`LagNotice`] and disconnected.
+async fn stream_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<ChangeEvent>,
+    pattern: String,
+) {
+    loop {
+        let message = match events.recv().await {
+            Ok(event) if glob_matches(&pattern, &event.key) => serde_json::to_string(&event),
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(lagged)) => {
+                if let Ok(notice) = serde_json::to_string(&LagNotice { lagged }) {
+                    let _ = socket.send(Message::Text(notice.into())).await;
+                }
+                break;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let Ok(message) = message else {
+            continue;
+        };
+        if socket.send(Message::Text(message.into())).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle_admin_cleanup(
+    State(state): State<AppState>,
+) -> Result<Json<CleanupResponse>, AppError> {
+    let evicted = state.kv_store.cleanup()?;
+    Ok(Json(CleanupResponse { evicted }))
+}
+
+async fn handle_admin_restore(
+    State(state): State<AppState>,
+    Json(payload): Json<RestoreRequest>,
+) -> Result<Response, AppError> {
+    let Some(backup_dir) = &state.backup_dir else {
+        return Ok(error_response(
+            StatusCode::FORBIDDEN,
+            "restores are disabled, as no backup directory is configured",
+        ));
+    };
+    // only plain relative paths, so that restores can't read outside the backup directory
+    let relative = path::Path::new(&payload.directory);
+    if relative.as_os_str().is_empty()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(KVError::InvalidInput(format!(
+            "{} is not a directory relative to the backup directory",
+            payload.directory
+        ))
+        .into());
+    }
+    let directory = path::Path::new(backup_dir).join(relative);
+    let directory = directory.to_string_lossy().into_owned();
+    let kv_store = state.kv_store.clone();
+    let restored = {
+        let directory = directory.clone();
+        tokio::task::spawn_blocking(move || kv_store.restore_from(&directory)).await??
+    };
+    tracing::info!("Restored {} entries from {}", restored, directory);
+    Ok(Json(RestoreResponse { restored }).into_response())
+}
+
+/// Flushes the default store, answering once done. The flush runs off the async workers, and its
+/// progress can be followed with `GET /admin/flush/status` meanwhile. Answers `409` while
+/// flushing is paused, as the directory is expected to stay untouched.
+async fn handle_admin_flush(
+    State(state): State<AppState>,
+) -> Result<Json<FlushProgress>, AppError> {
+    if state.kv_store.is_flushing_paused() {
+        return Err(KVError::Conflict("flushing is paused".to_string()).into());
+    }
+    let kv_store = state.kv_store.clone();
+    tokio::task::spawn_blocking(move || kv_store.to_disk()).await??;
+    Ok(Json(state.kv_store.flush_progress()))
+}
+
+async fn handle_flush_dry_run(
+    State(state): State<AppState>,
+) -> Result<Json<FlushDryRunResponse>, AppError> {
+    let kv_store = state.kv_store.clone();
+    let sizes = tokio::task::spawn_blocking(move || kv_store.flush_dry_run()).await??;
+    Ok(Json(FlushDryRunResponse {
+        total_bytes: sizes.iter().map(|(_, bytes)| bytes).sum(),
+        shards: sizes
+            .into_iter()
+            .map(|(shard, bytes)| ShardSize { shard, bytes })
+            .collect(),
+    }))
+}
+
+async fn handle_flush_status(State(state): State<AppState>) -> Json<FlushProgress> {
+    Json(state.kv_store.flush_progress())
+}
+
+/// Pauses (or resumes) the background flushing of every store served. Pausing waits for the
+/// flushes in progress.
+fn set_flushing_paused(state: &AppState, paused: bool) {
+    for kv_store in std::iter::once(&state.kv_store).chain(state.stores.values()) {
+        if paused {
+            kv_store.pause_flushing();
+        } else {
+            kv_store.resume_flushing();
+        }
+    }
+}
+
+/// Bearer token of the request, if any.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Replaces the API key. Only the current key can rotate it, not the one it replaced.
+async fn handle_rotate_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<RotateKeyRequest>,
+) -> Result<StatusCode, AppError> {
+    let Some(api_keys) = &state.api_keys else {
+        return Err(KVError::InvalidInput("API keys are not enabled".to_string()).into());
+    };
+    if !bearer_token(&headers).is_some_and(|token| api_keys.is_current(token)) {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+    if payload.key.is_empty() {
+        return Err(KVError::InvalidInput("the new key can't be empty".to_string()).into());
+    }
+    api_keys.rotate(payload.key);
+    tracing::info!("Rotated the API key");
+    Ok(StatusCode::OK)
+}
+
+async fn handle_pause_flush(State(state): State<AppState>) -> Result<StatusCode, AppError> {
+    tokio::task::spawn_blocking(move || set_flushing_paused(&state, true)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_resume_flush(State(state): State<AppState>) -> StatusCode {
+    set_flushing_paused(&state, false);
+    StatusCode::NO_CONTENT
+}
+
+/// Returns the namespaces of a state serving namespace routes, which only exist when enabled.
+fn namespaces(state: &AppState) -> &Namespaces {
+    state
+        .namespaces
+        .as_ref()
+        .expect("namespace routes are only served with namespaces enabled")
+}
+
+/// Returns the state serving a request to a namespace, `None` for unknown namespaces.
+fn namespace_state(state: &AppState, namespace: &str) -> anyhow::Result<Option<AppState>> {
+    Ok(namespaces(state).get(namespace)?.map(|kv_store| AppState {
+        kv_store,
+        ..state.clone()
+    }))
+}
+
+fn namespace_not_found(namespace: &str) -> Response {
+    error_response(
+        StatusCode::NOT_FOUND,
+        format!("namespace {} not found", echo_key(namespace)),
+    )
+}
+
+async fn handle_list_namespaces(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<String>>, AppError> {
+    Ok(Json(namespaces(&state).names()?))
+}
+
+/// Stores a key in a namespace, creating the namespace if needed.
+async fn handle_namespace_post(
+    State(state): State<AppState>,
+    Path(namespace): Path<String>,
+    uri: OriginalUri,
+    query: Query<PutQuery>,
+    payload: Json<PutRequest>,
+) -> Result<Response, AppError> {
+    let kv_store = namespaces(&state).get_or_create(&namespace)?;
+    let state = AppState { kv_store, ..state };
+    handle_post(State(state), uri, query, payload).await
+}
+
+async fn handle_namespace_get(
+    State(state): State<AppState>,
+    Path((namespace, key)): Path<(String, String)>,
+    query: Query<GetQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    match namespace_state(&state, &namespace)? {
+        Some(state) => handle_get(State(state), Path(key), query, headers).await,
+        None => Ok(namespace_not_found(&namespace)),
+    }
+}
+
+async fn handle_namespace_head(
+    State(state): State<AppState>,
+    Path((namespace, key)): Path<(String, String)>,
+    query: Query<GetQuery>,
+    headers: HeaderMap,
+) -> Response {
+    match namespace_state(&state, &namespace) {
+        Ok(Some(state)) => handle_head(State(state), Path(key), query, headers).await,
+        Ok(None) => namespace_not_found(&namespace),
+        Err(e) => AppError(e).into_response(),
+    }
+}
+
+async fn handle_namespace_delete(
+    State(state): State<AppState>,
+    Path((namespace, key)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    match namespace_state(&state, &namespace)? {
+        Some(state) => Ok(handle_delete(State(state), Path(key))
+            .await?
+            .into_response()),
+        None => Ok(namespace_not_found(&namespace)),
+    }
+}
+
+/// Drops a namespace, removing its subdirectory with its shard files.
+async fn handle_drop_namespace(
+    State(state): State<AppState>,
+    Path(namespace): Path<String>,
+) -> Result<Response, AppError> {
+    let namespaces = namespaces(&state).clone();
+    let name = namespace.clone();
+    if tokio::task::spawn_blocking(move || namespaces.drop_namespace(&name)).await?? {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(namespace_not_found(&namespace))
+    }
+}
+
+/// Answers `503` once the flushes of any store failed `max_flush_failures` times in a row, so
+/// that broken persistence takes the instance out of rotation.
+async fn handle_ready(State(state): State<AppState>) -> Result<Response, AppError> {
+    let flush = state.kv_store.flush_status()?;
+    let mut failing_stores = vec![];
+    if flush.consecutive_failures >= state.max_flush_failures {
+        failing_stores.push("default".to_string());
+    }
+    for (name, kv_store) in &state.stores {
+        if kv_store.flush_status()?.consecutive_failures >= state.max_flush_failures {
+            failing_stores.push(name.clone());
+        }
+    }
+    failing_stores.sort();
+    let ready = failing_stores.is_empty();
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = ReadyResponse {
+        ready,
+        flush,
+        failing_stores,
+    };
+    Ok(json_response(code, body))
+}
+
+async fn handle_info(State(state): State<AppState>) -> Result<Json<InfoResponse>, AppError> {
+    Ok(Json(InfoResponse {
+        directory: state.kv_store.directory().to_string(),
+        shards: state.kv_store.num_shards(),
+        read_only: state.read_only,
+        flush: state.kv_store.flush_status()?,
+        cleanup: state.kv_store.cleanup_status()?,
+    }))
+}
+
+async fn handle_metrics(State(state): State<AppState>) -> Json<MetricsSnapshot> {
+    Json(state.kv_store.metrics().snapshot())
+}
+
+async fn handle_metrics_snapshot(State(state): State<AppState>) -> Json<MetricsSnapshot> {
+    Json(state.kv_store.metrics().take_snapshot())
+}
+
+/// Adds a `Retry-After` header to every `503 Service Unavailable` response that does not set its
+/// own, so that well-behaved clients back off whatever the source of the `503` is.
+fn with_retry_after(router: Router, retry_after_secs: u64) -> Router {
+    router.layer(middleware::map_response(
+        move |mut response: Response| async move {
+            if response.status() == StatusCode::SERVICE_UNAVAILABLE
+                && !response.headers().contains_key(header::RETRY_AFTER)
+            {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+            }
+            response
+        },
+    ))
+}
+
+/// Rejects requests to `/kv/{key}` routes with `429` when the key is over its request rate.
+async fn limit_key_rate(
+    State(state): State<AppState>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let (Some(rate_limiter), Some(key)) = (&state.rate_limiter, params.get("key"))
+        // keys with the same name in different namespaces don't share a budget
+        && !rate_limiter.check(&match params.get("namespace") {
+            Some(namespace) => format!("{}/{}", namespace, key),
+            None => key.clone(),
+        })
+    {
+        return error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("too many requests for key {}", echo_key(key)),
+        );
+    }
+    next.run(request).await
+}
+
+/// Logs the method and key of requests to `/kv/{key}` routes, when enabled.
+async fn log_key_access(
+    State(state): State<AppState>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.log_keys
+        && let Some(key) = params.get("key")
+    {
+        log_key(request.method().as_str(), key);
+    }
+    next.run(request).await
+}
+
+/// Rejects requests without a valid API key with `401`, when keys are enabled. `/ready` stays
+/// open to health checks.
+async fn require_api_key(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if let Some(api_keys) = &state.api_keys
+        && request.uri().path() != "/ready"
+        && !bearer_token(request.headers()).is_some_and(|token| api_keys.check(token))
+    {
+        let mut response = error_response(StatusCode::UNAUTHORIZED, "missing or invalid API key");
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return response;
+    }
+    next.run(request).await
+}
+
+/// Rejects the requests that could modify the store with `403` when it's read-only.
+async fn reject_writes(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if state.read_only && !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return error_response(StatusCode::FORBIDDEN, "this instance is read-only");
+    }
+    next.run(request).await
+}
+
+fn kv_routes(state: &AppState) -> Router<AppState> {
+    let key_routes = Router::new()
+        .route(
+            "/kv/{key}",
+            get(handle_get)
+                .head(handle_head)
+                .post(handle_post_key)
+                .delete(handle_delete),
+        )
+        .route("/kv/{key}/incr", post(handle_incr))
+        .route("/kv/{key}/text", get(handle_get_text).put(handle_put_text))
+        .route("/kv/{key}/len", get(handle_len))
+        .route("/kv/{key}/ttl", get(handle_ttl))
+        .route("/kv/{key}/range", get(handle_list_range))
+        .route("/kv/{key}/ltrim", post(handle_list_trim))
+        .route("/kv/{key}/consume", post(handle_consume))
+        .route("/kv/{key}/rename", post(handle_rename))
+        .route("/kv/{key}/expire", put(handle_expire))
+        .route("/kv/{key}/upsert", post(handle_upsert))
+        .route("/kv/{key}/zadd", post(handle_zadd))
+        .route("/kv/{key}/zrange", get(handle_zrange))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            limit_key_rate,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            log_key_access,
+        ));
+    // endpoints working on several keys live under `/_kv`, so that they can't shadow a key
+    Router::new()
+        .route("/kv", post(handle_post).get(handle_list_keys))
+        .route("/_kv/drain", post(handle_drain))
+        .route("/_kv/import/ndjson", post(handle_import_ndjson))
+        .route("/_kv/export", get(handle_export))
+        .route("/_kv/random", get(handle_random))
+        .route("/_kv/query/contains", get(handle_query_contains))
+        .route("/_kv/prefix/{prefix}/stream", get(handle_prefix_stream))
+        .route("/_kv/batch", post(handle_batch_put))
+        .route("/_kv/tx", post(handle_tx))
+        .route("/cas", post(handle_cas))
+        .merge(key_routes)
+        .route_layer(middleware::from_fn_with_state(state.clone(), reject_writes))
+        // only reads, despite the POST
+        .route("/_kv/batch/ttl", post(handle_batch_ttl))
+        .route("/_kv/mget", post(handle_mget))
+}
+
+/// Routes of the namespaces, whose stores are picked (or created, by writes) per request.
+fn namespace_routes(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/ns/{namespace}", delete(handle_drop_namespace))
+        .route("/ns/{namespace}/kv", post(handle_namespace_post))
+        .merge(
+            Router::new()
+                .route(
+                    "/ns/{namespace}/kv/{key}",
+                    get(handle_namespace_get)
+                        .head(handle_namespace_head)
+                        .delete(handle_namespace_delete),
+                )
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    limit_key_rate,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    log_key_access,
+                )),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), reject_writes))
+        .route("/ns", get(handle_list_namespaces))
+}
+
+fn router(state: AppState) -> Router {
+    let retry_after_secs = state.retry_after_secs;
+    let mut app = kv_routes(&state)
+        .route("/debug/locate/{key}", get(handle_locate))
+        .route("/debug/entry/{key}", get(handle_debug_entry))
+        .route("/debug/rebalance", get(handle_rebalance))
+        .route("/ready", get(handle_ready))
+        .route("/info", get(handle_info))
+        .route("/metrics", get(handle_metrics))
+        .route("/metrics/snapshot", post(handle_metrics_snapshot))
+        .merge(
+            Router::new()
+                .route("/admin/cleanup", post(handle_admin_cleanup))
+                .route("/admin/restore", post(handle_admin_restore))
+                .route_layer(middleware::from_fn_with_state(state.clone(), reject_writes)),
+        )
+        .route("/admin/rotate-key", post(handle_rotate_key))
+        .route("/admin/flush", post(handle_admin_flush))
+        .route("/admin/flush/status", get(handle_flush_status))
+        .route("/admin/flush/dry-run", get(handle_flush_dry_run))
+        .route("/admin/flush/pause", post(handle_pause_flush))
+        .route("/admin/flush/resume", post(handle_resume_flush))
+        .route("/subscribe", get(handle_subscribe))
+        .with_state(state.clone());
+    // unknown store names don't match any route, so they get a 404
+    for (name, kv_store) in &state.stores {
+        let store_state = AppState {
+            kv_store: kv_store.clone(),
+            stores: HashMap::new(),
+            rate_limiter: state
+                .rate_limiter
+                .as_ref()
+                .map(|limiter| limiter.for_another_store()),
+            ..state.clone()
+        };
+        app = app.nest(
+            &format!("/store/{}", name),
+            kv_routes(&store_state).with_state(store_state),
+        );
+    }
+    if state.namespaces.is_some() {
+        let namespaces_state = AppState {
+            stores: HashMap::new(),
+            rate_limiter: state
+                .rate_limiter
+                .as_ref()
+                .map(|limiter| limiter.for_another_store()),
+            ..state.clone()
+        };
+        app = app.merge(namespace_routes(&namespaces_state).with_state(namespaces_state));
+    }
+    let app = app.layer(middleware::from_fn_with_state(state, require_api_key));
+    with_retry_after(app, retry_after_secs)
+}
+
+impl KVStoreServer {
+    pub fn new(port: Option<u16>, host: Option<String>) -> Self {
+        let server_port = match port {
+            Some(n) => n,
+            None => DEFAULT_PORT,
+        };
+        let server_host = match host {
+            Some(h) => {
+                IpAddr::V4(Ipv4Addr::from_str(&h).expect("You should provide a valid IPv4 address"))
+            }
+            None => IpAddr::V4(
+                Ipv4Addr::from_str(DEFAULT_HOST).expect("You should provide a valid IPv4 address"),
+            ),
+        };
+
+        Self {
+            port: server_port,
+            host: server_host,
+            expired_gone: false,
+            replicate_to: vec![],
+            peer_api_key: None,
+            retry_after_secs: DEFAULT_RETRY_AFTER_SECS,
+            stores: HashMap::new(),
+            namespaces: None,
+            max_request_rate_per_key: None,
+            #[cfg(feature = "grpc")]
+            grpc_port: None,
+            log_keys: false,
+            read_only: false,
+            max_flush_failures: DEFAULT_MAX_FLUSH_FAILURES,
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            api_key: None,
+            key_rotation_overlap_secs: DEFAULT_KEY_ROTATION_OVERLAP_SECS,
+            response_style: ResponseStyle::default(),
+            idle_timeout_secs: None,
+            backup_dir: None,
+        }
+    }
+
+    pub async fn serve(&self, kv_store: KVStore) -> anyhow::Result<()> {
+        let mut state = AppState::new(kv_store);
+        state.expired_gone = self.expired_gone;
+        state.retry_after_secs = self.retry_after_secs;
+        state.stores = self.stores.clone();
+        state.namespaces = self.namespaces.clone();
+        state.rate_limiter = self.max_request_rate_per_key.map(KeyRateLimiter::new);
+        state.log_keys = self.log_keys;
+        state.read_only = self.read_only;
+        state.max_flush_failures = self.max_flush_failures;
+        state.max_page_size = self.max_page_size;
+        state.response_style = self.response_style;
+        state.backup_dir = self.backup_dir.clone();
+        state.api_keys = self
+            .api_key
+            .clone()
+            .map(|key| ApiKeys::new(key, Duration::from_secs(self.key_rotation_overlap_secs)));
+        // peers are only sent the writes of the default store
+        if !self.replicate_to.is_empty() {
+            Replicator::new(self.replicate_to.clone())?
+                .with_api_key(self.peer_api_key.clone())
+                .start(&state.kv_store);
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_port) = self.grpc_port {
+            let grpc_service = crate::grpc::QuacheService::new(state.kv_store.clone())
+                .with_log_keys(self.log_keys)
+                .with_read_only(self.read_only)
+                .with_api_keys(state.api_keys.clone());
+            let grpc_addr = SocketAddr::from((self.host, grpc_port));
+            tokio::spawn(async move {
+                if let Err(e) = crate::grpc::serve_grpc(grpc_service, grpc_addr).await {
+                    tracing::error!("gRPC server error: {}", e);
+                }
+            });
+        }
+        let mut all_stores = vec![state.kv_store.clone()];
+        all_stores.extend(self.stores.values().cloned());
+        let app = router(state);
+        let addr = SocketAddr::from((self.host, self.port));
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tracing::info!("Starting to serve on {}:{:?}", self.host, self.port);
+        serve_until_shutdown(
+            listener,
+            app,
+            shutdown_signal(),
+            Duration::from_secs(self.shutdown_timeout_secs),
+            self.idle_timeout_secs.map(Duration::from_secs),
+            &all_stores,
+        )
+        .await?;
+        // namespaces can be created until the very end, so they are listed only now
+        if let Some(namespaces) = self.namespaces.clone() {
+            tokio::task::spawn_blocking(move || match namespaces.to_disk() {
+                Ok(_) => tracing::info!("Flushed the namespaces to disk before exiting"),
+                Err(e) => tracing::error!("Final flush of the namespaces failed: {}", e),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves on Ctrl+C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Builds the HTTP connections, closing HTTP/1 keep-alive connections that wait longer than
+/// `idle_timeout` (if any) for their next request.
+fn connection_builder(idle_timeout: Option<Duration>) -> Builder<TokioExecutor> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(idle_timeout);
+    builder
+}
+
+/// Serves `app` until `shutdown` resolves, then stops accepting connections and gives in-flight
+/// requests up to `shutdown_timeout` to complete. Requests still running after that are
+/// abandoned. The stores are flushed one last time either way, once their background flushing
+/// is paused: a background flush can't overwrite the final one with older contents, nor be cut
+/// short by the process exiting.
+///
+/// Connections idle for `idle_timeout` are closed, see [`connection_builder`].
+async fn serve_until_shutdown(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+    shutdown_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    stores: &[KVStore],
+) -> anyhow::Result<()> {
+    let builder = connection_builder(idle_timeout);
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // e.g. out of file descriptors: give the open connections time to close
+                    tracing::warn!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let app = TowerToHyperService::new(app.clone());
+        let service = service_fn(move |mut request: Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(remote_addr));
+            app.call(request)
+        });
+        let connection = graceful.watch(
+            builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("Connection from {} closed: {}", remote_addr, e);
+            }
+        });
+    }
+    tracing::info!(
+        "Shutting down, waiting up to {:?} for in-flight requests",
+        shutdown_timeout
+    );
+    if tokio::time::timeout(shutdown_timeout, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "In-flight requests didn't complete within {:?}, abandoning them",
+            shutdown_timeout
+        );
+    }
+    let stores = stores.to_vec();
+    tokio::task::spawn_blocking(move || {
+        for kv_store in stores {
+            kv_store.pause_flushing();
+            match kv_store.to_disk() {
+                Ok(_) => tracing::info!("Flushed {} to disk before exiting", kv_store.directory()),
+                Err(e) => tracing::error!("Final flush of {} failed: {}", kv_store.directory(), e),
+            }
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Request, StatusCode},
+    };
+    use tower::Service;
+
+    use crate::core::{TtlCapPolicy, shard_file_indices};
+
+    fn cleanup_test_directory(directory_name: String) {
+        if std::fs::exists(&directory_name).expect("Should be able to check directory existence") {
+            std::fs::remove_dir_all(directory_name)
+                .expect("Should be able to remove directory content");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kv_endpoints() {
+        let kv_store =
+            KVStore::new(3, ".quache-server/".to_string()).expect("Should be able to create test");
+
+        let state: AppState = AppState::new(kv_store);
+        let mut app = router(state);
+        let request_body = serde_json::to_string(&PutRequest {
+            key: "hello".to_string(),
+            value: serde_json::Value::from(1),
+            ttl: None,
+            seq: None,
+        })
+        .unwrap();
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(request_body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let get_response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hello")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(get_response.status(), StatusCode::OK);
+        let bytes = to_bytes(get_response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let get_response_json: GetResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(get_response_json.value, serde_json::Value::from(1));
+
+        let delete_response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hello")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(delete_response.status(), StatusCode::NO_CONTENT);
+
+        let get_deleted_response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hello")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(get_deleted_response.status(), StatusCode::NOT_FOUND);
+
+        cleanup_test_directory(".quache-server/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_named_stores() {
+        std::fs::create_dir_all(".quache-server-stores/")
+            .expect("Should be able to create directory");
+        std::fs::write(
+            ".quache-server-stores/stores.json",
+            r#"{"stores": [
+                {"name": "first", "directory": ".quache-server-stores/first/", "shards": 3},
+                {"name": "second", "directory": ".quache-server-stores/second/", "shards": 2}
+            ]}"#,
+        )
+        .expect("Should be able to write config");
+        let stores = open_stores(
+            ".quache-server-stores/stores.json",
+            ShardMismatchPolicy::default(),
+            |kv_store| Ok(kv_store.with_max_ttl(Some(60_f64), TtlCapPolicy::Reject)),
+        )
+        .expect("Should be able to open stores");
+        // the options of the default store apply to the named ones
+        assert!(
+            stores["first"]
+                .put(
+                    "long".to_string(),
+                    serde_json::Value::from(1),
+                    Some(120_f64)
+                )
+                .is_err()
+        );
+        let kv_store = KVStore::new(3, ".quache-server-stores/default/".to_string())
+            .expect("Should be able to create test");
+        let mut state = AppState::new(kv_store.clone());
+        state.stores = stores.clone();
+        let mut app = router(state);
+
+        let request_body = serde_json::to_string(&PutRequest {
+            key: "hello".to_string(),
+            value: serde_json::Value::from(1),
+            ttl: None,
+            seq: None,
+        })
+        .unwrap();
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/store/first/kv")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(request_body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        for (uri, expected_status) in [
+            ("/store/first/kv/hello", StatusCode::OK),
+            ("/store/second/kv/hello", StatusCode::NOT_FOUND),
+            ("/kv/hello", StatusCode::NOT_FOUND),
+            ("/store/unknown/kv/hello", StatusCode::NOT_FOUND),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "GET {}", uri);
+        }
+
+        // each store flushes to its own directory
+        for store in stores.values() {
+            store.to_disk().expect("Should be able to flush to disk");
+        }
+        let first = KVStore::new_from_disk(3, ".quache-server-stores/first/".to_string())
+            .expect("Should be able to load store");
+        assert_eq!(
+            first.get("hello".to_string()).unwrap(),
+            serde_json::Value::from(1)
+        );
+        let second = KVStore::new_from_disk(2, ".quache-server-stores/second/".to_string())
+            .expect("Should be able to load store");
+        assert!(second.get("hello".to_string()).is_err());
+
+        cleanup_test_directory(".quache-server-stores/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_namespaces() {
+        let kv_store = KVStore::new(3, ".quache-server-namespaces/".to_string())
+            .expect("Should be able to create test");
+        let namespaces = Namespaces::new(3, ".quache-server-namespaces/".to_string(), Ok)
+            .expect("Should be able to create namespaces");
+        let mut state = AppState::new(kv_store.clone());
+        state.namespaces = Some(namespaces.clone());
+        let mut app = router(state);
+
+        for namespace in ["sessions", "jobs", "bad.name"] {
+            let request_body = serde_json::to_string(&PutRequest {
+                key: "hello".to_string(),
+                value: serde_json::Value::from(namespace),
+                ttl: None,
+                seq: None,
+            })
+            .unwrap();
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/ns/{}/kv", namespace))
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(request_body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            // namespaces are directory names
+            let expected_status = if namespace == "bad.name" {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::CREATED
+            };
+            assert_eq!(response.status(), expected_status, "POST to {}", namespace);
+        }
+        for (uri, expected_status) in [
+            ("/ns/sessions/kv/hello", StatusCode::OK),
+            ("/ns/jobs/kv/hello", StatusCode::OK),
+            ("/kv/hello", StatusCode::NOT_FOUND),
+            ("/ns/unknown/kv/hello", StatusCode::NOT_FOUND),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "GET {}", uri);
+        }
+
+        // two namespaces, two file trees, next to the shard files of the default store
+        kv_store.to_disk().expect("Should be able to flush to disk");
+        namespaces
+            .to_disk()
+            .expect("Should be able to flush to disk");
+        let shard_idx = kv_store.find_shard("hello");
+        for namespace in ["sessions", "jobs"] {
+            let directory = format!(".quache-server-namespaces/{}/", namespace);
+            assert_eq!(shard_file_indices(&directory).unwrap(), vec![shard_idx]);
+        }
+
+        for (uri, expected_status) in [
+            ("/ns/sessions", StatusCode::NO_CONTENT),
+            ("/ns/sessions", StatusCode::NOT_FOUND),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("DELETE")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "DELETE {}", uri);
+        }
+        assert!(!std::fs::exists(".quache-server-namespaces/sessions/").unwrap());
+        assert_eq!(
+            shard_file_indices(".quache-server-namespaces/jobs/").unwrap(),
+            vec![shard_idx]
+        );
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/ns")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let names: Vec<String> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(names, vec!["jobs"]);
+
+        // the namespaces left are found again on load
+        let loaded = Namespaces::new_from_disk(
+            3,
+            ".quache-server-namespaces/".to_string(),
+            ShardMismatchPolicy::default(),
+            Ok,
+        )
+        .expect("Should be able to load namespaces");
+        assert_eq!(
+            loaded
+                .get("jobs")
+                .unwrap()
+                .unwrap()
+                .get("hello".to_string())
+                .unwrap(),
+            serde_json::Value::from("jobs")
+        );
+
+        cleanup_test_directory(".quache-server-namespaces/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_max_request_rate_per_key() {
+        let kv_store = KVStore::new(3, ".quache-server-rate/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hot".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        kv_store
+            .put("cold".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to put key");
+        let mut state = AppState::new(kv_store);
+        state.rate_limiter = Some(KeyRateLimiter::new(10));
+        let mut app = router(state);
+
+        let mut statuses = vec![];
+        for _ in 0..20 {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv/hot")
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            statuses.push(response.status());
+        }
+        assert_eq!(statuses.last(), Some(&StatusCode::TOO_MANY_REQUESTS));
+        assert!(statuses.contains(&StatusCode::OK));
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/cold")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        cleanup_test_directory(".quache-server-rate/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_batch_put_if_match() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        for key in ["fresh", "stale"] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(1), None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store.clone()));
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/fresh")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        // the revision read for "fresh" doesn't match the value of "stale" anymore
+        kv_store
+            .put("stale".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to put key");
+
+        let body = serde_json::json!({"items": [
+            {"key": "fresh", "value": 10, "if_match": etag},
+            {"key": "stale", "value": 20, "if_match": etag},
+            {"key": "new", "value": 30},
+            {"key": "missing", "value": 40, "if_match": etag},
+        ]});
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/_kv/batch")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let batch: BatchPutResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(batch.written, 2);
+        let statuses: Vec<_> = batch
+            .results
+            .iter()
+            .map(|result| (result.key.as_str(), result.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("fresh", BatchPutStatus::Applied),
+                ("stale", BatchPutStatus::Conflict),
+                ("new", BatchPutStatus::Applied),
+                ("missing", BatchPutStatus::Conflict),
+            ]
+        );
+        for (key, expected) in [("fresh", Some(10)), ("stale", Some(2)), ("new", Some(30))] {
+            assert_eq!(
+                kv_store.get(key.to_string()).ok(),
+                expected.map(serde_json::Value::from),
+                "{}",
+                key
+            );
+        }
+        assert!(kv_store.get("missing".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_null_values() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store));
+
+        for (body, expected_status) in [
+            (r#"{"key": "nothing", "value": null}"#, StatusCode::CREATED),
+            // the value may be null, but not omitted
+            (r#"{"key": "omitted"}"#, StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", body);
+        }
+
+        for (uri, expected_status, expected_body) in [
+            ("/kv/nothing", StatusCode::OK, Some(r#"{"value":null}"#)),
+            ("/kv/nothing?raw=true", StatusCode::OK, Some("null")),
+            ("/kv/omitted", StatusCode::NOT_FOUND, None),
+            ("/kv/missing", StatusCode::NOT_FOUND, None),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", uri);
+            if let Some(expected_body) = expected_body {
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                assert_eq!(bytes, expected_body.as_bytes(), "{}", uri);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_response_styles() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        let value = serde_json::json!({"name": "ada"});
+        kv_store
+            .put("user".to_string(), value.clone(), None)
+            .expect("Should be able to put key");
+        let wrapped = serde_json::json!({ "value": value });
+        for (style, query, expected) in [
+            (ResponseStyle::Wrapped, "", &wrapped),
+            (ResponseStyle::Wrapped, "?raw=true", &value),
+            (ResponseStyle::Raw, "", &value),
+            (ResponseStyle::Raw, "?raw=false", &wrapped),
+        ] {
+            let mut state = AppState::new(kv_store.clone());
+            state.response_style = style;
+            let mut app = router(state);
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/user{}", query))
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(&body, expected, "{:?}{}", style, query);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upsert_endpoint() {
+        use UpsertOutcome::{Created, Skipped, Updated};
+        // (stored before, request, outcome, value after)
+        for (stored, body, expected_outcome, expected_value) in [
+            (false, r#"{"value": 2}"#, Created, Some(2)),
+            (true, r#"{"value": 2}"#, Updated, Some(2)),
+            (false, r#"{"value": 2, "nx": true}"#, Created, Some(2)),
+            (true, r#"{"value": 2, "nx": true}"#, Skipped, Some(1)),
+            (false, r#"{"value": 2, "if_match": 1}"#, Skipped, None),
+            (true, r#"{"value": 2, "if_match": 1}"#, Updated, Some(2)),
+            (true, r#"{"value": 2, "if_match": 3}"#, Skipped, Some(1)),
+            (
+                false,
+                r#"{"value": 2, "if_match": 1, "nx": true}"#,
+                Created,
+                Some(2),
+            ),
+            (
+                true,
+                r#"{"value": 2, "if_match": 1, "nx": true}"#,
+                Updated,
+                Some(2),
+            ),
+            (
+                true,
+                r#"{"value": 2, "if_match": 3, "nx": true}"#,
+                Skipped,
+                Some(1),
+            ),
+            (true, r#"{"value": 2, "if_match": null}"#, Skipped, Some(1)),
+        ] {
+            let kv_store = KVStore::builder()
+                .in_memory()
+                .build()
+                .expect("Should be able to create test");
+            if stored {
+                kv_store
+                    .put(
+                        "counter".to_string(),
+                        serde_json::Value::from(1),
+                        Some(60_f64),
+                    )
+                    .expect("Should be able to put key");
+            }
+            let mut app = router(AppState::new(kv_store));
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv/counter/upsert")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let expected_status = match expected_outcome {
+                Created => StatusCode::CREATED,
+                Updated | Skipped => StatusCode::OK,
+            };
+            assert_eq!(response.status(), expected_status, "{}", body);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let upsert: UpsertResponse = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(upsert.outcome, expected_outcome, "{}", body);
+            assert_eq!(
+                upsert.value,
+                expected_value.map(serde_json::Value::from),
+                "{}",
+                body
+            );
+            // updates keep the expiry of the stored entry, created entries have no TTL
+            assert_eq!(upsert.ttl.is_some(), stored, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        kv_store
+            .put(
+                "hey".to_string(),
+                serde_json::json!({"large": "value"}),
+                None,
+            )
+            .expect("Should be able to put key");
+        kv_store
+            .put(
+                "expired".to_string(),
+                serde_json::Value::from(1),
+                Some(0.001),
+            )
+            .expect("Should be able to put key");
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let mut state = AppState::new(kv_store);
+        state.expired_gone = true;
+        let mut app = router(state);
+        for (key, expected_status) in [
+            ("hey", StatusCode::OK),
+            ("missing", StatusCode::NOT_FOUND),
+            ("expired", StatusCode::GONE),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/{}", key))
+                        .method("HEAD")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", key);
+            // same headers as a GET
+            assert_eq!(
+                response.headers().contains_key(header::ETAG),
+                expected_status == StatusCode::OK,
+                "{}",
+                key
+            );
+            if expected_status == StatusCode::OK {
+                assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+            }
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert!(bytes.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_len_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        for (key, value) in [
+            ("array", serde_json::json!([1, 2, 3])),
+            ("object", serde_json::json!({"a": 1, "b": [1, 2]})),
+            ("string", serde_json::json!("héllo")),
+            ("number", serde_json::json!(42)),
+        ] {
+            kv_store
+                .put(key.to_string(), value, None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store));
+        for (key, expected_status, expected_len) in [
+            ("array", StatusCode::OK, 3),
+            ("object", StatusCode::OK, 2),
+            ("string", StatusCode::OK, 5),
+            ("number", StatusCode::CONFLICT, 0),
+            ("missing", StatusCode::NOT_FOUND, 0),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/{}/len", key))
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", key);
+            if expected_status == StatusCode::OK {
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let len_response: LenResponse = serde_json::from_slice(&bytes).unwrap();
+                assert_eq!(len_response.len, expected_len, "{}", key);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tx_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(1)
+            .build()
+            .expect("Should be able to create test");
+        kv_store
+            .put("balance".to_string(), serde_json::Value::from(10), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (expected, expected_status, expected_body) in [
+            (
+                10,
+                StatusCode::OK,
+                serde_json::json!({"committed": true, "results": [true, 5, 5]}),
+            ),
+            (
+                10,
+                StatusCode::CONFLICT,
+                serde_json::json!({"committed": false, "results": [false], "failed_op": 0}),
+            ),
+        ] {
+            let body = serde_json::json!({"ops": [
+                {"op": "assert_equals", "key": "balance", "value": expected},
+                {"op": "incr", "key": "balance", "delta": -5},
+                {"op": "get", "key": "balance"},
+            ]});
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/_kv/tx")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body.to_string()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body, expected_body);
+            assert_eq!(kv_store.get("balance".to_string()).unwrap(), 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expire_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (key, expected_status) in [
+            ("hey", StatusCode::NO_CONTENT),
+            ("missing", StatusCode::NOT_FOUND),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/{}/expire", key))
+                        .method("PUT")
+                        .header("content-type", "application/json")
+                        .body(Body::from(r#"{"ttl": 0.05}"#))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", key);
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+        assert_eq!(kv_store.cleanup().unwrap(), 1);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hey")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        for (key, ttl) in [("persistent", None), ("expiring", Some(12.5))] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(1), ttl)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store));
+        for (key, expected_status) in [
+            ("persistent", StatusCode::OK),
+            ("expiring", StatusCode::OK),
+            ("missing", StatusCode::NOT_FOUND),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/{}/ttl", key))
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", key);
+            if expected_status == StatusCode::OK {
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let ttl_response: TtlResponse = serde_json::from_slice(&bytes).unwrap();
+                match ttl_response.ttl {
+                    None => assert_eq!(key, "persistent"),
+                    Some(ttl) => assert!(ttl > 12_f64 && ttl <= 12.5, "{}", ttl),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_range_and_trim_endpoints() {
+        let kv_store = KVStore::new(3, ".quache-server-list/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("list".to_string(), serde_json::json!([0, 1, 2, 3, 4]), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/list/range?start=-3&stop=-2")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let range: GetResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(range.value, serde_json::json!([2, 3]));
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/list/ltrim")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"start": 0, "stop": 1}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            kv_store.get("list".to_string()).unwrap(),
+            serde_json::json!([0, 1])
+        );
+
+        cleanup_test_directory(".quache-server-list/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_get_etag_revalidation() {
+        let kv_store = KVStore::new(3, ".quache-server-etag/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::json!({"a": 1}), None)
+            .expect("Should be able to put key");
+        kv_store
+            .put("hello".to_string(), serde_json::json!({"a": 1}), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+
+        let get = |key: &str, if_none_match: Option<&str>| {
+            let mut request = Request::builder().uri(format!("/kv/{}", key)).method("GET");
+            if let Some(etag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let response = app.call(get("hey", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(etag.starts_with("W/\""));
+
+        // identical values share the same ETag
+        let response = app.call(get("hello", Some(&etag))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(bytes.is_empty());
+
+        kv_store
+            .put("hey".to_string(), serde_json::json!({"a": 2}), None)
+            .expect("Should be able to put key");
+        let response = app.call(get("hey", Some(&etag))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], etag.as_str());
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hello")
+                    .method("HEAD")
+                    .header(header::IF_NONE_MATCH, format!("\"other\", {}", etag))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        cleanup_test_directory(".quache-server-etag/".to_string());
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_keys_omits_values() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let kv_store = KVStore::new(3, ".quache-server-log-keys/".to_string())
+            .expect("Should be able to create test");
+        let mut state = AppState::new(kv_store);
+        state.log_keys = true;
+        let mut app = router(state);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"key": "session-42", "value": "hunter2"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/session-42")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/_kv/batch")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        r#"{"items": [{"key": "session-43", "value": "hunter3"}]}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("POST session-42"));
+        assert!(logs.contains("GET session-42"));
+        assert!(logs.contains("BATCH session-43"));
+        assert!(!logs.contains("hunter2"));
+        assert!(!logs.contains("hunter3"));
+
+        cleanup_test_directory(".quache-server-log-keys/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_rejects_writes() {
+        let kv_store = KVStore::new(3, ".quache-server-read-only/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        let mut state = AppState::new(kv_store.clone());
+        state.read_only = true;
+        let mut app = router(state);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"key": "hey", "value": 2}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hey")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            kv_store.get("hey".to_string()).unwrap(),
+            serde_json::Value::from(1)
+        );
+        for (uri, body) in [
+            ("/admin/cleanup", "{}"),
+            ("/admin/restore", r#"{"directory": "backup"}"#),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hey")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        cleanup_test_directory(".quache-server-read-only/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_drain_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-drain/".to_string())
+            .expect("Should be able to create test");
+        for key in ["jobs:1", "jobs:2", "other"] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(key), None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store.clone()));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/_kv/drain")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"prefix": "jobs:"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let drained: DrainResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(drained.entries.len(), 2);
+        assert_eq!(drained.entries["jobs:1"], "jobs:1");
+        assert_eq!(kv_store.list_keys(None).unwrap(), vec!["other".to_string()]);
+
+        cleanup_test_directory(".quache-server-drain/".to_string());
+    }
+
+    /// Flush target whose writes fail while `failing` is set.
+    #[derive(Debug, Default)]
+    struct FlakyTarget {
+        failing: std::sync::atomic::AtomicBool,
+    }
+
+    impl crate::flush::FlushTarget for FlakyTarget {
+        fn write_shard(&self, _shard_idx: usize, _contents: &str) -> anyhow::Result<()> {
+            if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("disk full"));
+            }
+            Ok(())
+        }
+
+        fn read_shard(&self, _shard_idx: usize) -> anyhow::Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn describe(&self) -> String {
+            "flaky".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_reflects_flush_failures() {
+        let target = std::sync::Arc::new(FlakyTarget::default());
+        let kv_store = KVStore::new(3, ".quache-server-ready/".to_string())
+            .expect("Should be able to create test")
+            .with_flush_target(target.clone());
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+        let ready = || {
+            Request::builder()
+                .uri("/ready")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(app.call(ready()).await.unwrap().status(), StatusCode::OK);
+
+        target
+            .failing
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        for _ in 0..DEFAULT_MAX_FLUSH_FAILURES - 1 {
+            assert!(kv_store.to_disk().is_err());
+        }
+        assert_eq!(app.call(ready()).await.unwrap().status(), StatusCode::OK);
+        assert!(kv_store.to_disk().is_err());
+        let response = app.call(ready()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: ReadyResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body.flush.consecutive_failures, DEFAULT_MAX_FLUSH_FAILURES);
+        assert_eq!(body.flush.last_error.as_deref(), Some("disk full"));
+        assert_eq!(body.failing_stores, vec!["default".to_string()]);
+
+        target
+            .failing
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        kv_store.to_disk().expect("Should be able to flush");
+        assert_eq!(app.call(ready()).await.unwrap().status(), StatusCode::OK);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/info")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: InfoResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(info.shards, 3);
+        assert!(info.flush.last_flush_ms.is_some());
+        assert_eq!(info.flush.consecutive_failures, 0);
+
+        cleanup_test_directory(".quache-server-ready/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_sorted_set_endpoints() {
+        let kv_store = KVStore::new(3, ".quache-server-zset/".to_string())
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/board/zadd")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        r#"{"members": {"alice": 10, "bob": 5, "carol": 7.5}}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let zadd: ZaddResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(zadd.added, 3);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/board/zrange?min=6")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let range: ZrangeResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            range.members,
+            vec![
+                ScoredMember {
+                    member: "carol".to_string(),
+                    score: 7.5
+                },
+                ScoredMember {
+                    member: "alice".to_string(),
+                    score: 10_f64
+                },
+            ]
+        );
+
+        cleanup_test_directory(".quache-server-zset/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_random_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-random/".to_string())
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store.clone()));
+        let random = || {
+            Request::builder()
+                .uri("/_kv/random")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(
+            app.call(random()).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        let response = app.call(random()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let entry: RandomEntryResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(entry.key, "hey");
+        assert_eq!(entry.value, serde_json::Value::from(1));
+
+        cleanup_test_directory(".quache-server-random/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_consume_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-consume/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("code".to_string(), serde_json::Value::from("123456"), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store));
+
+        let consume = || {
+            Request::builder()
+                .uri("/kv/code/consume?grace_ms=50")
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.call(consume()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let consumed: GetResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(consumed.value, serde_json::Value::from("123456"));
+
+        let response = app.call(consume()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+        let response = app.call(consume()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        cleanup_test_directory(".quache-server-consume/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_cas_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-cas/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("lock".to_string(), serde_json::Value::from("a"), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (expected, value, expected_status, expected_ttl) in [
+            ("b", "c", StatusCode::CONFLICT, -1),
+            ("a", "c", StatusCode::OK, 30_000),
+        ] {
+            let request_body = serde_json::to_string(&CasRequest {
+                key: "lock".to_string(),
+                expected: serde_json::Value::from(expected),
+                value: serde_json::Value::from(value),
+                ttl: Some(30_f64),
+            })
+            .unwrap();
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/cas")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(request_body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status);
+            // the TTL only changes along with a successful swap
+            assert_eq!(
+                kv_store.entry("lock".to_string()).unwrap().ttl_ms(),
+                expected_ttl
+            );
+        }
+        assert_eq!(
+            kv_store.get("lock".to_string()).unwrap(),
+            serde_json::Value::from("c")
+        );
+
+        cleanup_test_directory(".quache-server-cas/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_pattern() {
+        use futures_util::StreamExt;
+
+        let kv_store = KVStore::new(3, ".quache-server-subscribe/".to_string())
+            .expect("Should be able to create test");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(AppState::new(kv_store.clone()));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}/subscribe?pattern=orders:*", addr))
+                .await
+                .expect("Should be able to subscribe");
+        kv_store
+            .put("users:1".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        kv_store
+            .put("orders:1".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to put key");
+        kv_store
+            .delete("orders:1".to_string())
+            .expect("Should be able to delete key");
+
+        // events arrive in order, so the first one proves users:1 was filtered out
+        let mut received = vec![];
+        for _ in 0..2 {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("Should receive an event in time")
+                .unwrap()
+                .unwrap();
+            let event: ChangeEvent = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            received.push(event);
+        }
+        assert_eq!(received[0].key, "orders:1");
+        assert_eq!(received[0].value, Some(serde_json::Value::from(2)));
+        assert_eq!(received[1].key, "orders:1");
+        assert_eq!(received[1].value, None);
+
+        cleanup_test_directory(".quache-server-subscribe/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_from_peer() {
+        let source = KVStore::builder()
+            .in_memory()
+            .shards(3)
+            .build()
+            .expect("Should be able to create test");
+        for i in 0..50 {
+            let ttl = (i % 2 == 0).then_some(600_f64);
+            source
+                .put(format!("key-{}", i), serde_json::json!({ "n": i }), ttl)
+                .expect("Should be able to put key");
+        }
+        source
+            .put("stale".to_string(), serde_json::Value::from(1), Some(0.001))
+            .expect("Should be able to put key");
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut state = AppState::new(source.clone());
+        state.api_keys = Some(ApiKeys::new("secret".to_string(), Duration::from_secs(60)));
+        let app = router(state);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // the copies bypass the write policies, which would reject the fractional TTLs
+        let target = KVStore::builder()
+            .in_memory()
+            .shards(5)
+            .build()
+            .expect("Should be able to create test")
+            .with_strict_ttl_seconds(true);
+        let peer = format!("http://{}", addr);
+        assert!(
+            crate::replication::bootstrap_from(&target, &peer, 1, None)
+                .await
+                .is_err()
+        );
+        let copied = crate::replication::bootstrap_from(&target, &peer, 1, Some("secret"))
+            .await
+            .expect("Should be able to bootstrap");
+        assert_eq!(copied, 50);
+
+        let export = |kv_store: &KVStore| {
+            let mut entries = vec![];
+            for shard_idx in 0..kv_store.num_shards() {
+                entries.extend(kv_store.shard_export(shard_idx).unwrap());
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        };
+        let (expected, copied) = (export(&source), export(&target));
+        assert_eq!(expected.len(), copied.len());
+        for ((key, value, ttl), (copied_key, copied_value, copied_ttl)) in
+            expected.into_iter().zip(copied)
+        {
+            assert_eq!(key, copied_key);
+            assert_eq!(value, copied_value);
+            match (ttl, copied_ttl) {
+                (None, None) => {}
+                (Some(ttl), Some(copied_ttl)) => assert!((ttl - copied_ttl).abs() < 1_f64),
+                other => panic!("TTL of {} not copied: {:?}", key, other),
+            }
+        }
+
+        // nothing listens on the port anymore once the listener is dropped
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        assert!(
+            crate::replication::bootstrap_from(
+                &target,
+                &format!("http://{}", closed_addr),
+                2,
+                None
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_closes_keep_alive_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        for idle_timeout in [Some(Duration::from_millis(100)), None] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let app = Router::new().route("/ping", get(|| async { "pong" }));
+            tokio::spawn(async move {
+                serve_until_shutdown(
+                    listener,
+                    app,
+                    std::future::pending(),
+                    Duration::from_secs(1),
+                    idle_timeout,
+                    &[],
+                )
+                .await
+            });
+
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buffer = [0; 1024];
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(String::from_utf8_lossy(&buffer[..read]).ends_with("pong"));
+
+            // the connection is kept alive, and closed once idle for too long (if ever)
+            let next_read =
+                tokio::time::timeout(Duration::from_millis(500), stream.read(&mut buffer)).await;
+            match idle_timeout {
+                Some(_) => assert_eq!(next_read.expect("Should be closed").unwrap(), 0),
+                None => assert!(next_read.is_err()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_abandons_hung_requests_and_flushes() {
+        let kv_store = KVStore::new(3, ".quache-server-shutdown/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/hang", get(std::future::pending::<StatusCode>));
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let stores = vec![kv_store.clone()];
+        let server = tokio::spawn(async move {
+            serve_until_shutdown(
+                listener,
+                app,
+                async {
+                    let _ = shutdown_rx.await;
+                },
+                Duration::from_millis(200),
+                None,
+                &stores,
+            )
+            .await
+        });
+
+        let hung = tokio::spawn(reqwest::get(format!("http://{}/hang", addr)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("Shutdown should not wait for the hung request")
+            .unwrap()
+            .expect("Should be able to shut down");
+        hung.abort();
+
+        let shard_file =
+            crate::core::shard_file_path(".quache-server-shutdown/", kv_store.find_shard("hey"));
+        assert!(std::fs::exists(&shard_file).unwrap());
+        // the background flushes stay away from the directory until the process exits
+        assert!(kv_store.is_flushing_paused());
+
+        cleanup_test_directory(".quache-server-shutdown/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_receives_expirations() {
+        use futures_util::StreamExt;
+
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(AppState::new(kv_store.clone()));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}/subscribe?pattern=session", addr))
+                .await
+                .expect("Should be able to subscribe");
+        kv_store
+            .put(
+                "session".to_string(),
+                serde_json::Value::from(1),
+                Some(0.001),
+            ) // 1 millisecond ttl
+            .expect("Should be able to put key");
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        assert_eq!(kv_store.cleanup().expect("Should be able to clean up"), 1);
+
+        let mut received = vec![];
+        for _ in 0..2 {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("Should receive an event in time")
+                .unwrap()
+                .unwrap();
+            let event: ChangeEvent = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            received.push(event);
+        }
+        assert_eq!(received[0].op, crate::events::ChangeOp::Put);
+        assert_eq!(
+            received[1],
+            ChangeEvent {
+                op: crate::events::ChangeOp::Expire,
+                key: "session".to_string(),
+                value: None,
+                expires_at_ms: None,
+                seq: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume_flush_endpoints() {
+        let kv_store = KVStore::new(3, ".quache-server-pause/".to_string())
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (uri, paused) in [("/admin/flush/pause", true), ("/admin/flush/resume", false)] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("POST")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            assert_eq!(kv_store.is_flushing_paused(), paused);
+
+            // explicit flushes leave the directory alone while paused as well
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/admin/flush")
+                        .method("POST")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let expected = if paused {
+                StatusCode::CONFLICT
+            } else {
+                StatusCode::OK
+            };
+            assert_eq!(response.status(), expected);
+        }
+
+        cleanup_test_directory(".quache-server-pause/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_admin_flush_progress() {
+        let kv_store = KVStore::new(3, ".quache-server-flush-progress/".to_string())
+            .expect("Should be able to create test");
+        for i in 0..10 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (uri, method) in [("/admin/flush", "POST"), ("/admin/flush/status", "GET")] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method(method)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let progress: FlushProgress = serde_json::from_slice(&bytes).unwrap();
+            assert!(!progress.running);
+            assert_eq!(progress.shards_total, 3);
+            assert_eq!(progress.shards_flushed, 3);
+            assert!(progress.bytes_written > 0);
+        }
+
+        cleanup_test_directory(".quache-server-flush-progress/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_admin_flush_dry_run() {
+        let kv_store = KVStore::new(3, ".quache-server-flush-dry-run/".to_string())
+            .expect("Should be able to create test")
+            .with_pretty_disk(true);
+        for i in 0..10 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::json!({ "n": i }), None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store.clone()));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/admin/flush/dry-run")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let dry_run: FlushDryRunResponse = serde_json::from_slice(&bytes).unwrap();
+        // nothing was written
+        for shard in 0..3 {
+            let path = crate::core::shard_file_path(".quache-server-flush-dry-run/", shard);
+            assert!(!std::fs::exists(path).unwrap());
+        }
+
+        kv_store.to_disk().expect("Should be able to flush");
+        let actual: Vec<ShardSize> = (0..3)
+            .map(|shard| {
+                let path = crate::core::shard_file_path(".quache-server-flush-dry-run/", shard);
+                let bytes = std::fs::metadata(path).unwrap().len() as usize;
+                ShardSize { shard, bytes }
+            })
+            .collect();
+        assert_eq!(dry_run.shards, actual);
+        assert_eq!(
+            dry_run.total_bytes,
+            actual.iter().map(|size| size.bytes).sum::<usize>()
+        );
+
+        cleanup_test_directory(".quache-server-flush-dry-run/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_admin_cleanup_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-admin-cleanup/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), Some(0.001)) // 1 millisecond ttl
+            .expect("Should be able to put key");
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to put key");
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        // no cleanup loop runs here, so the expired entry stays until cleanup is requested
+        assert!(kv_store.entry("hey".to_string()).is_ok());
+
+        let mut app = router(AppState::new(kv_store.clone()));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/admin/cleanup")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let cleanup_response: CleanupResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(cleanup_response.evicted, 1);
+        assert!(kv_store.entry("hey".to_string()).is_err());
+        assert!(kv_store.entry("hello".to_string()).is_ok());
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/info")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: InfoResponse = serde_json::from_slice(&bytes).unwrap();
+        let started = info
+            .cleanup
+            .last_started_ms
+            .expect("cleanup should have started");
+        let finished = info
+            .cleanup
+            .last_finished_ms
+            .expect("cleanup should have finished");
+        assert!(started <= finished);
+        assert_eq!(info.cleanup.last_evicted, 1);
+        assert_eq!(info.cleanup.total_evicted, 1);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/metrics")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let metrics: MetricsSnapshot = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(metrics.evicted, 1);
+        assert_eq!(metrics.last_cleanup_ms, Some(finished));
+
+        cleanup_test_directory(".quache-server-admin-cleanup/".to_string());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_admin_restore_endpoint() {
+        let backup = KVStore::new(3, ".quache-server-restore/backup/".to_string())
+            .expect("Should be able to create test");
+        for i in 0..10 {
+            backup
+                .put(format!("key-{}", i), serde_json::Value::from(100 + i), None)
+                .expect("Should be able to put key");
+        }
+        backup.to_disk().expect("Should be able to flush to disk");
+        let kv_store = KVStore::new(3, ".quache-server-restore/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("key-1".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        // without a backup directory, restores are disabled
+        let response = router(AppState::new(kv_store.clone()))
+            .call(
+                Request::builder()
+                    .uri("/admin/restore")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"directory": "backup"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let mut state = AppState::new(kv_store);
+        state.backup_dir = Some(".quache-server-restore/".to_string());
+        let app = router(state);
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut app = app.clone();
+                tokio::spawn(async move {
+                    for _ in 0..50 {
+                        let response = app
+                            .call(
+                                Request::builder()
+                                    .uri("/kv/key-1")
+                                    .method("GET")
+                                    .body(Body::empty())
+                                    .unwrap(),
+                            )
+                            .await
+                            .unwrap();
+                        assert_eq!(response.status(), StatusCode::OK);
+                        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                        let get_response: GetResponse = serde_json::from_slice(&bytes).unwrap();
+                        assert!([1, 101].contains(&get_response.value.as_i64().unwrap()));
+                    }
+                })
+            })
+            .collect();
+        let response = app
+            .clone()
+            .call(
+                Request::builder()
+                    .uri("/admin/restore")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"directory": "backup/"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let restore_response: RestoreResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(restore_response.restored, 10);
+        for reader in readers {
+            reader
+                .await
+                .expect("Reads should not fail during the restore");
+        }
+
+        let response = app
+            .clone()
+            .call(
+                Request::builder()
+                    .uri("/kv/key-7")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let get_response: GetResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(get_response.value, serde_json::Value::from(107));
+
+        // missing backups and paths leaving the backup directory are rejected
+        for directory in ["missing", "../backup", "/tmp", ""] {
+            let response = app
+                .clone()
+                .call(
+                    Request::builder()
+                        .uri("/admin/restore")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(
+                            serde_json::json!({"directory": directory}).to_string(),
+                        ))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", directory);
+        }
+
+        cleanup_test_directory(".quache-server-restore/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_prefix_stream_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(3)
+            .build()
+            .expect("Should be able to create test");
+        for (key, ttl) in [
+            ("users:1", None),
+            ("users:2", None),
+            ("users:3", Some(0.001)),
+            ("orders:1", None),
+        ] {
+            kv_store
+                .put(key.to_string(), serde_json::json!({"id": key}), ttl)
+                .expect("Should be able to put key");
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        let mut app = router(AppState::new(kv_store.clone()));
+
+        for (prefix, expected_keys) in [
+            ("users:", vec!["users:1", "users:2"]),
+            ("orders:", vec!["orders:1"]),
+            ("none:", vec![]),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/_kv/prefix/{}/stream", prefix))
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let mut body = response.into_body().into_data_stream();
+            let mut bytes = vec![];
+            while let Some(chunk) = body.next().await {
+                bytes.extend_from_slice(&chunk.unwrap());
+            }
+            let mut entries: Vec<PrefixEntry> = serde_json::from_slice(&bytes).unwrap();
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+            let expected: Vec<PrefixEntry> = expected_keys
+                .into_iter()
+                .map(|key| PrefixEntry {
+                    key: key.to_string(),
+                    value: serde_json::json!({"id": key}),
+                })
+                .collect();
+            assert_eq!(entries, expected, "{}", prefix);
+        }
+
+        // pages are capped to the maximum page size, clients follow the cursor header
+        let mut state = AppState::new(kv_store);
+        state.max_page_size = 1;
+        let mut app = router(state);
+        let mut keys = vec![];
+        let mut uri = "/_kv/prefix/users:/stream?limit=10".to_string();
+        loop {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(&uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let cursor = response
+                .headers()
+                .get(NEXT_CURSOR_HEADER)
+                .map(|cursor| cursor.to_str().unwrap().to_string());
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let entries: Vec<PrefixEntry> = serde_json::from_slice(&bytes).unwrap();
+            assert!(entries.len() <= 1);
+            keys.extend(entries.into_iter().map(|entry| entry.key));
+            match cursor {
+                Some(cursor) => {
+                    uri = format!(
+                        "/_kv/prefix/users:/stream?limit=10&cursor={}",
+                        utf8_percent_encode(&cursor, KEY_PATH_SEGMENT)
+                    )
+                }
+                None => break,
+            }
+        }
+        keys.sort();
+        assert_eq!(keys, vec!["users:1", "users:2"]);
+    }
+
+    #[tokio::test]
+    async fn test_import_ndjson_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-import/".to_string())
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store.clone()));
+        // lines are split across chunks, as they would be by the network
+        let chunks = vec![
+            "{\"key\": \"a\", \"value\": 1}\n{\"key\": \"b\", \"val",
+            "ue\": [1, 2], \"ttl\": 60}\n\nnot json\n{\"value\": 3}\n",
+            "{\"key\": \"c\", \"value\": {\"nested\": true}}",
+        ];
+        let body = Body::from_stream(futures_util::stream::iter(
+            chunks.into_iter().map(Ok::<_, std::io::Error>),
+        ));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/_kv/import/ndjson")
+                    .method("POST")
+                    .header("content-type", "application/x-ndjson")
+                    .body(body)
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let import_response: ImportResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(import_response.imported, 3);
+        let error_lines: Vec<usize> = import_response.errors.iter().map(|e| e.line).collect();
+        assert_eq!(error_lines, vec![4, 5]);
+
+        assert_eq!(
+            kv_store.get("a".to_string()).unwrap(),
+            serde_json::Value::from(1)
+        );
+        assert_eq!(
+            kv_store.get("b".to_string()).unwrap(),
+            serde_json::json!([1, 2])
+        );
+        assert!(kv_store.get_with_ttl("b".to_string()).unwrap().1.is_some());
+        assert_eq!(
+            kv_store.get("c".to_string()).unwrap(),
+            serde_json::json!({"nested": true})
+        );
+
+        cleanup_test_directory(".quache-server-import/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_post_location_header() {
+        let kv_store = KVStore::new(3, ".quache-server-location/".to_string())
+            .expect("Should be able to create test");
+        let named_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        let mut state = AppState::new(kv_store);
+        state.stores = HashMap::from([("named".to_string(), named_store)]);
+        let mut app = router(state);
+
+        for (uri, expected) in [
+            ("/kv", "/kv/user%201%2Fprofile%3Fv%3D2%25_%C3%A9.~-"),
+            (
+                "/store/named/kv",
+                "/store/named/kv/user%201%2Fprofile%3Fv%3D2%25_%C3%A9.~-",
+            ),
+        ] {
+            let request_body = serde_json::to_string(&PutRequest {
+                key: "user 1/profile?v=2%_é.~-".to_string(),
+                value: serde_json::Value::from(1),
+                ttl: None,
+                seq: None,
+            })
+            .unwrap();
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(request_body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let location = response.headers()[header::LOCATION].to_str().unwrap();
+            assert_eq!(location, expected);
+
+            // the header points to the created resource
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(location)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        cleanup_test_directory(".quache-server-location/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_rotate_api_key() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        let mut state = AppState::new(kv_store);
+        state.api_keys = Some(ApiKeys::new(
+            "old".to_string(),
+            std::time::Duration::from_millis(200),
+        ));
+        let mut app = router(state);
+
+        async fn status(
+            app: &mut Router,
+            method: &str,
+            uri: &str,
+            key: Option<&str>,
+        ) -> StatusCode {
+            let mut request = Request::builder().uri(uri).method(method);
+            if let Some(key) = key {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", key));
+            }
+            let body = if method == "POST" {
+                request = request.header("content-type", "application/json");
+                Body::from(r#"{"key": "new"}"#)
+            } else {
+                Body::empty()
+            };
+            app.call(request.body(body).unwrap())
+                .await
+                .unwrap()
+                .status()
+        }
+
+        assert_eq!(
+            status(&mut app, "GET", "/info", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&mut app, "GET", "/info", Some("new")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&mut app, "GET", "/info", Some("old")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&mut app, "GET", "/ready", None).await,
+            StatusCode::OK
+        );
+
+        assert_eq!(
+            status(&mut app, "POST", "/admin/rotate-key", Some("old")).await,
+            StatusCode::OK
+        );
+        // both keys work during the overlap, but only the new one can rotate again
+        assert_eq!(
+            status(&mut app, "GET", "/info", Some("old")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&mut app, "GET", "/info", Some("new")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&mut app, "POST", "/admin/rotate-key", Some("old")).await,
+            StatusCode::FORBIDDEN
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        assert_eq!(
+            status(&mut app, "GET", "/info", Some("old")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&mut app, "GET", "/info", Some("new")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_above_max_ttl_is_rejected() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test")
+            .with_max_ttl(Some(60_f64), crate::core::TtlCapPolicy::Reject);
+        let mut app = router(AppState::new(kv_store));
+        for (body, expected_status) in [
+            (r#"{"value": 1, "ttl": 120}"#, StatusCode::BAD_REQUEST),
+            (r#"{"value": 1, "ttl": 30}"#, StatusCode::CREATED),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv/hey")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strict_ttl_seconds() {
+        for (strict, body, expected_status) in [
+            (true, r#"{"value": 1, "ttl": 1.5}"#, StatusCode::BAD_REQUEST),
+            (true, r#"{"value": 1, "ttl": -1}"#, StatusCode::BAD_REQUEST),
+            (true, r#"{"value": 1, "ttl": 30}"#, StatusCode::CREATED),
+            (true, r#"{"value": 1}"#, StatusCode::CREATED),
+            (false, r#"{"value": 1, "ttl": 1.5}"#, StatusCode::CREATED),
+        ] {
+            let kv_store = KVStore::builder()
+                .in_memory()
+                .build()
+                .expect("Should be able to create test")
+                .with_strict_ttl_seconds(strict);
+            let mut app = router(AppState::new(kv_store));
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv/hey")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", body);
+        }
+    }
+
+    #[cfg(feature = "schema")]
+    #[tokio::test]
+    async fn test_put_validates_schema() {
+        let schemas = crate::schema::Schemas::from_value(&serde_json::json!({
+            "users:": {
+                "type": "object",
+                "properties": {"name": {"type": "string"}, "age": {"type": "integer"}},
+                "required": ["name"],
+            }
+        }))
+        .expect("Should be able to compile schemas");
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test")
+            .with_schemas(schemas);
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (value, expected_status) in [
+            (
+                serde_json::json!({"name": "ada", "age": 36}),
+                StatusCode::CREATED,
+            ),
+            (
+                serde_json::json!({"age": "old"}),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ] {
+            let request_body = serde_json::to_string(&PutRequest {
+                key: "users:1".to_string(),
+                value,
+                ttl: None,
+                seq: None,
+            })
+            .unwrap();
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(request_body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status);
+            if expected_status == StatusCode::UNPROCESSABLE_ENTITY {
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let message = String::from_utf8(bytes.to_vec()).unwrap();
+                assert!(message.contains("/age"), "{}", message);
+                assert!(message.contains("name"), "{}", message);
+            }
+        }
+        // the rejected write left the conforming value in place
+        assert_eq!(
+            kv_store.get("users:1".to_string()).unwrap(),
+            serde_json::json!({"name": "ada", "age": 36})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_endpoints() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        kv_store
+            .put("number".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+
+        let text = "hello \"world\"\nsecond line";
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/greeting/text?ttl=60")
+                    .method("PUT")
+                    .header("content-type", "text/plain")
+                    .body(Body::from(text))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            kv_store
+                .get("greeting".to_string())
+                .expect("Should be able to get key"),
+            serde_json::Value::from(text)
+        );
+
+        for (key, expected_status, expected_body) in [
+            ("greeting", StatusCode::OK, Some(text)),
+            ("number", StatusCode::CONFLICT, None),
+            ("missing", StatusCode::NOT_FOUND, None),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/{}/text", key))
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status);
+            if let Some(expected_body) = expected_body {
+                assert_eq!(
+                    response.headers().get(header::CONTENT_TYPE).unwrap(),
+                    "text/plain; charset=utf-8"
+                );
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                assert_eq!(bytes, expected_body.as_bytes());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_text_range_requests() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        kv_store
+            .put(
+                "log".to_string(),
+                serde_json::Value::from("hello world"),
+                None,
+            )
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store));
+
+        for (range, expected_status, expected_content_range, expected_body) in [
+            (
+                "bytes=0-4",
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 0-4/11"),
+                "hello",
+            ),
+            (
+                "bytes=-5",
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 6-10/11"),
+                "world",
+            ),
+            (
+                "bytes=0-",
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 0-10/11"),
+                "hello world",
+            ),
+            (
+                "bytes=6-100",
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 6-10/11"),
+                "world",
+            ),
+            (
+                "bytes=11-20",
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                Some("bytes */11"),
+                "",
+            ),
+            // multiple ranges aren't supported, so the whole value is served
+            ("bytes=0-1,3-4", StatusCode::OK, None, "hello world"),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv/log/text")
+                        .method("GET")
+                        .header(header::RANGE, range)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", range);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::CONTENT_RANGE)
+                    .map(|v| v.to_str().unwrap()),
+                expected_content_range,
+                "{}",
+                range
+            );
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(bytes, expected_body.as_bytes(), "{}", range);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_content_types() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store));
+        for (uri, expected_status, expected_type) in [
+            ("/kv/hey", StatusCode::OK, "application/json"),
+            (
+                "/kv/missing",
+                StatusCode::NOT_FOUND,
+                "text/plain; charset=utf-8",
+            ),
+            ("/metrics", StatusCode::OK, "application/json"),
+            ("/_kv/random", StatusCode::OK, "application/json"),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", uri);
+            assert_eq!(
+                response.headers().get(header::CONTENT_TYPE).unwrap(),
+                expected_type,
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rename_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(3)
+            .build()
+            .expect("Should be able to create test");
+        let other_shard = (0..20)
+            .map(|i| format!("new{}", i))
+            .find(|k| kv_store.find_shard(k) != kv_store.find_shard("old"))
+            .expect("Some key should be in another shard");
+        for (key, value) in [("old", 1), (other_shard.as_str(), 2)] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(value), None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (query, expected_status) in [
+            ("?nx=true", StatusCode::CONFLICT),
+            ("", StatusCode::NO_CONTENT),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/old/rename{}", query))
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(format!(r#"{{"to": "{}"}}"#, other_shard)))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", query);
+        }
+        assert_eq!(kv_store.get(other_shard).unwrap(), 1);
+        assert!(kv_store.get("old".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_batch_ttl_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        for (key, ttl) in [
+            ("persistent", None),
+            ("expiring", Some(60_f64)),
+            ("stale", Some(0.001)),
+        ] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(key), ttl)
+                .expect("Should be able to put key");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let mut state = AppState::new(kv_store);
+        state.read_only = true;
+        let mut app = router(state);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/_kv/batch/ttl")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        r#"{"keys": ["persistent", "missing", "expiring", "stale"]}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let batch: BatchTtlResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(batch.ttls.len(), 2);
+        assert_eq!(batch.ttls["persistent"], None);
+        let remaining = batch.ttls["expiring"].expect("expiring key should have a TTL");
+        assert!(remaining > 59_f64 && remaining <= 60_f64);
+        assert_eq!(batch.missing, vec!["missing", "stale"]);
+    }
+
+    #[tokio::test]
+    async fn test_multi_key_endpoints_dont_shadow_keys() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store));
+        for key in [
+            "mget", "random", "drain", "export", "batch", "tx", "query", "import", "prefix",
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/{}", key))
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(r#"{"value": 1, "ttl": 60}"#))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED, "{}", key);
+            for uri in [format!("/kv/{}", key), format!("/kv/{}/ttl", key)] {
+                let response = app
+                    .call(
+                        Request::builder()
+                            .uri(&uri)
+                            .method("GET")
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mget_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        for (key, ttl) in [("present", None), ("stale", Some(0.001))] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(key), ttl)
+                .expect("Should be able to put key");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let mut state = AppState::new(kv_store);
+        state.read_only = true;
+        let mut app = router(state);
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/_kv/mget")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"keys": ["present", "missing", "stale"]}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "results": {"present": "present", "missing": null, "stale": null}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_contains_endpoint() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        for (key, tags) in [
+            ("apple", serde_json::json!(["red", "fruit"])),
+            ("sky", serde_json::json!(["blue"])),
+            ("cherry", serde_json::json!(["red"])),
+            ("query", serde_json::json!([1, 2])),
+        ] {
+            kv_store
+                .put(key.to_string(), serde_json::json!({ "tags": tags }), None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (query, expected_status, expected_keys) in [
+            (
+                "path=/tags&value=%22red%22",
+                StatusCode::OK,
+                vec!["apple", "cherry"],
+            ),
+            ("path=/tags&value=2", StatusCode::OK, vec!["query"]),
+            ("path=/tags&value=%22green%22", StatusCode::OK, vec![]),
+            ("path=/tags&value=red", StatusCode::BAD_REQUEST, vec![]),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/_kv/query/contains?{}", query))
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", query);
+            if expected_status == StatusCode::OK {
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let query_response: QueryResponse = serde_json::from_slice(&bytes).unwrap();
+                assert_eq!(query_response.keys, expected_keys);
+            }
+        }
+
+        // pages are capped to the maximum page size
+        let mut state = AppState::new(kv_store.clone());
+        state.max_page_size = 1;
+        let mut capped = router(state);
+        let mut keys = vec![];
+        let base = "/_kv/query/contains?path=/tags&value=%22red%22&limit=10";
+        let mut uri = base.to_string();
+        loop {
+            let response = capped
+                .call(
+                    Request::builder()
+                        .uri(&uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let page: QueryResponse = serde_json::from_slice(&bytes).unwrap();
+            assert!(page.keys.len() <= 1);
+            keys.extend(page.keys);
+            match page.next_cursor {
+                Some(cursor) => uri = format!("{}&cursor={}", base, cursor),
+                None => break,
+            }
+        }
+        assert_eq!(keys, vec!["apple", "cherry"]);
+
+        // a key named like the route is still reachable
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/query")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_error_body_truncates_long_keys() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri(format!("/kv/{}", "k".repeat(1000)))
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            String::from_utf8(bytes.to_vec()).unwrap(),
+            format!(
+                "Error: key {}… not found",
+                "k".repeat(crate::core::DEFAULT_MAX_KEY_ECHO)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_entry_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-entry/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), Some(1.5))
+            .expect("Should be able to put key");
+
+        let mut app = router(AppState::new(kv_store));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/debug/entry/hey")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let entry_response: EntryResponse = serde_json::from_slice(&bytes).unwrap();
+        // TTLs are given in seconds, but stored in milliseconds
+        assert_eq!(entry_response.entry.ttl_ms(), 1500);
+        assert_eq!(entry_response.entry.value(), &serde_json::Value::from(1));
+        assert!(entry_response.remaining_ms.unwrap() <= 1500);
+        assert!(!entry_response.expired);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/debug/entry/missing")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
-async fn handle_post(
-    State(state): State<AppState>,
-    Json(payload): Json<PutRequest>,
-) -> Result<StatusCode, AppError> {
-    state
-        .kv_store
-        .put(payload.key, payload.value, payload.ttl)?;
-    Ok(StatusCode::CREATED)
-}
+        cleanup_test_directory(".quache-server-entry/".to_string());
+    }
 
-async fn handle_get(
-    State(state): State<AppState>,
-    Path(key): Path<String>,
-) -> Result<Json<GetResponse>, AppError> {
-    let value = state.kv_store.get(key)?;
-    Ok(Json(GetResponse { value }))
-}
+    #[tokio::test]
+    async fn test_debug_locate_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-locate/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
 
-async fn handle_delete(
-    State(state): State<AppState>,
-    Path(key): Path<String>,
-) -> Result<StatusCode, AppError> {
-    state.kv_store.delete(key)?;
-    Ok(StatusCode::NO_CONTENT)
-}
+        let state: AppState = AppState::new(kv_store.clone());
+        let mut app = router(state);
+        for (key, should_exist) in [
+            ("hey", true),
+            ("thisisaverylongkey", false),
+            ("notthekindofthingyouwouldfind", false),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/debug/locate/{}", key))
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let locate_response: LocateResponse = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(locate_response.key, key);
+            assert_eq!(locate_response.shard, kv_store.find_shard(key));
+            assert_eq!(locate_response.exists, should_exist);
+        }
 
-impl KVStoreServer {
-    pub fn new(port: Option<u16>, host: Option<String>) -> Self {
-        let server_port = match port {
-            Some(n) => n,
-            None => DEFAULT_PORT,
-        };
-        let server_host = match host {
-            Some(h) => {
-                IpAddr::V4(Ipv4Addr::from_str(&h).expect("You should provide a valid IPv4 address"))
-            }
-            None => IpAddr::V4(
-                Ipv4Addr::from_str(DEFAULT_HOST).expect("You should provide a valid IPv4 address"),
-            ),
-        };
+        cleanup_test_directory(".quache-server-locate/".to_string());
+    }
 
-        Self {
-            port: server_port,
-            host: server_host,
+    #[tokio::test]
+    async fn test_get_cache_control() {
+        let kv_store = KVStore::new(3, ".quache-server-cache-control/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), Some(60_f64))
+            .expect("Should be able to put key");
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(2), None)
+            .expect("Should be able to put key");
+
+        let mut app = router(AppState::new(kv_store));
+        for (key, expected) in [
+            ("hey", ["max-age=60", "max-age=59"]),
+            ("hello", ["no-store"; 2]),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/{}", key))
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            // the remaining TTL is rounded down to whole seconds
+            let cache_control = response.headers().get(header::CACHE_CONTROL).unwrap();
+            assert!(expected.contains(&cache_control.to_str().unwrap()));
         }
-    }
 
-    pub async fn serve(&self, kv_store: KVStore) -> anyhow::Result<()> {
-        let state = AppState { kv_store };
-        let app = Router::new()
-            .route("/kv", post(handle_post))
-            .route("/kv/{key}", get(handle_get).delete(handle_delete))
-            .with_state(state);
-        let addr = SocketAddr::from((self.host, self.port));
-        let listener = tokio::net::TcpListener::bind(addr).await?;
-        println!("Starting to serve on {}:{:?}", self.host, self.port);
-        axum::serve(
-            listener,
-            app.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .await?;
-        Ok(())
+        cleanup_test_directory(".quache-server-cache-control/".to_string());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::usize;
+    #[tokio::test]
+    async fn test_get_expired_gone() {
+        let kv_store = KVStore::new(3, ".quache-server-gone/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), Some(0.001)) // 1 millisecond ttl
+            .expect("Should be able to put key");
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(2), Some(0.001))
+            .expect("Should be able to put key");
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
 
-    use super::*;
+        let mut state = AppState::new(kv_store);
+        state.expired_gone = true;
+        let mut app = router(state.clone());
+        let expired_response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hey")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(expired_response.status(), StatusCode::GONE);
+        let never_present_response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/never-present")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(never_present_response.status(), StatusCode::NOT_FOUND);
 
-    use axum::{
-        body::{Body, to_bytes},
-        http::{Request, StatusCode},
-    };
-    use tower::Service;
+        // without the flag, expired keys are indistinguishable from missing ones
+        state.expired_gone = false;
+        let mut app = router(state);
+        let expired_response = app
+            .call(
+                Request::builder()
+                    .uri("/kv/hello")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(expired_response.status(), StatusCode::NOT_FOUND);
 
-    fn cleanup_test_directory(directory_name: String) {
-        if std::fs::exists(&directory_name).expect("Should be able to check directory existence") {
-            std::fs::remove_dir_all(directory_name)
-                .expect("Should be able to remove directory content");
-        }
+        cleanup_test_directory(".quache-server-gone/".to_string());
     }
 
     #[tokio::test]
-    async fn test_kv_endpoints() {
-        let kv_store =
-            KVStore::new(3, ".quache-server/".to_string()).expect("Should be able to create test");
+    async fn test_replicate_to_peer() {
+        let replica_store = KVStore::new(3, ".quache-server-replica/".to_string())
+            .expect("Should be able to create test");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica_addr = listener.local_addr().unwrap();
+        let replica_app = router(AppState::new(replica_store.clone()));
+        tokio::spawn(async move { axum::serve(listener, replica_app).await });
 
-        let state: AppState = AppState { kv_store };
-        let mut app = Router::new()
-            .route("/kv", post(handle_post))
-            .route("/kv/{key}", get(handle_get).delete(handle_delete))
-            .with_state(state);
+        let primary_store = KVStore::new(3, ".quache-server-primary/".to_string())
+            .expect("Should be able to create test");
+        let replicator = Replicator::new(vec![format!("http://{}", replica_addr)]).unwrap();
+        replicator.start(&primary_store);
+        let mut app = router(AppState::new(primary_store.clone()));
         let request_body = serde_json::to_string(&PutRequest {
-            key: "hello".to_string(),
+            key: "hello world".to_string(),
             value: serde_json::Value::from(1),
             ttl: None,
+            seq: None,
         })
         .unwrap();
         let response = app
@@ -170,47 +5111,554 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::CREATED);
-        let get_response = app
+
+        let mut replicated = None;
+        for _ in 0..100 {
+            if let Ok(value) = replica_store.get("hello world".to_string()) {
+                replicated = Some(value);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(replicated, Some(serde_json::Value::from(1)));
+
+        // every kind of write is forwarded, in order, with its sequence number
+        for _ in 0..20 {
+            primary_store
+                .incr_bounded("counter".to_string(), 1, None, None)
+                .expect("Should be able to increment");
+        }
+        primary_store
+            .rename("counter".to_string(), "total".to_string(), false)
+            .expect("Should be able to rename");
+        let mut replicated = None;
+        for _ in 0..100 {
+            if let Ok(value) = replica_store.get("total".to_string()) {
+                replicated = Some(value);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(replicated, Some(serde_json::Value::from(20)));
+        assert!(replica_store.get("counter".to_string()).is_err());
+        assert_eq!(
+            replica_store.entry("total".to_string()).unwrap().seq(),
+            primary_store.entry("total".to_string()).unwrap().seq()
+        );
+
+        let delete_response = app
             .call(
                 Request::builder()
-                    .uri("/kv/hello")
+                    .uri("/kv/hello%20world")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(delete_response.status(), StatusCode::NO_CONTENT);
+        let mut deleted = false;
+        for _ in 0..100 {
+            if replica_store.get("hello world".to_string()).is_err() {
+                deleted = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(deleted);
+        assert_eq!(replicator.failures(), 0);
+
+        cleanup_test_directory(".quache-server-replica/".to_string());
+        cleanup_test_directory(".quache-server-primary/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_sequenced_put() {
+        let kv_store = KVStore::new(3, ".quache-server-seq/".to_string())
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (seq, value, expected_status) in [
+            (2, 2, StatusCode::CREATED),
+            (1, 1, StatusCode::CONFLICT),
+            (3, 3, StatusCode::CREATED),
+        ] {
+            let request_body = serde_json::to_string(&PutRequest {
+                key: "hello".to_string(),
+                value: serde_json::Value::from(value),
+                ttl: None,
+                seq: Some(seq),
+            })
+            .unwrap();
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(request_body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status);
+        }
+        assert_eq!(
+            kv_store.get("hello".to_string()).unwrap(),
+            serde_json::Value::from(3)
+        );
+
+        cleanup_test_directory(".quache-server-seq/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_only_extend_put() {
+        let kv_store = KVStore::new(3, ".quache-server-only-extend/".to_string())
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (ttl, value, expected_status) in [
+            (10_f64, 1, StatusCode::CREATED), // new key
+            (60_f64, 2, StatusCode::CREATED), // extends
+            (5_f64, 3, StatusCode::CONFLICT), // would shorten
+        ] {
+            let request_body = serde_json::to_string(&PutRequest {
+                key: "lease".to_string(),
+                value: serde_json::Value::from(value),
+                ttl: Some(ttl),
+                seq: None,
+            })
+            .unwrap();
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv?only_extend=true")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(request_body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status);
+        }
+        assert_eq!(
+            kv_store.get("lease".to_string()).unwrap(),
+            serde_json::Value::from(2)
+        );
+
+        cleanup_test_directory(".quache-server-only-extend/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_snapshot_partitions_operations() {
+        let kv_store = KVStore::new(3, ".quache-server-metrics/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+
+        async fn read_metrics(app: &mut Router, method: &str, uri: &str) -> MetricsSnapshot {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(uri)
+                        .method(method)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice(&bytes).unwrap()
+        }
+
+        let _ = kv_store.get("hello".to_string());
+        let _ = kv_store.get("hello".to_string());
+        let _ = kv_store.get("missing".to_string());
+        let first = read_metrics(&mut app, "POST", "/metrics/snapshot").await;
+        assert_eq!(first.hits, 2);
+        assert_eq!(first.misses, 1);
+        // the put made before the first snapshot
+        assert_eq!(first.value_sizes.under_256b, 1);
+
+        let _ = kv_store.get("hello".to_string());
+        let _ = kv_store.get("missing".to_string());
+        let _ = kv_store.get("missing".to_string());
+        // reading /metrics does not reset the counters
+        let current = read_metrics(&mut app, "GET", "/metrics").await;
+        assert_eq!(
+            current,
+            MetricsSnapshot {
+                hits: 1,
+                misses: 2,
+                ..Default::default()
+            }
+        );
+        let second = read_metrics(&mut app, "POST", "/metrics/snapshot").await;
+        assert_eq!(
+            second,
+            MetricsSnapshot {
+                hits: 1,
+                misses: 2,
+                ..Default::default()
+            }
+        );
+        let after = read_metrics(&mut app, "GET", "/metrics").await;
+        assert_eq!(after, MetricsSnapshot::default());
+
+        cleanup_test_directory(".quache-server-metrics/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_list_keys_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-list/".to_string())
+            .expect("Should be able to create test")
+            .with_case_insensitive_keys(true);
+        for key in ["Hey", "hello", "thisisaverylongkey"] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(1), None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv?prefix=h")
                     .method("GET")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(get_response.status(), StatusCode::OK);
-        let bytes = to_bytes(get_response.into_body(), usize::MAX)
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut list_response: ListKeysResponse = serde_json::from_slice(&bytes).unwrap();
+        list_response.keys.sort();
+        assert_eq!(list_response.keys, vec!["Hey", "hello"]);
+
+        cleanup_test_directory(".quache-server-list/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_list_keys_page_size_cap() {
+        let kv_store = KVStore::new(3, ".quache-server-page/".to_string())
+            .expect("Should be able to create test");
+        for i in 0..5 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to put key");
+        }
+        let mut state = AppState::new(kv_store);
+        state.max_page_size = 2;
+        let mut app = router(state);
+
+        let mut pages = vec![];
+        let mut uri = "/kv?limit=100".to_string();
+        loop {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(&uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let page: ListKeysResponse = serde_json::from_slice(&bytes).unwrap();
+            assert!(page.keys.len() <= 2);
+            pages.push(page.keys);
+            match page.next_cursor {
+                Some(cursor) => uri = format!("/kv?limit=100&cursor={}", cursor),
+                None => break,
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                vec!["key-0", "key-1"],
+                vec!["key-2", "key-3"],
+                vec!["key-4"]
+            ]
+        );
+
+        cleanup_test_directory(".quache-server-page/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_list_keys_by_offset() {
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .shards(3)
+            .build()
+            .expect("Should be able to create test");
+        for i in 0..5 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to put key");
+        }
+        let mut state = AppState::new(kv_store);
+        state.max_page_size = 2;
+        let mut app = router(state);
+
+        let mut keys = vec![];
+        let mut uri = "/kv?prefix=key-&offset=0&limit=100".to_string();
+        loop {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(&uri)
+                        .method("GET")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let page: ListKeysResponse = serde_json::from_slice(&bytes).unwrap();
+            assert!(page.keys.len() <= 2);
+            assert!(page.next_cursor.is_none());
+            keys.extend(page.keys);
+            match page.next_offset {
+                Some(offset) => uri = format!("/kv?prefix=key-&offset={}&limit=100", offset),
+                None => break,
+            }
+        }
+        keys.sort();
+        assert_eq!(keys, vec!["key-0", "key-1", "key-2", "key-3", "key-4"]);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/kv?offset=0&cursor=key-1")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
             .await
             .unwrap();
-        let get_response_json: GetResponse = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(get_response_json.value, serde_json::Value::from(1));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 
-        let delete_response = app
+    #[tokio::test]
+    async fn test_retry_after_on_service_unavailable() {
+        let app = Router::new()
+            .route(
+                "/busy",
+                get(|| async { (StatusCode::SERVICE_UNAVAILABLE, "busy") }),
+            )
+            .route("/ok", get(|| async { StatusCode::OK }));
+        let mut app = with_retry_after(app, 7);
+        let busy_response = app
             .call(
                 Request::builder()
-                    .uri("/kv/hello")
-                    .method("DELETE")
+                    .uri("/busy")
+                    .method("GET")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(delete_response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(busy_response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            busy_response.headers().get(header::RETRY_AFTER).unwrap(),
+            "7"
+        );
+        let ok_response = app
+            .call(
+                Request::builder()
+                    .uri("/ok")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(ok_response.status(), StatusCode::OK);
+        assert!(ok_response.headers().get(header::RETRY_AFTER).is_none());
+    }
 
-        let get_deleted_response = app
+    #[tokio::test]
+    async fn test_incr_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-incr/".to_string())
+            .expect("Should be able to create test");
+        kv_store
+            .put("text".to_string(), serde_json::Value::from("hello"), None)
+            .expect("Should be able to put key");
+        let mut app = router(AppState::new(kv_store.clone()));
+
+        for (key, body, expected_status, expected) in [
+            (
+                "counter",
+                r#"{"delta": 5}"#,
+                StatusCode::OK,
+                Some((5, false)),
+            ),
+            ("counter", r#"{}"#, StatusCode::OK, Some((6, false))),
+            (
+                "counter",
+                r#"{"delta": 10, "max": 8}"#,
+                StatusCode::OK,
+                Some((8, true)),
+            ),
+            (
+                "counter",
+                r#"{"delta": -20, "min": 0}"#,
+                StatusCode::OK,
+                Some((0, true)),
+            ),
+            ("text", r#"{"delta": 1}"#, StatusCode::CONFLICT, None),
+            (
+                "counter",
+                r#"{"delta": 1, "min": 5, "max": 1}"#,
+                StatusCode::BAD_REQUEST,
+                None,
+            ),
+            (
+                "window",
+                r#"{"delta": 2, "window_ttl": 60}"#,
+                StatusCode::OK,
+                Some((2, false)),
+            ),
+            (
+                "window",
+                r#"{"delta": 3, "window_ttl": 1}"#,
+                StatusCode::OK,
+                Some((5, false)),
+            ),
+            (
+                "window",
+                r#"{"delta": 1, "window_ttl": 60, "max": 3}"#,
+                StatusCode::BAD_REQUEST,
+                None,
+            ),
+        ] {
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri(format!("/kv/{}/incr", key))
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status);
+            if let Some((value, clamped)) = expected {
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let incr_response: IncrResponse = serde_json::from_slice(&bytes).unwrap();
+                assert_eq!(incr_response.value, value);
+                assert_eq!(incr_response.clamped, clamped);
+            }
+        }
+
+        // the window TTL is only set when the counter is created
+        assert_eq!(
+            kv_store
+                .entry("window".to_string())
+                .expect("Should be able to read entry")
+                .ttl_ms(),
+            60_000
+        );
+
+        cleanup_test_directory(".quache-server-incr/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_get_or_insert_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-get-or-insert/".to_string())
+            .expect("Should be able to create test");
+        let mut app = router(AppState::new(kv_store.clone()));
+        for (value, expected) in [(1, 1), (2, 1)] {
+            let request_body = serde_json::to_string(&PutValueRequest {
+                value: serde_json::Value::from(value),
+                ttl: None,
+            })
+            .unwrap();
+            let response = app
+                .call(
+                    Request::builder()
+                        .uri("/kv/hello?get_or_insert=true")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(request_body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let get_response: GetResponse = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(get_response.value, serde_json::Value::from(expected));
+        }
+        // without the flag, the value is overwritten
+        let request_body = serde_json::to_string(&PutValueRequest {
+            value: serde_json::Value::from(3),
+            ttl: None,
+        })
+        .unwrap();
+        let response = app
             .call(
                 Request::builder()
                     .uri("/kv/hello")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(request_body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            kv_store.get("hello".to_string()).unwrap(),
+            serde_json::Value::from(3)
+        );
+
+        cleanup_test_directory(".quache-server-get-or-insert/".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_debug_rebalance_endpoint() {
+        let kv_store = KVStore::new(3, ".quache-server-rebalance/".to_string())
+            .expect("Should be able to create test");
+        for i in 0..20 {
+            kv_store
+                .put(format!("key-{}", i), serde_json::Value::from(i), None)
+                .expect("Should be able to put key");
+        }
+        let mut app = router(AppState::new(kv_store.clone()));
+        let response = app
+            .call(
+                Request::builder()
+                    .uri("/debug/rebalance?shards=7")
                     .method("GET")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(get_deleted_response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let plan: RebalancePlan = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(plan, kv_store.rebalance_plan(7).unwrap());
 
-        cleanup_test_directory(".quache-server/".to_string());
+        let invalid_response = app
+            .call(
+                Request::builder()
+                    .uri("/debug/rebalance?shards=0")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(invalid_response.status(), StatusCode::BAD_REQUEST);
+
+        cleanup_test_directory(".quache-server-rebalance/".to_string());
     }
 }
@@ -0,0 +1,329 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    sync::Mutex,
+    time,
+};
+
+use anyhow::{Result, anyhow};
+use reqwest::{Client, StatusCode, header};
+
+use crate::{
+    core::KVStore,
+    events::{ChangeEvent, ChangeOp},
+};
+
+/// Appends every change of `kv_store` to the file at `path` (created if missing), one
+/// JSON-encoded [`ChangeEvent`] per line. Failed appends are logged, never returned to writers.
+///
+/// Expirations are left out: puts carry their expiry, which followers apply on their own. The
+/// listeners hear of an eviction once its shard is unlocked, so an expiration record could land
+/// after the put of a newer value and delete it on followers.
+pub fn write_wal(kv_store: &KVStore, path: &str) -> Result<()> {
+    let file = Mutex::new(OpenOptions::new().create(true).append(true).open(path)?);
+    kv_store.on_change(move |event| {
+        if event.op == ChangeOp::Expire {
+            return;
+        }
+        let appended = serde_json::to_string(event)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut line| {
+                line.push('\n');
+                // a single write, so that followers never see half a record followed by another
+                let mut file = file.lock().map_err(|e| anyhow!(e.to_string()))?;
+                file.write_all(line.as_bytes())?;
+                Ok(())
+            });
+        if let Err(e) = appended {
+            tracing::error!("Could not append to the WAL: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Applies a WAL record to `kv_store`. Puts are copied as they are, with their expiry (see
+/// [`KVStore::put_copy`]): the primary already enforced its write policies, which the replica
+/// must not apply a second time. Puts whose value expired in the meantime become deletes.
+/// Expirations (only found in WALs written by older versions) are skipped: the expiry of the
+/// put they follow already applies.
+fn apply_event(kv_store: &KVStore, event: ChangeEvent) -> Result<()> {
+    match event.op {
+        ChangeOp::Delete => kv_store.delete(event.key),
+        ChangeOp::Expire => Ok(()),
+        ChangeOp::Put => {
+            let value = event
+                .value
+                .ok_or_else(|| anyhow!("put of key {} has no value", event.key))?;
+            if let Some(expires_at) = event.expires_at_ms {
+                let now = time::SystemTime::now()
+                    .duration_since(time::UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_millis() as u64;
+                if expires_at <= now {
+                    return kv_store.delete(event.key);
+                }
+            }
+            kv_store.put_copy(event.key, value, event.expires_at_ms)
+        }
+    }
+}
+
+/// Keeps a store up to date with a WAL written by [`write_wal`], read from a local path or an
+/// http(s) URL.
+#[derive(Debug)]
+pub struct WalFollower {
+    source: String,
+    /// Bytes of the WAL applied so far
+    offset: usize,
+    /// First record of the WAL, to notice it was replaced by another one
+    first_record: Option<String>,
+    client: Client,
+}
+
+impl WalFollower {
+    pub fn new(source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            offset: 0,
+            first_record: None,
+            client: Client::new(),
+        }
+    }
+
+    fn is_remote(&self) -> bool {
+        self.source.starts_with("http://") || self.source.starts_with("https://")
+    }
+
+    /// Reads the WAL from the offset applied so far on, so that a poll costs what was appended
+    /// since the previous one. Returns the records read and whether the WAL was truncated or
+    /// rotated (its first record changed), in which case it's read whole.
+    async fn fetch(&self) -> Result<(String, bool)> {
+        let head = match &self.first_record {
+            Some(first) if self.offset > 0 => format!("{}\n", first),
+            _ => return Ok((self.read_from(0).await?.unwrap_or_default(), false)),
+        };
+        if self.read_head(head.len()).await? != head.as_bytes() {
+            return Ok((self.read_from(0).await?.unwrap_or_default(), true));
+        }
+        match self.read_from(self.offset).await? {
+            Some(pending) => Ok((pending, false)),
+            // shorter than what was applied
+            None => Ok((self.read_from(0).await?.unwrap_or_default(), true)),
+        }
+    }
+
+    /// The WAL from byte `start` on, or `None` if it's shorter than `start`.
+    async fn read_from(&self, start: usize) -> Result<Option<String>> {
+        if !self.is_remote() {
+            let mut file = fs::File::open(&self.source)?;
+            if file.metadata()?.len() < start as u64 {
+                return Ok(None);
+            }
+            file.seek(SeekFrom::Start(start as u64))?;
+            let mut pending = String::new();
+            file.read_to_string(&mut pending)?;
+            return Ok(Some(pending));
+        }
+        let response = self
+            .client
+            .get(&self.source)
+            .header(header::RANGE, format!("bytes={}-", start))
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::RANGE_NOT_SATISFIABLE => Ok(None),
+            StatusCode::PARTIAL_CONTENT => Ok(Some(response.text().await?)),
+            // servers ignoring ranges send the whole WAL
+            _ => {
+                let wal = response.error_for_status()?.text().await?;
+                Ok(wal.get(start..).map(str::to_string))
+            }
+        }
+    }
+
+    /// The first `len` bytes of the WAL, fewer if it's shorter.
+    async fn read_head(&self, len: usize) -> Result<Vec<u8>> {
+        if !self.is_remote() {
+            let mut head = vec![];
+            fs::File::open(&self.source)?
+                .take(len as u64)
+                .read_to_end(&mut head)?;
+            return Ok(head);
+        }
+        let response = self
+            .client
+            .get(&self.source)
+            .header(header::RANGE, format!("bytes=0-{}", len.max(1) - 1))
+            .send()
+            .await?;
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(vec![]);
+        }
+        let mut head = response.error_for_status()?.bytes().await?.to_vec();
+        // servers ignoring ranges send the whole WAL
+        head.truncate(len);
+        Ok(head)
+    }
+
+    /// Applies the complete records of `pending`, the part of the WAL following the records
+    /// applied so far, returning how many were applied. With `rotated`, `pending` is a new WAL
+    /// (the previous one was truncated or rotated), replayed from the start into the emptied
+    /// store: records are applied in order, so the store converges to the state the WAL
+    /// describes.
+    pub fn apply(&mut self, kv_store: &KVStore, pending: &str, rotated: bool) -> Result<usize> {
+        if rotated {
+            tracing::warn!(
+                "The WAL at {} was truncated or rotated, replaying it from the start",
+                self.source
+            );
+            // keys the new WAL doesn't mention must not linger
+            kv_store.drain_prefix("")?;
+            self.offset = 0;
+        }
+        // the last line may still be being written
+        let Some(end) = pending.rfind('\n') else {
+            return Ok(0);
+        };
+        let mut applied = 0;
+        for line in pending[..end].lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<ChangeEvent>(line) {
+                Ok(event) => {
+                    apply_event(kv_store, event)?;
+                    applied += 1;
+                }
+                Err(e) => tracing::warn!("Skipping invalid WAL record: {}", e),
+            }
+        }
+        if self.offset == 0 {
+            self.first_record = pending.lines().next().map(str::to_string);
+        }
+        self.offset += end + 1;
+        Ok(applied)
+    }
+
+    /// Reads the new part of the WAL and applies its records.
+    pub async fn poll(&mut self, kv_store: &KVStore) -> Result<usize> {
+        let (pending, rotated) = self.fetch().await?;
+        self.apply(kv_store, &pending, rotated)
+    }
+}
+
+/// Polls the WAL every `interval` forever, applying its new records to `kv_store`.
+pub async fn follow_wal(kv_store: KVStore, mut follower: WalFollower, interval: time::Duration) {
+    loop {
+        match follower.poll(&kv_store).await {
+            Ok(0) => {}
+            Ok(applied) => tracing::debug!("Applied {} WAL records", applied),
+            Err(e) => tracing::warn!("Could not follow the WAL at {}: {}", follower.source, e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(kv_store: &KVStore) -> Vec<(String, serde_json::Value)> {
+        let mut keys = kv_store.list_keys(None).unwrap();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| (key.clone(), kv_store.get(key).unwrap()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_wal_replay_reproduces_primary() {
+        let wal_path = ".quache-wal-test.log";
+        let _ = fs::remove_file(wal_path);
+        let primary = KVStore::builder().in_memory().build().unwrap();
+        write_wal(&primary, wal_path).expect("Should be able to open the WAL");
+        primary
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .unwrap();
+        primary
+            .put(
+                "session".to_string(),
+                serde_json::json!({"a": 1}),
+                Some(60_f64),
+            )
+            .unwrap();
+        primary
+            .put("gone".to_string(), serde_json::Value::from(2), None)
+            .unwrap();
+        primary
+            .incr_bounded("hey".to_string(), 5, None, None)
+            .unwrap();
+        primary.delete("gone".to_string()).unwrap();
+
+        // the replica's own write policies don't apply to replayed puts
+        let replica = KVStore::builder()
+            .in_memory()
+            .build()
+            .unwrap()
+            .with_default_ttl(Some(1_f64))
+            .with_strict_ttl_seconds(true);
+        let mut follower = WalFollower::new(wal_path);
+        assert_eq!(follower.poll(&replica).await.unwrap(), 5);
+        assert_eq!(snapshot(&replica), snapshot(&primary));
+        assert!(replica.get_with_ttl("hey".to_string()).unwrap().1.is_none());
+        let expires_at = |kv_store: &KVStore| {
+            let entry = kv_store.entry("session".to_string()).unwrap();
+            entry.timestamp() as i128 + entry.ttl_ms()
+        };
+        assert_eq!(expires_at(&replica), expires_at(&primary));
+
+        // only new records are read and applied, and half-written ones wait for the next poll
+        primary
+            .put("hello".to_string(), serde_json::Value::from(3), None)
+            .unwrap();
+        assert_eq!(follower.poll(&replica).await.unwrap(), 1);
+        assert_eq!(follower.poll(&replica).await.unwrap(), 0);
+        assert_eq!(snapshot(&replica), snapshot(&primary));
+        let mut wal = OpenOptions::new().append(true).open(wal_path).unwrap();
+        wal.write_all(b"{\"op\"").unwrap();
+        assert_eq!(follower.poll(&replica).await.unwrap(), 0);
+        wal.write_all(b":\"put\",\"key\":\"half\",\"value\":6}\n")
+            .unwrap();
+        assert_eq!(follower.poll(&replica).await.unwrap(), 1);
+        assert_eq!(replica.get("half".to_string()).unwrap(), 6);
+
+        // a truncated WAL is replayed from the start, into the emptied store
+        fs::write(wal_path, "{\"op\":\"put\",\"key\":\"fresh\",\"value\":4}\n").unwrap();
+        assert_eq!(follower.poll(&replica).await.unwrap(), 1);
+        assert_eq!(replica.get("fresh".to_string()).unwrap(), 4);
+        assert_eq!(replica.list_keys(None).unwrap(), vec!["fresh".to_string()]);
+        // and so is a rotated one, even if it's longer
+        fs::write(
+            wal_path,
+            "{\"op\":\"put\",\"key\":\"other\",\"value\":7}\n\
+            {\"op\":\"put\",\"key\":\"more\",\"value\":8}\n",
+        )
+        .unwrap();
+        assert_eq!(follower.poll(&replica).await.unwrap(), 2);
+        let mut keys = replica.list_keys(None).unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["more".to_string(), "other".to_string()]);
+
+        // evictions are not logged, and stale expirations don't delete newer values
+        fs::remove_file(wal_path).unwrap();
+        write_wal(&primary, wal_path).expect("Should be able to open the WAL");
+        primary
+            .put("short".to_string(), serde_json::Value::from(5), Some(0.001))
+            .unwrap();
+        std::thread::sleep(time::Duration::from_millis(5));
+        primary.cleanup().unwrap();
+        assert!(!fs::read_to_string(wal_path).unwrap().contains("\"expire\""));
+        let stale = "{\"op\":\"put\",\"key\":\"other\",\"value\":4}\n\
+            {\"op\":\"expire\",\"key\":\"other\"}\n";
+        assert_eq!(
+            WalFollower::new(wal_path)
+                .apply(&replica, stale, false)
+                .unwrap(),
+            2
+        );
+        assert_eq!(replica.get("other".to_string()).unwrap(), 4);
+
+        fs::remove_file(wal_path).unwrap();
+    }
+}
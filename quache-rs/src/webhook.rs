@@ -0,0 +1,150 @@
+use std::time::{self, Duration};
+
+use anyhow::Result;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    core::KVStore,
+    events::{ChangeEvent, ChangeOp},
+};
+
+/// Header carrying the shared secret, so that receivers can tell webhooks from forged requests
+pub const WEBHOOK_SECRET_HEADER: &str = "x-quache-webhook-secret";
+/// Changes waiting to be delivered: once full, further changes are dropped
+const WEBHOOK_QUEUE_CAPACITY: usize = 1024;
+const WEBHOOK_MAX_ATTEMPTS: u32 = 5;
+const WEBHOOK_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Body of a webhook, sent for every change.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WebhookPayload {
+    pub op: ChangeOp,
+    pub key: String,
+    /// New value of the key, omitted for deletes and expirations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    /// Millisecond timestamp of the change
+    pub timestamp: u64,
+}
+
+/// POSTs a [`WebhookPayload`] to `url` for every change of `kv_store`, with `secret` (if any) in
+/// the [`WEBHOOK_SECRET_HEADER`] header. Must be called within a tokio runtime.
+///
+/// Delivery is best-effort and in order: failed deliveries are retried with exponential
+/// backoff, then logged and dropped. Writers only enqueue the change, they never wait for it to
+/// be delivered; changes are dropped (and logged) if the queue is full.
+pub fn send_webhooks(kv_store: &KVStore, url: &str, secret: Option<String>) -> Result<()> {
+    let url = Url::parse(url)?;
+    let (sender, receiver) = mpsc::channel(WEBHOOK_QUEUE_CAPACITY);
+    kv_store.on_change(move |event: &ChangeEvent| {
+        let payload = WebhookPayload {
+            op: event.op,
+            key: event.key.clone(),
+            value: event.value.clone(),
+            timestamp: time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64,
+        };
+        if sender.try_send(payload).is_err() {
+            tracing::warn!("Webhook queue full, dropping a change");
+        }
+    });
+    tokio::spawn(deliver_webhooks(Client::new(), url, secret, receiver));
+    Ok(())
+}
+
+async fn deliver_webhooks(
+    client: Client,
+    url: Url,
+    secret: Option<String>,
+    mut receiver: mpsc::Receiver<WebhookPayload>,
+) {
+    while let Some(payload) = receiver.recv().await {
+        let mut backoff = WEBHOOK_INITIAL_BACKOFF;
+        for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+            let mut request = client.post(url.clone()).json(&payload);
+            if let Some(secret) = &secret {
+                request = request.header(WEBHOOK_SECRET_HEADER, secret);
+            }
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => break,
+                Ok(response) => format!("status {}", response.status()),
+                Err(e) => e.to_string(),
+            };
+            if attempt == WEBHOOK_MAX_ATTEMPTS {
+                tracing::warn!(
+                    "Giving up on a webhook after {} attempts: {}",
+                    attempt,
+                    error
+                );
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Json, Router, http::HeaderMap, routing::post};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_webhook_fires_on_put() {
+        let (received_sender, mut received) = mpsc::unbounded_channel();
+        let receiver_app = Router::new().route(
+            "/hook",
+            post(
+                move |headers: HeaderMap, Json(payload): Json<WebhookPayload>| {
+                    let received_sender = received_sender.clone();
+                    async move {
+                        let secret = headers
+                            .get(WEBHOOK_SECRET_HEADER)
+                            .map(|value| value.to_str().unwrap().to_string());
+                        received_sender.send((secret, payload)).unwrap();
+                    }
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver_app).await });
+
+        let kv_store = KVStore::builder()
+            .in_memory()
+            .build()
+            .expect("Should be able to create KV store");
+        send_webhooks(
+            &kv_store,
+            &format!("http://{}/hook", addr),
+            Some("s3cret".to_string()),
+        )
+        .expect("Should be able to set up webhooks");
+        kv_store
+            .put("hello".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        kv_store
+            .delete("hello".to_string())
+            .expect("Should be able to delete key");
+
+        for (op, value) in [
+            (ChangeOp::Put, Some(serde_json::Value::from(1))),
+            (ChangeOp::Delete, None),
+        ] {
+            let (secret, payload) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await
+                .expect("The webhook should fire")
+                .unwrap();
+            assert_eq!(secret.as_deref(), Some("s3cret"));
+            assert_eq!(payload.op, op);
+            assert_eq!(payload.key, "hello");
+            assert_eq!(payload.value, value);
+            assert!(payload.timestamp > 0);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    str::FromStr,
+    time::{self, Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{core::KVStore, namespaces::Namespaces};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+/// How many times larger than the smallest TTL the cleanup interval can be before warning
+const CLEANUP_INTERVAL_WARNING_RATIO: u128 = 10;
+
+/// Daily time range (in UTC) during which cleanup runs more thoroughly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaintenanceWindow {
+    /// Start of the window, in minutes since midnight
+    start: u32,
+    /// End of the window (exclusive), in minutes since midnight
+    end: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CleanupMode {
+    /// Evict expired entries only
+    Light,
+    /// Evict expired entries, then compact shards to release memory
+    Full,
+}
+
+fn parse_minute_of_day(s: &str) -> Result<u32> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("{} is not in HH:MM format", s))?;
+    let hours: u32 = hours.parse()?;
+    let minutes: u32 = minutes.parse()?;
+    if hours > 23 || minutes > 59 {
+        return Err(anyhow!("{} is not a valid time of day", s));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("{} is not in HH:MM-HH:MM format", s))?;
+        Ok(Self {
+            start: parse_minute_of_day(start)?,
+            end: parse_minute_of_day(end)?,
+        })
+    }
+}
+
+impl MaintenanceWindow {
+    /// Whether a time of day (in minutes since midnight) falls in the window.
+    /// Windows whose end precedes their start wrap around midnight.
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            minute_of_day >= self.start && minute_of_day < self.end
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+pub fn cleanup_mode(window: Option<&MaintenanceWindow>, minute_of_day: u32) -> CleanupMode {
+    match window {
+        Some(w) if w.contains(minute_of_day) => CleanupMode::Full,
+        _ => CleanupMode::Light,
+    }
+}
+
+fn current_minute_of_day() -> u32 {
+    let secs = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    ((secs / 60) % MINUTES_PER_DAY as u64) as u32
+}
+
+/// Flushes the stores whose flushing isn't paused. Paused stores keep their unflushed changes
+/// for the first tick after they are resumed.
+fn flush_tick(kv_stores: &[KVStore]) {
+    for kv_store in kv_stores {
+        let flush_result = kv_store.to_disk_unless_paused();
+        match flush_result {
+            Ok(false) => tracing::debug!("Flushing of {} is paused", kv_store.directory()),
+            Ok(true) => tracing::debug!("Flushed {} to disk", kv_store.directory()),
+            Err(e) => tracing::error!("An error occurred while flushing to disk: {}", e),
+        }
+    }
+}
+
+pub fn to_disk_worker(kv_stores: Vec<KVStore>, flushing_interval: u64) {
+    loop {
+        std::thread::sleep(time::Duration::from_millis(flushing_interval));
+        flush_tick(&kv_stores);
+    }
+}
+
+/// Flushes the namespaces every `flushing_interval` milliseconds, picking up the ones created
+/// since the previous tick. Dropped namespaces have their flushing paused, so they are skipped.
+pub fn namespaces_to_disk_worker(namespaces: Namespaces, flushing_interval: u64) {
+    loop {
+        std::thread::sleep(time::Duration::from_millis(flushing_interval));
+        match namespaces.stores() {
+            Ok(kv_stores) => flush_tick(&kv_stores),
+            Err(e) => tracing::error!("Could not list the namespaces to flush: {}", e),
+        }
+    }
+}
+
+/// Flush schedule of the shards of a store, each checked on its own interval. A shard found with
+/// changes to flush is checked twice as often next time (down to the minimum interval), one found
+/// unchanged half as often (up to the maximum interval): hot shards are flushed often, while cold
+/// ones are barely looked at and never rewritten needlessly.
+#[derive(Debug)]
+pub struct ShardFlushSchedule {
+    min_interval: Duration,
+    max_interval: Duration,
+    /// Current interval and next check of every shard
+    shards: Vec<(Duration, Instant)>,
+}
+
+impl ShardFlushSchedule {
+    /// Every shard starts at the minimum interval, due at `now`.
+    pub fn new(
+        num_shards: usize,
+        min_interval: Duration,
+        max_interval: Duration,
+        now: Instant,
+    ) -> Self {
+        Self {
+            min_interval,
+            max_interval: max_interval.max(min_interval),
+            shards: vec![(min_interval, now); num_shards],
+        }
+    }
+
+    /// Flushes the shards due at `now` that changed since they were last flushed, returning
+    /// their indices. Shards failing to flush are retried at the minimum interval.
+    pub fn tick(&mut self, kv_store: &KVStore, now: Instant) -> Result<Vec<usize>> {
+        let mut flushed = vec![];
+        let mut first_error = None;
+        for (shard_idx, (interval, next_check)) in self.shards.iter_mut().enumerate() {
+            if now < *next_check {
+                continue;
+            }
+            *interval = match kv_store.flush_shard(shard_idx) {
+                Ok(true) => {
+                    flushed.push(shard_idx);
+                    (*interval / 2).max(self.min_interval)
+                }
+                Ok(false) => (*interval * 2).min(self.max_interval),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                    self.min_interval
+                }
+            };
+            *next_check = now + *interval;
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(flushed),
+        }
+    }
+}
+
+/// Flushes the shards of every store on their own adaptive schedule (see [`ShardFlushSchedule`]),
+/// instead of all at once like [`to_disk_worker`].
+pub fn shard_flush_worker(kv_stores: Vec<KVStore>, min_interval: u64, max_interval: u64) {
+    let now = Instant::now();
+    let mut schedules: Vec<ShardFlushSchedule> = kv_stores
+        .iter()
+        .map(|kv_store| {
+            ShardFlushSchedule::new(
+                kv_store.num_shards(),
+                Duration::from_millis(min_interval),
+                Duration::from_millis(max_interval),
+                now,
+            )
+        })
+        .collect();
+    loop {
+        std::thread::sleep(Duration::from_millis(min_interval));
+        for (kv_store, schedule) in kv_stores.iter().zip(&mut schedules) {
+            if kv_store.is_flushing_paused() {
+                tracing::debug!("Flushing of {} is paused", kv_store.directory());
+                continue;
+            }
+            match schedule.tick(kv_store, Instant::now()) {
+                Ok(flushed) if flushed.is_empty() => {}
+                Ok(flushed) => tracing::debug!(
+                    "Flushed shards {:?} of {} to disk",
+                    flushed,
+                    kv_store.directory()
+                ),
+                Err(e) => tracing::error!("An error occurred while flushing to disk: {}", e),
+            }
+        }
+    }
+}
+
+pub fn cleanup_worker(
+    kv_stores: Vec<KVStore>,
+    cleanup_interval: u64,
+    maintenance_window: Option<MaintenanceWindow>,
+) {
+    let mut warned = false;
+    loop {
+        std::thread::sleep(time::Duration::from_millis(cleanup_interval));
+        let mode = cleanup_mode(maintenance_window.as_ref(), current_minute_of_day());
+        for kv_store in &kv_stores {
+            if !warned
+                && let Some(min_ttl) = kv_store.min_ttl_seen()
+                && cleanup_interval as u128 > min_ttl.as_millis() * CLEANUP_INTERVAL_WARNING_RATIO
+            {
+                tracing::warn!(
+                    "The cleanup interval ({}ms) is much larger than the smallest TTL seen ({}ms), expired entries will linger in memory",
+                    cleanup_interval,
+                    min_ttl.as_millis()
+                );
+                warned = true;
+            }
+            let cleanup_result = kv_store.cleanup().and_then(|evicted| match mode {
+                CleanupMode::Full => kv_store.compact().map(|_| evicted),
+                CleanupMode::Light => Ok(evicted),
+            });
+            match cleanup_result {
+                Ok(evicted) => tracing::debug!(
+                    "Evicted {} expired entries from {}",
+                    evicted,
+                    kv_store.directory()
+                ),
+                Err(e) => {
+                    tracing::error!("An error occurred while cleaning up expired entries: {}", e)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_tick_skips_paused_stores() {
+        let kv_store = KVStore::new(3, ".quache-workers-test/".to_string())
+            .expect("Should be able to create KV store");
+        kv_store.pause_flushing();
+        kv_store
+            .put("hey".to_string(), serde_json::Value::from(1), None)
+            .expect("Should be able to put key");
+        flush_tick(std::slice::from_ref(&kv_store));
+        let shard_file =
+            crate::core::shard_file_path(".quache-workers-test/", kv_store.find_shard("hey"));
+        assert!(!std::fs::exists(&shard_file).unwrap());
+
+        kv_store.resume_flushing();
+        flush_tick(std::slice::from_ref(&kv_store));
+        assert!(std::fs::exists(&shard_file).unwrap());
+
+        std::fs::remove_dir_all(".quache-workers-test/")
+            .expect("Should be able to remove directory content");
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingTarget {
+        writes: std::sync::Mutex<std::collections::HashMap<usize, usize>>,
+    }
+
+    impl crate::flush::FlushTarget for CountingTarget {
+        fn write_shard(&self, shard_idx: usize, _contents: &str) -> Result<()> {
+            *self.writes.lock().unwrap().entry(shard_idx).or_default() += 1;
+            Ok(())
+        }
+
+        fn read_shard(&self, _shard_idx: usize) -> Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn describe(&self) -> String {
+            "counting".to_string()
+        }
+    }
+
+    #[test]
+    fn test_shard_flush_schedule_skips_cold_shards() {
+        let target = std::sync::Arc::new(CountingTarget::default());
+        let kv_store = KVStore::new_from_target(
+            3,
+            ".quache-workers-shard-flush/".to_string(),
+            target.clone(),
+        )
+        .expect("Should be able to create KV store");
+        let (hot, cold) = ("hey", "notthekindofthingyouwouldfind");
+        let (hot_shard, cold_shard) = (kv_store.find_shard(hot), kv_store.find_shard(cold));
+        assert_ne!(hot_shard, cold_shard);
+        for key in [hot, cold] {
+            kv_store
+                .put(key.to_string(), serde_json::Value::from(0), None)
+                .expect("Should be able to put key");
+        }
+
+        let start = Instant::now();
+        let min_interval = Duration::from_millis(100);
+        let mut schedule = ShardFlushSchedule::new(3, min_interval, Duration::from_secs(10), start);
+        let mut flushes = 0;
+        for tick in 0..5 {
+            if tick > 0 {
+                kv_store
+                    .put(hot.to_string(), serde_json::Value::from(tick), None)
+                    .expect("Should be able to put key");
+            }
+            let flushed = schedule
+                .tick(&kv_store, start + min_interval * tick)
+                .expect("Should be able to flush");
+            assert!(flushed.contains(&hot_shard));
+            flushes += 1;
+        }
+
+        let writes = target.writes.lock().unwrap();
+        assert_eq!(writes.get(&hot_shard), Some(&flushes));
+        // only written once, when first flushed
+        assert_eq!(writes.get(&cold_shard), Some(&1));
+    }
+
+    #[test]
+    fn test_maintenance_window_parse() {
+        let window: MaintenanceWindow = "02:30-04:00".parse().expect("Should be able to parse");
+        assert_eq!(
+            window,
+            MaintenanceWindow {
+                start: 150,
+                end: 240
+            }
+        );
+        assert!("2:30".parse::<MaintenanceWindow>().is_err());
+        assert!("24:00-01:00".parse::<MaintenanceWindow>().is_err());
+        assert!("01:60-02:00".parse::<MaintenanceWindow>().is_err());
+        assert!("aa:bb-cc:dd".parse::<MaintenanceWindow>().is_err());
+    }
+
+    #[test]
+    fn test_cleanup_mode_switches_with_window() {
+        let window: MaintenanceWindow = "02:30-04:00".parse().expect("Should be able to parse");
+        assert_eq!(cleanup_mode(Some(&window), 149), CleanupMode::Light);
+        assert_eq!(cleanup_mode(Some(&window), 150), CleanupMode::Full);
+        assert_eq!(cleanup_mode(Some(&window), 239), CleanupMode::Full);
+        assert_eq!(cleanup_mode(Some(&window), 240), CleanupMode::Light);
+        assert_eq!(cleanup_mode(None, 200), CleanupMode::Light);
+
+        // windows can span midnight
+        let overnight: MaintenanceWindow = "23:00-01:00".parse().expect("Should be able to parse");
+        assert_eq!(cleanup_mode(Some(&overnight), 22 * 60), CleanupMode::Light);
+        assert_eq!(
+            cleanup_mode(Some(&overnight), 23 * 60 + 30),
+            CleanupMode::Full
+        );
+        assert_eq!(cleanup_mode(Some(&overnight), 30), CleanupMode::Full);
+        assert_eq!(cleanup_mode(Some(&overnight), 60), CleanupMode::Light);
+    }
+}
@@ -0,0 +1,79 @@
+//! Exercises the public `core` API the way an embedding crate would, so it keeps working
+//! when the crate is built with `--no-default-features` (i.e. without the HTTP server).
+
+use quache_rs::core::{KVStore, Shard, shard_file_path};
+
+#[test]
+fn test_core_api_standalone() {
+    let directory = ".quache-standalone-test/".to_string();
+    let kv_store = KVStore::new(4, directory.clone()).expect("Should be able to create KV store");
+    kv_store
+        .put("hello".to_string(), serde_json::Value::from("world"), None)
+        .expect("Should be able to put key");
+    kv_store
+        .put(
+            "counter".to_string(),
+            serde_json::Value::from(1),
+            Some(60_f64),
+        )
+        .expect("Should be able to put key");
+    assert_eq!(
+        kv_store
+            .get("hello".to_string())
+            .expect("Should be able to get key"),
+        serde_json::Value::from("world")
+    );
+    kv_store.to_disk().expect("Should be able to flush to disk");
+
+    let restored =
+        KVStore::new_from_disk(4, directory.clone()).expect("Should be able to load from disk");
+    assert_eq!(
+        restored
+            .get("counter".to_string())
+            .expect("Should be able to get key"),
+        serde_json::Value::from(1)
+    );
+    restored
+        .delete("hello".to_string())
+        .expect("Should be able to delete key");
+    assert!(restored.get("hello".to_string()).is_err());
+    assert_eq!(restored.metrics().snapshot().misses, 1);
+
+    std::fs::remove_dir_all(&directory).expect("Should be able to remove directory content");
+}
+
+#[test]
+fn test_shard_entries_snapshot() {
+    let directory = ".quache-standalone-snapshot-test/".to_string();
+    let kv_store = KVStore::new(1, directory.clone()).expect("Should be able to create KV store");
+    kv_store
+        .put("b".to_string(), serde_json::Value::from(2), Some(60_f64))
+        .expect("Should be able to put key");
+    kv_store
+        .put("a".to_string(), serde_json::Value::from(1), None)
+        .expect("Should be able to put key");
+    kv_store.to_disk().expect("Should be able to flush to disk");
+
+    let shard = Shard::from_file(&shard_file_path(&directory, 0))
+        .expect("Should be able to load the shard");
+    let snapshot = shard
+        .entries_snapshot()
+        .expect("Should be able to snapshot entries");
+    let keys: Vec<&str> = snapshot.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["a", "b"]);
+
+    let (_, persistent) = &snapshot[0];
+    assert_eq!(persistent.value(), &serde_json::Value::from(1));
+    assert_eq!(persistent.ttl_ms(), -1);
+    assert_eq!(persistent.remaining_ttl(), None);
+
+    let (_, expiring) = &snapshot[1];
+    assert_eq!(expiring.value(), &serde_json::Value::from(2));
+    assert_eq!(expiring.ttl_ms(), 60_000);
+    assert!(expiring.timestamp() > 0);
+    let remaining = expiring.remaining_ttl().expect("Should have a TTL");
+    assert!(remaining <= std::time::Duration::from_secs(60));
+    assert!(remaining > std::time::Duration::from_secs(55));
+
+    std::fs::remove_dir_all(&directory).expect("Should be able to remove directory content");
+}